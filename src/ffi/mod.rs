@@ -14,6 +14,7 @@ pub use session::*;
 pub use nat_traversal::*;
 
 use std::os::raw::{c_char, c_void};
+use std::cell::RefCell;
 use std::ffi::{CStr, CString};
 use std::panic;
 
@@ -44,35 +45,34 @@ pub extern "C" fn pineapple_free_string(ptr: *mut c_char) {
     }
 }
 
-/// Get last error message
-static mut LAST_ERROR: Option<String> = None;
+thread_local! {
+    /// Last error of each calling thread, kept as a C string so it can be lent out
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
 
+/// Get last error message of the calling thread
+///
+/// The pointer is owned by the library (do not free it) and stays valid
+/// until the next call on this thread that sets or clears the error.
 #[no_mangle]
 pub extern "C" fn pineapple_last_error() -> *const c_char {
-    unsafe {
-        match &LAST_ERROR {
-            Some(err) => {
-                let c_str = CString::new(err.as_str()).unwrap();
-                c_str.into_raw()
-            }
-            None => std::ptr::null(),
-        }
-    }
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(err) => err.as_ptr(),
+        None => std::ptr::null(),
+    })
 }
 
-/// Set last error (internal helper)
+/// Set last error of the calling thread (internal helper)
 pub(crate) fn set_last_error(error: &str) {
-    unsafe {
-        LAST_ERROR = Some(error.to_string());
-    }
+    // An interior NUL would truncate the message in C; drop it instead of failing
+    let err = CString::new(error.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(err));
 }
 
-/// Clear last error
+/// Clear last error of the calling thread
 #[no_mangle]
 pub extern "C" fn pineapple_clear_error() {
-    unsafe {
-        LAST_ERROR = None;
-    }
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
 }
 
 /// Helper to convert C string to Rust string
@@ -87,3 +87,41 @@ pub(crate) fn c_str_to_rust(ptr: *const c_char) -> Option<String> {
             .map(|s| s.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    fn last_error() -> Option<String> {
+        let ptr = pineapple_last_error();
+        (!ptr.is_null()).then(|| unsafe { CStr::from_ptr(ptr) }.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn errors_stay_on_their_thread() {
+        // Both threads set their error before either reads, so a shared slot would show the other's
+        let barrier = Barrier::new(2);
+        std::thread::scope(|scope| {
+            for name in ["first", "second"] {
+                let barrier = &barrier;
+                scope.spawn(move || {
+                    assert_eq!(last_error(), None);
+                    set_last_error(&format!("{} failed", name));
+                    barrier.wait();
+                    assert_eq!(last_error().as_deref(), Some(format!("{} failed", name).as_str()));
+                    barrier.wait();
+                    pineapple_clear_error();
+                    assert_eq!(last_error(), None);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn interior_nul_is_dropped() {
+        set_last_error("bad\0input");
+        assert_eq!(last_error().as_deref(), Some("badinput"));
+        pineapple_clear_error();
+    }
+}
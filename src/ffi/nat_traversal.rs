@@ -5,9 +5,119 @@
  */
 
 use super::*;
-use crate::nat_traversal::{NatTraversal as RustNatTraversal, NatTraversalConfig as RustConfig};
-use std::os::raw::c_char;
+use crate::nat_traversal::{
+    CancelHandle, NatTraversal as RustNatTraversal, NatTraversalConfig as RustConfig, NatTraversalError,
+};
+use std::os::raw::{c_char, c_void};
 use std::ffi::CString;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::time::Instant;
+
+/// What a `NatTraversalHandle` points to
+///
+/// Only ever borrowed shared, since cancel and the state getters run on other
+/// threads while a connect is in flight: the connect holds the `nat` lock for
+/// the whole attempt, everything else goes through `shared`.
+struct FfiNatTraversal {
+    nat: Mutex<RustNatTraversal>,
+    shared: Arc<FfiShared>,
+    /// Stream of the last successful connect, until taken with pineapple_nat_get_tcp_fd
+    stream: Mutex<Option<std::net::TcpStream>>,
+}
+
+/// The parts of a handle used while a connect runs
+struct FfiShared {
+    /// Cloned at creation so a cancel never touches `nat`
+    cancel: CancelHandle,
+    /// Mirror of the pipeline state, kept by its state handler
+    progress: Mutex<Progress>,
+    on_state: Mutex<Option<(StateCallback, UserData)>>,
+    on_complete: Mutex<Option<(CompletionCallback, UserData)>>,
+}
+
+/// What pineapple_nat_get_progress reports
+struct Progress {
+    state: ConnectionState,
+    running: bool,
+    transitions: u32,
+    started: Instant,
+    state_since: Instant,
+    /// End of the last attempt, so its timings stop growing
+    ended: Option<Instant>,
+}
+
+impl FfiShared {
+    fn new(cancel: CancelHandle) -> Self {
+        let now = Instant::now();
+        Self {
+            cancel,
+            progress: Mutex::new(Progress {
+                state: ConnectionState::Idle,
+                running: false,
+                transitions: 0,
+                started: now,
+                state_since: now,
+                ended: Some(now),
+            }),
+            on_state: Mutex::new(None),
+            on_complete: Mutex::new(None),
+        }
+    }
+
+    fn attempt_started(&self) {
+        let now = Instant::now();
+        let mut progress = lock(&self.progress);
+        progress.running = true;
+        progress.transitions = 0;
+        progress.started = now;
+        progress.state_since = now;
+        progress.ended = None;
+    }
+
+    /// State handler of the wrapped `NatTraversal`
+    fn state_changed(&self, state: ConnectionState) {
+        {
+            let mut progress = lock(&self.progress);
+            progress.state = state;
+            progress.transitions += 1;
+            progress.state_since = Instant::now();
+        }
+        // Copied out so the callback may replace itself without deadlocking
+        let on_state = *lock(&self.on_state);
+        if let Some((callback, user_data)) = on_state {
+            callback(state, user_data.0);
+        }
+    }
+
+    fn attempt_ended(&self, result: ConnectResult) {
+        {
+            let mut progress = lock(&self.progress);
+            progress.running = false;
+            progress.ended = Some(Instant::now());
+        }
+        let on_complete = *lock(&self.on_complete);
+        if let Some((callback, user_data)) = on_complete {
+            callback(result, user_data.0);
+        }
+    }
+
+    fn progress(&self) -> NatProgress {
+        let progress = lock(&self.progress);
+        let now = progress.ended.unwrap_or_else(Instant::now);
+        NatProgress {
+            state: progress.state,
+            running: progress.running,
+            transitions: progress.transitions,
+            elapsed_ms: now.saturating_duration_since(progress.started).as_millis() as u64,
+            state_elapsed_ms: now.saturating_duration_since(progress.state_since).as_millis() as u64,
+        }
+    }
+}
+
+/// Lock a mutex, ignoring poisoning: no callback ever runs under these locks
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
 /// Create a new NAT traversal instance
 #[no_mangle]
@@ -60,19 +170,47 @@ pub extern "C" fn pineapple_nat_create(config: NatTraversalConfig) -> *mut NatTr
 
     let rust_config = RustConfig {
         signalling_url,
+        signalling_addrs: Vec::new(),
+        sni_hostname: None,
+        signalling_codec: Default::default(),
+        accept_invalid_certs: false,
         stun_server_addr,
+        stun_server_host: None,
+        stun_server_addr_v6: None,
+        stun_secondary_addr: None,
+        stun_attribute_preference: Default::default(),
+        stun_keepalive: Some(crate::nat_traversal::DEFAULT_STUN_KEEPALIVE),
+        turn_server_addr: None,
+        turn_credentials: None,
         local_fingerprint,
         signing_key,
         tcp_port: config.tcp_port,
+        tcp_bind_ip: std::net::Ipv4Addr::UNSPECIFIED.into(),
+        app_id: Vec::new(),
+        socket_options: Default::default(),
+        duplicate_grace: crate::nat_traversal::DEFAULT_DUPLICATE_GRACE,
     };
 
-    let nat = Box::new(RustNatTraversal::new(rust_config));
-    Box::into_raw(nat) as *mut NatTraversalHandle
+    into_handle(rust_config)
+}
+
+/// Wrap a configured `NatTraversal` in a handle whose state is mirrored for polling
+fn into_handle(config: RustConfig) -> *mut NatTraversalHandle {
+    let mut nat = RustNatTraversal::new(config);
+    let shared = Arc::new(FfiShared::new(nat.cancel_handle()));
+    let handler_shared = shared.clone();
+    nat.set_state_handler(move |state| handler_shared.state_changed(ffi_state(state)));
+
+    let handle = Box::new(FfiNatTraversal {
+        nat: Mutex::new(nat),
+        shared,
+        stream: Mutex::new(None),
+    });
+    Box::into_raw(handle) as *mut NatTraversalHandle
 }
 
 /// Connect to peer using NAT traversal
-/// Returns 0 on success, -1 on error
-/// The resulting TCP stream is stored internally and can be retrieved with pineapple_nat_get_tcp_fd
+/// Always fails; kept for ABI compatibility, use pineapple_nat_connect_blocking
 #[no_mangle]
 pub extern "C" fn pineapple_nat_connect(
     handle: *mut NatTraversalHandle,
@@ -91,13 +229,194 @@ pub extern "C" fn pineapple_nat_connect(
         }
     };
 
-    let nat = unsafe { &mut *(handle as *mut RustNatTraversal) };
-
     // This requires async runtime - for now, return error
     set_last_error("Async runtime required - use pineapple_nat_connect_blocking");
     -1
 }
 
+/// Connect to peer using NAT traversal, blocking the calling thread until done
+/// Returns 0 on success, -1 on error
+///
+/// Runs the whole pipeline on a runtime owned by this call, so call it from a
+/// background isolate/thread. The resulting TCP stream is stored in the handle
+/// and can be retrieved with pineapple_nat_get_tcp_fd; a later connect replaces
+/// (and closes) a stream that was never taken. The completion callback fires
+/// just before this returns. A second connect on the same handle while one
+/// runs fails straight away.
+#[no_mangle]
+pub extern "C" fn pineapple_nat_connect_blocking(
+    handle: *mut NatTraversalHandle,
+    peer_fingerprint: *const c_char,
+) -> i32 {
+    if handle.is_null() {
+        set_last_error("Null NAT traversal handle");
+        return -1;
+    }
+
+    let peer_fp = match c_str_to_rust(peer_fingerprint) {
+        Some(s) => s,
+        None => {
+            set_last_error("Invalid peer fingerprint");
+            return -1;
+        }
+    };
+
+    let ffi = unsafe { &*(handle as *const FfiNatTraversal) };
+    let mut nat = match ffi.nat.try_lock() {
+        Ok(nat) => nat,
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        Err(TryLockError::WouldBlock) => {
+            set_last_error("A connect is already running on this handle");
+            return -1;
+        }
+    };
+    ffi.shared.attempt_started();
+
+    // Unwinding across extern "C" aborts the host app, so stop it here
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        connect_blocking(&mut nat, &peer_fp)
+    }));
+    drop(nat);
+
+    let (code, outcome) = match result {
+        Ok(Ok(stream)) => {
+            *lock(&ffi.stream) = Some(stream);
+            (0, ConnectResult::Connected)
+        }
+        Ok(Err(e)) => {
+            set_last_error(&format!("Connect failed: {:#}", e));
+            match e.downcast_ref() {
+                Some(NatTraversalError::Cancelled) => (-1, ConnectResult::Cancelled),
+                _ => (-1, ConnectResult::Failed),
+            }
+        }
+        Err(panic) => {
+            let msg = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            set_last_error(&format!("Connect panicked: {}", msg));
+            (-1, ConnectResult::Failed)
+        }
+    };
+    ffi.shared.attempt_ended(outcome);
+    code
+}
+
+/// Run `connect` to completion and hand the stream back in blocking mode
+fn connect_blocking(nat: &mut RustNatTraversal, peer_fingerprint: &str) -> anyhow::Result<std::net::TcpStream> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(nat.connect(peer_fingerprint));
+    // A cancel can leave STUN/TURN/TCP calls on the blocking pool; let them
+    // run out their timeouts in the background instead of waiting here
+    runtime.shutdown_background();
+    let stream = result?;
+
+    // Callers expect a plain blocking fd whatever mode the pipeline left it in
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+/// Take the TCP stream of the last successful connect as a raw fd
+/// Returns the fd, or -1 if there is none
+///
+/// Ownership passes to the caller, who must close it (or hand it to the
+/// session functions). A second call returns -1 until the next connect.
+#[cfg(unix)]
+#[no_mangle]
+pub extern "C" fn pineapple_nat_get_tcp_fd(handle: *mut NatTraversalHandle) -> i32 {
+    use std::os::unix::io::IntoRawFd;
+
+    if handle.is_null() {
+        set_last_error("Null NAT traversal handle");
+        return -1;
+    }
+
+    let ffi = unsafe { &*(handle as *const FfiNatTraversal) };
+    match lock(&ffi.stream).take() {
+        Some(stream) => stream.into_raw_fd(),
+        None => {
+            set_last_error("No connected stream; call pineapple_nat_connect_blocking first");
+            -1
+        }
+    }
+}
+
+/// Cancel a connect running on another thread
+/// Returns 0 on success, -1 on error
+///
+/// The connect returns -1 with the last error set to "Connection attempt
+/// cancelled", the state becomes Failed and the completion callback fires with
+/// Cancelled. It does not wait for a STUN, TURN or TCP call in flight, which
+/// ends on its own timeout in the background. The handle can be reused for a
+/// new attempt. A cancel while no connect is running stops the next one, so a
+/// cancel racing the start of a connect is never lost.
+#[no_mangle]
+pub extern "C" fn pineapple_nat_cancel(handle: *const NatTraversalHandle) -> i32 {
+    if handle.is_null() {
+        set_last_error("Null NAT traversal handle");
+        return -1;
+    }
+
+    let ffi = unsafe { &*(handle as *const FfiNatTraversal) };
+    ffi.shared.cancel.cancel();
+    0
+}
+
+/// Register a callback fired on every state change of a connect
+/// Returns 0 on success, -1 on error
+///
+/// The callback runs on the thread calling pineapple_nat_connect_blocking,
+/// once per transition up to and including Connected or Failed. `user_data`
+/// is passed back untouched. Passing a null callback removes it. It can be
+/// changed while a connect runs and applies from the next transition.
+#[no_mangle]
+pub extern "C" fn pineapple_nat_set_state_callback(
+    handle: *const NatTraversalHandle,
+    callback: Option<StateCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    if handle.is_null() {
+        set_last_error("Null NAT traversal handle");
+        return -1;
+    }
+
+    let ffi = unsafe { &*(handle as *const FfiNatTraversal) };
+    *lock(&ffi.shared.on_state) = callback.map(|callback| (callback, UserData(user_data)));
+    0
+}
+
+/// Register a callback fired once at the end of every connect
+/// Returns 0 on success, -1 on error
+///
+/// The callback runs on the thread calling pineapple_nat_connect_blocking,
+/// right before it returns, with Connected, Failed or Cancelled. On Connected
+/// the stream can already be taken with pineapple_nat_get_tcp_fd. `user_data`
+/// is passed back untouched. Passing a null callback removes it.
+#[no_mangle]
+pub extern "C" fn pineapple_nat_set_completion_callback(
+    handle: *const NatTraversalHandle,
+    callback: Option<CompletionCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    if handle.is_null() {
+        set_last_error("Null NAT traversal handle");
+        return -1;
+    }
+
+    let ffi = unsafe { &*(handle as *const FfiNatTraversal) };
+    *lock(&ffi.shared.on_complete) = callback.map(|callback| (callback, UserData(user_data)));
+    0
+}
+
+/// Caller context handed back to a callback
+#[derive(Clone, Copy)]
+struct UserData(*mut c_void);
+
+// The pointer is only passed back to the caller, never dereferenced here
+unsafe impl Send for UserData {}
+
 /// Get current connection state
 #[no_mangle]
 pub extern "C" fn pineapple_nat_get_state(handle: *const NatTraversalHandle) -> ConnectionState {
@@ -105,10 +424,36 @@ pub extern "C" fn pineapple_nat_get_state(handle: *const NatTraversalHandle) ->
         return ConnectionState::Failed;
     }
 
-    let nat = unsafe { &*(handle as *const RustNatTraversal) };
-    
-    match nat.state() {
+    let ffi = unsafe { &*(handle as *const FfiNatTraversal) };
+    lock(&ffi.shared.progress).state
+}
+
+/// Get the progress of the running (or last) connect
+/// Returns 0 on success, -1 on error
+///
+/// Can be polled from any thread while pineapple_nat_connect_blocking runs.
+/// Before the first connect the state is Idle and the timings are zero.
+///
+/// # Safety
+///
+/// `progress` must be null or valid for writing one `NatProgress`.
+#[no_mangle]
+pub unsafe extern "C" fn pineapple_nat_get_progress(handle: *const NatTraversalHandle, progress: *mut NatProgress) -> i32 {
+    if handle.is_null() || progress.is_null() {
+        set_last_error("Null NAT traversal handle or progress");
+        return -1;
+    }
+
+    let ffi = unsafe { &*(handle as *const FfiNatTraversal) };
+    progress.write(ffi.shared.progress());
+    0
+}
+
+/// Map a connection state onto its C enum
+fn ffi_state(state: &crate::nat_traversal::ConnectionState) -> ConnectionState {
+    match state {
         crate::nat_traversal::ConnectionState::Idle => ConnectionState::Idle,
+        crate::nat_traversal::ConnectionState::Discovering => ConnectionState::Discovering,
         crate::nat_traversal::ConnectionState::ConnectingSignalling => ConnectionState::ConnectingSignalling,
         crate::nat_traversal::ConnectionState::Registering => ConnectionState::Registering,
         crate::nat_traversal::ConnectionState::StunDiscovery => ConnectionState::StunDiscovery,
@@ -116,17 +461,21 @@ pub extern "C" fn pineapple_nat_get_state(handle: *const NatTraversalHandle) ->
         crate::nat_traversal::ConnectionState::WaitingForOffer => ConnectionState::WaitingForOffer,
         crate::nat_traversal::ConnectionState::UdpHolePunching => ConnectionState::UdpHolePunching,
         crate::nat_traversal::ConnectionState::TcpConnecting => ConnectionState::TcpConnecting,
+        crate::nat_traversal::ConnectionState::RelayFallback => ConnectionState::RelayFallback,
+        crate::nat_traversal::ConnectionState::TurnAllocating => ConnectionState::TurnAllocating,
         crate::nat_traversal::ConnectionState::Connected => ConnectionState::Connected,
         crate::nat_traversal::ConnectionState::Failed(_) => ConnectionState::Failed,
     }
 }
 
 /// Free NAT traversal instance
+///
+/// No other call on the handle may be running or made afterwards.
 #[no_mangle]
 pub extern "C" fn pineapple_nat_free(handle: *mut NatTraversalHandle) {
     if !handle.is_null() {
         unsafe {
-            let _ = Box::from_raw(handle as *mut RustNatTraversal);
+            let _ = Box::from_raw(handle as *mut FfiNatTraversal);
         }
     }
 }
@@ -145,8 +494,104 @@ pub extern "C" fn pineapple_state_to_string(state: ConnectionState) -> *const c_
         ConnectionState::TcpConnecting => "TCP connecting",
         ConnectionState::Connected => "Connected",
         ConnectionState::Failed => "Failed",
+        ConnectionState::RelayFallback => "Falling back to relay",
+        ConnectionState::TurnAllocating => "Allocating TURN relay",
+        ConnectionState::Discovering => "Contacting signalling and STUN",
     };
 
     let c_str = CString::new(s).unwrap();
     c_str.into_raw()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nat_traversal::test_harness::LoopbackHarness;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    /// Handle shared with the connecting thread, which the C side would do with the raw pointer
+    #[derive(Clone, Copy)]
+    struct SendHandle(*mut NatTraversalHandle);
+
+    unsafe impl Send for SendHandle {}
+
+    extern "C" fn record_result(result: ConnectResult, user_data: *mut c_void) {
+        let results = unsafe { &*(user_data as *const mpsc::Sender<ConnectResult>) };
+        let _ = results.send(result);
+    }
+
+    fn progress(handle: *mut NatTraversalHandle) -> NatProgress {
+        let mut progress = std::mem::MaybeUninit::uninit();
+        assert_eq!(unsafe { pineapple_nat_get_progress(handle, progress.as_mut_ptr()) }, 0);
+        unsafe { progress.assume_init() }
+    }
+
+    /// Run the blocking connect off the runtime workers, which the harness servers need
+    fn connect_in_background(handle: SendHandle, peer: &'static str) -> tokio::task::JoinHandle<i32> {
+        tokio::task::spawn_blocking(move || {
+            let handle = handle;
+            let peer = CString::new(peer).unwrap();
+            pineapple_nat_connect_blocking(handle.0, peer.as_ptr())
+        })
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancel_is_reported_and_the_handle_reused() {
+        let harness = LoopbackHarness::start().await.unwrap();
+        let alice = into_handle(harness.config("alice"));
+        let (tx, results) = mpsc::channel();
+        let tx_ptr = &tx as *const mpsc::Sender<ConnectResult> as *mut c_void;
+        assert_eq!(pineapple_nat_set_completion_callback(alice, Some(record_result), tx_ptr), 0);
+        assert_eq!(progress(alice).state, ConnectionState::Idle);
+
+        // Bob is not there yet, so alice waits on her offer until cancelled from this thread
+        let connecting = connect_in_background(SendHandle(alice), "bob");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pineapple_nat_get_state(alice) != ConnectionState::SendingOffer {
+            assert!(Instant::now() < deadline, "never reached SendingOffer");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let running = progress(alice);
+        assert!(running.running);
+        assert_eq!(running.transitions, 2);
+
+        assert_eq!(pineapple_nat_cancel(alice), 0);
+        assert_eq!(connecting.await.unwrap(), -1);
+        assert_eq!(results.recv_timeout(Duration::from_secs(1)), Ok(ConnectResult::Cancelled));
+        let ended = progress(alice);
+        assert!(!ended.running);
+        assert_eq!(ended.state, ConnectionState::Failed);
+        assert_eq!(pineapple_nat_get_tcp_fd(alice), -1);
+
+        // Same handle, now with bob connecting back
+        let bob = into_handle(harness.config("bob"));
+        let alice_done = connect_in_background(SendHandle(alice), "bob");
+        let bob_done = connect_in_background(SendHandle(bob), "alice");
+        assert_eq!(alice_done.await.unwrap(), 0);
+        assert_eq!(bob_done.await.unwrap(), 0);
+        assert_eq!(results.recv_timeout(Duration::from_secs(1)), Ok(ConnectResult::Connected));
+        assert_eq!(pineapple_nat_get_state(alice), ConnectionState::Connected);
+
+        let fd = pineapple_nat_get_tcp_fd(alice);
+        assert!(fd >= 0);
+        unsafe { libc::close(fd) };
+        pineapple_nat_free(alice);
+        pineapple_nat_free(bob);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cancel_before_connect_stops_it() {
+        let harness = LoopbackHarness::start().await.unwrap();
+        let alice = into_handle(harness.config("alice"));
+        let (tx, results) = mpsc::channel();
+        let tx_ptr = &tx as *const mpsc::Sender<ConnectResult> as *mut c_void;
+        assert_eq!(pineapple_nat_set_completion_callback(alice, Some(record_result), tx_ptr), 0);
+
+        assert_eq!(pineapple_nat_cancel(alice), 0);
+        assert_eq!(connect_in_background(SendHandle(alice), "bob").await.unwrap(), -1);
+        assert_eq!(results.recv_timeout(Duration::from_secs(1)), Ok(ConnectResult::Cancelled));
+        pineapple_nat_free(alice);
+    }
+}
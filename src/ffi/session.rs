@@ -9,34 +9,145 @@ use crate::{Session as RustSession, pqxdh};
 use std::os::raw::c_char;
 
 /// Create a new user identity
+///
+/// Returns the identity encoded with `User::to_bytes`, private keys
+/// included; store it securely and free it with pineapple_free_buffer.
+/// Returns an empty buffer on error.
 #[no_mangle]
 pub extern "C" fn pineapple_user_new() -> ByteBuffer {
     let user = pqxdh::User::new();
-    
-    // Serialize user to bytes (you'll need to implement serialization)
-    // For now, return empty buffer
-    ByteBuffer::empty()
+
+    match user.to_bytes() {
+        Ok(bytes) => ByteBuffer::from_secret_vec(bytes),
+        Err(e) => {
+            set_last_error(&format!("User serialization failed: {}", e));
+            ByteBuffer::empty()
+        }
+    }
+}
+
+/// Public prekey bundle of an encoded user, to hand to peers
+/// Returns an empty buffer on error
+///
+/// # Safety
+///
+/// `user_bytes` must be null or point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn pineapple_user_bundle(user_bytes: ByteBuffer) -> ByteBuffer {
+    match pqxdh::User::from_bytes(unsafe { user_bytes.as_slice() }) {
+        Ok(user) => ByteBuffer::from_vec(crate::network::serialize_prekey_bundle(&user)),
+        Err(e) => {
+            set_last_error(&format!("Invalid user: {}", e));
+            ByteBuffer::empty()
+        }
+    }
 }
 
 /// Create session as initiator (Alice)
+///
+/// `alice_bytes` is our encoded user and `bob_bytes` the peer's prekey
+/// bundle (from pineapple_user_bundle). The PQXDH init message is written to
+/// `init_message_out`; it must reach the peer before any message from this
+/// session. Input buffers stay owned by the caller.
+/// Returns null on error
+///
+/// # Safety
+///
+/// Both input buffers must be null or point to `len` readable bytes, and
+/// `init_message_out` must be null or valid for writing one `ByteBuffer`.
 #[no_mangle]
-pub extern "C" fn pineapple_session_new_initiator(
+pub unsafe extern "C" fn pineapple_session_new_initiator(
     alice_bytes: ByteBuffer,
     bob_bytes: ByteBuffer,
+    init_message_out: *mut ByteBuffer,
 ) -> *mut SessionHandle {
-    // This is a placeholder - proper implementation would deserialize users
-    // and create session
-    std::ptr::null_mut()
+    if init_message_out.is_null() {
+        set_last_error("Invalid arguments");
+        return std::ptr::null_mut();
+    }
+
+    let alice = match pqxdh::User::from_bytes(unsafe { alice_bytes.as_slice() }) {
+        Ok(user) => user,
+        Err(e) => {
+            set_last_error(&format!("Invalid user: {}", e));
+            return std::ptr::null_mut();
+        }
+    };
+    let mut bob = match crate::network::deserialize_prekey_bundle(unsafe { bob_bytes.as_slice() }) {
+        Ok(bundle) => bundle,
+        Err(e) => {
+            set_last_error(&format!("Invalid prekey bundle: {:#}", e));
+            return std::ptr::null_mut();
+        }
+    };
+
+    match RustSession::new_initiator(&alice, &mut bob) {
+        Ok((mut session, init_message)) => {
+            // Handing it to the caller is as far as this side can take it
+            session.init_message_sent();
+            let serialized = crate::network::serialize_pqxdh_init_message(&init_message);
+            unsafe { init_message_out.write(ByteBuffer::from_vec(serialized)) };
+            Box::into_raw(Box::new(session)) as *mut SessionHandle
+        }
+        Err(e) => {
+            set_last_error(&format!("Session setup failed: {}", e));
+            std::ptr::null_mut()
+        }
+    }
 }
 
 /// Create session as responder (Bob)
+///
+/// `bob_bytes` is our encoded user and `init_message_bytes` the init message
+/// received from the initiator. Answering consumes one-time prekeys; if
+/// `bob_out` is not null the updated user is written there and should
+/// replace the stored one. Input buffers stay owned by the caller.
+/// Returns null on error
+///
+/// # Safety
+///
+/// Both input buffers must be null or point to `len` readable bytes, and
+/// `bob_out` must be null or valid for writing one `ByteBuffer`.
 #[no_mangle]
-pub extern "C" fn pineapple_session_new_responder(
+pub unsafe extern "C" fn pineapple_session_new_responder(
     bob_bytes: ByteBuffer,
     init_message_bytes: ByteBuffer,
+    bob_out: *mut ByteBuffer,
 ) -> *mut SessionHandle {
-    // Placeholder
-    std::ptr::null_mut()
+    let mut bob = match pqxdh::User::from_bytes(unsafe { bob_bytes.as_slice() }) {
+        Ok(user) => user,
+        Err(e) => {
+            set_last_error(&format!("Invalid user: {}", e));
+            return std::ptr::null_mut();
+        }
+    };
+    let init_message = match crate::network::deserialize_pqxdh_init_message(unsafe { init_message_bytes.as_slice() }) {
+        Ok(message) => message,
+        Err(e) => {
+            set_last_error(&format!("Invalid init message: {}", e));
+            return std::ptr::null_mut();
+        }
+    };
+
+    let session = match RustSession::new_responder(&mut bob, &init_message) {
+        Ok(session) => session,
+        Err(e) => {
+            set_last_error(&format!("Session setup failed: {}", e));
+            return std::ptr::null_mut();
+        }
+    };
+
+    if !bob_out.is_null() {
+        match bob.to_bytes() {
+            Ok(bytes) => unsafe { bob_out.write(ByteBuffer::from_secret_vec(bytes)) },
+            Err(e) => {
+                set_last_error(&format!("User serialization failed: {}", e));
+                return std::ptr::null_mut();
+            }
+        }
+    }
+
+    Box::into_raw(Box::new(session)) as *mut SessionHandle
 }
 
 /// Send message through session
@@ -92,7 +203,7 @@ pub extern "C" fn pineapple_session_receive(
     };
 
     match session.receive(msg) {
-        Ok(plaintext) => ByteBuffer::from_vec(plaintext),
+        Ok(plaintext) => ByteBuffer::from_secret_vec(plaintext),
         Err(e) => {
             set_last_error(&format!("Receive failed: {}", e));
             ByteBuffer::empty()
@@ -109,3 +220,163 @@ pub extern "C" fn pineapple_session_free(handle: *mut SessionHandle) {
         }
     }
 }
+
+/// Tell the peer the session is over, then free it
+///
+/// A `Bye` message is sent over `stream_fd` before the session is freed.
+/// The fd remains owned by the caller and is not closed. A dead or invalid
+/// fd is tolerated: the session is freed either way.
+/// Returns 0 if the Bye was sent, -1 otherwise
+#[cfg(unix)]
+#[no_mangle]
+pub extern "C" fn pineapple_session_close(handle: *mut SessionHandle, stream_fd: i32) -> i32 {
+    if handle.is_null() {
+        set_last_error("Invalid arguments");
+        return -1;
+    }
+
+    let mut session = unsafe { Box::from_raw(handle as *mut RustSession) };
+    let result = send_bye(&mut session, stream_fd);
+    drop(session);
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(&format!("Failed to send Bye: {}", e));
+            -1
+        }
+    }
+}
+
+/// Encrypt a Bye and write it as one length-prefixed frame on a borrowed fd
+#[cfg(unix)]
+fn send_bye(session: &mut RustSession, stream_fd: i32) -> anyhow::Result<()> {
+    use std::os::unix::io::FromRawFd;
+
+    if stream_fd < 0 {
+        anyhow::bail!("Invalid stream fd {}", stream_fd);
+    }
+
+    let bye = crate::messages::serialize_message(&crate::messages::MessageType::Bye);
+    let data = crate::network::serialize_ratchet_message(&session.send_bytes(&bye)?);
+    let mut frame = (data.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&data);
+
+    // Borrow the caller's fd; ManuallyDrop keeps it from being closed here
+    let stream = std::mem::ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(stream_fd) });
+    let socket = socket2::SockRef::from(&*stream);
+
+    // A peer that already hung up must not raise SIGPIPE, and a stalled
+    // one must not block teardown
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    socket.set_nosigpipe(true)?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let flags = libc::MSG_NOSIGNAL | libc::MSG_DONTWAIT;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let flags = libc::MSG_DONTWAIT;
+
+    let mut sent = 0;
+    while sent < frame.len() {
+        match socket.send_with_flags(&frame[sent..], flags)? {
+            0 => anyhow::bail!("Connection closed"),
+            n => sent += n,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The callee only borrows input buffers, so hand it a copy of the fields
+    fn view(buffer: &ByteBuffer) -> ByteBuffer {
+        ByteBuffer { data: buffer.data, len: buffer.len, capacity: buffer.capacity, secret: buffer.secret }
+    }
+
+    fn take(buffer: ByteBuffer) -> Vec<u8> {
+        let bytes = unsafe { buffer.as_slice() }.to_vec();
+        pineapple_free_buffer(buffer);
+        bytes
+    }
+
+    #[test]
+    fn sessions_round_trip_through_the_c_abi() {
+        let alice = pineapple_user_new();
+        let bob = pineapple_user_new();
+        assert!(alice.len > 0 && bob.len > 0);
+        let bob_bundle = unsafe { pineapple_user_bundle(view(&bob)) };
+        assert!(bob_bundle.len > 0);
+
+        let mut init_message = ByteBuffer::empty();
+        let alice_session =
+            unsafe { pineapple_session_new_initiator(view(&alice), view(&bob_bundle), &mut init_message) };
+        assert!(!alice_session.is_null());
+        assert!(init_message.len > 0);
+
+        let mut bob_after = ByteBuffer::empty();
+        let bob_session = unsafe { pineapple_session_new_responder(view(&bob), view(&init_message), &mut bob_after) };
+        assert!(!bob_session.is_null());
+        assert!(pqxdh::User::from_bytes(&take(bob_after)).is_ok());
+
+        let hello = b"hello bob";
+        let sent = take(pineapple_session_send(alice_session, hello.as_ptr(), hello.len()));
+        let received = take(pineapple_session_receive(bob_session, sent.as_ptr(), sent.len()));
+        assert_eq!(received, hello);
+
+        let reply = b"hi alice";
+        let sent = take(pineapple_session_send(bob_session, reply.as_ptr(), reply.len()));
+        let received = take(pineapple_session_receive(alice_session, sent.as_ptr(), sent.len()));
+        assert_eq!(received, reply);
+
+        pineapple_session_free(alice_session);
+        pineapple_session_free(bob_session);
+        for buffer in [alice, bob, bob_bundle, init_message] {
+            pineapple_free_buffer(buffer);
+        }
+    }
+
+    /// Start a session against `bundle`, expecting a refusal rather than a panic
+    fn refused_bundle(bundle: &[u8]) -> String {
+        let alice = pineapple_user_new();
+        let bundle = ByteBuffer::from_vec(bundle.to_vec());
+        let mut init_message = ByteBuffer::empty();
+        let session = unsafe { pineapple_session_new_initiator(view(&alice), view(&bundle), &mut init_message) };
+        assert!(session.is_null());
+        assert!(init_message.data.is_null());
+        pineapple_free_buffer(alice);
+        pineapple_free_buffer(bundle);
+        unsafe { CStr::from_ptr(pineapple_last_error()) }.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn truncated_bundle_is_refused() {
+        let bob = pineapple_user_new();
+        let bundle = take(unsafe { pineapple_user_bundle(view(&bob)) });
+        pineapple_free_buffer(bob);
+
+        assert!(refused_bundle(&bundle[..100]).contains("truncated"));
+        assert!(refused_bundle(&bundle[..bundle.len() - 1]).contains("truncated"));
+        assert!(refused_bundle(&[bundle.as_slice(), &[0]].concat()).contains("trailing"));
+    }
+
+    #[test]
+    fn algorithm_byte_alone_is_refused() {
+        let bundle = [crate::pqxdh::IDENTITY_ALGORITHM.id()];
+        assert!(refused_bundle(&bundle).contains("truncated"));
+    }
+
+    #[test]
+    fn garbage_bundle_is_refused() {
+        let alice = pineapple_user_new();
+        let garbage = ByteBuffer::from_vec(vec![0xAB; 64]);
+        let mut init_message = ByteBuffer::empty();
+        let session = unsafe {
+            pineapple_session_new_initiator(view(&alice), view(&garbage), &mut init_message)
+        };
+        assert!(session.is_null());
+        assert!(init_message.data.is_null());
+        pineapple_free_buffer(alice);
+        pineapple_free_buffer(garbage);
+    }
+}
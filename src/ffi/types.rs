@@ -5,6 +5,7 @@
  */
 
 use std::os::raw::c_char;
+use zeroize::Zeroize;
 
 /// Opaque handle for NatTraversal instance
 #[repr(C)]
@@ -32,6 +33,33 @@ pub enum ConnectionState {
     TcpConnecting = 7,
     Connected = 8,
     Failed = 9,
+    RelayFallback = 10,
+    TurnAllocating = 11,
+    Discovering = 12,
+}
+
+/// How a pineapple_nat_connect_blocking call ended
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConnectResult {
+    Connected = 0,
+    Failed = 1,
+    Cancelled = 2,
+}
+
+/// Progress of the running (or last) connect, filled by pineapple_nat_get_progress
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NatProgress {
+    pub state: ConnectionState,
+    /// Whether a connect is running right now
+    pub running: bool,
+    /// State changes so far in this attempt
+    pub transitions: u32,
+    /// Milliseconds since the attempt started
+    pub elapsed_ms: u64,
+    /// Milliseconds spent in `state` so far
+    pub state_elapsed_ms: u64,
 }
 
 /// FFI-safe buffer structure
@@ -40,6 +68,8 @@ pub struct ByteBuffer {
     pub data: *mut u8,
     pub len: usize,
     pub capacity: usize,
+    /// Zero the contents when freed (set for decrypted plaintext; callers may set it too)
+    pub secret: bool,
 }
 
 impl ByteBuffer {
@@ -49,7 +79,12 @@ impl ByteBuffer {
         let len = vec.len();
         let capacity = vec.capacity();
         std::mem::forget(vec);
-        Self { data, len, capacity }
+        Self { data, len, capacity, secret: false }
+    }
+
+    /// Create from Vec<u8> holding secret data, wiped by `pineapple_free_buffer`
+    pub fn from_secret_vec(vec: Vec<u8>) -> Self {
+        Self { secret: true, ..Self::from_vec(vec) }
     }
 
     /// Convert to Vec<u8>
@@ -57,22 +92,37 @@ impl ByteBuffer {
         Vec::from_raw_parts(self.data, self.len, self.capacity)
     }
 
+    /// Borrow the contents without taking ownership (empty for a null buffer)
+    ///
+    /// # Safety
+    /// `data` must be null or point to `len` readable bytes that outlive the borrow
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        if self.data.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(self.data, self.len)
+        }
+    }
+
     /// Create empty buffer
     pub fn empty() -> Self {
         Self {
             data: std::ptr::null_mut(),
             len: 0,
             capacity: 0,
+            secret: false,
         }
     }
 }
 
 /// Free a ByteBuffer
+/// Buffers flagged `secret` are zeroed first, including spare capacity
 #[no_mangle]
 pub extern "C" fn pineapple_free_buffer(buffer: ByteBuffer) {
     if !buffer.data.is_null() {
-        unsafe {
-            let _ = Vec::from_raw_parts(buffer.data, buffer.len, buffer.capacity);
+        let mut vec = unsafe { Vec::from_raw_parts(buffer.data, buffer.len, buffer.capacity) };
+        if buffer.secret {
+            vec.zeroize();
         }
     }
 }
@@ -90,5 +140,8 @@ pub struct NatTraversalConfig {
 /// Callback type for connection state changes
 pub type StateCallback = extern "C" fn(state: ConnectionState, user_data: *mut std::ffi::c_void);
 
+/// Callback type for the end of a connect
+pub type CompletionCallback = extern "C" fn(result: ConnectResult, user_data: *mut std::ffi::c_void);
+
 /// Callback type for log messages
 pub type LogCallback = extern "C" fn(level: i32, message: *const c_char, user_data: *mut std::ffi::c_void);
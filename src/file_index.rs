@@ -0,0 +1,127 @@
+/**
+ * file_index.rs
+ *
+ * Optional append-only record of received files (`file-index` feature)
+ */
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One received file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReceivedFile {
+    pub sender_fingerprint: String,
+    /// Name as the sender gave it
+    pub filename: String,
+    /// Where it was written (deleted again if `integrity_ok` is false)
+    pub saved_path: PathBuf,
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub integrity_ok: bool,
+}
+
+impl ReceivedFile {
+    /// Entry timestamped now
+    pub fn new(sender_fingerprint: &str, filename: &str, saved_path: &Path, size: u64, integrity_ok: bool) -> Self {
+        Self {
+            sender_fingerprint: sender_fingerprint.to_string(),
+            filename: filename.to_string(),
+            saved_path: saved_path.to_path_buf(),
+            size,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            integrity_ok,
+        }
+    }
+}
+
+/// Index of received files, one JSON object per line
+///
+/// The file is plain text and NOT encrypted: anyone who can read it learns
+/// who sent which files and when. Keep it somewhere only the user can read,
+/// or leave it disabled.
+pub struct FileIndex {
+    path: PathBuf,
+}
+
+impl FileIndex {
+    /// The file is created on the first `record`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append an entry
+    pub fn record(&self, entry: &ReceivedFile) -> Result<()> {
+        let mut line = serde_json::to_vec(entry).context("File index serialization failed")?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open file index: {}", self.path.display()))?;
+        file.write_all(&line)
+            .with_context(|| format!("Failed to write file index: {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Every recorded entry, oldest first (empty if nothing was recorded yet)
+    ///
+    /// A truncated last line, left by a crash mid-write, is skipped.
+    pub fn entries(&self) -> Result<Vec<ReceivedFile>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let text = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read file index: {}", self.path.display()))?;
+
+        let mut entries = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(_) if !text.ends_with('\n') && number + 1 == text.lines().count() => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Bad file index entry on line {}", number + 1));
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(all(test, feature = "file-index"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_appended_and_a_torn_last_line_is_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = FileIndex::new(dir.path().join("received.jsonl"));
+        assert!(index.entries().unwrap().is_empty());
+
+        let first = ReceivedFile::new("alice", "a.txt", &dir.path().join("a.txt"), 3, true);
+        let second = ReceivedFile::new("bob", "b.txt", &dir.path().join("b.txt"), 5, false);
+        index.record(&first).unwrap();
+        index.record(&second).unwrap();
+        assert_eq!(index.entries().unwrap(), vec![first.clone(), second.clone()]);
+
+        // A crash mid-write leaves half a line behind
+        let mut file = OpenOptions::new().append(true).open(index.path()).unwrap();
+        file.write_all(br#"{"sender_fingerprint":"car"#).unwrap();
+        assert_eq!(index.entries().unwrap(), vec![first.clone(), second.clone()]);
+
+        // A complete line that does not parse is an error, not skipped
+        file.write_all(b"\n").unwrap();
+        assert!(index.entries().is_err());
+    }
+}
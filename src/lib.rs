@@ -11,8 +11,13 @@
 pub mod pqxdh;
 pub mod ratchet;
 pub mod session;
+pub mod session_manager;
+pub mod trust_store;
 pub mod network;
 pub mod messages;
+pub mod transfer;
+#[cfg(feature = "file-index")]
+pub mod file_index;
 pub mod nat_traversal;
 pub mod ffi;
 
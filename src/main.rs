@@ -3,18 +3,18 @@ use crossterm::{
     event::{self, Event, KeyCode, KeyModifiers},
     terminal,
 };
-use pineapple::{messages, network, pqxdh, Session};
-use pineapple::nat_traversal::{NatTraversal, NatTraversalConfig};
+use pineapple::{messages, network, pqxdh, ratchet, session, transfer, Session};
+use pineapple::session::Role;
+use pineapple::nat_traversal::{self, NatTraversal, NatTraversalConfig};
+use pineapple::trust_store::{TrustResult, TrustStore};
 use ed25519_dalek::SigningKey;
+use zeroize::Zeroize;
 use std::{
     env,
     io::{self, Write},
-    net::TcpStream,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
-    },
-    thread,
+    net::{Ipv4Addr, TcpStream},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
 };
 
 fn main() -> Result<()> {
@@ -57,7 +57,7 @@ fn main() -> Result<()> {
         }
         "connect" => {
             if args.len() < 3 {
-                eprintln!("Usage: {} connect <ip:port>", args[0]);
+                eprintln!("Usage: {} connect <ip:port or [ipv6]:port>", args[0]);
                 eprintln!();
                 eprintln!("Note: This mode requires direct network access (no NAT).");
                 eprintln!("      For connections behind NAT, use 'nat' mode instead.");
@@ -83,7 +83,7 @@ fn print_usage(program_name: &str) {
     eprintln!("USAGE:");
     eprintln!("  {} nat <peer_fingerprint>    # NAT traversal mode (RECOMMENDED)", program_name);
     eprintln!("  {} listen <port>              # Direct listen mode (no NAT)", program_name);
-    eprintln!("  {} connect <ip:port>          # Direct connect mode (no NAT)", program_name);
+    eprintln!("  {} connect <ip:port>          # Direct connect mode (no NAT, IPv6 as [addr]:port)", program_name);
     eprintln!();
     eprintln!("NAT TRAVERSAL MODE (Recommended):");
     eprintln!("  This mode works behind NAT/firewalls using signalling + STUN servers.");
@@ -93,13 +93,36 @@ fn print_usage(program_name: &str) {
     eprintln!("    SIGNALLING_URL      WebSocket signalling server");
     eprintln!("                        Example: wss://your-server.com:8443");
     eprintln!();
+    eprintln!("    SIGNALLING_SNI      TLS server name to present, if it differs from the URL host");
+    eprintln!("                        (Optional: e.g. an IP URL behind an SNI-routing proxy)");
+    eprintln!();
+    eprintln!("    SIGNALLING_MSGPACK  Set to 1 to ask the server for MessagePack instead of JSON");
+    eprintln!("                        (Optional: falls back to JSON if the server declines)");
+    eprintln!();
     eprintln!("    STUN_SERVER         STUN server for NAT discovery");
     eprintln!("                        Example: your-server.com:3478");
     eprintln!();
+    eprintln!("    STUN_SERVER_V6      IPv6 STUN server for dual-stack candidates");
+    eprintln!("                        (Optional: default IPv4 only)");
+    eprintln!();
+    eprintln!("    STUN_SERVER_2       Second STUN server at another IP, to detect symmetric NAT");
+    eprintln!("                        (Optional: uses STUN_SERVER's alternate address if it has one)");
+    eprintln!();
+    eprintln!("    TURN_SERVER         TURN server to relay through if no direct path works");
+    eprintln!("    TURN_USERNAME       TURN long-term credentials");
+    eprintln!("    TURN_PASSWORD       (Optional: default no relay)");
+    eprintln!();
     eprintln!("    LOCAL_FINGERPRINT   Your identity (like a username)");
     eprintln!("                        Example: alice");
     eprintln!("                        (Optional: defaults to random ID)");
     eprintln!();
+    eprintln!("    PINEAPPLE_APP_ID    Deployment identifier, isolates probes from other apps");
+    eprintln!("                        (Optional: both peers must use the same value)");
+    eprintln!();
+    eprintln!("    PINEAPPLE_TCP_PORT  Local TCP port for the peer connection, e.g. a forwarded one");
+    eprintln!("    PINEAPPLE_TCP_BIND  Local IP to bind it on");
+    eprintln!("                        (Optional: default random port on all interfaces)");
+    eprintln!();
     eprintln!("  Example workflow:");
     eprintln!("    # Peer 1 (Alice)");
     eprintln!("    export SIGNALLING_URL=\"wss://example.com:8443\"");
@@ -155,23 +178,101 @@ fn run_nat_traversal(peer_fingerprint: &str) -> Result<()> {
         std::process::exit(1);
     }
     
-    // Parse STUN server address
-    let stun_addr: std::net::SocketAddr = stun_server
-        .parse()
-        .context("Invalid STUN server address. Expected format: host:port")?;
+    // STUN server may be given as ip:port or host:port
+    let (stun_addr, stun_host) = match stun_server.parse::<std::net::SocketAddr>() {
+        Ok(addr) => (addr, None),
+        Err(_) => {
+            let addr = nat_traversal::resolve_stun(&stun_server)
+                .context("Invalid STUN server address. Expected format: host:port")?;
+            (addr, Some(stun_server.clone()))
+        }
+    };
+    
+    // Optional IPv6 STUN server for dual-stack candidates
+    let stun_addr_v6 = match env::var("STUN_SERVER_V6") {
+        Ok(server) => Some(match server.parse::<std::net::SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => nat_traversal::resolve_stun_v6(&server)
+                .context("Invalid IPv6 STUN server address. Expected format: host:port")?,
+        }),
+        Err(_) => None,
+    };
+    
+    // Optional second STUN server at another IP, to classify the NAT
+    let stun_secondary_addr = match env::var("STUN_SERVER_2") {
+        Ok(server) => Some(
+            nat_traversal::resolve_stun(&server)
+                .context("Invalid second STUN server address. Expected format: host:port")?,
+        ),
+        Err(_) => None,
+    };
+    
+    // Optional TURN relay for peers no direct path reaches
+    let turn_server_addr = match env::var("TURN_SERVER") {
+        Ok(server) => Some(
+            nat_traversal::resolve_host(&server)
+                .context("Invalid TURN server address. Expected format: host:port")?[0],
+        ),
+        Err(_) => None,
+    };
+    let turn_credentials = match (env::var("TURN_USERNAME"), env::var("TURN_PASSWORD")) {
+        (Ok(username), Ok(password)) => Some(nat_traversal::TurnCredentials { username, password }),
+        _ => None,
+    };
+    if turn_server_addr.is_some() && turn_credentials.is_none() {
+        anyhow::bail!("TURN_SERVER needs TURN_USERNAME and TURN_PASSWORD");
+    }
+    
+    // Optional deployment identifier to isolate this network's probes
+    let app_id = env::var("PINEAPPLE_APP_ID").unwrap_or_default().into_bytes();
+    
+    // Optional fixed TCP port (e.g. a manually forwarded one) and bind address
+    let tcp_port = match env::var("PINEAPPLE_TCP_PORT") {
+        Ok(port) => port.parse().context("PINEAPPLE_TCP_PORT must be a port number")?,
+        Err(_) => 0,
+    };
+    let tcp_bind_ip = match env::var("PINEAPPLE_TCP_BIND") {
+        Ok(ip) => ip.parse().context("PINEAPPLE_TCP_BIND must be an IP address")?,
+        Err(_) => Ipv4Addr::UNSPECIFIED.into(),
+    };
     
     // Generate signing key for UDP probes
     let signing_key = SigningKey::from_bytes(&rand::random::<[u8; 32]>());
     
     // Configure NAT traversal
-    let config = NatTraversalConfig {
+    let mut config = NatTraversalConfig {
         signalling_url,
+        signalling_addrs: Vec::new(),
+        sni_hostname: env::var("SIGNALLING_SNI").ok(),
+        signalling_codec: match env::var("SIGNALLING_MSGPACK").as_deref() {
+            Ok("1") => nat_traversal::SignallingCodec::MessagePack,
+            _ => nat_traversal::SignallingCodec::Json,
+        },
+        accept_invalid_certs: env::var("PINEAPPLE_INSECURE_TLS").as_deref() == Ok("1"),
         stun_server_addr: stun_addr,
+        stun_server_host: stun_host,
+        stun_server_addr_v6: stun_addr_v6,
+        stun_secondary_addr,
+        stun_attribute_preference: Default::default(),
+        stun_keepalive: Some(nat_traversal::DEFAULT_STUN_KEEPALIVE),
+        turn_server_addr,
+        turn_credentials,
         local_fingerprint: local_fingerprint.clone(),
         signing_key,
-        tcp_port: 0, // Random port
+        tcp_port,
+        tcp_bind_ip,
+        app_id,
+        socket_options: network::SocketOptions::default(),
+        duplicate_grace: nat_traversal::DEFAULT_DUPLICATE_GRACE,
     };
     
+    if config.accept_invalid_certs {
+        println!("Warning: PINEAPPLE_INSECURE_TLS is set, signalling certificates are not checked");
+    }
+
+    // Resolve server names up front so retries skip DNS
+    config.resolve()?;
+    
     // Create NAT traversal instance
     let mut nat = NatTraversal::new(config);
     
@@ -187,7 +288,7 @@ fn run_nat_traversal(peer_fingerprint: &str) -> Result<()> {
     
     println!();
     println!("✅ NAT traversal complete!");
-    println!("✅ TCP connection established directly with peer!");
+    println!("✅ TCP connection established with peer!");
     println!("🔒 Starting encrypted session...");
     println!();
     
@@ -196,76 +297,113 @@ fn run_nat_traversal(peer_fingerprint: &str) -> Result<()> {
     let is_initiator = local_fingerprint < peer_fingerprint.to_string();
     
     if is_initiator {
-        run_session_initiator(stream)?;
+        run_session_initiator(stream, peer_fingerprint)?;
     } else {
-        run_session_responder(stream)?;
+        run_session_responder(stream, peer_fingerprint)?;
     }
     
     Ok(())
 }
 
 /// Run as session initiator (Alice)
-fn run_session_initiator(mut stream: TcpStream) -> Result<()> {
+fn run_session_initiator(mut stream: TcpStream, peer_fingerprint: &str) -> Result<()> {
     println!("📋 Role: Initiator");
     println!("🔐 Performing PQXDH handshake...");
     
-    let alice = pqxdh::User::new();
-    send_public_keys(&mut stream, &alice)?;
-    
-    let mut bob = receive_public_keys(&mut stream)?;
-    
-    let (session, init_message) = Session::new_initiator(&alice, &mut bob)?;
-    
-    network::send_message(
-        &mut stream,
-        &network::serialize_pqxdh_init_message(&init_message),
-    )?;
+    let mut alice = pqxdh::User::new();
+    println!("🔑 My key fingerprint: {}", alice.fingerprint());
+    let session = establish_with_peer(&mut stream, Role::Initiator, &mut alice, peer_fingerprint)?;
     
     println!("✅ Session established!");
     println!();
     println!("═══════════════════════════════════════════════════════════");
     println!("  Type your message and press Enter to send.");
-    println!("  To send a file: !path/to/file.txt");
+    println!("  To send a file: /file path/to/file.txt (or a directory)");
+    println!("  Commands: /nick <name>, /quit");
     println!("  Press Ctrl+L to clear screen.");
     println!("  Press Ctrl+C to exit.");
     println!("═══════════════════════════════════════════════════════════");
     println!();
     
-    chat_loop(session, stream)?;
+    chat_loop(session, stream, Some(peer_fingerprint))?;
     
     Ok(())
 }
 
 /// Run as session responder (Bob)
-fn run_session_responder(mut stream: TcpStream) -> Result<()> {
+fn run_session_responder(mut stream: TcpStream, peer_fingerprint: &str) -> Result<()> {
     println!("📋 Role: Responder");
     println!("🔐 Performing PQXDH handshake...");
     
     let mut bob = pqxdh::User::new();
-    
-    let alice = receive_public_keys(&mut stream)?;
-    send_public_keys(&mut stream, &bob)?;
-    
-    let init_message_data = network::receive_message(&mut stream)?;
-    let init_message = network::deserialize_pqxdh_init_message(&init_message_data)?;
-    
-    let session = Session::new_responder(&mut bob, &init_message)?;
+    println!("🔑 My key fingerprint: {}", bob.fingerprint());
+    let session = establish_with_peer(&mut stream, Role::Responder, &mut bob, peer_fingerprint)?;
     
     println!("✅ Session established!");
     println!();
     println!("═══════════════════════════════════════════════════════════");
     println!("  Type your message and press Enter to send.");
-    println!("  To send a file: !path/to/file.txt");
+    println!("  To send a file: /file path/to/file.txt (or a directory)");
+    println!("  Commands: /nick <name>, /quit");
     println!("  Press Ctrl+L to clear screen.");
     println!("  Press Ctrl+C to exit.");
     println!("═══════════════════════════════════════════════════════════");
     println!();
     
-    chat_loop(session, stream)?;
+    chat_loop(session, stream, Some(peer_fingerprint))?;
     
     Ok(())
 }
 
+/// Run the handshake, aborting if the dialed identifier is a key fingerprint
+/// the peer does not own
+/// Plain usernames cannot be checked cryptographically and only get a notice
+fn establish_with_peer(
+    stream: &mut TcpStream,
+    role: Role,
+    local: &mut pqxdh::User,
+    peer_fingerprint: &str,
+) -> Result<Session> {
+    let session = if !pqxdh::is_fingerprint(peer_fingerprint) {
+        let session = session::establish(stream, role, local, handshake_timeout()?)?;
+        println!("⚠️  '{}' is not a key fingerprint; peer identity not verified", peer_fingerprint);
+        session
+    } else {
+        let session = session::establish_verified(stream, role, local, handshake_timeout()?, peer_fingerprint)?;
+        println!("✅ Peer identity matches fingerprint {}", peer_fingerprint);
+        session
+    };
+    if let Ok(path) = env::var("PINEAPPLE_TRUST_STORE") {
+        check_pinned_identity(&path, peer_fingerprint, session.peer_identity_key(local))?;
+    }
+    Ok(session)
+}
+
+/// Pin the peer's identity key on first contact and compare it afterwards
+/// A changed key is only reported; the old pin stays until revoked
+fn check_pinned_identity(path: &str, peer_fingerprint: &str, identity_key: &[u8]) -> Result<()> {
+    let mut store = TrustStore::open(path)?;
+    match store.verify(peer_fingerprint, identity_key) {
+        TrustResult::New => {
+            store.record(peer_fingerprint, identity_key)?;
+            println!("📌 Pinned identity key for '{}' (first contact)", peer_fingerprint);
+        }
+        TrustResult::Matched => {
+            println!("✅ Identity key matches the one pinned for '{}'", peer_fingerprint);
+        }
+        TrustResult::Changed => {
+            println!();
+            println!("🚨🚨🚨 SECURITY WARNING 🚨🚨🚨");
+            println!("   The identity key of '{}' has CHANGED since it was pinned.", peer_fingerprint);
+            println!("   Someone may be intercepting this conversation, or the peer reinstalled.");
+            println!("   Compare safety numbers out of band before trusting this session.");
+            println!("   Pinned keys are in {}", store.path().display());
+            println!();
+        }
+    }
+    Ok(())
+}
+
 /// Legacy direct listen mode (Alice)
 fn run_alice(port: &str) -> Result<()> {
     println!("pineapple - Direct Listen Mode");
@@ -273,12 +411,13 @@ fn run_alice(port: &str) -> Result<()> {
     println!();
     println!("Waiting for connection on port {}...", port);
 
-    let listener = std::net::TcpListener::bind(format!("0.0.0.0:{}", port))
-        .context("Failed to bind to port")?;
+    let port: u16 = port.parse().context("Invalid port")?;
+    let listener = network::listen_dual_stack(port)?;
 
     let (mut stream, addr) = listener
         .accept()
         .context("Failed to accept connection")?;
+    network::SocketOptions::default().apply(&stream)?;
 
     println!("Incoming connection from {}", addr);
     println!("Accept? (yes/no)");
@@ -294,24 +433,16 @@ fn run_alice(port: &str) -> Result<()> {
     println!("Connection accepted!");
     println!("Performing handshake...");
 
-    let alice = pqxdh::User::new();
-    send_public_keys(&mut stream, &alice)?;
-
-    let mut bob = receive_public_keys(&mut stream)?;
-
-    let (session, init_message) = Session::new_initiator(&alice, &mut bob)?;
-
-    network::send_message(
-        &mut stream,
-        &network::serialize_pqxdh_init_message(&init_message),
-    )?;
+    let mut alice = pqxdh::User::new();
+    let session = session::establish(&mut stream, Role::Initiator, &mut alice, handshake_timeout()?)?;
 
     println!("Session established!");
     println!("Type your message and press Enter.");
-    println!("To send a file, type !path/to/file.txt");
+    println!("To send a file, type /file path/to/file.txt (a directory sends every file in it)");
+    println!("Commands: /nick <name>, /quit");
     println!("Press Ctrl+L to clear screen. Press Ctrl+C to exit.");
 
-    chat_loop(session, stream)?;
+    chat_loop(session, stream, None)?;
 
     Ok(())
 }
@@ -323,185 +454,185 @@ fn run_bob(address: &str) -> Result<()> {
     println!();
     println!("Connecting to {}...", address);
 
-    let mut stream = TcpStream::connect(address)
+    let mut policy = network::RetryPolicy::default();
+    if let Ok(attempts) = env::var("PINEAPPLE_CONNECT_ATTEMPTS") {
+        policy.attempts = attempts
+            .parse()
+            .context("PINEAPPLE_CONNECT_ATTEMPTS must be a positive integer")?;
+    }
+
+    network::validate_peer_address(address)?;
+    let mut stream = network::connect_with_retry(address, &policy)
         .context("Failed to connect to peer")?;
+    network::SocketOptions::default().apply(&stream)?;
 
     println!("Connected!");
     println!("Performing handshake...");
 
     let mut bob = pqxdh::User::new();
-
-    let alice = receive_public_keys(&mut stream)?;
-    send_public_keys(&mut stream, &bob)?;
-
-    let init_message_data = network::receive_message(&mut stream)?;
-    let init_message = network::deserialize_pqxdh_init_message(&init_message_data)?;
-
-    let session = Session::new_responder(&mut bob, &init_message)?;
+    let session = session::establish(&mut stream, Role::Responder, &mut bob, handshake_timeout()?)?;
 
     println!("Session established!");
     println!("Type your message and press Enter.");
-    println!("To send a file, type !path/to/file.txt");
+    println!("To send a file, type /file path/to/file.txt (a directory sends every file in it)");
+    println!("Commands: /nick <name>, /quit");
     println!("Press Ctrl+L to clear screen. Press Ctrl+C to exit.");
 
-    chat_loop(session, stream)?;
+    chat_loop(session, stream, None)?;
 
     Ok(())
 }
 
-fn send_public_keys(stream: &mut TcpStream, user: &pqxdh::User) -> Result<()> {
-    let bundle = network::serialize_prekey_bundle(user);
-    network::send_message(stream, &bundle)?;
-    Ok(())
+/// Handshake read timeout from PINEAPPLE_HANDSHAKE_TIMEOUT (seconds, 0 disables)
+fn handshake_timeout() -> Result<Option<Duration>> {
+    match env::var("PINEAPPLE_HANDSHAKE_TIMEOUT") {
+        Ok(secs) => {
+            let secs: u64 = secs
+                .parse()
+                .context("PINEAPPLE_HANDSHAKE_TIMEOUT must be a number of seconds")?;
+            Ok((secs > 0).then(|| Duration::from_secs(secs)))
+        }
+        Err(_) => Ok(Some(session::DEFAULT_HANDSHAKE_TIMEOUT)),
+    }
 }
 
-fn receive_public_keys(stream: &mut TcpStream) -> Result<pqxdh::User> {
-    let bundle_data = network::receive_message(stream)?;
-    let user = network::deserialize_prekey_bundle(&bundle_data)?;
-    Ok(user)
-}
+/// `peer_fingerprint` is the fingerprint the peer was verified against, if any
+#[cfg_attr(not(feature = "file-index"), allow(unused_variables))]
+fn chat_loop(mut session: Session, mut stream: TcpStream, peer_fingerprint: Option<&str>) -> Result<()> {
+    if let Ok(limit) = env::var("PINEAPPLE_MAX_RECEIVED_BYTES") {
+        let limit = limit
+            .parse()
+            .context("PINEAPPLE_MAX_RECEIVED_BYTES must be a byte count")?;
+        session.set_max_received_bytes(Some(limit));
+    }
+    if let Ok(after) = env::var("PINEAPPLE_HEARTBEAT_AFTER") {
+        let after = after
+            .parse()
+            .context("PINEAPPLE_HEARTBEAT_AFTER must be a message count")?;
+        session.set_heartbeat_after(Some(after));
+    }
 
-fn chat_loop(session: Session, mut stream: TcpStream) -> Result<()> {
-    let stream_clone = stream.try_clone()?;
     let session = Arc::new(Mutex::new(session));
-    let session_clone = Arc::clone(&session);
-    let input_buffer = Arc::new(Mutex::new(String::new()));
-    let input_buffer_clone = Arc::clone(&input_buffer);
-    let running = Arc::new(AtomicBool::new(true));
-    let running_clone = Arc::clone(&running);
+    let incoming = session::spawn_receiver(Arc::clone(&session), stream.try_clone()?);
+    // The CLI has no control subprotocols of its own
+    let mut dispatcher = messages::ControlDispatcher::new();
+    let mut transfers = transfer::TransferManager::new(".");
+    #[cfg(feature = "file-index")]
+    if let (Ok(path), Some(peer)) = (env::var("PINEAPPLE_FILE_INDEX"), peer_fingerprint) {
+        transfers.set_index(pineapple::file_index::FileIndex::new(path), peer);
+    }
+    let mut buf = String::new();
 
     terminal::enable_raw_mode()?;
 
-    let receive_handle = thread::spawn(move || {
-        let mut stream = stream_clone;
+    print!("You: ");
+    io::stdout().flush()?;
+    let mut nick = String::from("You");
 
+    loop {
+        // Render everything received since the last key press
+        let mut rekey_requested = false;
+        let mut capabilities_received = false;
         loop {
-            if !running_clone.load(Ordering::SeqCst) {
-                break;
-            }
-
-            match network::receive_message(&mut stream) {
-                Ok(msg_data) => {
-                    if msg_data == b"\x1B[2J\x1B[H" {
-                        print!("\x1B[2J\x1B[H");
-                        let buf = input_buffer_clone.lock().unwrap();
-                        print!("You: {}", *buf);
-                        io::stdout().flush().unwrap();
-                        continue;
-                    }
-
-                    match network::deserialize_ratchet_message(&msg_data) {
-                        Ok(msg) => {
-                            let mut sess = session_clone.lock().unwrap();
-
-                            match sess.receive(msg) {
-                                Ok(plaintext_bytes) => {
-                                    match messages::deserialize_message(&plaintext_bytes) {
-                                        Ok(messages::MessageType::Text(text)) => {
-                                            let buf = input_buffer_clone.lock().unwrap();
-                                            print!("\r\x1B[K");
-                                            println!("Peer: {}", text);
-                                            print!("You: {}", *buf);
-                                            io::stdout().flush().unwrap();
-                                        }
-                                        Ok(messages::MessageType::File { filename, data }) => {
-                                            let save_path = format!("received_{}", filename);
-                                            let buf = input_buffer_clone.lock().unwrap();
-                                            print!("\r\x1B[K");
-
-                                            match std::fs::write(&save_path, data) {
-                                                Ok(_) => {
-                                                    println!(
-                                                        "Received file - {} -> {}",
-                                                        filename,
-                                                        save_path,
-                                                    );
-                                                }
-                                                Err(e) => {
-                                                    eprintln!("Failed to save file: {}", e);
-                                                }
-                                            }
-
-                                            print!("You: {}", *buf);
-                                            io::stdout().flush().unwrap();
-                                        }
-                                        Err(e) => {
-                                            let buf = input_buffer_clone.lock().unwrap();
-                                            print!("\r\x1B[K");
-                                            eprintln!("Failed to parse message: {}", e);
-                                            print!("You: {}", *buf);
-                                            io::stdout().flush().unwrap();
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    let buf = input_buffer_clone.lock().unwrap();
-                                    print!("\r\x1B[K");
-                                    eprintln!("Failed to decrypt message: {}", e);
-                                    print!("You: {}", *buf);
-                                    io::stdout().flush().unwrap();
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            let buf = input_buffer_clone.lock().unwrap();
-                            print!("\r\x1B[K");
-                            eprintln!("Failed to deserialize message: {}", e);
-                            print!("You: {}", *buf);
-                            io::stdout().flush().unwrap();
-                        }
+            match incoming.try_recv() {
+                Ok(mut received) => {
+                    rekey_requested |= matches!(received, Ok(messages::MessageType::Rekey { .. }));
+                    capabilities_received |= matches!(received, Ok(messages::MessageType::Capabilities { .. }));
+                    render_received(&received, &buf, &mut dispatcher, &mut transfers);
+                    if let Ok(message) = received.as_mut() {
+                        message.zeroize();
                     }
                 }
-                Err(_) => {
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
                     print!("\r\x1B[K");
                     println!("Connection closed by peer.");
-                    terminal::disable_raw_mode().unwrap();
+                    terminal::disable_raw_mode()?;
                     std::process::exit(0);
                 }
             }
         }
-    });
 
-    print!("You: ");
-    io::stdout().flush()?;
+        // The peer started a rekey; it completes once our acknowledgement arrives
+        if rekey_requested {
+            let ack = session.lock().unwrap().rekey_ack();
+            match ack {
+                Ok(Some(msg)) => {
+                    if let Err(e) = network::send_message(&mut stream, &network::serialize_ratchet_message(&msg)) {
+                        eprintln!("Failed to acknowledge rekey: {}", e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("Failed to acknowledge rekey: {}", e),
+            }
+        }
+
+        // Answer the initiator's capabilities with ours (no-op if already sent)
+        if capabilities_received {
+            let reply = session.lock().unwrap().capabilities_reply();
+            match reply {
+                Ok(Some(msg)) => {
+                    if let Err(e) = network::send_message(&mut stream, &network::serialize_ratchet_message(&msg)) {
+                        eprintln!("Failed to send capabilities: {}", e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("Failed to send capabilities: {}", e),
+            }
+        }
+
+        // Only receiving for a while: move our ratchet key on
+        let heartbeat = session.lock().unwrap().maybe_emit_heartbeat_ratchet();
+        match heartbeat {
+            Ok(Some(msg)) => {
+                if let Err(e) = network::send_message(&mut stream, &network::serialize_ratchet_message(&msg)) {
+                    eprintln!("Failed to send heartbeat: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Failed to send heartbeat: {}", e),
+        }
 
-    loop {
         if event::poll(std::time::Duration::from_millis(100))? {
             if let Event::Key(k) = event::read()? {
-                let mut buf = input_buffer.lock().unwrap();
-
                 match (k.code, k.modifiers) {
                     (KeyCode::Char('c'), KeyModifiers::CONTROL) => {
                         print!("\r\n");
-                        running.store(false, Ordering::SeqCst);
                         terminal::disable_raw_mode()?;
                         std::process::exit(0);
                     }
                     (KeyCode::Char('l'), KeyModifiers::CONTROL) => {
-                        let clear_msg = b"\x1B[2J\x1B[H";
-                        if network::send_message(&mut stream, clear_msg).is_ok() {
+                        let clear = messages::serialize_message(&messages::MessageType::Control {
+                            channel: CLEAR_CHANNEL.to_string(),
+                            payload: Vec::new(),
+                        });
+                        let sent = session.lock().unwrap().send_bytes(&clear).and_then(|msg| {
+                            network::send_message(&mut stream, &network::serialize_ratchet_message(&msg))
+                        });
+                        if sent.is_ok() {
                             print!("\x1B[2J\x1B[H");
-                            buf.clear();
+                            buf.zeroize();
                             print!("You: ");
                             io::stdout().flush()?;
                         }
                     }
                     (KeyCode::Enter, _) => {
-                        let line = buf.clone();
-                        buf.clear();
+                        let mut line = std::mem::take(&mut buf);
 
                         if !line.trim().is_empty() {
                             match messages::parse_input(&line) {
-                                Ok(messages::MessageType::Text(text)) => {
+                                Ok(messages::ParsedInput::Message(messages::MessageType::Text { text, ttl_secs })) => {
                                     print!("\r\x1B[K");
-                                    println!("You: {}", text);
+                                    println!("{}: {}", nick, text);
 
-                                    let msg_bytes = messages::serialize_message(
-                                        &messages::MessageType::Text(text),
-                                    );
+                                    let mut message = messages::MessageType::Text { text, ttl_secs };
+                                    let mut msg_bytes = serialize_for_peer(&session, &message);
+                                    message.zeroize();
                                     let mut sess = session.lock().unwrap();
 
-                                    match sess.send_bytes(&msg_bytes) {
+                                    let sent = sess.send_bytes(&msg_bytes);
+                                    msg_bytes.zeroize();
+                                    match sent {
                                         Ok(msg) => {
                                             drop(sess);
                                             let msg_data =
@@ -520,7 +651,7 @@ fn chat_loop(session: Session, mut stream: TcpStream) -> Result<()> {
                                         }
                                     }
                                 }
-                                Ok(messages::MessageType::File { filename, data }) => {
+                                Ok(messages::ParsedInput::Message(messages::MessageType::File { filename, data, ttl_secs })) => {
                                     print!("\r\x1B[K");
                                     println!(
                                         "Sending file: {} ({} bytes)",
@@ -528,15 +659,18 @@ fn chat_loop(session: Session, mut stream: TcpStream) -> Result<()> {
                                         data.len(),
                                     );
 
-                                    let msg_bytes = messages::serialize_message(
-                                        &messages::MessageType::File {
-                                            filename: filename.clone(),
-                                            data,
-                                        },
-                                    );
+                                    let mut message = messages::MessageType::File {
+                                        filename: filename.clone(),
+                                        data,
+                                        ttl_secs,
+                                    };
+                                    let mut msg_bytes = serialize_for_peer(&session, &message);
+                                    message.zeroize();
                                     let mut sess = session.lock().unwrap();
 
-                                    match sess.send_bytes(&msg_bytes) {
+                                    let sent = sess.send_bytes(&msg_bytes);
+                                    msg_bytes.zeroize();
+                                    match sent {
                                         Ok(msg) => {
                                             drop(sess);
                                             let msg_data =
@@ -557,11 +691,67 @@ fn chat_loop(session: Session, mut stream: TcpStream) -> Result<()> {
                                         }
                                     }
                                 }
+                                Ok(messages::ParsedInput::Message(messages::MessageType::Archive { entries })) => {
+                                    print!("\r\x1B[K");
+                                    let total: usize = entries.iter().map(|(_, data)| data.len()).sum();
+                                    println!("Sending {} files ({} bytes)", entries.len(), total);
+
+                                    let count = entries.len();
+                                    let mut message = messages::MessageType::Archive { entries };
+                                    let mut msg_bytes = serialize_for_peer(&session, &message);
+                                    message.zeroize();
+                                    let mut sess = session.lock().unwrap();
+
+                                    let sent = sess.send_bytes(&msg_bytes);
+                                    msg_bytes.zeroize();
+                                    match sent {
+                                        Ok(msg) => {
+                                            drop(sess);
+                                            let msg_data =
+                                                network::serialize_ratchet_message(&msg);
+
+                                            if let Err(e) = network::send_message(
+                                                &mut stream,
+                                                &msg_data,
+                                            ) {
+                                                eprintln!("Failed to send files: {}", e);
+                                                break Ok(());
+                                            }
+
+                                            println!("Sent {} files", count);
+                                        }
+                                        Err(e) => {
+                                            eprintln!("Failed to encrypt files: {}", e);
+                                        }
+                                    }
+                                }
+                                Ok(messages::ParsedInput::Message(_)) => {
+                                    // Control and transfer messages are never typed at the prompt
+                                }
+                                Ok(messages::ParsedInput::Command(messages::Command::Nick(name))) => {
+                                    print!("\r\x1B[K");
+                                    println!("Your messages are now shown as {}", name);
+                                    nick = name;
+                                }
+                                Ok(messages::ParsedInput::Command(messages::Command::Quit)) => {
+                                    print!("\r\x1B[K");
+                                    // Best effort: the peer may already be gone
+                                    let bye = messages::serialize_message(&messages::MessageType::Bye);
+                                    if let Ok(msg) = session.lock().unwrap().send_bytes(&bye) {
+                                        let _ = network::send_message(
+                                            &mut stream,
+                                            &network::serialize_ratchet_message(&msg),
+                                        );
+                                    }
+                                    terminal::disable_raw_mode()?;
+                                    std::process::exit(0);
+                                }
                                 Err(e) => {
                                     eprintln!("Error: {}", e);
                                 }
                             }
                         }
+                        line.zeroize();
 
                         print!("You: ");
                         io::stdout().flush()?;
@@ -569,7 +759,7 @@ fn chat_loop(session: Session, mut stream: TcpStream) -> Result<()> {
                     (KeyCode::Backspace, _) => {
                         if !buf.is_empty() {
                             buf.pop();
-                            print!("\r\x1B[KYou: {}", *buf);
+                            print!("\r\x1B[KYou: {}", buf);
                             io::stdout().flush()?;
                         }
                     }
@@ -584,3 +774,133 @@ fn chat_loop(session: Session, mut stream: TcpStream) -> Result<()> {
         }
     }
 }
+
+/// Serialize a chat message, compressed if the peer advertised "compression"
+fn serialize_for_peer(session: &Mutex<Session>, message: &messages::MessageType) -> Vec<u8> {
+    if session.lock().unwrap().peer_supports("compression") {
+        messages::serialize_message_compressed(message)
+    } else {
+        messages::serialize_message(message)
+    }
+}
+
+/// Control channel the CLI uses to clear both peers' screens
+const CLEAR_CHANNEL: &str = "pineapple.clear";
+
+/// Print a message from the receive thread above the line being typed
+fn render_received(
+    received: &Result<messages::MessageType>,
+    input: &str,
+    dispatcher: &mut messages::ControlDispatcher,
+    transfers: &mut transfer::TransferManager,
+) {
+    print!("\r\x1B[K");
+
+    match received {
+        Ok(messages::MessageType::Text { text, ttl_secs }) => match ttl_secs {
+            Some(ttl) => println!("Peer: {} (expires after {}s)", text, ttl),
+            None => println!("Peer: {}", text),
+        },
+        Ok(messages::MessageType::File { filename, data, .. }) => {
+            match transfers.save_file(filename, data) {
+                Ok(save_path) => {
+                    println!(
+                        "Received file - {} -> {}",
+                        filename,
+                        save_path.display(),
+                    );
+                }
+                Err(e) => {
+                    eprintln!("Failed to save file: {}", e);
+                }
+            }
+        }
+        Ok(messages::MessageType::Archive { entries }) => {
+            match transfers.save_archive(entries) {
+                Ok(paths) => {
+                    println!("Received {} files:", paths.len());
+                    for path in paths {
+                        println!("  {}", path.display());
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Failed to save archive: {}", e);
+                }
+            }
+        }
+        Ok(messages::MessageType::Control { channel, .. }) if channel == CLEAR_CHANNEL => {
+            print!("\x1B[2J\x1B[H");
+        }
+        Ok(messages::MessageType::Control { channel, payload }) => {
+            if !dispatcher.dispatch(&channel, &payload) {
+                println!(
+                    "Ignored control message on channel '{}' ({} bytes)",
+                    channel,
+                    payload.len(),
+                );
+            }
+        }
+        Ok(messages::MessageType::Payload { data }) => {
+            println!("Received {} byte payload", data.len());
+        }
+        Ok(messages::MessageType::Profile { display_name, .. }) => {
+            println!("Peer's display name: {}", display_name);
+        }
+        Ok(messages::MessageType::FileRef { url, size, .. }) => {
+            // This client has no blob downloader; apps fetch and call transfer::open_file_ref
+            println!("Peer shared a {} byte file stored at {} (not fetched)", size, url);
+        }
+        Ok(messages::MessageType::Unknown { tag, raw }) => {
+            println!("Ignored message of unknown type {} ({} bytes)", tag, raw.len());
+        }
+        Ok(messages::MessageType::Bye) => {
+            println!("Peer ended the session.");
+            terminal::disable_raw_mode().unwrap();
+            std::process::exit(0);
+        }
+        Ok(message) => {
+            match transfers.handle_incoming(&message) {
+                Ok(Some(transfer::TransferEvent::Started(info))) => {
+                    println!(
+                        "Receiving file: {} ({} bytes)",
+                        info.filename,
+                        info.total_size,
+                    );
+                }
+                Ok(Some(transfer::TransferEvent::Completed { info, path })) => {
+                    println!(
+                        "Received file - {} -> {}",
+                        info.filename,
+                        path.display(),
+                    );
+                }
+                Ok(Some(transfer::TransferEvent::Progress(info))) => {
+                    // Shown before the prompt until the next render clears it
+                    let percent = (info.bytes_done * 100).checked_div(info.total_size).unwrap_or(100);
+                    print!("[{} {}%] ", info.filename, percent);
+                }
+                Ok(Some(transfer::TransferEvent::Cancelled(info))) => {
+                    println!("Transfer cancelled: {}", info.filename);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("File transfer failed: {}", e);
+                }
+            }
+        }
+        Err(e) => {
+            if matches!(
+                e.downcast_ref::<ratchet::RatchetError>(),
+                Some(ratchet::RatchetError::AuthenticationFailed),
+            ) {
+                eprintln!("⚠️  SECURITY WARNING: a message failed authentication and was dropped.");
+                eprintln!("    It may have been tampered with in transit.");
+            } else {
+                eprintln!("Failed to receive message: {}", e);
+            }
+        }
+    }
+
+    print!("You: {}", input);
+    io::stdout().flush().unwrap();
+}
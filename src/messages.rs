@@ -2,82 +2,1082 @@
  * messages.rs
  */
 use anyhow::{Context, Result};
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use zeroize::Zeroize;
+
+/// Longest allowed control channel name in bytes
+pub const MAX_CHANNEL_LEN: usize = 64;
+
+/// Longest allowed display name in a `Profile`, in bytes
+pub const MAX_DISPLAY_NAME_LEN: usize = 64;
+
+/// Largest avatar image allowed in a `Profile`, in bytes
+pub const MAX_AVATAR_SIZE: usize = 64 * 1024;
+
+/// Optional message groups this build understands, advertised in `Capabilities`
+/// Text, File and Bye predate the exchange and are always supported.
+pub const FEATURES: &[&str] = &["control", "file-transfer", "archive", "rekey", "segments", "heartbeat", "file-ref", "profile", "compression"];
 
 #[derive(Debug)]
 pub enum MessageType {
-    Text(String),
-    File { filename: String, data: Vec<u8> },
+    /// `ttl_secs`: the sender asks for the message to be deleted this long after receipt
+    Text { text: String, ttl_secs: Option<u32> },
+    File { filename: String, data: Vec<u8>, ttl_secs: Option<u32> },
+    /// App-defined subprotocol payload, routed by channel name
+    Control { channel: String, payload: Vec<u8> },
+    /// First message of a chunked transfer
+    FileStart { transfer_id: u64, filename: String, total_size: u64 },
+    /// Next slice of a chunked transfer, in order
+    FileChunk { transfer_id: u64, data: Vec<u8> },
+    /// All chunks of a transfer have been sent
+    /// `hash` is the BLAKE3 hash of the whole file, computed chunk by chunk
+    FileEnd { transfer_id: u64, hash: [u8; 32] },
+    /// Either side abandoned a transfer; partial data should be discarded
+    /// Each side numbers its own uploads, so `sender_side` says whose id this
+    /// is: true when the canceller is the one sending the file
+    FileCancel { transfer_id: u64, sender_side: bool },
+    /// Several files delivered together as (filename, data) pairs
+    Archive { entries: Vec<(String, Vec<u8>)> },
+    /// The sender is closing the session; nothing follows it
+    Bye,
+    /// Start of a rekey (see `Session::rekey`): the sender's ephemeral X25519 key
+    Rekey { public_key: [u8; 32] },
+    /// Answer to a `Rekey` with the responder's ephemeral X25519 key
+    RekeyAck { public_key: [u8; 32] },
+    /// One slice of a payload split by `Session::send_large`
+    Segment { payload_id: u64, index: u32, count: u32, data: Vec<u8> },
+    /// App payload from `Session::send_large`, sent whole or reassembled from segments
+    Payload { data: Vec<u8> },
+    /// The feature names (see `FEATURES`) the sender understands
+    Capabilities { features: Vec<String> },
+    /// Carries nothing; sent only to move the sender's ratchet key forward
+    /// (see `Session::maybe_emit_heartbeat_ratchet`)
+    Heartbeat,
+    /// A file stored elsewhere (e.g. an object store), encrypted under `key`
+    /// and `nonce`; `hash` is the BLAKE3 hash of the stored ciphertext and
+    /// `size` the plaintext length (see `transfer::encrypt_blob`)
+    FileRef { url: String, key: [u8; 32], nonce: [u8; 12], hash: [u8; 32], size: u64 },
+    /// How the sender wants to be shown; build with `MessageType::profile`
+    Profile { display_name: String, avatar: Option<Vec<u8>> },
+    /// A type tag this build does not know, most likely from a newer peer
+    /// `raw` is the undecoded body; receivers should log and skip it
+    Unknown { tag: u8, raw: Vec<u8> },
+}
+
+impl MessageType {
+    /// Build a control message, validating the channel name
+    pub fn control(channel: &str, payload: Vec<u8>) -> Result<Self> {
+        validate_channel(channel)?;
+        Ok(MessageType::Control {
+            channel: channel.to_string(),
+            payload,
+        })
+    }
+
+    /// Build a profile message, validating the name and avatar size
+    pub fn profile(display_name: &str, avatar: Option<Vec<u8>>) -> Result<Self> {
+        validate_profile(display_name, avatar.as_deref())?;
+        Ok(MessageType::Profile {
+            display_name: display_name.to_string(),
+            avatar,
+        })
+    }
+}
+
+/// Wipes the user content (text, file data, payloads) once a message is done with
+impl Zeroize for MessageType {
+    fn zeroize(&mut self) {
+        match self {
+            MessageType::Text { text, .. } => text.zeroize(),
+            MessageType::File { filename, data, .. } => {
+                filename.zeroize();
+                data.zeroize();
+            }
+            MessageType::Control { payload, .. } => payload.zeroize(),
+            MessageType::FileStart { filename, .. } => filename.zeroize(),
+            MessageType::FileChunk { data, .. }
+            | MessageType::Segment { data, .. }
+            | MessageType::Payload { data }
+            | MessageType::Unknown { raw: data, .. } => data.zeroize(),
+            MessageType::Archive { entries } => {
+                for (filename, data) in entries.iter_mut() {
+                    filename.zeroize();
+                    data.zeroize();
+                }
+            }
+            MessageType::FileRef { key, .. } => key.zeroize(),
+            MessageType::Profile { display_name, avatar } => {
+                display_name.zeroize();
+                avatar.zeroize();
+            }
+            MessageType::FileEnd { .. }
+            | MessageType::FileCancel { .. }
+            | MessageType::Bye
+            | MessageType::Rekey { .. }
+            | MessageType::RekeyAck { .. }
+            | MessageType::Capabilities { .. }
+            | MessageType::Heartbeat => {}
+        }
+    }
+}
+
+/// Check that a control channel name is non-empty and not too long
+pub fn validate_channel(channel: &str) -> Result<()> {
+    if channel.is_empty() {
+        anyhow::bail!("Empty control channel name");
+    }
+    if channel.len() > MAX_CHANNEL_LEN {
+        anyhow::bail!(
+            "Control channel name too long: {} bytes (max {})",
+            channel.len(),
+            MAX_CHANNEL_LEN
+        );
+    }
+    Ok(())
+}
+
+/// Check a display name (non-empty, at most `MAX_DISPLAY_NAME_LEN` bytes,
+/// no control characters) and avatar size (at most `MAX_AVATAR_SIZE`)
+pub fn validate_profile(display_name: &str, avatar: Option<&[u8]>) -> Result<()> {
+    if display_name.trim().is_empty() {
+        anyhow::bail!("Empty display name");
+    }
+    if display_name.len() > MAX_DISPLAY_NAME_LEN {
+        anyhow::bail!(
+            "Display name too long: {} bytes (max {})",
+            display_name.len(),
+            MAX_DISPLAY_NAME_LEN
+        );
+    }
+    // Names end up on terminals and in UIs; refuse escape sequences and line breaks
+    if display_name.chars().any(char::is_control) {
+        anyhow::bail!("Display name contains control characters");
+    }
+    if let Some(avatar) = avatar {
+        if avatar.len() > MAX_AVATAR_SIZE {
+            anyhow::bail!("Avatar too large: {} bytes (max {})", avatar.len(), MAX_AVATAR_SIZE);
+        }
+    }
+    Ok(())
+}
+
+/// Handler invoked with the payload of a control message
+pub type ControlHandler = Box<dyn FnMut(&[u8]) + Send>;
+
+/// Routes received control messages to handlers by channel name
+#[derive(Default)]
+pub struct ControlDispatcher {
+    handlers: HashMap<String, ControlHandler>,
 }
 
-/// Parse input from user - detect file transfer command with !
-pub fn parse_input(input: &str) -> Result<MessageType> {
-    if input.starts_with('!') {
-        let path = input[1..].trim();
-        let filename = Path::new(path)
+impl ControlDispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register (or replace) the handler for a channel
+    pub fn register<F>(&mut self, channel: &str, handler: F) -> Result<()>
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        validate_channel(channel)?;
+        self.handlers.insert(channel.to_string(), Box::new(handler));
+        Ok(())
+    }
+
+    /// Deliver a payload to its channel handler
+    /// Returns false if no handler is registered for the channel
+    pub fn dispatch(&mut self, channel: &str, payload: &[u8]) -> bool {
+        match self.handlers.get_mut(channel) {
+            Some(handler) => {
+                handler(payload);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A line typed by the user: something to send, or a command handled locally
+#[derive(Debug)]
+pub enum ParsedInput {
+    Message(MessageType),
+    Command(Command),
+}
+
+/// Commands handled by the local client and never sent to the peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Change the name shown for your own messages
+    Nick(String),
+    /// End the session
+    Quit,
+}
+
+/// Parse input from user
+///
+/// `/file <path>` sends a file (a directory sends every file in it),
+/// `/nick <name>` and `/quit` are local commands, and a leading `//` sends
+/// text starting with `/`. `!<path>` is kept as a shorthand for `/file <path>`.
+/// Anything else is sent as text.
+pub fn parse_input(input: &str) -> Result<ParsedInput> {
+    if let Some(path) = input.strip_prefix('!') {
+        return read_file_input(path.trim()).map(ParsedInput::Message);
+    }
+    if let Some(text) = input.strip_prefix("//") {
+        return Ok(ParsedInput::Message(MessageType::Text { text: format!("/{}", text), ttl_secs: None }));
+    }
+    let Some(command) = input.strip_prefix('/') else {
+        return Ok(ParsedInput::Message(MessageType::Text { text: input.to_string(), ttl_secs: None }));
+    };
+
+    let (name, arg) = match command.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (command, ""),
+    };
+
+    match name {
+        "file" if !arg.is_empty() => read_file_input(arg).map(ParsedInput::Message),
+        "file" => anyhow::bail!("Usage: /file <path>"),
+        "nick" if !arg.is_empty() => Ok(ParsedInput::Command(Command::Nick(arg.to_string()))),
+        "nick" => anyhow::bail!("Usage: /nick <name>"),
+        "quit" if arg.is_empty() => Ok(ParsedInput::Command(Command::Quit)),
+        "quit" => anyhow::bail!("Usage: /quit"),
+        _ => anyhow::bail!("Unknown command: /{} (start with // to send it as text)", name),
+    }
+}
+
+/// Read a file, or every file in a directory, into a message
+fn read_file_input(path: &str) -> Result<MessageType> {
+    let filename = Path::new(path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("Invalid filename")?
+        .to_string();
+
+    if Path::new(path).is_dir() {
+        return read_archive(Path::new(path));
+    }
+
+    let data = fs::read(path)
+        .context(format!("Failed to read file: {}", path))?;
+
+    Ok(MessageType::File { filename, data, ttl_secs: None })
+}
+
+/// Collect the regular files directly inside `dir` into an Archive message
+fn read_archive(dir: &Path) -> Result<MessageType> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir).context(format!("Failed to read directory: {}", dir.display()))? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let filename = path
             .file_name()
             .and_then(|n| n.to_str())
             .context("Invalid filename")?
             .to_string();
-        
-        let data = fs::read(path)
-            .context(format!("Failed to read file: {}", path))?;
-        
-        Ok(MessageType::File { filename, data })
-    } else {
-        Ok(MessageType::Text(input.to_string()))
+        let data = fs::read(&path)
+            .context(format!("Failed to read file: {}", path.display()))?;
+        entries.push((filename, data));
+    }
+
+    if entries.is_empty() {
+        anyhow::bail!("No files to send in {}", dir.display());
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(MessageType::Archive { entries })
+}
+
+/// Extensions of formats that are already compressed
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp3", "aac", "ogg", "opus", "flac", "m4a",
+    "mp4", "mkv", "mov", "webm", "avi",
+    "zip", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar",
+    "pdf", "docx", "xlsx", "pptx", "odt", "apk", "jar",
+];
+
+/// Bytes sampled by the entropy estimate
+const ENTROPY_SAMPLE: usize = 1024;
+
+/// Shannon entropy (bits per byte) above which data is treated as incompressible
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.5;
+
+/// Whether compressing a message is likely to pay off (see `serialize_message_compressed`)
+///
+/// Text always is. File data is skipped for known compressed formats by
+/// extension, and otherwise (like chunks and payloads, which carry no name)
+/// judged by the entropy of its first KB. An archive qualifies if any
+/// entry does. Everything else is too small to bother with.
+pub fn worth_compressing(message: &MessageType) -> bool {
+    match message {
+        MessageType::Text { .. } => true,
+        MessageType::File { filename, data, .. } => file_worth_compressing(filename, data),
+        MessageType::Archive { entries } => entries
+            .iter()
+            .any(|(filename, data)| file_worth_compressing(filename, data)),
+        MessageType::FileChunk { data, .. }
+        | MessageType::Segment { data, .. }
+        | MessageType::Payload { data } => !looks_incompressible(data),
+        _ => false,
+    }
+}
+
+fn file_worth_compressing(filename: &str, data: &[u8]) -> bool {
+    let compressed_format = Path::new(filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| COMPRESSED_EXTENSIONS.iter().any(|known| known.eq_ignore_ascii_case(ext)));
+    !compressed_format && !looks_incompressible(data)
+}
+
+/// Entropy estimate over the first `ENTROPY_SAMPLE` bytes
+fn looks_incompressible(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(ENTROPY_SAMPLE)];
+    if sample.is_empty() {
+        return false;
+    }
+
+    let mut counts = [0u32; 256];
+    for &byte in sample {
+        counts[byte as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    entropy > INCOMPRESSIBLE_ENTROPY
+}
+
+/// Wire tags for each message type
+const TAG_TEXT: u8 = 0;
+const TAG_FILE: u8 = 1;
+const TAG_CONTROL: u8 = 2;
+const TAG_FILE_START: u8 = 3;
+const TAG_FILE_CHUNK: u8 = 4;
+const TAG_FILE_END: u8 = 5;
+const TAG_FILE_CANCEL: u8 = 6;
+const TAG_ARCHIVE: u8 = 7;
+const TAG_BYE: u8 = 8;
+const TAG_REKEY: u8 = 9;
+const TAG_REKEY_ACK: u8 = 10;
+const TAG_SEGMENT: u8 = 11;
+const TAG_PAYLOAD: u8 = 12;
+const TAG_CAPABILITIES: u8 = 13;
+const TAG_HEARTBEAT: u8 = 14;
+const TAG_FILE_REF: u8 = 15;
+const TAG_PROFILE: u8 = 16;
+/// A DEFLATE-compressed serialized message of any other tag
+const TAG_COMPRESSED: u8 = 17;
+
+/// DEFLATE level for compressed messages
+const COMPRESSION_LEVEL: u8 = 6;
+
+/// Errors produced while decoding a decrypted message
+#[derive(Debug)]
+pub enum MessageError {
+    Empty,
+    MalformedMessage { tag: u8, reason: String },
+}
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageError::Empty => write!(f, "Empty message buffer"),
+            MessageError::MalformedMessage { tag, reason } => {
+                write!(f, "Malformed message (tag {}): {}", tag, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+/// Append a u32 length prefix (little endian) followed by the bytes
+fn put_field(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Append a fixed-width u64 (little endian)
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Append a fixed-width u32 (little endian)
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Append a TTL as a trailing u32; messages without one end after their last field
+fn put_ttl(buf: &mut Vec<u8>, ttl_secs: Option<u32>) {
+    if let Some(ttl) = ttl_secs {
+        put_u32(buf, ttl);
+    }
+}
+
+/// Bounds-checked reader over a message body
+struct FieldReader<'a> {
+    tag: u8,
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> FieldReader<'a> {
+    fn new(tag: u8, buf: &'a [u8]) -> Self {
+        Self { tag, buf, offset: 0 }
+    }
+
+    fn malformed(&self, reason: impl Into<String>) -> MessageError {
+        MessageError::MalformedMessage {
+            tag: self.tag,
+            reason: reason.into(),
+        }
+    }
+
+    /// Read a length-prefixed field
+    fn field(&mut self, name: &str) -> Result<&'a [u8], MessageError> {
+        let remaining = self.buf.len() - self.offset;
+        if remaining < 4 {
+            return Err(self.malformed(format!("truncated {} length", name)));
+        }
+        let len_bytes: [u8; 4] = self.buf[self.offset..self.offset + 4].try_into().unwrap();
+        let len = u32::from_le_bytes(len_bytes) as usize;
+        self.offset += 4;
+
+        if len > remaining - 4 {
+            return Err(self.malformed(format!(
+                "{} length {} exceeds remaining {} bytes",
+                name,
+                len,
+                remaining - 4
+            )));
+        }
+        let bytes = &self.buf[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    /// Read a one-byte boolean, 0 or 1
+    fn flag(&mut self, name: &str) -> Result<bool, MessageError> {
+        match self.buf.get(self.offset) {
+            Some(&value @ (0 | 1)) => {
+                self.offset += 1;
+                Ok(value == 1)
+            }
+            Some(value) => Err(self.malformed(format!("invalid {} {}", name, value))),
+            None => Err(self.malformed(format!("truncated {}", name))),
+        }
+    }
+
+    /// Read a fixed-width u32
+    fn u32(&mut self, name: &str) -> Result<u32, MessageError> {
+        if self.buf.len() - self.offset < 4 {
+            return Err(self.malformed(format!("truncated {}", name)));
+        }
+        let bytes: [u8; 4] = self.buf[self.offset..self.offset + 4].try_into().unwrap();
+        self.offset += 4;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Read a fixed-width u64
+    fn u64(&mut self, name: &str) -> Result<u64, MessageError> {
+        if self.buf.len() - self.offset < 8 {
+            return Err(self.malformed(format!("truncated {}", name)));
+        }
+        let bytes: [u8; 8] = self.buf[self.offset..self.offset + 8].try_into().unwrap();
+        self.offset += 8;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Read a fixed-width 32-byte hash or key
+    fn bytes32(&mut self, name: &str) -> Result<[u8; 32], MessageError> {
+        if self.buf.len() - self.offset < 32 {
+            return Err(self.malformed(format!("truncated {}", name)));
+        }
+        let bytes: [u8; 32] = self.buf[self.offset..self.offset + 32].try_into().unwrap();
+        self.offset += 32;
+        Ok(bytes)
+    }
+
+    /// Read a fixed-width 12-byte nonce
+    fn bytes12(&mut self, name: &str) -> Result<[u8; 12], MessageError> {
+        if self.buf.len() - self.offset < 12 {
+            return Err(self.malformed(format!("truncated {}", name)));
+        }
+        let bytes: [u8; 12] = self.buf[self.offset..self.offset + 12].try_into().unwrap();
+        self.offset += 12;
+        Ok(bytes)
+    }
+
+    /// Read a length-prefixed UTF-8 field
+    fn string(&mut self, name: &str) -> Result<String, MessageError> {
+        let bytes = self.field(name)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| self.malformed(format!("invalid UTF-8 in {}", name)))
+    }
+
+    /// Read the optional trailing TTL of a Text or File message
+    fn ttl(&mut self) -> Result<Option<u32>, MessageError> {
+        if self.offset == self.buf.len() {
+            return Ok(None);
+        }
+        self.u32("ttl").map(Some)
+    }
+
+    /// Require that every byte of the body was consumed
+    fn finish(self) -> Result<(), MessageError> {
+        let trailing = self.buf.len() - self.offset;
+        if trailing != 0 {
+            return Err(self.malformed(format!("{} trailing bytes", trailing)));
+        }
+        Ok(())
     }
 }
 
 /// Serialize message to bytes with type tag
+///
+/// Every variable-length field carries a u32 little-endian length prefix,
+/// so the total length can be validated exactly on receipt.
 pub fn serialize_message(msg_type: &MessageType) -> Vec<u8> {
     match msg_type {
-        MessageType::Text(text) => {
-            let mut buf = vec![0u8]; // Type byte: 0 = text
-            buf.extend_from_slice(text.as_bytes());
+        MessageType::Text { text, ttl_secs } => {
+            let mut buf = vec![TAG_TEXT];
+            put_field(&mut buf, text.as_bytes());
+            put_ttl(&mut buf, *ttl_secs);
+            buf
+        }
+        MessageType::File { filename, data, ttl_secs } => {
+            let mut buf = vec![TAG_FILE];
+            put_field(&mut buf, filename.as_bytes());
+            put_field(&mut buf, data);
+            put_ttl(&mut buf, *ttl_secs);
+            buf
+        }
+        MessageType::Control { channel, payload } => {
+            let mut buf = vec![TAG_CONTROL];
+            put_field(&mut buf, channel.as_bytes());
+            put_field(&mut buf, payload);
+            buf
+        }
+        MessageType::FileStart { transfer_id, filename, total_size } => {
+            let mut buf = vec![TAG_FILE_START];
+            put_u64(&mut buf, *transfer_id);
+            put_field(&mut buf, filename.as_bytes());
+            put_u64(&mut buf, *total_size);
+            buf
+        }
+        MessageType::FileChunk { transfer_id, data } => {
+            let mut buf = vec![TAG_FILE_CHUNK];
+            put_u64(&mut buf, *transfer_id);
+            put_field(&mut buf, data);
+            buf
+        }
+        MessageType::FileEnd { transfer_id, hash } => {
+            let mut buf = vec![TAG_FILE_END];
+            put_u64(&mut buf, *transfer_id);
+            buf.extend_from_slice(hash);
             buf
         }
-        MessageType::File { filename, data } => {
-            let mut buf = vec![1u8]; // Type byte: 1 = file
-            let name_bytes = filename.as_bytes();
-            buf.extend_from_slice(&(name_bytes.len() as u32).to_le_bytes());
-            buf.extend_from_slice(name_bytes);
-            buf.extend_from_slice(data);
+        MessageType::FileCancel { transfer_id, sender_side } => {
+            let mut buf = vec![TAG_FILE_CANCEL];
+            put_u64(&mut buf, *transfer_id);
+            buf.push(*sender_side as u8);
+            buf
+        }
+        MessageType::Archive { entries } => {
+            let mut buf = vec![TAG_ARCHIVE];
+            put_u32(&mut buf, entries.len() as u32);
+            for (filename, data) in entries {
+                put_field(&mut buf, filename.as_bytes());
+                put_field(&mut buf, data);
+            }
+            buf
+        }
+        MessageType::Bye => vec![TAG_BYE],
+        MessageType::Heartbeat => vec![TAG_HEARTBEAT],
+        MessageType::Rekey { public_key } => {
+            let mut buf = vec![TAG_REKEY];
+            buf.extend_from_slice(public_key);
+            buf
+        }
+        MessageType::RekeyAck { public_key } => {
+            let mut buf = vec![TAG_REKEY_ACK];
+            buf.extend_from_slice(public_key);
+            buf
+        }
+        MessageType::Segment { payload_id, index, count, data } => {
+            let mut buf = vec![TAG_SEGMENT];
+            put_u64(&mut buf, *payload_id);
+            put_u32(&mut buf, *index);
+            put_u32(&mut buf, *count);
+            put_field(&mut buf, data);
+            buf
+        }
+        MessageType::Payload { data } => {
+            let mut buf = vec![TAG_PAYLOAD];
+            put_field(&mut buf, data);
+            buf
+        }
+        MessageType::Capabilities { features } => {
+            let mut buf = vec![TAG_CAPABILITIES];
+            put_u32(&mut buf, features.len() as u32);
+            for feature in features {
+                put_field(&mut buf, feature.as_bytes());
+            }
+            buf
+        }
+        MessageType::FileRef { url, key, nonce, hash, size } => {
+            let mut buf = vec![TAG_FILE_REF];
+            put_field(&mut buf, url.as_bytes());
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(nonce);
+            buf.extend_from_slice(hash);
+            put_u64(&mut buf, *size);
+            buf
+        }
+        MessageType::Profile { display_name, avatar } => {
+            let mut buf = vec![TAG_PROFILE];
+            put_field(&mut buf, display_name.as_bytes());
+            // Like a TTL, the avatar is a trailing field present only when set
+            if let Some(avatar) = avatar {
+                put_field(&mut buf, avatar);
+            }
+            buf
+        }
+        MessageType::Unknown { tag, raw } => {
+            let mut buf = vec![*tag];
+            buf.extend_from_slice(raw);
             buf
         }
     }
 }
 
+/// Serialize like `serialize_message`, compressing the result when it pays off
+///
+/// Only messages `worth_compressing` are tried, and the compressed form is
+/// kept only if it is smaller. Send this only to peers advertising the
+/// "compression" feature; `deserialize_message` undoes it transparently.
+pub fn serialize_message_compressed(msg_type: &MessageType) -> Vec<u8> {
+    let mut plain = serialize_message(msg_type);
+    if !worth_compressing(msg_type) {
+        return plain;
+    }
+
+    let mut buf = vec![TAG_COMPRESSED];
+    buf.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(&plain, COMPRESSION_LEVEL));
+    if buf.len() >= plain.len() {
+        return plain;
+    }
+    plain.zeroize();
+    buf
+}
+
+/// Inflate a compressed body (capped at `MAX_MESSAGE_SIZE`) and decode the message inside
+fn decompress_message(body: &[u8]) -> Result<MessageType> {
+    let reader = FieldReader::new(TAG_COMPRESSED, body);
+    let mut plain = miniz_oxide::inflate::decompress_to_vec_with_limit(body, crate::network::MAX_MESSAGE_SIZE)
+        .map_err(|e| reader.malformed(format!("invalid compressed body: {}", e)))?;
+    if plain.first() == Some(&TAG_COMPRESSED) {
+        plain.zeroize();
+        return Err(reader.malformed("nested compression").into());
+    }
+    let message = deserialize_message(&plain);
+    plain.zeroize();
+    message
+}
+
 /// Deserialize message from bytes
+///
+/// Fails with `MessageError::MalformedMessage` (carrying the observed tag)
+/// if the fields of a known tag don't exactly cover the payload. Unknown
+/// tags decode to `MessageType::Unknown`, since the transport frames every
+/// message and the body can be skipped without parsing it.
 pub fn deserialize_message(buf: &[u8]) -> Result<MessageType> {
-    if buf.is_empty() {
-        anyhow::bail!("Empty message buffer");
+    let (&tag, body) = buf.split_first().ok_or(MessageError::Empty)?;
+    if tag == TAG_COMPRESSED {
+        return decompress_message(body);
     }
-    
-    match buf[0] {
-        0 => {
-            // Text message
-            Ok(MessageType::Text(
-                String::from_utf8(buf[1..].to_vec())
-                    .context("Invalid UTF-8 in text message")?
-            ))
+    let mut reader = FieldReader::new(tag, body);
+
+    let message = match tag {
+        TAG_TEXT => {
+            let text = reader.string("text")?;
+            let ttl_secs = reader.ttl()?;
+            MessageType::Text { text, ttl_secs }
         }
-        1 => {
-            // File message
-            if buf.len() < 5 {
-                anyhow::bail!("File message too short");
+        TAG_FILE => {
+            let filename = reader.string("filename")?;
+            let data = reader.field("file data")?.to_vec();
+            let ttl_secs = reader.ttl()?;
+            MessageType::File { filename, data, ttl_secs }
+        }
+        TAG_CONTROL => {
+            let channel = reader.string("channel")?;
+            validate_channel(&channel).map_err(|e| reader.malformed(e.to_string()))?;
+            let payload = reader.field("control payload")?.to_vec();
+            MessageType::Control { channel, payload }
+        }
+        TAG_FILE_START => {
+            let transfer_id = reader.u64("transfer id")?;
+            let filename = reader.string("filename")?;
+            let total_size = reader.u64("total size")?;
+            MessageType::FileStart { transfer_id, filename, total_size }
+        }
+        TAG_FILE_CHUNK => {
+            let transfer_id = reader.u64("transfer id")?;
+            let data = reader.field("chunk data")?.to_vec();
+            MessageType::FileChunk { transfer_id, data }
+        }
+        TAG_FILE_END => MessageType::FileEnd {
+            transfer_id: reader.u64("transfer id")?,
+            hash: reader.bytes32("file hash")?,
+        },
+        TAG_FILE_CANCEL => MessageType::FileCancel {
+            transfer_id: reader.u64("transfer id")?,
+            sender_side: reader.flag("sender side")?,
+        },
+        TAG_ARCHIVE => {
+            let count = reader.u32("entry count")? as usize;
+            // Each entry needs at least two length prefixes
+            if count > (reader.buf.len() - reader.offset) / 8 {
+                return Err(reader.malformed(format!("entry count {} exceeds body", count)).into());
             }
-            let name_len = u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
-            if buf.len() < 5 + name_len {
-                anyhow::bail!("Invalid file message format");
+            let mut entries = Vec::with_capacity(count);
+            for _ in 0..count {
+                let filename = reader.string("entry filename")?;
+                let data = reader.field("entry data")?.to_vec();
+                entries.push((filename, data));
             }
-            let filename = String::from_utf8(buf[5..5+name_len].to_vec())
-                .context("Invalid UTF-8 in filename")?;
-            let data = buf[5+name_len..].to_vec();
-            Ok(MessageType::File { filename, data })
+            MessageType::Archive { entries }
         }
-        _ => anyhow::bail!("Unknown message type: {}", buf[0]),
-    }
+        TAG_BYE => MessageType::Bye,
+        TAG_HEARTBEAT => MessageType::Heartbeat,
+        TAG_REKEY => MessageType::Rekey {
+            public_key: reader.bytes32("rekey public key")?,
+        },
+        TAG_REKEY_ACK => MessageType::RekeyAck {
+            public_key: reader.bytes32("rekey public key")?,
+        },
+        TAG_SEGMENT => {
+            let payload_id = reader.u64("payload id")?;
+            let index = reader.u32("segment index")?;
+            let count = reader.u32("segment count")?;
+            if index >= count {
+                return Err(reader.malformed(format!("segment {} of {}", index, count)).into());
+            }
+            let data = reader.field("segment data")?.to_vec();
+            MessageType::Segment { payload_id, index, count, data }
+        }
+        TAG_PAYLOAD => MessageType::Payload {
+            data: reader.field("payload data")?.to_vec(),
+        },
+        TAG_CAPABILITIES => {
+            let count = reader.u32("feature count")? as usize;
+            // Each feature needs at least a length prefix
+            if count > (reader.buf.len() - reader.offset) / 4 {
+                return Err(reader.malformed(format!("feature count {} exceeds body", count)).into());
+            }
+            let mut features = Vec::with_capacity(count);
+            for _ in 0..count {
+                features.push(reader.string("feature")?);
+            }
+            MessageType::Capabilities { features }
+        }
+        TAG_FILE_REF => MessageType::FileRef {
+            url: reader.string("url")?,
+            key: reader.bytes32("blob key")?,
+            nonce: reader.bytes12("blob nonce")?,
+            hash: reader.bytes32("blob hash")?,
+            size: reader.u64("blob size")?,
+        },
+        TAG_PROFILE => {
+            let display_name = reader.string("display name")?;
+            let avatar = if reader.offset == reader.buf.len() {
+                None
+            } else {
+                Some(reader.field("avatar")?.to_vec())
+            };
+            validate_profile(&display_name, avatar.as_deref()).map_err(|e| reader.malformed(e.to_string()))?;
+            MessageType::Profile { display_name, avatar }
+        }
+        _ => return Ok(MessageType::Unknown { tag, raw: body.to_vec() }),
+    };
+
+    reader.finish()?;
+    Ok(message)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(filename: &str, data: Vec<u8>) -> MessageType {
+        MessageType::File { filename: filename.into(), data, ttl_secs: None }
+    }
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        (0..len).map(|_| rand::random()).collect()
+    }
+
+    #[test]
+    fn text_and_documents_are_compressed() {
+        let log = "2026-10-17 INFO connection established\n".repeat(200);
+        for message in [
+            MessageType::Text { text: log.clone(), ttl_secs: Some(60) },
+            file("server.log", log.clone().into_bytes()),
+        ] {
+            let compressed = serialize_message_compressed(&message);
+            assert_eq!(compressed[0], TAG_COMPRESSED);
+            assert!(compressed.len() < serialize_message(&message).len() / 10);
+            let decoded = deserialize_message(&compressed).unwrap();
+            assert_eq!(serialize_message(&decoded), serialize_message(&message));
+        }
+    }
+
+    #[test]
+    fn compressed_media_is_sent_as_is() {
+        // Compressible bytes, but the extension says the format is already compressed
+        let jpeg = file("photo.JPG", vec![0xff; 4096]);
+        assert!(!worth_compressing(&jpeg));
+        assert_eq!(serialize_message_compressed(&jpeg), serialize_message(&jpeg));
+
+        // No telling extension, but the data looks random
+        let blob = file("capture.bin", random_bytes(4096));
+        assert!(!worth_compressing(&blob));
+        assert_eq!(serialize_message_compressed(&blob), serialize_message(&blob));
+
+        // Short text that deflate cannot shrink stays uncompressed
+        let short = MessageType::Text { text: "hi".into(), ttl_secs: None };
+        assert_eq!(serialize_message_compressed(&short), serialize_message(&short));
+    }
+
+    #[test]
+    fn hostile_compressed_bodies_are_refused() {
+        let mut nested = vec![TAG_COMPRESSED];
+        nested.extend(miniz_oxide::deflate::compress_to_vec(&[TAG_COMPRESSED, 0], COMPRESSION_LEVEL));
+        let mut bomb = vec![TAG_COMPRESSED];
+        bomb.extend(miniz_oxide::deflate::compress_to_vec(
+            &vec![0; crate::network::MAX_MESSAGE_SIZE + 1],
+            COMPRESSION_LEVEL,
+        ));
+
+        for buf in [nested, bomb, vec![TAG_COMPRESSED, 0xff, 0xff]] {
+            let error = deserialize_message(&buf).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref(),
+                Some(MessageError::MalformedMessage { tag: TAG_COMPRESSED, .. })
+            ));
+        }
+    }
+
+    /// One of every variant, with optional fields both set and unset
+    fn every_variant() -> Vec<MessageType> {
+        vec![
+            MessageType::Text { text: "hello".into(), ttl_secs: None },
+            MessageType::Text { text: "héllo".into(), ttl_secs: Some(30) },
+            file("a.txt", b"contents".to_vec()),
+            MessageType::File { filename: "b.bin".into(), data: vec![], ttl_secs: Some(5) },
+            MessageType::control("app.sync", vec![1, 2, 3]).unwrap(),
+            MessageType::FileStart { transfer_id: 7, filename: "big.iso".into(), total_size: 1 << 40 },
+            MessageType::FileChunk { transfer_id: 7, data: vec![9; 100] },
+            MessageType::FileEnd { transfer_id: 7, hash: [3; 32] },
+            MessageType::FileCancel { transfer_id: 7, sender_side: true },
+            MessageType::FileCancel { transfer_id: u64::MAX, sender_side: false },
+            MessageType::Archive { entries: vec![("x".into(), vec![1]), ("y".into(), vec![])] },
+            MessageType::Archive { entries: vec![] },
+            MessageType::Bye,
+            MessageType::Rekey { public_key: [4; 32] },
+            MessageType::RekeyAck { public_key: [5; 32] },
+            MessageType::Segment { payload_id: 1, index: 2, count: 3, data: vec![6; 10] },
+            MessageType::Payload { data: vec![7; 10] },
+            MessageType::Capabilities { features: FEATURES.iter().map(|f| f.to_string()).collect() },
+            MessageType::Heartbeat,
+            MessageType::FileRef { url: "https://example.com/blob".into(), key: [1; 32], nonce: [2; 12], hash: [3; 32], size: 42 },
+            MessageType::profile("Alice", None).unwrap(),
+            MessageType::profile("Alice", Some(vec![8; 64])).unwrap(),
+        ]
+    }
+
+    fn assert_malformed(buf: &[u8], tag: u8) {
+        let Err(error) = deserialize_message(buf) else {
+            panic!("{:?} decoded", buf);
+        };
+        assert!(
+            matches!(error.downcast_ref(), Some(MessageError::MalformedMessage { tag: t, .. }) if *t == tag),
+            "tag {}: {}",
+            tag,
+            error
+        );
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        for message in every_variant() {
+            let buf = serialize_message(&message);
+            let decoded = deserialize_message(&buf).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+            assert_eq!(serialize_message(&decoded), buf);
+        }
+    }
+
+    #[test]
+    fn truncated_and_padded_messages_are_malformed() {
+        for message in every_variant() {
+            let buf = serialize_message(&message);
+            if buf.len() > 1 {
+                assert_malformed(&buf[..buf.len() - 1], buf[0]);
+            }
+            let mut padded = buf.clone();
+            padded.push(0);
+            assert_malformed(&padded, buf[0]);
+        }
+
+        // A length prefix claiming more than the body holds
+        let mut buf = serialize_message(&MessageType::Payload { data: vec![1; 4] });
+        buf[1..5].copy_from_slice(&5u32.to_le_bytes());
+        assert_malformed(&buf, TAG_PAYLOAD);
+        buf[1..5].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_malformed(&buf, TAG_PAYLOAD);
+
+        assert!(matches!(deserialize_message(&[]).unwrap_err().downcast_ref(), Some(MessageError::Empty)));
+    }
+
+    #[test]
+    fn unknown_tags_are_kept_raw() {
+        let buf = [200, 1, 2, 3];
+        let message = deserialize_message(&buf).unwrap();
+        assert!(matches!(&message, MessageType::Unknown { tag: 200, raw } if raw == &[1, 2, 3]));
+        assert_eq!(serialize_message(&message), buf);
+    }
+
+    #[test]
+    fn control_messages_reach_their_channel_handler() {
+        let received = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut dispatcher = ControlDispatcher::new();
+        let log = received.clone();
+        dispatcher.register("typing", move |payload| log.lock().unwrap().push(("typing", payload.to_vec()))).unwrap();
+        let log = received.clone();
+        dispatcher.register("receipts", move |payload| log.lock().unwrap().push(("receipts", payload.to_vec()))).unwrap();
+
+        let MessageType::Control { channel, payload } =
+            deserialize_message(&serialize_message(&MessageType::control("receipts", vec![1, 2]).unwrap())).unwrap()
+        else {
+            panic!("not a control message");
+        };
+        assert!(dispatcher.dispatch(&channel, &payload));
+        assert!(!dispatcher.dispatch("presence", b"away"));
+
+        // Registering again replaces the handler
+        let log = received.clone();
+        dispatcher.register("typing", move |payload| log.lock().unwrap().push(("replaced", payload.to_vec()))).unwrap();
+        assert!(dispatcher.dispatch("typing", &[3]));
+        assert_eq!(*received.lock().unwrap(), vec![("receipts", vec![1, 2]), ("replaced", vec![3])]);
+    }
+
+    #[test]
+    fn bad_channel_names_are_refused() {
+        let too_long = "c".repeat(MAX_CHANNEL_LEN + 1);
+        for channel in ["", too_long.as_str()] {
+            assert!(MessageType::control(channel, Vec::new()).is_err());
+            assert!(ControlDispatcher::new().register(channel, |_| {}).is_err());
+            let forged = MessageType::Control { channel: channel.into(), payload: Vec::new() };
+            assert_malformed(&serialize_message(&forged), TAG_CONTROL);
+        }
+        assert!(MessageType::control(&"c".repeat(MAX_CHANNEL_LEN), Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn every_input_form_parses() {
+        let text = |input: &str| match parse_input(input).unwrap() {
+            ParsedInput::Message(MessageType::Text { text, ttl_secs: None }) => text,
+            other => panic!("{:?} parsed as {:?}", input, other),
+        };
+        let command = |input: &str| match parse_input(input).unwrap() {
+            ParsedInput::Command(command) => command,
+            other => panic!("{:?} parsed as {:?}", input, other),
+        };
+        let error = |input: &str| parse_input(input).unwrap_err().to_string();
+
+        assert_eq!(text("hello /there"), "hello /there");
+        assert_eq!(text("//nick is a command"), "/nick is a command");
+        assert_eq!(command("/nick  Alice B "), Command::Nick("Alice B".into()));
+        assert_eq!(command("/quit"), Command::Quit);
+        assert_eq!(error("/nick"), "Usage: /nick <name>");
+        assert_eq!(error("/quit now"), "Usage: /quit");
+        assert_eq!(error("/file "), "Usage: /file <path>");
+        assert_eq!(error("/shrug"), "Unknown command: /shrug (start with // to send it as text)");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        fs::write(&path, b"notes").unwrap();
+        for input in [format!("/file {}", path.display()), format!("! {}", path.display())] {
+            let Ok(ParsedInput::Message(MessageType::File { filename, data, ttl_secs: None })) = parse_input(&input) else {
+                panic!("{:?} did not read the file", input);
+            };
+            assert_eq!((filename.as_str(), data.as_slice()), ("notes.txt", b"notes".as_slice()));
+        }
+        assert!(parse_input(&format!("/file {}", dir.path().join("missing").display())).is_err());
+    }
+
+    #[test]
+    fn ttl_is_an_optional_trailing_field() {
+        let text = |ttl_secs| MessageType::Text { text: "gone soon".into(), ttl_secs };
+        let file = |ttl_secs| MessageType::File { filename: "a.txt".into(), data: vec![1, 2], ttl_secs };
+
+        for (without, with, tag) in [(text(None), text(Some(30)), TAG_TEXT), (file(None), file(Some(30)), TAG_FILE)] {
+            let plain = serialize_message(&without);
+            let expiring = serialize_message(&with);
+            // Without a TTL the encoding is the one older peers send
+            assert_eq!(&expiring[..plain.len()], plain.as_slice());
+            assert_eq!(&expiring[plain.len()..], 30u32.to_le_bytes());
+            assert_eq!(serialize_message(&deserialize_message(&expiring).unwrap()), expiring);
+            assert_eq!(serialize_message(&deserialize_message(&plain).unwrap()), plain);
+            for cut in 1..4 {
+                assert_malformed(&expiring[..expiring.len() - cut], tag);
+            }
+        }
+    }
+
+    #[test]
+    fn profiles_round_trip_within_their_limits() {
+        for avatar in [None, Some(Vec::new()), Some(vec![5u8; MAX_AVATAR_SIZE])] {
+            let profile = MessageType::profile("Ünïcode name", avatar.clone()).unwrap();
+            match deserialize_message(&serialize_message(&profile)).unwrap() {
+                MessageType::Profile { display_name, avatar: received } => {
+                    assert_eq!(display_name, "Ünïcode name");
+                    assert_eq!(received, avatar);
+                }
+                other => panic!("expected a profile, got {:?}", other),
+            }
+        }
+
+        let longest = "a".repeat(MAX_DISPLAY_NAME_LEN);
+        assert!(MessageType::profile(&longest, None).is_ok());
+        let too_long = longest + "a";
+        let too_large = vec![0u8; MAX_AVATAR_SIZE + 1];
+        let refused: [(&str, Option<Vec<u8>>); 5] = [
+            ("", None),
+            ("   ", None),
+            ("Mallory\u{1b}[2J", None),
+            (&too_long, None),
+            ("Alice", Some(too_large)),
+        ];
+        for (display_name, avatar) in refused {
+            assert!(MessageType::profile(display_name, avatar.clone()).is_err());
+            // A peer that skips the builder is refused on receipt
+            let raw = serialize_message(&MessageType::Profile { display_name: display_name.into(), avatar });
+            let error = deserialize_message(&raw).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(MessageError::MalformedMessage { tag: TAG_PROFILE, .. })), "{}", error);
+        }
+    }
+}
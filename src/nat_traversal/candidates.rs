@@ -0,0 +1,189 @@
+/**
+ * nat_traversal/candidates.rs
+ *
+ * ICE-lite candidate priorities and deterministic pair nomination
+ */
+
+use std::cmp::Reverse;
+use std::net::SocketAddr;
+
+/// Where a candidate address came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandidateKind {
+    /// Address of the local interface
+    Host,
+    /// Public mapping reported by STUN
+    ServerReflexive,
+}
+
+impl CandidateKind {
+    /// RFC 8445 recommended type preference
+    fn type_preference(self) -> u32 {
+        match self {
+            CandidateKind::Host => 126,
+            CandidateKind::ServerReflexive => 100,
+        }
+    }
+}
+
+/// One address a peer can be reached on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub addr: SocketAddr,
+    pub kind: CandidateKind,
+    pub priority: u32,
+}
+
+impl Candidate {
+    pub fn new(addr: SocketAddr, kind: CandidateKind) -> Self {
+        // IPv6 is preferred over IPv4 among candidates of the same type
+        let local_preference = if addr.is_ipv6() { 65535 } else { 65534 };
+        Self {
+            addr,
+            kind,
+            priority: candidate_priority(kind, local_preference),
+        }
+    }
+}
+
+/// RFC 8445 candidate priority for the single component we use
+pub fn candidate_priority(kind: CandidateKind, local_preference: u16) -> u32 {
+    const COMPONENT: u32 = 1;
+    (kind.type_preference() << 24) + ((local_preference as u32) << 8) + (256 - COMPONENT)
+}
+
+/// Candidates for one peer, highest priority first
+///
+/// Only address families that also appear in `peer_addrs` are kept, since
+/// the other side has no way to reach the rest (empty `peer_addrs` keeps
+/// every family). A reflexive address equal to the host address (no NAT)
+/// is listed once, as a host candidate.
+pub fn gather_candidates(
+    reflexive_addrs: &[SocketAddr],
+    local_addr: SocketAddr,
+    peer_addrs: &[SocketAddr],
+) -> Vec<Candidate> {
+    let mut candidates = vec![Candidate::new(local_addr, CandidateKind::Host)];
+    for addr in reflexive_addrs {
+        if !candidates.iter().any(|c| c.addr == *addr) {
+            candidates.push(Candidate::new(*addr, CandidateKind::ServerReflexive));
+        }
+    }
+    candidates.retain(|c| peer_addrs.is_empty() || shares_family(c.addr, peer_addrs));
+    candidates.sort_by_key(|c| Reverse(c.priority));
+    candidates
+}
+
+/// Whether any of `addrs` is in the same address family as `addr`
+pub fn shares_family(addr: SocketAddr, addrs: &[SocketAddr]) -> bool {
+    addrs.iter().any(|other| other.is_ipv4() == addr.is_ipv4())
+}
+
+/// Which peer decides the nominated pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IceRole {
+    Controlling,
+    Controlled,
+}
+
+impl IceRole {
+    /// The peer with the lower fingerprint controls, as it also initiates the handshake
+    pub fn for_peers(local_fingerprint: &str, peer_fingerprint: &str) -> Self {
+        if local_fingerprint < peer_fingerprint {
+            IceRole::Controlling
+        } else {
+            IceRole::Controlled
+        }
+    }
+}
+
+/// A local and a remote candidate, ranked identically by both peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CandidatePair {
+    pub local: Candidate,
+    pub remote: Candidate,
+    pub priority: u64,
+}
+
+/// RFC 8445 pair priority from the controlling (g) and controlled (d) candidates
+pub fn pair_priority(g: u32, d: u32) -> u64 {
+    let (g, d) = (g as u64, d as u64);
+    (g.min(d) << 32) + 2 * g.max(d) + u64::from(g > d)
+}
+
+/// Every same-family pair, highest priority first
+///
+/// Both peers rank pairs with the same formula, so given the same successful
+/// checks they nominate the same pair.
+pub fn pair_candidates(role: IceRole, local: &[Candidate], remote: &[Candidate]) -> Vec<CandidatePair> {
+    let mut pairs = Vec::new();
+    for l in local {
+        for r in remote.iter().filter(|r| shares_family(r.addr, &[l.addr])) {
+            let priority = match role {
+                IceRole::Controlling => pair_priority(l.priority, r.priority),
+                IceRole::Controlled => pair_priority(r.priority, l.priority),
+            };
+            pairs.push(CandidatePair { local: *l, remote: *r, priority });
+        }
+    }
+    pairs.sort_by_key(|pair| Reverse(pair.priority));
+    pairs
+}
+
+/// Highest priority pair whose remote candidate answered our probes
+pub fn nominate(pairs: &[CandidatePair], responded: &[SocketAddr]) -> Option<CandidatePair> {
+    pairs.iter().find(|pair| responded.contains(&pair.remote.addr)).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn both_peers_nominate_the_same_pair() {
+        let alice_local = addr("192.168.1.2:4000");
+        let alice_reflexive = [addr("203.0.113.5:50000"), addr("[2001:db8::5]:4000")];
+        let bob_local = addr("10.0.0.7:5000");
+        let bob_reflexive = [addr("198.51.100.9:60000")];
+
+        let alice = gather_candidates(&alice_reflexive, alice_local, &[]);
+        let bob = gather_candidates(&bob_reflexive, bob_local, &[]);
+        // Bob has no IPv6, so Alice's IPv6 candidate never pairs
+        let alice_pairs = pair_candidates(IceRole::for_peers("aaaa", "bbbb"), &alice, &bob);
+        let bob_pairs = pair_candidates(IceRole::for_peers("bbbb", "aaaa"), &bob, &alice);
+        assert_eq!(alice_pairs.len(), 4);
+        assert_eq!(bob_pairs.len(), 4);
+
+        let mirrored = |pair: &CandidatePair| (pair.remote.addr, pair.local.addr, pair.priority);
+        let alice_ranking: Vec<_> = alice_pairs.iter().map(|p| (p.local.addr, p.remote.addr, p.priority)).collect();
+        let bob_ranking: Vec<_> = bob_pairs.iter().map(mirrored).collect();
+        assert_eq!(alice_ranking, bob_ranking);
+
+        // Each peer dials its nominee, so both must land on the same pair
+        let converge = |alice_saw: &[SocketAddr], bob_saw: &[SocketAddr]| {
+            let alice_pick = nominate(&alice_pairs, alice_saw).unwrap();
+            let bob_pick = nominate(&bob_pairs, bob_saw).unwrap();
+            (bob_pick.remote.addr, alice_pick.remote.addr)
+        };
+        // Behind NATs only the reflexive addresses answer
+        assert_eq!(converge(&bob_reflexive, &alice_reflexive[..1]), (alice_reflexive[0], bob_reflexive[0]));
+        // On one LAN every address answers and the host pair wins
+        let alice_all = [alice_local, alice_reflexive[0]];
+        let bob_all = [bob_local, bob_reflexive[0]];
+        assert_eq!(converge(&bob_all, &alice_all), (alice_local, bob_local));
+
+        assert!(nominate(&alice_pairs, &[addr("192.0.2.1:1")]).is_none());
+    }
+
+    #[test]
+    fn host_candidates_outrank_reflexive_and_duplicates_collapse() {
+        let local = addr("203.0.113.5:4000");
+        let candidates = gather_candidates(&[local, addr("[2001:db8::5]:4000")], local, &[addr("198.51.100.9:1")]);
+        assert_eq!(candidates, vec![Candidate::new(local, CandidateKind::Host)]);
+        assert!(candidate_priority(CandidateKind::Host, 0) > candidate_priority(CandidateKind::ServerReflexive, 65535));
+    }
+}
@@ -0,0 +1,96 @@
+/**
+ * nat_traversal/checkpoint.rs
+ *
+ * Optional persistence of in-flight NAT candidate state for crash recovery
+ */
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use crate::nat_traversal::types::{ConnectionState, PeerInfo};
+
+/// Snapshot of an in-flight connection attempt
+/// Deliberately holds no key material (the signing key is never persisted)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NatCheckpoint {
+    pub peer_fingerprint: String,
+    /// Id of the interrupted attempt, kept when it is resumed
+    #[serde(default)]
+    pub attempt_id: String,
+    pub state: ConnectionState,
+    pub external_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub peer_info: Option<PeerInfo>,
+}
+
+impl NatCheckpoint {
+    pub fn new(peer_fingerprint: &str, attempt_id: &str) -> Self {
+        Self {
+            peer_fingerprint: peer_fingerprint.to_string(),
+            attempt_id: attempt_id.to_string(),
+            state: ConnectionState::Idle,
+            external_addr: None,
+            local_addr: None,
+            peer_info: None,
+        }
+    }
+
+    /// Whether enough state was saved to skip straight to hole punching
+    pub fn can_resume(&self) -> bool {
+        self.local_addr.is_some() && self.peer_info.is_some()
+    }
+}
+
+/// Caller-provided storage for checkpoints
+pub trait CheckpointStore: Send {
+    /// Persist the latest checkpoint, replacing any previous one
+    fn save(&mut self, checkpoint: &NatCheckpoint) -> Result<()>;
+
+    /// Load the last saved checkpoint, if any
+    fn load(&mut self) -> Result<Option<NatCheckpoint>>;
+
+    /// Forget the saved checkpoint (called once connected)
+    fn clear(&mut self) -> Result<()>;
+}
+
+/// Checkpoint store backed by a JSON file
+pub struct FileCheckpointStore {
+    path: PathBuf,
+}
+
+impl FileCheckpointStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl CheckpointStore for FileCheckpointStore {
+    fn save(&mut self, checkpoint: &NatCheckpoint) -> Result<()> {
+        let json = serde_json::to_vec(checkpoint)
+            .context("Checkpoint serialization failed")?;
+        fs::write(&self.path, json)
+            .with_context(|| format!("Failed to write checkpoint: {}", self.path.display()))?;
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Option<NatCheckpoint>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let json = fs::read(&self.path)
+            .with_context(|| format!("Failed to read checkpoint: {}", self.path.display()))?;
+        let checkpoint = serde_json::from_slice(&json)
+            .context("Failed to decode checkpoint")?;
+        Ok(Some(checkpoint))
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)
+                .with_context(|| format!("Failed to remove checkpoint: {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+}
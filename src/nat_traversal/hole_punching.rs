@@ -5,149 +5,361 @@
  */
 
 use anyhow::{Context, Result, anyhow};
-use ed25519_dalek::{SigningKey, Signature, Signer, VerifyingKey, Verifier};
+use ed25519_dalek::{SigningKey, VerifyingKey};
 use std::net::{SocketAddr, UdpSocket};
 use std::time::{Duration, Instant};
+use crate::pqxdh::{Ed25519, Ed448, SignatureAlgorithm, SignatureScheme};
+
+/// Probe magic used by deployments without an app_id
+const DEFAULT_PROBE_MAGIC: [u8; 4] = *b"PNPL";
+
+/// Probe length on the wire before the signature
+const PROBE_HEADER_LEN: usize = 23;
+
+/// Largest UDP payload assumed to cross any path without fragmentation
+///
+/// Fragmented probes are silently dropped by some NATs, so punching would
+/// just time out. Anything that grows `ProbePacket` must stay under this.
+pub const MAX_SAFE_PROBE_LEN: usize = 512;
+
+// Ed448 has the longest signatures
+const _: () = assert!(
+    PROBE_HEADER_LEN + <Ed448 as SignatureScheme>::SIGNATURE_LEN <= MAX_SAFE_PROBE_LEN,
+    "probe would risk UDP fragmentation"
+);
 
 /// UDP probe packet structure
+///
+/// A probe doubles as the acknowledgement of the peer's: once a peer probe
+/// has arrived, ours carry its nonce in `echo`. Receiving a probe that
+/// echoes our own nonce shows both directions are open, since the peer
+/// could only have learnt the nonce from a probe that reached it.
 #[derive(Debug, Clone)]
 pub struct ProbePacket {
     pub nonce: u64,
-    pub tcp_port: u16,
-    pub signature: Signature,
+    /// TCP port for the simultaneous open; None (0 on the wire) in UDP-only mode
+    pub tcp_port: Option<u16>,
+    /// Nonce of the last peer probe received; None (0 on the wire) before any
+    pub echo: Option<u64>,
+    /// Scheme `signature` was made with
+    pub algorithm: SignatureAlgorithm,
+    pub signature: Vec<u8>,
 }
 
 impl ProbePacket {
     /// Create and sign a new probe packet
-    pub fn new(tcp_port: u16, signing_key: &SigningKey) -> Self {
-        let nonce = rand::random::<u64>();
-        let message = Self::message_to_sign(nonce, tcp_port);
-        let signature = signing_key.sign(&message);
+    pub fn new<S: SignatureScheme>(tcp_port: Option<u16>, signing_key: &S::SigningKey, app_id: &[u8]) -> Self {
+        Self::signed::<S>(rand::random::<u64>(), tcp_port, None, signing_key, app_id)
+    }
+
+    /// The same probe, re-signed to acknowledge the peer probe `peer_nonce`
+    pub fn echoing<S: SignatureScheme>(&self, peer_nonce: u64, signing_key: &S::SigningKey, app_id: &[u8]) -> Self {
+        Self::signed::<S>(self.nonce, self.tcp_port, Some(peer_nonce), signing_key, app_id)
+    }
+
+    fn signed<S: SignatureScheme>(
+        nonce: u64,
+        tcp_port: Option<u16>,
+        echo: Option<u64>,
+        signing_key: &S::SigningKey,
+        app_id: &[u8],
+    ) -> Self {
+        let message = Self::message_to_sign(S::ALGORITHM, nonce, tcp_port, echo, app_id);
+        let signature = S::sign(signing_key, &message);
 
         Self {
             nonce,
             tcp_port,
+            echo,
+            algorithm: S::ALGORITHM,
             signature,
         }
     }
 
     /// Verify probe packet signature
-    pub fn verify(&self, verifying_key: &VerifyingKey) -> Result<()> {
-        let message = Self::message_to_sign(self.nonce, self.tcp_port);
-        verifying_key
-            .verify(&message, &self.signature)
-            .context("Invalid probe signature")?;
-        Ok(())
+    pub fn verify<S: SignatureScheme>(&self, verifying_key: &S::VerifyingKey, app_id: &[u8]) -> Result<()> {
+        S::ALGORITHM.check_peer(self.algorithm.id())?;
+        let message = Self::message_to_sign(self.algorithm, self.nonce, self.tcp_port, self.echo, app_id);
+        S::verify(verifying_key, &message, &self.signature).context("Invalid probe signature")
     }
 
     /// Serialize to bytes
-    pub fn to_bytes(&self) -> Vec<u8> {
+    pub fn to_bytes(&self, app_id: &[u8]) -> Vec<u8> {
         let mut bytes = Vec::new();
         
         // Magic marker (4 bytes)
-        bytes.extend_from_slice(b"PNPL");
+        bytes.extend_from_slice(&Self::magic(app_id));
+
+        // Identity signature algorithm (1 byte)
+        bytes.push(self.algorithm.id());
         
         // Nonce (8 bytes)
         bytes.extend_from_slice(&self.nonce.to_be_bytes());
         
-        // TCP port (2 bytes)
-        bytes.extend_from_slice(&self.tcp_port.to_be_bytes());
+        // TCP port (2 bytes, 0 = UDP-only)
+        bytes.extend_from_slice(&self.tcp_port.unwrap_or(0).to_be_bytes());
+
+        // Echoed peer nonce (8 bytes, 0 = none received yet)
+        bytes.extend_from_slice(&self.echo.unwrap_or(0).to_be_bytes());
         
-        // Signature (64 bytes)
-        bytes.extend_from_slice(&self.signature.to_bytes());
+        // Signature (64 bytes for Ed25519, 114 for Ed448)
+        bytes.extend_from_slice(&self.signature);
         
         bytes
     }
 
-    /// Deserialize from bytes
-    pub fn from_bytes(data: &[u8]) -> Result<Self> {
-        if data.len() != 78 {
+    /// Deserialize from bytes, rejecting probes from other deployments
+    /// and probes signed with another scheme than `S`
+    pub fn from_bytes<S: SignatureScheme>(data: &[u8], app_id: &[u8]) -> Result<Self> {
+        if data.len() < PROBE_HEADER_LEN {
             return Err(anyhow!("Invalid probe packet length: {}", data.len()));
         }
 
         // Check magic marker
-        if &data[0..4] != b"PNPL" {
+        if data[0..4] != Self::magic(app_id) {
             return Err(anyhow!("Invalid probe packet magic"));
         }
 
+        S::ALGORITHM.check_peer(data[4])?;
+        if data.len() != PROBE_HEADER_LEN + S::SIGNATURE_LEN {
+            return Err(anyhow!("Invalid probe packet length: {}", data.len()));
+        }
+
         let nonce = u64::from_be_bytes(
-            data[4..12].try_into().context("Invalid nonce")?,
+            data[5..13].try_into().context("Invalid nonce")?,
         );
 
-        let tcp_port = u16::from_be_bytes(
-            data[12..14].try_into().context("Invalid TCP port")?,
-        );
+        let tcp_port = match u16::from_be_bytes(
+            data[13..15].try_into().context("Invalid TCP port")?,
+        ) {
+            0 => None,
+            port => Some(port),
+        };
 
-        let signature = Signature::from_bytes(
-            data[14..78].try_into().context("Invalid signature")?,
-        );
+        let echo = match u64::from_be_bytes(
+            data[15..23].try_into().context("Invalid echo")?,
+        ) {
+            0 => None,
+            nonce => Some(nonce),
+        };
+
+        let signature = data[PROBE_HEADER_LEN..].to_vec();
 
         Ok(Self {
             nonce,
             tcp_port,
+            echo,
+            algorithm: S::ALGORITHM,
             signature,
         })
     }
 
+    /// Magic marker for a deployment
+    /// An empty app_id keeps the original "PNPL" marker
+    pub fn magic(app_id: &[u8]) -> [u8; 4] {
+        if app_id.is_empty() {
+            return DEFAULT_PROBE_MAGIC;
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&DEFAULT_PROBE_MAGIC);
+        hasher.update(app_id);
+        let hash = hasher.finalize();
+
+        let mut magic = [0u8; 4];
+        magic.copy_from_slice(&hash.as_bytes()[..4]);
+        magic
+    }
+
     /// Generate message to sign/verify
-    fn message_to_sign(nonce: u64, tcp_port: u16) -> Vec<u8> {
+    fn message_to_sign(
+        algorithm: SignatureAlgorithm,
+        nonce: u64,
+        tcp_port: Option<u16>,
+        echo: Option<u64>,
+        app_id: &[u8],
+    ) -> Vec<u8> {
         let mut message = Vec::new();
         message.extend_from_slice(b"PINEAPPLE_PROBE");
+        message.push(algorithm.id());
+        message.extend_from_slice(app_id);
         message.extend_from_slice(&nonce.to_be_bytes());
-        message.extend_from_slice(&tcp_port.to_be_bytes());
+        message.extend_from_slice(&tcp_port.unwrap_or(0).to_be_bytes());
+        message.extend_from_slice(&echo.unwrap_or(0).to_be_bytes());
         message
     }
 }
 
+/// How long to keep listening for other candidates after the first one answers
+const NOMINATION_WINDOW: Duration = Duration::from_millis(300);
+
+/// Outcome of a successful hole punch
+#[derive(Debug, Clone)]
+pub struct PunchResult {
+    /// TCP port the peer advertised in its probe
+    pub tcp_port: u16,
+    /// TCP port we advertised; the TCP open must bind it
+    pub local_tcp_port: u16,
+    /// Responding candidate with the lowest RTT, nominated for the TCP open
+    pub candidate: SocketAddr,
+    /// Every address a valid probe arrived from, fastest first
+    pub candidate_rtts: Vec<(SocketAddr, Duration)>,
+}
+
+/// Outcome of a UDP-only hole punch: the punched socket itself
+///
+/// Datagrams carry no delivery, ordering or congestion guarantees, and a
+/// NAT mapping expires unless traffic keeps flowing; the caller owns all of
+/// that. Prefer `punch_hole` and the TCP open unless latency matters more.
+#[derive(Debug)]
+pub struct UdpPunchResult {
+    /// Socket the hole was punched on, still non-blocking
+    pub socket: UdpSocket,
+    /// Responding candidate with the lowest RTT; send datagrams here
+    pub peer: SocketAddr,
+    /// Every address a valid probe arrived from, fastest first
+    pub candidate_rtts: Vec<(SocketAddr, Duration)>,
+}
+
 /// UDP hole puncher
-pub struct UdpHolePuncher {
+///
+/// Probes are signed with `S`; a peer using another scheme never gets
+/// through, so both sides time out.
+pub struct UdpHolePuncher<S: SignatureScheme = Ed25519> {
     socket: UdpSocket,
-    signing_key: SigningKey,
-    verifying_key: VerifyingKey,
+    signing_key: S::SigningKey,
+    /// The peer's key from signalling; probes it did not sign are ignored
+    peer_key: S::VerifyingKey,
+    app_id: Vec<u8>,
+    /// TCP port to advertise in probes (None: pick a free one)
+    tcp_port: Option<u16>,
 }
 
 impl UdpHolePuncher {
-    /// Create a new hole puncher
-    pub fn new(socket: UdpSocket, signing_key: &SigningKey) -> Result<Self> {
+    /// Create a new hole puncher signing with Ed25519
+    /// `peer_key` is the verifying key the peer advertised over signalling
+    pub fn new(socket: UdpSocket, signing_key: &SigningKey, peer_key: &VerifyingKey, app_id: &[u8]) -> Result<Self> {
+        Self::with_scheme(socket, signing_key, peer_key, app_id)
+    }
+}
+
+impl<S: SignatureScheme> UdpHolePuncher<S> {
+    /// Create a new hole puncher signing with scheme `S`
+    pub fn with_scheme(
+        socket: UdpSocket,
+        signing_key: &S::SigningKey,
+        peer_key: &S::VerifyingKey,
+        app_id: &[u8],
+    ) -> Result<Self> {
         socket.set_nonblocking(true)
             .context("Failed to set socket non-blocking")?;
 
-        let verifying_key = signing_key.verifying_key();
-
         Ok(Self {
             socket,
             signing_key: signing_key.clone(),
-            verifying_key,
+            peer_key: peer_key.clone(),
+            app_id: app_id.to_vec(),
+            tcp_port: None,
         })
     }
 
+    /// Advertise `tcp_port` to the peer instead of a randomly chosen free port
+    pub fn with_tcp_port(mut self, tcp_port: u16) -> Self {
+        self.tcp_port = Some(tcp_port);
+        self
+    }
+
     /// Punch hole to peer addresses
-    /// Returns peer's TCP port when connection is established
-    pub async fn punch_hole(&self, peer_addrs: &[SocketAddr], timeout: Duration) -> Result<u16> {
+    ///
+    /// A candidate only counts once a probe from it echoes our nonce, i.e.
+    /// our probes reach the peer and its reach us. After the first such
+    /// probe, probing continues for a short window so other candidates can
+    /// answer too. Each confirmed address is timed from our first probe to
+    /// its first echoing probe, and the fastest is nominated in the result.
+    pub async fn punch_hole(&self, peer_addrs: &[SocketAddr], timeout: Duration) -> Result<PunchResult> {
+        let local_tcp_port = self.get_local_tcp_port()?;
+        println!("  Local TCP port: {}", local_tcp_port);
+
+        let responses = self.probe(peer_addrs, timeout, Some(local_tcp_port)).await?;
+        let (candidate, _, tcp_port) = responses[0];
+        let tcp_port = tcp_port.ok_or_else(|| anyhow!("Peer is punching for a UDP-only session"))?;
+        println!("Nominated candidate {}", candidate);
+
+        Ok(PunchResult {
+            tcp_port,
+            local_tcp_port,
+            candidate,
+            candidate_rtts: responses.into_iter().map(|(addr, rtt, _)| (addr, rtt)).collect(),
+        })
+    }
+
+    /// Punch hole to peer addresses and keep the UDP socket for the session
+    ///
+    /// Probes advertise no TCP port. Returns once a probe from the peer has
+    /// echoed ours, so both directions are known to be open.
+    pub async fn punch_hole_udp(self, peer_addrs: &[SocketAddr], timeout: Duration) -> Result<UdpPunchResult> {
+        let responses = self.probe(peer_addrs, timeout, None).await?;
+        let peer = responses[0].0;
+        println!("Nominated candidate {}", peer);
+
+        Ok(UdpPunchResult {
+            socket: self.socket,
+            peer,
+            candidate_rtts: responses.into_iter().map(|(addr, rtt, _)| (addr, rtt)).collect(),
+        })
+    }
+
+    /// Exchange probes until the nomination window closes
+    ///
+    /// Returns every address that echoed our nonce with its RTT and
+    /// advertised TCP port, fastest first. A peer probe without the echo
+    /// only shows the peer-to-us direction, so it is answered (our probes
+    /// echo it from then on, the first straight away) but not counted.
+    ///
+    /// Packets that do not parse or are not signed by the peer's key are
+    /// counted and logged, then ignored: anyone on the path can send them.
+    async fn probe(
+        &self,
+        peer_addrs: &[SocketAddr],
+        timeout: Duration,
+        tcp_port: Option<u16>,
+    ) -> Result<Vec<(SocketAddr, Duration, Option<u16>)>> {
         let start = Instant::now();
-        let tcp_port = self.get_local_tcp_port()?;
-        let probe = ProbePacket::new(tcp_port, &self.signing_key);
-        let probe_bytes = probe.to_bytes();
+        let probe = ProbePacket::new::<S>(tcp_port, &self.signing_key, &self.app_id);
+        let mut probe_bytes = probe.to_bytes(&self.app_id);
+        let mut echoed_nonce = None;
+        if probe_bytes.len() > MAX_SAFE_PROBE_LEN {
+            println!("Warning: {}-byte probes may be fragmented and dropped", probe_bytes.len());
+            tracing::warn!(len = probe_bytes.len(), max = MAX_SAFE_PROBE_LEN, "probe exceeds safe UDP payload");
+        }
 
         println!("Starting UDP hole punching...");
-        println!("  Local TCP port: {}", tcp_port);
         println!("  Sending to {} peer addresses", peer_addrs.len());
 
         let mut last_send = Instant::now();
         let send_interval = Duration::from_millis(200);
+        let mut first_send = None;
+        let mut responses: Vec<(SocketAddr, Duration, Option<u16>)> = Vec::new();
+        let mut nominate_at = None;
+        let mut rejected = 0usize;
 
         loop {
-            // Check timeout
-            if start.elapsed() > timeout {
-                return Err(anyhow!("UDP hole punching timeout"));
+            if let Some(deadline) = nominate_at {
+                if Instant::now() >= deadline {
+                    responses.sort_by_key(|(_, rtt, _)| *rtt);
+                    return Ok(responses);
+                }
+            } else if start.elapsed() > timeout {
+                return Err(anyhow!("UDP hole punching timeout ({} invalid probes ignored)", rejected));
             }
 
             // Send probes periodically
-            if last_send.elapsed() > send_interval {
+            if first_send.is_none() || last_send.elapsed() > send_interval {
                 for addr in peer_addrs {
                     let _ = self.socket.send_to(&probe_bytes, addr);
                 }
                 last_send = Instant::now();
+                first_send.get_or_insert(last_send);
             }
 
             // Try to receive peer's probe
@@ -156,17 +368,42 @@ impl UdpHolePuncher {
                 Ok((len, from_addr)) => {
                     println!("Received UDP packet from {}", from_addr);
 
-                    match ProbePacket::from_bytes(&buffer[..len]) {
+                    match ProbePacket::from_bytes::<S>(&buffer[..len], &self.app_id) {
                         Ok(peer_probe) => {
-                            // Note: In production, you would get the peer's verifying key
-                            // from the signalling exchange. For now, we skip verification
-                            // or use a pre-shared key mechanism.
+                            if let Err(e) = peer_probe.verify::<S>(&self.peer_key, &self.app_id) {
+                                rejected += 1;
+                                println!("Ignoring probe from {}: {}", from_addr, e);
+                                tracing::warn!(from = %from_addr, rejected, "probe failed signature check");
+                                continue;
+                            }
+                            if echoed_nonce != Some(peer_probe.nonce) {
+                                echoed_nonce = Some(peer_probe.nonce);
+                                probe_bytes = probe
+                                    .echoing::<S>(peer_probe.nonce, &self.signing_key, &self.app_id)
+                                    .to_bytes(&self.app_id);
+                                let _ = self.socket.send_to(&probe_bytes, from_addr);
+                            }
+                            if peer_probe.echo != Some(probe.nonce) {
+                                println!("Peer probe does not echo ours yet");
+                                continue;
+                            }
+                            if responses.iter().any(|(addr, _, _)| *addr == from_addr) {
+                                continue;
+                            }
+                            let rtt = first_send.map_or(Duration::ZERO, |sent: Instant| sent.elapsed());
                             println!("Valid probe packet received!");
-                            println!("  Peer TCP port: {}", peer_probe.tcp_port);
-                            return Ok(peer_probe.tcp_port);
+                            match peer_probe.tcp_port {
+                                Some(port) => println!("  Peer TCP port: {}", port),
+                                None => println!("  Peer wants a UDP-only session"),
+                            }
+                            println!("  Candidate RTT: {:?}", rtt);
+                            responses.push((from_addr, rtt, peer_probe.tcp_port));
+                            nominate_at.get_or_insert(Instant::now() + NOMINATION_WINDOW);
                         }
                         Err(e) => {
+                            rejected += 1;
                             println!("Invalid probe packet: {}", e);
+                            tracing::warn!(from = %from_addr, rejected, "invalid probe packet");
                         }
                     }
                 }
@@ -183,6 +420,9 @@ impl UdpHolePuncher {
 
     /// Get a local TCP port for simultaneous open
     fn get_local_tcp_port(&self) -> Result<u16> {
+        if let Some(port) = self.tcp_port {
+            return Ok(port);
+        }
         // Bind a TCP socket to get a port number, then drop it
         let listener = std::net::TcpListener::bind("0.0.0.0:0")
             .context("Failed to bind TCP listener")?;
@@ -190,3 +430,164 @@ impl UdpHolePuncher {
         Ok(port)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    const APP_ID: &[u8] = b"test";
+
+    fn puncher(signing_key: &SigningKey, peer_key: &VerifyingKey) -> (UdpHolePuncher, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        (UdpHolePuncher::new(socket, signing_key, peer_key, APP_ID).unwrap(), addr)
+    }
+
+    fn probe_len<S: SignatureScheme>() -> usize {
+        let key = S::generate();
+        let probe = ProbePacket::new::<S>(Some(40000), &key, b"app").echoing::<S>(7, &key, b"app");
+        probe.to_bytes(b"app").len()
+    }
+
+    #[test]
+    fn probe_fits_in_one_datagram() {
+        assert_eq!(probe_len::<Ed25519>(), 87);
+        assert_eq!(probe_len::<Ed448>(), 137);
+        assert!(probe_len::<Ed448>() <= MAX_SAFE_PROBE_LEN);
+    }
+
+    #[test]
+    fn mismatched_app_ids_reject_each_other() {
+        let key = SigningKey::generate(&mut OsRng);
+        let bytes = ProbePacket::new::<Ed25519>(Some(40000), &key, b"alpha").to_bytes(b"alpha");
+
+        assert!(ProbePacket::from_bytes::<Ed25519>(&bytes, b"alpha").is_ok());
+        assert!(ProbePacket::from_bytes::<Ed25519>(&bytes, b"beta").is_err());
+        assert!(ProbePacket::from_bytes::<Ed25519>(&bytes, b"").is_err());
+
+        // Same magic would not help: the app_id is part of the signed message
+        let probe = ProbePacket::from_bytes::<Ed25519>(&bytes, b"alpha").unwrap();
+        assert!(probe.verify::<Ed25519>(&key.verifying_key(), b"alpha").is_ok());
+        assert!(probe.verify::<Ed25519>(&key.verifying_key(), b"beta").is_err());
+    }
+
+    #[tokio::test]
+    async fn one_way_reachability_times_out() {
+        let our_key = SigningKey::generate(&mut OsRng);
+        let peer_key = SigningKey::generate(&mut OsRng);
+        let (puncher, addr) = puncher(&our_key, &peer_key.verifying_key());
+
+        // The peer's probes reach us, but ours never reach it, so it never echoes our nonce
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let black_hole = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer_probe = ProbePacket::new::<Ed25519>(Some(40000), &peer_key, APP_ID).to_bytes(APP_ID);
+        let sender = std::thread::spawn(move || {
+            for _ in 0..10 {
+                peer.send_to(&peer_probe, addr).unwrap();
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let result = puncher
+            .probe(&[black_hole.local_addr().unwrap()], Duration::from_millis(700), Some(40001))
+            .await;
+        sender.join().unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn two_way_reachability_succeeds() {
+        let alice_key = SigningKey::generate(&mut OsRng);
+        let bob_key = SigningKey::generate(&mut OsRng);
+        let (alice, alice_addr) = puncher(&alice_key, &bob_key.verifying_key());
+        let (bob, bob_addr) = puncher(&bob_key, &alice_key.verifying_key());
+
+        let (to_bob, to_alice) = ([bob_addr], [alice_addr]);
+        let (alice_result, bob_result) = tokio::join!(
+            alice.probe(&to_bob, Duration::from_secs(5), Some(40001)),
+            bob.probe(&to_alice, Duration::from_secs(5), Some(40002)),
+        );
+        assert_eq!(alice_result.unwrap()[0].0, bob_addr);
+        assert_eq!(bob_result.unwrap()[0].0, alice_addr);
+    }
+
+    #[tokio::test]
+    async fn forged_probe_is_ignored_and_signed_probe_accepted() {
+        let our_key = SigningKey::generate(&mut OsRng);
+        let peer_key = SigningKey::generate(&mut OsRng);
+        let forger_key = SigningKey::generate(&mut OsRng);
+        let (puncher, addr) = puncher(&our_key, &peer_key.verifying_key());
+
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let responder = std::thread::spawn(move || {
+            // Learn our nonce from the first probe, then answer with a forgery before the real thing
+            let mut buffer = [0u8; 1024];
+            let (len, _) = peer.recv_from(&mut buffer).unwrap();
+            let ours = ProbePacket::from_bytes::<Ed25519>(&buffer[..len], APP_ID).unwrap();
+
+            let forged = ProbePacket::new::<Ed25519>(Some(50000), &forger_key, APP_ID).echoing::<Ed25519>(ours.nonce, &forger_key, APP_ID);
+            peer.send_to(&forged.to_bytes(APP_ID), addr).unwrap();
+            std::thread::sleep(Duration::from_millis(100));
+
+            let signed = ProbePacket::new::<Ed25519>(Some(40000), &peer_key, APP_ID).echoing::<Ed25519>(ours.nonce, &peer_key, APP_ID);
+            peer.send_to(&signed.to_bytes(APP_ID), addr).unwrap();
+        });
+
+        let responses = puncher.probe(&[peer_addr], Duration::from_secs(5), Some(40001)).await.unwrap();
+        responder.join().unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].0, peer_addr);
+        assert_eq!(responses[0].2, Some(40000));
+    }
+
+    #[tokio::test]
+    async fn ed25519_and_ed448_peers_refuse_each_other() {
+        let alice_key = SigningKey::generate(&mut OsRng);
+        let bob_key = Ed448::generate();
+        let alice_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let bob_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (alice_addr, bob_addr) = (alice_socket.local_addr().unwrap(), bob_socket.local_addr().unwrap());
+
+        // Neither side can even parse the other's probes, let alone verify them
+        let alice = UdpHolePuncher::new(alice_socket, &alice_key, &SigningKey::generate(&mut OsRng).verifying_key(), APP_ID)
+            .unwrap();
+        let bob = UdpHolePuncher::<Ed448>::with_scheme(bob_socket, &bob_key, &Ed448::generate().verifying_key(), APP_ID)
+            .unwrap();
+
+        let (to_bob, to_alice) = ([bob_addr], [alice_addr]);
+        let (alice_result, bob_result) = tokio::join!(
+            alice.probe(&to_bob, Duration::from_millis(700), Some(40001)),
+            bob.probe(&to_alice, Duration::from_millis(700), Some(40002)),
+        );
+        assert!(alice_result.is_err());
+        assert!(bob_result.is_err());
+
+        let ed448_probe = ProbePacket::new::<Ed448>(Some(40000), &bob_key, APP_ID).to_bytes(APP_ID);
+        let error = ProbePacket::from_bytes::<Ed25519>(&ed448_probe, APP_ID).unwrap_err();
+        assert_eq!(error.to_string(), "Peer identity uses Ed448, this side uses Ed25519");
+        assert!(ProbePacket::from_bytes::<Ed448>(&ed448_probe, APP_ID).is_ok());
+    }
+
+    #[tokio::test]
+    async fn configured_tcp_port_is_advertised_and_bound() {
+        let alice_key = SigningKey::generate(&mut OsRng);
+        let bob_key = SigningKey::generate(&mut OsRng);
+        let (alice, alice_addr) = puncher(&alice_key, &bob_key.verifying_key());
+        let (bob, bob_addr) = puncher(&bob_key, &alice_key.verifying_key());
+        let alice = alice.with_tcp_port(40123);
+
+        let (to_bob, to_alice) = ([bob_addr], [alice_addr]);
+        let (alice_result, bob_result) = tokio::join!(
+            alice.punch_hole(&to_bob, Duration::from_secs(5)),
+            bob.punch_hole(&to_alice, Duration::from_secs(5)),
+        );
+        let (alice_result, bob_result) = (alice_result.unwrap(), bob_result.unwrap());
+        assert_eq!(alice_result.local_tcp_port, 40123);
+        assert_eq!(bob_result.tcp_port, 40123);
+        // Without a configured port a free one is picked, and the peer learns it
+        assert_ne!(bob_result.local_tcp_port, 0);
+        assert_eq!(alice_result.tcp_port, bob_result.local_tcp_port);
+    }
+}
@@ -0,0 +1,187 @@
+/**
+ * nat_traversal/manager.rs
+ *
+ * Throttled NAT traversal to many peers from one runtime
+ */
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio::sync::{Mutex, Semaphore};
+use crate::nat_traversal::{
+    connect_signalling, NatTraversal, NatTraversalConfig, SignallingClient, SignallingConnectOptions, TurnRelay,
+};
+
+/// Dials many peers with one runtime and one signalling registration
+///
+/// At most `max_concurrent` attempts run at once; the rest wait for a slot.
+/// Attempts share the client from `register_signalling` (or
+/// `with_signalling`) and only lock it to exchange offers, so punching and
+/// TCP setup to different peers overlap. Without one, each attempt dials and
+/// registers its own, which makes the server route a peer's offer to
+/// whichever registration came last.
+///
+/// TURN allocations under relayed streams are kept until the manager is dropped.
+pub struct NatTraversalManager {
+    config: NatTraversalConfig,
+    runtime: Runtime,
+    signalling: Option<Arc<Mutex<SignallingClient>>>,
+    permits: Arc<Semaphore>,
+    max_concurrent: usize,
+    relays: Arc<std::sync::Mutex<Vec<TurnRelay>>>,
+}
+
+impl NatTraversalManager {
+    pub fn new(config: NatTraversalConfig, max_concurrent: usize) -> Result<Self> {
+        let max_concurrent = max_concurrent.max(1);
+        Ok(Self {
+            config,
+            runtime: Runtime::new().context("Failed to start runtime")?,
+            signalling: None,
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            relays: Arc::default(),
+        })
+    }
+
+    /// Share an already connected and registered signalling client
+    pub fn with_signalling(mut self, mut signalling: SignallingClient) -> Self {
+        signalling.keep_other_offers(true);
+        self.signalling = Some(Arc::new(Mutex::new(signalling)));
+        self
+    }
+
+    /// Connect and register the shared signalling client
+    pub fn register_signalling(&mut self) -> Result<()> {
+        let options = SignallingConnectOptions::from_config(&self.config);
+        let config = &mut self.config;
+        let mut signalling = self.runtime.block_on(async {
+            let mut signalling = connect_signalling(&config.signalling_url, &mut config.signalling_addrs, options)
+                .await
+                .context("Failed to connect to signalling server")?;
+            signalling
+                .register(&config.local_fingerprint)
+                .await
+                .context("Failed to register with signalling server")?;
+            anyhow::Ok(signalling)
+        })?;
+        signalling.keep_other_offers(true);
+        self.signalling = Some(Arc::new(Mutex::new(signalling)));
+        Ok(())
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    /// Attempts currently running (not counting those waiting for a slot)
+    pub fn in_flight(&self) -> usize {
+        self.max_concurrent - self.permits.available_permits()
+    }
+
+    /// The shared runtime, for driving the returned streams' peers or other work
+    pub fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    /// Connect to every peer, returning each one's stream or error by fingerprint
+    ///
+    /// Blocks until all attempts finish. Must not be called from within an
+    /// async context.
+    pub fn connect_all(&self, peer_fingerprints: &[&str]) -> HashMap<String, Result<TcpStream>> {
+        let attempts: Vec<_> = peer_fingerprints
+            .iter()
+            .map(|peer| {
+                let peer = peer.to_string();
+                let attempt = self.attempt(&peer);
+                (peer, self.runtime.spawn(attempt))
+            })
+            .collect();
+
+        self.runtime.block_on(async {
+            let mut results = HashMap::new();
+            for (peer, attempt) in attempts {
+                let result = attempt.await.unwrap_or_else(|e| Err(anyhow::anyhow!("Connect task failed: {}", e)));
+                results.insert(peer, result);
+            }
+            results
+        })
+    }
+
+    /// Connect to one peer once a slot is free
+    pub async fn connect(&self, peer_fingerprint: &str) -> Result<TcpStream> {
+        self.attempt(peer_fingerprint).await
+    }
+
+    /// A self-contained attempt, so it can be spawned on the runtime
+    fn attempt(&self, peer_fingerprint: &str) -> impl std::future::Future<Output = Result<TcpStream>> + Send + 'static {
+        let config = self.config.clone();
+        let signalling = self.signalling.clone();
+        let permits = Arc::clone(&self.permits);
+        let relays = Arc::clone(&self.relays);
+        let peer = peer_fingerprint.to_string();
+        async move {
+            let _permit = permits.acquire_owned().await.context("Manager shut down")?;
+            let mut nat = match signalling {
+                Some(shared) => NatTraversal::with_shared_signalling(config, shared),
+                None => NatTraversal::new(config),
+            };
+            let stream = nat.connect(&peer).await?;
+            if let Some(relay) = nat.take_relay() {
+                relays.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(relay);
+            }
+            Ok(stream)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nat_traversal::test_harness::LoopbackHarness;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn dials_beyond_the_limit_wait_for_a_slot() {
+        let peers_runtime = Runtime::new().unwrap();
+        let harness = peers_runtime.block_on(LoopbackHarness::start()).unwrap();
+        let mut manager = NatTraversalManager::new(harness.config("alice"), 2).unwrap();
+        manager.register_signalling().unwrap();
+
+        let peers = ["bob", "carol", "dave", "erin", "frank"];
+        let peer_attempts: Vec<_> = peers
+            .iter()
+            .map(|peer| {
+                let mut nat = NatTraversal::new(harness.config(peer));
+                peers_runtime.spawn(async move { nat.connect("alice").await })
+            })
+            .collect();
+
+        let done = AtomicBool::new(false);
+        let most_in_flight = AtomicUsize::new(0);
+        let results = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    most_in_flight.fetch_max(manager.in_flight(), Ordering::Relaxed);
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            });
+            let results = manager.connect_all(&peers);
+            done.store(true, Ordering::Relaxed);
+            results
+        });
+
+        assert_eq!(results.len(), peers.len());
+        for (peer, result) in &results {
+            assert!(result.is_ok(), "{}: {:?}", peer, result.as_ref().err());
+        }
+        for attempt in peer_attempts {
+            peers_runtime.block_on(attempt).unwrap().unwrap();
+        }
+        assert_eq!(most_in_flight.load(Ordering::Relaxed), 2);
+        assert_eq!(manager.in_flight(), 0);
+    }
+}
@@ -6,6 +6,7 @@
  * - STUN client
  * - UDP hole punching
  * - TCP simultaneous open
+ * - TURN relay fallback
  */
 
 mod signalling;
@@ -13,22 +14,105 @@ mod stun;
 mod hole_punching;
 mod tcp_connect;
 mod types;
+mod checkpoint;
+mod resolve;
+mod candidates;
+mod manager;
+mod turn;
+mod nat_behavior;
+#[cfg(any(test, feature = "test-harness"))]
+pub mod test_harness;
 
-pub use signalling::{SignallingClient, SignallingMessage, SignallingError};
-pub use stun::{StunClient, StunResponse};
-pub use hole_punching::{UdpHolePuncher, ProbePacket};
-pub use tcp_connect::{tcp_simultaneous_open, TcpConnectError};
-pub use types::{PeerInfo, NatTraversalConfig, ConnectionState};
+pub use signalling::{
+    SignallingClient, SignallingConnectOptions, SignallingMessage, SignallingError, SignallingCodec, PRESENCE_TIMEOUT,
+    MSGPACK_SUBPROTOCOL,
+};
+pub use stun::{StunClient, StunResponse, StunError, AddressAttribute, AttributePreference, DEFAULT_STUN_KEEPALIVE};
+pub use hole_punching::{UdpHolePuncher, ProbePacket, PunchResult, UdpPunchResult, MAX_SAFE_PROBE_LEN};
+pub use tcp_connect::{
+    tcp_simultaneous_open, tcp_simultaneous_open_as, tcp_connect_as, DEFAULT_DUPLICATE_GRACE, tcp_dial, tcp_accept, TcpConnectError,
+};
+pub use types::{PeerInfo, LocalOffer, NatTraversalConfig, ConnectionState};
+pub use checkpoint::{NatCheckpoint, CheckpointStore, FileCheckpointStore};
+pub use resolve::{resolve_host, resolve_stun, resolve_stun_v6, resolve_signalling};
+pub use candidates::{
+    Candidate, CandidateKind, CandidatePair, IceRole,
+    candidate_priority, gather_candidates, shares_family, pair_candidates, pair_priority, nominate,
+};
+pub use manager::NatTraversalManager;
+pub use turn::{TurnClient, TurnCredentials, TurnAllocation, TurnError, TurnRelay};
+pub use nat_behavior::{NatBehavior, classify_nat};
 
 use anyhow::{Context, Result};
-use std::net::{SocketAddr, TcpStream};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::Arc;
 use std::time::Duration;
+use tracing::Instrument;
+
+/// Peer TCP candidates (public and LAN address) attempted at once
+const TCP_PARALLELISM: usize = 2;
+
+/// How long each side waits for the other's relay offer and connection
+const RELAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A registered signalling client handed to `NatTraversal`
+enum SuppliedSignalling {
+    Owned(Box<SignallingClient>),
+    /// Also used by other attempts; locked only while exchanging offers
+    Shared(Arc<tokio::sync::Mutex<SignallingClient>>),
+}
+
+/// Why `connect` stopped, for the cases a caller handles differently
+#[derive(Debug)]
+pub enum NatTraversalError {
+    /// `CancelHandle::cancel` was called while the attempt ran
+    Cancelled,
+}
+
+impl std::fmt::Display for NatTraversalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NatTraversalError::Cancelled => write!(f, "Connection attempt cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for NatTraversalError {}
+
+/// Called with the new state on every transition of `NatTraversal`
+pub type StateHandler = Box<dyn FnMut(&ConnectionState) + Send>;
+
+/// Stops a running `NatTraversal::connect` from another task or thread
+#[derive(Clone)]
+pub struct CancelHandle(Arc<tokio::sync::watch::Sender<bool>>);
+
+impl CancelHandle {
+    /// Make the running `connect` return `NatTraversalError::Cancelled`
+    ///
+    /// Sockets of the attempt are closed as it unwinds. Blocking STUN, TURN and
+    /// TCP calls run on the blocking pool, so the cancel does not wait for them:
+    /// they finish on their own timeouts with nobody listening. The flag is
+    /// cleared when the attempt ends, so a cancel issued while no `connect` is
+    /// running stops the next one as soon as it starts.
+    pub fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+}
 
 /// Complete NAT traversal state machine
 pub struct NatTraversal {
     config: NatTraversalConfig,
-    signalling: Option<SignallingClient>,
+    /// Caller-supplied signalling session, reused by every `connect` and never closed here
+    signalling: Option<SuppliedSignalling>,
     state: ConnectionState,
+    checkpoint_store: Option<Box<dyn CheckpointStore>>,
+    checkpoint: Option<NatCheckpoint>,
+    attempt_id: Option<String>,
+    cancel: CancelHandle,
+    /// TURN allocation under the last relayed connection
+    relay: Option<TurnRelay>,
+    on_state: Option<StateHandler>,
 }
 
 impl NatTraversal {
@@ -38,87 +122,1011 @@ impl NatTraversal {
             config,
             signalling: None,
             state: ConnectionState::Idle,
+            checkpoint_store: None,
+            checkpoint: None,
+            attempt_id: None,
+            cancel: CancelHandle(Arc::new(tokio::sync::watch::Sender::new(false))),
+            relay: None,
+            on_state: None,
+        }
+    }
+
+    /// Handle for cancelling `connect` while it runs
+    /// The manager stays usable afterwards; just call `connect` again
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Create a manager that reuses an already connected and registered signalling client
+    ///
+    /// Every `connect` sends its offer through this client instead of dialling
+    /// signalling itself, and leaves it open afterwards. Reclaim it with
+    /// `take_signalling`.
+    pub fn with_signalling(config: NatTraversalConfig, signalling: SignallingClient) -> Self {
+        let mut nat = Self::new(config);
+        nat.signalling = Some(SuppliedSignalling::Owned(Box::new(signalling)));
+        nat
+    }
+
+    /// Like `with_signalling`, for a client other attempts use at the same time
+    ///
+    /// The client is only locked for the offer exchange, so attempts to
+    /// different peers punch in parallel (see `NatTraversalManager`). It
+    /// needs `keep_other_offers` set, or one attempt fails on another's offer.
+    pub fn with_shared_signalling(config: NatTraversalConfig, signalling: Arc<tokio::sync::Mutex<SignallingClient>>) -> Self {
+        let mut nat = Self::new(config);
+        nat.signalling = Some(SuppliedSignalling::Shared(signalling));
+        nat
+    }
+
+    /// Hand back a client supplied through `with_signalling`
+    /// Later `connect` calls dial signalling themselves again
+    /// (a shared client stays with its other users and is not returned)
+    pub fn take_signalling(&mut self) -> Option<SignallingClient> {
+        match self.signalling.take()? {
+            SuppliedSignalling::Owned(signalling) => Some(*signalling),
+            SuppliedSignalling::Shared(_) => None,
+        }
+    }
+
+    /// Opt in to saving candidate state at every transition so a restarted
+    /// process can resume an interrupted `connect` at the hole punching step
+    pub fn set_checkpoint_store(&mut self, store: Box<dyn CheckpointStore>) {
+        self.checkpoint_store = Some(store);
+    }
+
+    /// Report every state change of `connect` as it happens, e.g. to show progress
+    ///
+    /// Runs on the task driving `connect`, so keep it short. Every attempt
+    /// ends in `Connected` or `Failed`.
+    pub fn set_state_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&ConnectionState) + Send + 'static,
+    {
+        self.on_state = Some(Box::new(handler));
+    }
+
+    /// Rotate the key used to sign UDP probes from the next connection attempt on
+    ///
+    /// Offers carry the new `verifying_key` to peers automatically. Any saved
+    /// checkpoint is discarded, since the peer it records expects probes signed
+    /// by the old key. Rotating while a connection attempt is in flight breaks
+    /// the punch under way: the peer keeps verifying against the old key.
+    pub fn set_signing_key(&mut self, signing_key: SigningKey) {
+        self.config.signing_key = signing_key;
+        self.checkpoint = None;
+        if let Some(store) = self.checkpoint_store.as_mut() {
+            if let Err(e) = store.clear() {
+                println!("Failed to clear checkpoint: {}", e);
+            }
         }
     }
 
+    /// Key peers should use to verify our probes
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.config.signing_key.verifying_key()
+    }
+
     /// Execute the complete NAT traversal pipeline
     /// Returns a connected TCP stream ready for pineapple session
+    ///
+    /// Every attempt gets a random id (see `attempt_id`), sent to the peer in
+    /// the offer. All `tracing` events from the attempt are emitted inside a
+    /// `nat_connect` span carrying `attempt_id`, plus `peer_attempt_id` once
+    /// the offers are exchanged, so both peers' logs can be matched up.
+    ///
+    /// A `CancelHandle::cancel` makes it return `NatTraversalError::Cancelled`
+    /// with the state set to `Failed`.
     pub async fn connect(&mut self, peer_fingerprint: &str) -> Result<TcpStream> {
-        // Step 1: Connect to signalling server
-        self.state = ConnectionState::ConnectingSignalling;
-        let mut signalling = SignallingClient::connect(&self.config.signalling_url)
-            .await
-            .context("Failed to connect to signalling server")?;
+        let cancelled = cancelled(self.cancel.0.subscribe());
+        let result = tokio::select! {
+            biased;
+            _ = cancelled => Err(NatTraversalError::Cancelled.into()),
+            result = self.connect_or_resume(peer_fingerprint) => result,
+        };
+        // Cleared here rather than on entry so a cancel racing the start is kept
+        self.cancel.0.send_replace(false);
+        match &result {
+            Err(e) if matches!(e.downcast_ref(), Some(NatTraversalError::Cancelled)) => {
+                println!("Connection attempt cancelled");
+                tracing::info!("connection attempt cancelled");
+                self.set_state(ConnectionState::Failed("Cancelled".to_string()));
+            }
+            Err(e) => self.set_state(ConnectionState::Failed(format!("{:#}", e))),
+            Ok(_) => {}
+        }
+        result
+    }
 
-        // Step 2: Register our identity
-        self.state = ConnectionState::Registering;
-        signalling
-            .register(&self.config.local_fingerprint)
-            .await
-            .context("Failed to register with signalling server")?;
+    /// `connect` without cancellation: resume a checkpoint, or start afresh
+    async fn connect_or_resume(&mut self, peer_fingerprint: &str) -> Result<TcpStream> {
+        if let Some(saved) = self.load_checkpoint(peer_fingerprint) {
+            self.attempt_id = Some(saved.attempt_id.clone());
+            let span = attempt_span(&saved.attempt_id);
+            if let Some(peer_info) = &saved.peer_info {
+                span.record("peer_attempt_id", peer_info.attempt_id.as_str());
+            }
+            println!("Resuming interrupted connection attempt {}...", saved.attempt_id);
+            match self.resume(saved).instrument(span.clone()).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    span.in_scope(|| tracing::warn!(error = %format!("{:#}", e), "resume failed"));
+                    println!("Resume failed ({}), starting over", e);
+                }
+            }
+        }
 
-        // Step 3: STUN discovery
-        self.state = ConnectionState::StunDiscovery;
-        let stun_client = StunClient::new(&self.config.stun_server_addr)?;
-        let stun_response = stun_client
-            .query()
-            .await
-            .context("STUN query failed")?;
+        let attempt_id = format!("{:016x}", rand::random::<u64>());
+        self.attempt_id = Some(attempt_id.clone());
+        let span = attempt_span(&attempt_id);
+        println!("Connection attempt {}", attempt_id);
+        let result = self.connect_attempt(peer_fingerprint, &attempt_id).instrument(span.clone()).await;
+        if let Err(e) = &result {
+            span.in_scope(|| tracing::warn!(error = %format!("{:#}", e), "connection attempt failed"));
+        }
+        result
+    }
 
-        let external_addr = SocketAddr::new(stun_response.external_ip, stun_response.external_port);
-        let local_addr = stun_client.local_addr();
+    /// Id of the current (or last) connection attempt, None before the first `connect`
+    pub fn attempt_id(&self) -> Option<&str> {
+        self.attempt_id.as_deref()
+    }
 
-        println!("NAT discovery complete:");
-        println!("  External: {}", external_addr);
-        println!("  Local: {}", local_addr);
+    /// One fresh run of the pipeline, inside the attempt's span
+    async fn connect_attempt(&mut self, peer_fingerprint: &str, attempt_id: &str) -> Result<TcpStream> {
+        self.checkpoint = Some(NatCheckpoint::new(peer_fingerprint, attempt_id));
 
-        // Step 4: Send offer
-        self.state = ConnectionState::SendingOffer;
-        let peer_info = signalling
-            .send_offer(peer_fingerprint, external_addr, local_addr)
-            .await
+        // Steps 1-3: signalling connect + register and STUN discovery are
+        // independent, so run them concurrently. A supplied signalling client
+        // is already registered and skips the first part.
+        self.set_state(ConnectionState::Discovering);
+        // The NAT type decides whether punching is worth trying at all
+        let classify_servers: Vec<SocketAddr> =
+            std::iter::once(self.config.stun_server_addr).chain(self.config.stun_secondary_addr).collect();
+        let signalling_options = SignallingConnectOptions::from_config(&self.config);
+        let config = &mut self.config;
+        let reuse_signalling = self.signalling.is_some();
+        let signalling_step = async {
+            if reuse_signalling {
+                return anyhow::Ok(None);
+            }
+            let mut signalling = connect_signalling(&config.signalling_url, &mut config.signalling_addrs, signalling_options)
+                .await
+                .context("Failed to connect to signalling server")?;
+            signalling
+                .register(&config.local_fingerprint)
+                .await
+                .context("Failed to register with signalling server")?;
+            Ok(Some(signalling))
+        };
+        let stun_step = async {
+            // One query per configured family, each on its own socket
+            let stun_v6 = config.stun_server_addr_v6;
+            let preference = config.stun_attribute_preference;
+            let (v4, v6) = tokio::join!(
+                query_stun(&mut config.stun_server_addr, config.stun_server_host.as_deref(), preference),
+                async {
+                    let mut server_addr = stun_v6?;
+                    Some(query_stun(&mut server_addr, None, preference).await)
+                },
+            );
+            // Silence from every server means UDP itself is blocked
+            if udp_blocked(&v4, &v6) {
+                return Ok(None);
+            }
+            collect_bindings(v4, v6).map(Some).context("STUN query failed")
+        };
+        let (signalling_result, stun_result, nat_behavior) =
+            tokio::join!(signalling_step, stun_step, classify_nat(&classify_servers));
+        let (mut dialled, bindings) = match (signalling_result, stun_result) {
+            (Ok(dialled), Ok(stun)) => (dialled, stun),
+            (Err(e), Ok(_)) | (Ok(_), Err(e)) => return Err(e),
+            (Err(signalling_error), Err(stun_error)) => {
+                return Err(signalling_error.context(format!("{:#}", stun_error)));
+            }
+        };
+        let signalling_addr = match (dialled.as_ref(), self.signalling.as_ref()) {
+            (Some(signalling), _) => signalling.local_addr(),
+            (None, Some(SuppliedSignalling::Owned(signalling))) => signalling.local_addr(),
+            (None, Some(SuppliedSignalling::Shared(shared))) => shared.lock().await.local_addr(),
+            (None, None) => unreachable!("signalling is only skipped when a client was supplied"),
+        };
+
+        // The port the offer advertises is the one the probes carry too
+        let tcp_port = reserve_tcp_port(&self.config)?;
+
+        let (offer, bindings) = match bindings {
+            Some(bindings) => {
+                let reflexive_addrs: Vec<SocketAddr> = bindings
+                    .iter()
+                    .map(|(_, response)| SocketAddr::new(response.external_ip, response.external_port))
+                    .collect();
+                let external_addr = reflexive_addrs[0];
+                let local_addr = bindings[0].0.local_addr();
+
+                println!("NAT discovery complete:");
+                for addr in &reflexive_addrs {
+                    println!("  External: {}", addr);
+                }
+                println!("  Local: {}", local_addr);
+                println!("  NAT type: {}", nat_behavior);
+                tracing::info!(
+                    external = ?reflexive_addrs,
+                    local = %local_addr,
+                    nat = nat_behavior.as_str(),
+                    "NAT discovery complete"
+                );
+
+                if let Some(checkpoint) = self.checkpoint.as_mut() {
+                    checkpoint.external_addr = Some(external_addr);
+                    checkpoint.local_addr = Some(local_addr);
+                }
+
+                let offer = LocalOffer {
+                    external_addr,
+                    local_addr,
+                    extra_addrs: reflexive_addrs[1..].to_vec(),
+                    attempt_id: attempt_id.to_string(),
+                    tcp_port,
+                    udp_blocked: false,
+                    verifying_key: self.config.signing_key.verifying_key(),
+                    relay_addr: None,
+                    nat_behavior,
+                };
+                (offer, bindings)
+            }
+            None => {
+                // Only the host address is known; offer it for direct TCP
+                println!("No STUN server answered, UDP looks blocked: skipping hole punching");
+                tracing::warn!("no STUN response, skipping hole punching");
+                self.set_state(ConnectionState::RelayFallback);
+                let host_addr = SocketAddr::new(signalling_addr.ip(), tcp_port);
+                let offer = LocalOffer {
+                    external_addr: host_addr,
+                    local_addr: host_addr,
+                    extra_addrs: Vec::new(),
+                    attempt_id: attempt_id.to_string(),
+                    tcp_port,
+                    udp_blocked: true,
+                    verifying_key: self.config.signing_key.verifying_key(),
+                    relay_addr: None,
+                    nat_behavior: NatBehavior::Unknown,
+                };
+                (offer, Vec::new())
+            }
+        };
+
+        // Step 4: Send offer, holding the STUN mappings open until the peer's arrives
+        self.set_state(ConnectionState::SendingOffer);
+        let exchange = async {
+            match (dialled.as_mut(), self.signalling.as_mut()) {
+                (Some(signalling), _) => signalling.send_offer(peer_fingerprint, &offer).await,
+                (None, Some(SuppliedSignalling::Owned(signalling))) => signalling.send_offer(peer_fingerprint, &offer).await,
+                (None, Some(SuppliedSignalling::Shared(shared))) => {
+                    shared.lock().await.send_offer(peer_fingerprint, &offer).await
+                }
+                (None, None) => unreachable!("signalling is only skipped when a client was supplied"),
+            }
+        };
+        let peer_info = tokio::select! {
+            peer_info = exchange => peer_info,
+            never = keep_mappings_alive(&bindings, self.config.stun_keepalive) => match never {},
+        }
             .context("Failed to send offer")?;
+        tracing::Span::current().record("peer_attempt_id", peer_info.attempt_id.as_str());
+        tracing::info!(
+            external = ?peer_info.reflexive_addrs(),
+            local = %peer_info.local_addr,
+            udp_blocked = peer_info.udp_blocked,
+            nat = peer_info.nat_behavior.as_str(),
+            "received peer offer"
+        );
 
         println!("Received peer info:");
-        println!("  External: {}", peer_info.external_addr);
+        for addr in peer_info.reflexive_addrs() {
+            println!("  External: {}", addr);
+        }
         println!("  Local: {}", peer_info.local_addr);
+        println!("  NAT type: {}", peer_info.nat_behavior);
+
+        // Both sides see the same pair of NAT types, so both skip punching together
+        let punchable = offer.nat_behavior.can_punch_with(peer_info.nat_behavior);
+        if !punchable && self.config.turn_server_addr.is_none() {
+            println!("Warning: hole punching between these NAT types rarely works and no TURN relay is configured");
+        }
+
+        let direct = if offer.udp_blocked || peer_info.udp_blocked {
+            if !offer.udp_blocked {
+                println!("Peer cannot use UDP: skipping hole punching");
+                self.set_state(ConnectionState::RelayFallback);
+            }
+            // Nothing to resume without a punched UDP mapping
+            if let Some(checkpoint) = self.checkpoint.as_mut() {
+                checkpoint.local_addr = None;
+            }
+            drop(bindings);
+            self.connect_tcp_direct(tcp_port, offer.udp_blocked, &peer_info).await
+        } else if !punchable && self.config.turn_server_addr.is_some() {
+            if let Some(checkpoint) = self.checkpoint.as_mut() {
+                checkpoint.local_addr = None;
+            }
+            drop(bindings);
+            Err(anyhow::anyhow!(
+                "{} NAT on our side and {} NAT on the peer's cannot be hole punched",
+                offer.nat_behavior,
+                peer_info.nat_behavior
+            ))
+        } else {
+            // Punch from the family the peer can reach; the other socket is dropped
+            let (stun_client, stun_response) = select_binding(bindings, &peer_info)?;
+            let external_addr = SocketAddr::new(stun_response.external_ip, stun_response.external_port);
+            let local_addr = stun_client.local_addr();
+
+            if let Some(checkpoint) = self.checkpoint.as_mut() {
+                checkpoint.external_addr = Some(external_addr);
+                checkpoint.local_addr = Some(local_addr);
+                checkpoint.peer_info = Some(peer_info.clone());
+            }
+
+            // Steps 5-6: UDP hole punching and TCP simultaneous open
+            let local_candidates = gather_candidates(&[external_addr], local_addr, &peer_info.addrs());
+            self.punch_and_connect(stun_client.into_socket(), tcp_port, &local_candidates, &peer_info)
+                .await
+        };
+
+        let tcp_stream = match direct {
+            Ok(tcp_stream) => tcp_stream,
+            Err(e) if self.config.turn_server_addr.is_some() => {
+                println!("Direct connection failed ({:#}), falling back to the TURN relay", e);
+                tracing::warn!(error = %format!("{:#}", e), "direct connection failed, relaying");
+                self.connect_relayed(dialled.as_mut(), attempt_id, &peer_info)
+                    .await
+                    .context("TURN relay failed")?
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Step 7: Cleanup (a supplied signalling client stays open)
+        self.set_state(ConnectionState::Connected);
+        if let Some(signalling) = dialled {
+            signalling.close().await?;
+        }
+
+        Ok(tcp_stream)
+    }
 
+    /// UDP hole punching followed by TCP simultaneous open
+    async fn punch_and_connect(
+        &mut self,
+        socket: UdpSocket,
+        tcp_port: u16,
+        local_candidates: &[Candidate],
+        peer_info: &PeerInfo,
+    ) -> Result<TcpStream> {
         // Step 5: UDP hole punching
-        self.state = ConnectionState::UdpHolePunching;
+        self.set_state(ConnectionState::UdpHolePunching);
+        let peer_key = peer_info
+            .decode_verifying_key()
+            .context("Cannot authenticate peer probes")?;
+        // Advertise the port we will actually bind, so a forwarded port is honoured
         let hole_puncher = UdpHolePuncher::new(
-            stun_client.into_socket(),
+            socket,
             &self.config.signing_key,
-        )?;
+            &peer_key,
+            &self.config.app_id,
+        )?
+            .with_tcp_port(tcp_port);
 
-        let peer_addrs = vec![peer_info.external_addr, peer_info.local_addr];
-        let tcp_port = hole_puncher
+        // Peer candidates in families our socket cannot reach are dropped here
+        let local_addrs: Vec<SocketAddr> = local_candidates.iter().map(|c| c.addr).collect();
+        let remote_candidates = gather_candidates(&peer_info.reflexive_addrs(), peer_info.local_addr, &local_addrs);
+        let role = IceRole::for_peers(&self.config.local_fingerprint, &peer_info.fingerprint);
+        let pairs = pair_candidates(role, local_candidates, &remote_candidates);
+
+        let peer_addrs: Vec<SocketAddr> = remote_candidates.iter().map(|c| c.addr).collect();
+        let punch = hole_puncher
             .punch_hole(&peer_addrs, Duration::from_secs(30))
             .await
             .context("UDP hole punching failed")?;
+        let tcp_port = punch.tcp_port;
 
         println!("UDP hole punched! Peer TCP port: {}", tcp_port);
+        tracing::info!(peer = %punch.candidate, tcp_port, "UDP hole punched");
+
+        // Both peers rank pairs identically, so prefer the best answering pair
+        // over the lowest RTT; fall back to RTT for addresses outside the pairs
+        // (e.g. a peer reflexive source)
+        let responded: Vec<SocketAddr> = punch.candidate_rtts.iter().map(|(addr, _)| *addr).collect();
+        let nominated = match nominate(&pairs, &responded) {
+            Some(pair) => {
+                println!("Nominated pair {} -> {} ({:?})", pair.local.addr, pair.remote.addr, role);
+                tracing::info!(local = %pair.local.addr, remote = %pair.remote.addr, ?role, "nominated pair");
+                pair.remote.addr
+            }
+            None => punch.candidate,
+        };
 
-        // Step 6: TCP simultaneous open
-        self.state = ConnectionState::TcpConnecting;
-        let local_tcp_port = self.config.tcp_port;
-        let peer_tcp_addr = SocketAddr::new(peer_info.external_addr.ip(), tcp_port);
+        // Step 6: TCP connect (controlled side listens), nominated candidate first
+        self.set_state(ConnectionState::TcpConnecting);
+        let local_tcp_addr = SocketAddr::new(self.config.tcp_bind_ip, punch.local_tcp_port);
+        let mut peer_tcp_addrs = Vec::new();
+        let candidate_ips = std::iter::once(nominated.ip())
+            .chain(pairs.iter().map(|pair| pair.remote.addr.ip()))
+            .chain(responded.iter().map(|addr| addr.ip()));
+        for ip in candidate_ips {
+            let addr = SocketAddr::new(ip, tcp_port);
+            if !peer_tcp_addrs.contains(&addr) {
+                peer_tcp_addrs.push(addr);
+            }
+        }
 
-        let tcp_stream = tcp_simultaneous_open(local_tcp_port, peer_tcp_addr, Duration::from_secs(10))
+        let tcp_stream = tcp_connect_as(
+            role,
+            local_tcp_addr,
+            &peer_tcp_addrs,
+            TCP_PARALLELISM,
+            Duration::from_secs(10),
+            self.config.duplicate_grace,
+        )
             .await
-            .context("TCP simultaneous open failed")?;
+            .context("TCP connect failed")?;
+        self.config.socket_options.apply(&tcp_stream)?;
+
+        println!("TCP connection established!");
+        tracing::info!(peer = ?tcp_stream.peer_addr().ok(), "TCP connection established");
+
+        Ok(tcp_stream)
+    }
+
+    /// Plain TCP straight to the peer's signalled addresses, for when UDP is blocked
+    ///
+    /// Without a punched mapping one side has to accept inbound TCP: the side
+    /// that cannot use UDP dials (such networks usually still allow outbound
+    /// TCP) and the other listens on its `tcp_port`, which must be reachable
+    /// (public address or forwarded port). If both are blocked, the
+    /// controlling side dials.
+    async fn connect_tcp_direct(&mut self, tcp_port: u16, udp_blocked: bool, peer_info: &PeerInfo) -> Result<TcpStream> {
+        let dial = if udp_blocked != peer_info.udp_blocked {
+            udp_blocked
+        } else {
+            IceRole::for_peers(&self.config.local_fingerprint, &peer_info.fingerprint) == IceRole::Controlling
+        };
+
+        self.set_state(ConnectionState::TcpConnecting);
+        let timeout = Duration::from_secs(10);
+        let tcp_stream = if dial {
+            let peer_tcp_port = peer_info
+                .tcp_port
+                .context("Peer did not advertise a TCP port, cannot connect without UDP")?;
+            let mut peer_tcp_addrs = Vec::new();
+            for addr in peer_info.addrs() {
+                let addr = SocketAddr::new(addr.ip(), peer_tcp_port);
+                if !peer_tcp_addrs.contains(&addr) {
+                    peer_tcp_addrs.push(addr);
+                }
+            }
+            println!("Connecting to peer over TCP...");
+            tcp_dial(&peer_tcp_addrs, timeout).await
+        } else {
+            println!("Waiting for peer to connect over TCP on port {}...", tcp_port);
+            tcp_accept(SocketAddr::new(self.config.tcp_bind_ip, tcp_port), timeout).await
+        }
+            .context("Direct TCP connect failed")?;
+        self.config.socket_options.apply(&tcp_stream)?;
 
         println!("TCP connection established!");
+        tracing::info!(peer = ?tcp_stream.peer_addr().ok(), dial, "TCP connection established without UDP");
+
+        Ok(tcp_stream)
+    }
+
+    /// Connect through the TURN server after the direct paths failed
+    ///
+    /// Both sides know they are relaying once their own attempt fails, and
+    /// exchange a second pair of offers. The controlling side allocates a
+    /// TCP relay and, once the peer's offer says where it will connect from,
+    /// permits that address and offers the relayed address. The controlled
+    /// side dials the relayed address directly, so only one side needs a
+    /// TURN allocation. Either way the result is a plain `TcpStream`.
+    async fn connect_relayed(
+        &mut self,
+        dialled: Option<&mut SignallingClient>,
+        attempt_id: &str,
+        peer_info: &PeerInfo,
+    ) -> Result<TcpStream> {
+        self.set_state(ConnectionState::RelayFallback);
+        let (Some(server), Some(credentials)) = (self.config.turn_server_addr, self.config.turn_credentials.clone()) else {
+            anyhow::bail!("TURN relay needs both a server and credentials");
+        };
+        let role = IceRole::for_peers(&self.config.local_fingerprint, &peer_info.fingerprint);
+        let (turn, mapped_addr) = blocking(move || {
+            let mut turn = TurnClient::connect(server, credentials)?;
+            // Where our TCP connections appear to come from, for the peer's permission
+            let mapped_addr = turn.mapped_address()?;
+            Ok((turn, mapped_addr))
+        })
+        .await?;
 
-        // Step 7: Cleanup
-        self.state = ConnectionState::Connected;
-        signalling.close().await?;
-        self.signalling = None;
+        let mut offer = LocalOffer {
+            external_addr: mapped_addr,
+            local_addr: mapped_addr,
+            extra_addrs: Vec::new(),
+            attempt_id: attempt_id.to_string(),
+            tcp_port: 0,
+            udp_blocked: peer_info.udp_blocked,
+            verifying_key: self.config.signing_key.verifying_key(),
+            relay_addr: None,
+            nat_behavior: NatBehavior::Unknown,
+        };
+
+        let (mut turn, allocation) = if role == IceRole::Controlling {
+            self.set_state(ConnectionState::TurnAllocating);
+            let (turn, allocation) = blocking(move || {
+                let mut turn = turn;
+                let allocation = turn.allocate()?;
+                Ok((turn, allocation))
+            })
+            .await?;
+            println!("TURN relay allocated at {}", allocation.relayed_addr);
+            tracing::info!(relayed = %allocation.relayed_addr, "TURN relay allocated");
+            offer.tcp_port = allocation.relayed_addr.port();
+            offer.relay_addr = Some(allocation.relayed_addr);
+            (turn, Some(allocation))
+        } else {
+            (turn, None)
+        };
+
+        let peer_relay_info = {
+            let mut shared_guard;
+            let signalling = match (dialled, self.signalling.as_mut()) {
+                (Some(signalling), _) => signalling,
+                (None, Some(SuppliedSignalling::Owned(signalling))) => signalling.as_mut(),
+                (None, Some(SuppliedSignalling::Shared(shared))) => {
+                    shared_guard = shared.lock().await;
+                    &mut *shared_guard
+                }
+                (None, None) => unreachable!("signalling is only skipped when a client was supplied"),
+            };
+            if allocation.is_some() {
+                // The permission must be in place before the peer learns where to connect
+                let peer_relay_info = tokio::time::timeout(RELAY_TIMEOUT, signalling.wait_for_offer(&peer_info.fingerprint))
+                    .await
+                    .context("Peer did not fall back to the relay")??;
+                let peer_ips: Vec<IpAddr> = peer_relay_info.addrs().iter().map(SocketAddr::ip).collect();
+                turn = blocking(move || {
+                    let mut turn = turn;
+                    turn.create_permission(&peer_ips)?;
+                    Ok(turn)
+                })
+                .await?;
+                signalling.post_offer(&peer_info.fingerprint, &offer).await?;
+                peer_relay_info
+            } else {
+                tokio::time::timeout(RELAY_TIMEOUT, signalling.send_offer(&peer_info.fingerprint, &offer))
+                    .await
+                    .context("Peer did not fall back to the relay")??
+            }
+        };
+
+        self.set_state(ConnectionState::TcpConnecting);
+        let tcp_stream = match allocation {
+            Some(allocation) => {
+                println!("Waiting for peer to connect through the relay...");
+                let (turn, tcp_stream) = blocking(move || {
+                    let mut turn = turn;
+                    let tcp_stream = turn.accept(RELAY_TIMEOUT)?;
+                    Ok((turn, tcp_stream))
+                })
+                .await?;
+                self.relay = Some(turn.keep_alive(allocation.lifetime));
+                tcp_stream
+            }
+            None => {
+                let relay_addr = peer_relay_info
+                    .relay_addr
+                    .context("Peer's relay offer has no relayed address")?;
+                println!("Connecting to peer through the relay at {}...", relay_addr);
+                tcp_dial(&[relay_addr], RELAY_TIMEOUT).await?
+            }
+        };
+        self.config.socket_options.apply(&tcp_stream)?;
+
+        println!("TCP connection established through the TURN relay!");
+        tracing::info!(server = %server, "relayed TCP connection established");
 
         Ok(tcp_stream)
     }
 
+    /// Hand over the TURN allocation under the last relayed connection
+    ///
+    /// The relayed stream only works while the allocation lives, which is
+    /// as long as this `NatTraversal` unless it is taken out here.
+    pub fn take_relay(&mut self) -> Option<TurnRelay> {
+        self.relay.take()
+    }
+
+    /// Re-bind the saved UDP port and continue from hole punching
+    async fn resume(&mut self, saved: NatCheckpoint) -> Result<TcpStream> {
+        let (Some(local_addr), Some(peer_info)) = (saved.local_addr, saved.peer_info.clone()) else {
+            anyhow::bail!("Checkpoint has no candidates to resume from");
+        };
+        let local_candidates = gather_candidates(saved.external_addr.as_slice(), local_addr, &peer_info.addrs());
+        self.checkpoint = Some(saved);
+
+        // The advertised external mapping only survives if we reuse the same local port
+        let unspecified: IpAddr = if local_addr.is_ipv6() {
+            Ipv6Addr::UNSPECIFIED.into()
+        } else {
+            Ipv4Addr::UNSPECIFIED.into()
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified, local_addr.port()))
+            .context("Failed to re-bind saved UDP port")?;
+
+        let tcp_port = reserve_tcp_port(&self.config)?;
+        let tcp_stream = self.punch_and_connect(socket, tcp_port, &local_candidates, &peer_info).await?;
+        self.set_state(ConnectionState::Connected);
+
+        Ok(tcp_stream)
+    }
+
+    /// Load a resumable checkpoint for this peer from the store, if any
+    fn load_checkpoint(&mut self, peer_fingerprint: &str) -> Option<NatCheckpoint> {
+        let store = self.checkpoint_store.as_mut()?;
+        match store.load() {
+            Ok(Some(saved)) if saved.peer_fingerprint == peer_fingerprint && saved.can_resume() => {
+                Some(saved)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                println!("Ignoring unreadable checkpoint: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Record a state transition, report it and checkpoint it if a store is configured
+    fn set_state(&mut self, state: ConnectionState) {
+        tracing::debug!(?state, "state changed");
+        self.state = state;
+        if let Some(on_state) = self.on_state.as_mut() {
+            on_state(&self.state);
+        }
+
+        let (Some(store), Some(checkpoint)) = (self.checkpoint_store.as_mut(), self.checkpoint.as_mut()) else {
+            return;
+        };
+        checkpoint.state = self.state.clone();
+
+        // Persistence is best-effort; it must never break the connection itself
+        let result = if self.state == ConnectionState::Connected {
+            store.clear()
+        } else {
+            store.save(checkpoint)
+        };
+        if let Err(e) = result {
+            println!("Failed to update checkpoint: {}", e);
+        }
+    }
+
     /// Get current connection state
     pub fn state(&self) -> &ConnectionState {
         &self.state
     }
 }
+
+/// Resolves once the flag behind a `CancelHandle` is set
+async fn cancelled(mut flag: tokio::sync::watch::Receiver<bool>) {
+    if flag.wait_for(|&cancelled| cancelled).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Span wrapping every event of one connection attempt
+fn attempt_span(attempt_id: &str) -> tracing::Span {
+    tracing::info_span!("nat_connect", attempt_id, peer_attempt_id = tracing::field::Empty)
+}
+
+/// Connect to signalling through the cached addresses, re-resolving once if they fail
+///
+/// `options.addrs` is replaced by `cached_addrs`, which is refreshed when re-resolving.
+async fn connect_signalling(
+    url: &str,
+    cached_addrs: &mut Vec<SocketAddr>,
+    mut options: SignallingConnectOptions,
+) -> Result<SignallingClient> {
+    options.addrs = cached_addrs.clone();
+    if cached_addrs.is_empty() {
+        return SignallingClient::connect_with_options(url, &options).await;
+    }
+    match SignallingClient::connect_with_options(url, &options).await {
+        Ok(client) => Ok(client),
+        Err(e) => {
+            println!("Cached signalling address failed ({}), re-resolving...", e);
+            tracing::warn!(error = %e, "cached signalling address failed");
+            *cached_addrs = resolve_signalling(url)?;
+            options.addrs = cached_addrs.clone();
+            SignallingClient::connect_with_options(url, &options).await
+        }
+    }
+}
+
+/// A STUN client and the mapping it discovered, one per address family
+type StunBinding = (StunClient, StunResponse);
+
+/// Send a STUN keepalive from every binding each `interval`, forever
+/// (or never, without an interval or bindings)
+async fn keep_mappings_alive(bindings: &[StunBinding], interval: Option<Duration>) -> std::convert::Infallible {
+    let Some(interval) = interval.filter(|_| !bindings.is_empty()) else {
+        return std::future::pending().await;
+    };
+    let mut ticks = tokio::time::interval(interval);
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        for (stun_client, _) in bindings {
+            match stun_client.keepalive() {
+                Ok(()) => tracing::debug!(local = %stun_client.local_addr(), "STUN keepalive sent"),
+                Err(e) => tracing::warn!(error = %e, "STUN keepalive failed"),
+            }
+        }
+    }
+}
+
+/// Keep whichever per-family STUN queries succeeded, IPv4 first
+/// One family failing is only logged as long as the other yields a candidate
+fn collect_bindings(v4: Result<StunBinding>, v6: Option<Result<StunBinding>>) -> Result<Vec<StunBinding>> {
+    match (v4, v6) {
+        (Ok(v4), None) => Ok(vec![v4]),
+        (Ok(v4), Some(Ok(v6))) => Ok(vec![v4, v6]),
+        (Ok(v4), Some(Err(e))) => {
+            println!("IPv6 STUN query failed ({}), continuing with IPv4 only", e);
+            tracing::warn!(error = %e, "IPv6 STUN query failed");
+            Ok(vec![v4])
+        }
+        (Err(e), Some(Ok(v6))) => {
+            println!("IPv4 STUN query failed ({}), continuing with IPv6 only", e);
+            tracing::warn!(error = %e, "IPv4 STUN query failed");
+            Ok(vec![v6])
+        }
+        (Err(e), Some(Err(v6_error))) => Err(e.context(format!("{:#}", v6_error))),
+        (Err(e), None) => Err(e),
+    }
+}
+
+/// Whether every STUN query went unanswered, as on networks that drop all UDP
+/// Any other failure (e.g. an error response) shows UDP does get through
+fn udp_blocked(v4: &Result<StunBinding>, v6: &Option<Result<StunBinding>>) -> bool {
+    let no_response = |result: &Result<StunBinding>| {
+        matches!(result, Err(e) if matches!(e.downcast_ref(), Some(StunError::NoResponse { .. })))
+    };
+    no_response(v4) && v6.as_ref().is_none_or(no_response)
+}
+
+/// Run blocking network calls (the TURN client's) on tokio's blocking pool
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.context("Blocking task failed")?
+}
+
+/// The TCP port to open from: the configured one, or a free one picked now
+fn reserve_tcp_port(config: &NatTraversalConfig) -> Result<u16> {
+    if config.tcp_port != 0 {
+        return Ok(config.tcp_port);
+    }
+    // Bind to learn a free port, then release it for the simultaneous open
+    let listener = std::net::TcpListener::bind(SocketAddr::new(config.tcp_bind_ip, 0))
+        .context("Failed to pick a local TCP port")?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// Pick the binding to punch from
+///
+/// Prefers the family of the peer's primary address, so both sides settle
+/// on the same family, then any family the peer advertised at all.
+fn select_binding(mut bindings: Vec<StunBinding>, peer_info: &PeerInfo) -> Result<StunBinding> {
+    let external = |(_, response): &StunBinding| SocketAddr::new(response.external_ip, response.external_port);
+    let position = bindings
+        .iter()
+        .position(|binding| shares_family(external(binding), &[peer_info.external_addr]))
+        .or_else(|| {
+            bindings
+                .iter()
+                .position(|binding| shares_family(external(binding), &peer_info.reflexive_addrs()))
+        })
+        .ok_or_else(|| anyhow::anyhow!("Peer advertised no address family we have a candidate in"))?;
+
+    let selected = bindings.swap_remove(position);
+    for binding in &bindings {
+        println!("Skipping candidate {} in favour of {}", external(binding), external(&selected));
+    }
+    Ok(selected)
+}
+
+/// Query STUN, re-resolving a named server once if the first query fails
+async fn query_stun(
+    server_addr: &mut SocketAddr,
+    server_host: Option<&str>,
+    preference: AttributePreference,
+) -> Result<StunBinding> {
+    let stun_client = StunClient::new(server_addr)?.with_preference(preference);
+    match (stun_client.query().await, server_host) {
+        (Ok(response), _) => Ok((stun_client, response)),
+        (Err(e), Some(host)) => {
+            println!("STUN query failed ({}), re-resolving server...", e);
+            tracing::warn!(error = %e, "STUN query failed");
+            *server_addr = resolve_stun(host)?;
+            let stun_client = StunClient::new(server_addr)?.with_preference(preference);
+            let response = stun_client.query().await?;
+            Ok((stun_client, response))
+        }
+        (Err(e), None) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nat_traversal::test_harness::LoopbackHarness;
+    use std::sync::mpsc;
+
+    /// A manager reporting its states on a channel
+    fn observed(config: NatTraversalConfig) -> (NatTraversal, mpsc::Receiver<ConnectionState>) {
+        let (tx, rx) = mpsc::channel();
+        let mut nat = NatTraversal::new(config);
+        nat.set_state_handler(move |state| {
+            let _ = tx.send(state.clone());
+        });
+        (nat, rx)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn states_are_reported_in_order() {
+        let harness = LoopbackHarness::start().await.unwrap();
+        let (mut alice, states) = observed(harness.config("alice"));
+        let mut bob = NatTraversal::new(harness.config("bob"));
+
+        let (alice_stream, bob_stream) = tokio::join!(alice.connect("bob"), bob.connect("alice"));
+        alice_stream.unwrap();
+        bob_stream.unwrap();
+
+        assert_eq!(
+            states.try_iter().collect::<Vec<_>>(),
+            [
+                ConnectionState::Discovering,
+                ConnectionState::SendingOffer,
+                ConnectionState::UdpHolePunching,
+                ConnectionState::TcpConnecting,
+                ConnectionState::Connected,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_attempt_ends_failed() {
+        let harness = LoopbackHarness::start().await.unwrap();
+        let mut alice = NatTraversal::new(harness.config("alice"));
+        let cancel = alice.cancel_handle();
+        let (tx, states) = mpsc::channel();
+        alice.set_state_handler(move |state| {
+            // Bob never shows up, so the attempt would wait for his offer forever
+            if *state == ConnectionState::SendingOffer {
+                cancel.cancel();
+            }
+            let _ = tx.send(state.clone());
+        });
+
+        let error = alice.connect("bob").await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(NatTraversalError::Cancelled)));
+        assert_eq!(
+            states.try_iter().collect::<Vec<_>>(),
+            [
+                ConnectionState::Discovering,
+                ConnectionState::SendingOffer,
+                ConnectionState::Failed("Cancelled".to_string()),
+            ]
+        );
+        assert_eq!(*alice.state(), ConnectionState::Failed("Cancelled".to_string()));
+    }
+
+    #[tokio::test]
+    async fn cancel_before_connect_is_kept() {
+        let harness = LoopbackHarness::start().await.unwrap();
+        let mut alice = NatTraversal::new(harness.config("alice"));
+        alice.cancel_handle().cancel();
+
+        let error = alice.connect("bob").await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(NatTraversalError::Cancelled)));
+        assert!(!*alice.cancel.0.borrow(), "flag must be cleared once the attempt ends");
+    }
+
+    fn binding(external: &str) -> StunBinding {
+        let client = StunClient::new(&"127.0.0.1:3478".parse().unwrap()).unwrap();
+        let external: SocketAddr = external.parse().unwrap();
+        let response = StunResponse {
+            external_ip: external.ip(),
+            external_port: external.port(),
+            attribute: stun::AddressAttribute::XorMapped,
+            other_addr: None,
+        };
+        (client, response)
+    }
+
+    fn peer(external_addr: &str, extra_addrs: &[&str]) -> PeerInfo {
+        serde_json::from_value(serde_json::json!({
+            "fingerprint": "bob",
+            "external_addr": external_addr,
+            "local_addr": "10.0.0.7:5000",
+            "nonce": 1,
+            "extra_addrs": extra_addrs,
+        }))
+        .unwrap()
+    }
+
+    fn selected(bindings: Vec<StunBinding>, peer_info: &PeerInfo) -> Option<u16> {
+        select_binding(bindings, peer_info).ok().map(|(_, response)| response.external_port)
+    }
+
+    #[test]
+    fn bindings_follow_the_families_both_peers_have() {
+        let v4 = || binding("203.0.113.5:4000");
+        let v6 = || binding("[2001:db8::5]:6000");
+
+        // The peer's primary family wins, then any family it advertised
+        assert_eq!(selected(vec![v4(), v6()], &peer("[2001:db8::9]:1", &["198.51.100.9:1"])), Some(6000));
+        assert_eq!(selected(vec![v4(), v6()], &peer("198.51.100.9:1", &[])), Some(4000));
+        assert_eq!(selected(vec![v6()], &peer("198.51.100.9:1", &["[2001:db8::9]:1"])), Some(6000));
+        assert_eq!(selected(vec![v4()], &peer("[2001:db8::9]:1", &[])), None);
+
+        // One family failing is tolerated, both failing is not
+        let failed = || Err(anyhow::anyhow!("timed out"));
+        assert_eq!(collect_bindings(Ok(v4()), Some(failed())).unwrap().len(), 1);
+        assert_eq!(collect_bindings(failed(), Some(Ok(v6()))).unwrap()[0].1.external_port, 6000);
+        assert_eq!(collect_bindings(Ok(v4()), Some(Ok(v6()))).unwrap().len(), 2);
+        assert!(collect_bindings(failed(), Some(failed())).is_err());
+        assert!(collect_bindings(failed(), None).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn silent_stun_falls_back_to_direct_tcp() {
+        let harness = LoopbackHarness::start().await.unwrap();
+        // Alice's STUN server never answers, as when UDP is blocked
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut config = harness.config("alice");
+        config.stun_server_addr = silent.local_addr().unwrap();
+        let (mut alice, states) = observed(config);
+        let mut bob = NatTraversal::new(harness.config("bob"));
+
+        let (alice_stream, bob_stream) = tokio::join!(alice.connect("bob"), bob.connect("alice"));
+        let (alice_stream, bob_stream) = (alice_stream.unwrap(), bob_stream.unwrap());
+        assert_eq!(alice_stream.local_addr().unwrap(), bob_stream.peer_addr().unwrap());
+
+        let states: Vec<ConnectionState> = states.try_iter().collect();
+        assert!(states.contains(&ConnectionState::RelayFallback), "{:?}", states);
+        assert!(!states.contains(&ConnectionState::UdpHolePunching), "{:?}", states);
+        assert_eq!(states.last(), Some(&ConnectionState::Connected));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stun_mapping_is_refreshed_while_waiting_for_the_peer() {
+        let harness = LoopbackHarness::start().await.unwrap();
+        let mut config = harness.config("alice");
+        config.stun_keepalive = Some(Duration::from_millis(50));
+        let mut alice = NatTraversal::new(config);
+        let mut config = harness.config("bob");
+        config.stun_keepalive = None;
+        let mut bob = NatTraversal::new(config);
+
+        let late_bob = async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            bob.connect("alice").await
+        };
+        let (alice_stream, bob_stream) = tokio::join!(alice.connect("bob"), late_bob);
+        alice_stream.unwrap();
+        bob_stream.unwrap();
+        // One discovery query each, plus Alice's keepalives while Bob was away
+        assert!(harness.stun.answered() >= 2 + 5, "{} binding requests", harness.stun.answered());
+    }
+}
@@ -0,0 +1,349 @@
+/**
+ * nat_traversal/nat_behavior.rs
+ *
+ * NAT type classification from STUN mappings (RFC 3489 names, RFC 5780 tests)
+ */
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+use crate::nat_traversal::stun::{ChangeRequest, StunClient};
+
+/// How long each classification request waits for its response
+const CLASSIFY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How a NAT maps and filters UDP traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NatBehavior {
+    /// No NAT: the mapped address is our own
+    Open,
+    /// One mapping for every destination; anyone may send to it
+    FullCone,
+    /// One mapping for every destination; only addresses we sent to may answer
+    RestrictedCone,
+    /// One mapping for every destination; only address and port pairs we sent to may answer
+    PortRestrictedCone,
+    /// A new mapping per destination, so the address STUN saw is not the one the peer will see
+    Symmetric,
+    /// Not enough STUN responses to tell
+    #[default]
+    Unknown,
+}
+
+impl NatBehavior {
+    /// Name used in offers
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NatBehavior::Open => "open",
+            NatBehavior::FullCone => "full_cone",
+            NatBehavior::RestrictedCone => "restricted_cone",
+            NatBehavior::PortRestrictedCone => "port_restricted_cone",
+            NatBehavior::Symmetric => "symmetric",
+            NatBehavior::Unknown => "unknown",
+        }
+    }
+
+    /// Parse a name from `as_str`; anything else (e.g. from a newer peer) is `Unknown`
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "open" => NatBehavior::Open,
+            "full_cone" => NatBehavior::FullCone,
+            "restricted_cone" => NatBehavior::RestrictedCone,
+            "port_restricted_cone" => NatBehavior::PortRestrictedCone,
+            "symmetric" => NatBehavior::Symmetric,
+            _ => NatBehavior::Unknown,
+        }
+    }
+
+    /// Whether UDP hole punching can work between this NAT and the peer's
+    ///
+    /// A symmetric NAT's punching mapping is unknown to the peer, so the peer
+    /// must accept packets from any port of our address: that fails against
+    /// another symmetric or a port-restricted NAT. `Unknown` is assumed to
+    /// punch, so missing information never skips the direct path.
+    pub fn can_punch_with(self, peer: NatBehavior) -> bool {
+        !matches!(
+            (self, peer),
+            (NatBehavior::Symmetric, NatBehavior::Symmetric | NatBehavior::PortRestrictedCone)
+                | (NatBehavior::PortRestrictedCone, NatBehavior::Symmetric)
+        )
+    }
+}
+
+impl std::fmt::Display for NatBehavior {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            NatBehavior::Open => "no NAT",
+            NatBehavior::FullCone => "full cone",
+            NatBehavior::RestrictedCone => "restricted cone",
+            NatBehavior::PortRestrictedCone => "port-restricted cone",
+            NatBehavior::Symmetric => "symmetric",
+            NatBehavior::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Classify the NAT in front of this host
+///
+/// One socket queries the first server, then a server at another IP: the
+/// next one in `servers` of the same family, or the first server's
+/// OTHER-ADDRESS. Different mappings mean a symmetric NAT. Otherwise the
+/// filtering is tested with CHANGE-REQUEST, which needs a server with an
+/// OTHER-ADDRESS that honours it; when that cannot be tested the result is
+/// `Unknown`. Failures are logged and yield `Unknown` too.
+pub async fn classify_nat(servers: &[SocketAddr]) -> NatBehavior {
+    // The probes block on their socket for up to a second each; keep them off the async workers
+    let servers = servers.to_vec();
+    let result = tokio::task::spawn_blocking(move || classify(&servers))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| result);
+    match result {
+        Ok(behavior) => behavior,
+        Err(e) => {
+            println!("NAT classification failed: {:#}", e);
+            tracing::warn!(error = %format!("{:#}", e), "NAT classification failed");
+            NatBehavior::Unknown
+        }
+    }
+}
+
+fn classify(servers: &[SocketAddr]) -> Result<NatBehavior> {
+    let primary = *servers.first().context("No STUN server to classify against")?;
+    let client = StunClient::new(&primary)?;
+
+    // Mapping as seen by the first server
+    let Some((first, _)) = client.probe(primary, ChangeRequest::None, CLASSIFY_TIMEOUT)? else {
+        return Ok(NatBehavior::Unknown);
+    };
+    let mapped = SocketAddr::new(first.external_ip, first.external_port);
+    if mapped == SocketAddr::new(route_ip(primary)?, client.local_addr().port()) {
+        return Ok(NatBehavior::Open);
+    }
+
+    // Mapping as seen from another IP
+    let alternate = servers[1..]
+        .iter()
+        .copied()
+        .chain(first.other_addr)
+        .find(|addr| addr.is_ipv4() == primary.is_ipv4() && addr.ip() != primary.ip());
+    let Some(alternate) = alternate else {
+        return Ok(NatBehavior::Unknown);
+    };
+    let Some((second, _)) = client.probe(alternate, ChangeRequest::None, CLASSIFY_TIMEOUT)? else {
+        return Ok(NatBehavior::Unknown);
+    };
+    if SocketAddr::new(second.external_ip, second.external_port) != mapped {
+        return Ok(NatBehavior::Symmetric);
+    }
+
+    // Filtering: does an answer from an address we never sent to get through?
+    let Some(other_addr) = first.other_addr else {
+        tracing::debug!("server has no alternate address, filtering untested");
+        return Ok(NatBehavior::Unknown);
+    };
+    // Servers that ignore CHANGE-REQUEST answer from the primary address; that proves nothing
+    let changed = |from: SocketAddr, ip_too: bool| {
+        from.port() != primary.port() && (from.ip() != primary.ip()) == ip_too
+    };
+    match client.probe(primary, ChangeRequest::IpAndPort, CLASSIFY_TIMEOUT)? {
+        Some((_, from)) if changed(from, true) => return Ok(NatBehavior::FullCone),
+        Some((_, from)) => return Ok(ignored_change_request(from)),
+        None => {}
+    }
+    match client.probe(primary, ChangeRequest::Port, CLASSIFY_TIMEOUT)? {
+        Some((_, from)) if changed(from, false) => Ok(NatBehavior::RestrictedCone),
+        Some((_, from)) => Ok(ignored_change_request(from)),
+        None => {
+            tracing::debug!(%other_addr, "no response from the alternate address");
+            Ok(NatBehavior::PortRestrictedCone)
+        }
+    }
+}
+
+/// A CHANGE-REQUEST answered from the wrong address leaves filtering untested
+fn ignored_change_request(from: SocketAddr) -> NatBehavior {
+    tracing::debug!(%from, "server ignored CHANGE-REQUEST, filtering untested");
+    NatBehavior::Unknown
+}
+
+/// Local IP that traffic to `server` leaves from
+fn route_ip(server: SocketAddr) -> Result<std::net::IpAddr> {
+    let bind_addr = if server.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+    let socket = UdpSocket::bind(bind_addr).context("Failed to bind UDP socket")?;
+    socket.connect(server).context("No route to STUN server")?;
+    Ok(socket.local_addr()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    const MAGIC_COOKIE: u32 = 0x2112A442;
+    const PUBLIC_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 9);
+
+    /// What the simulated NAT and server do
+    #[derive(Clone, Copy)]
+    struct Mock {
+        /// None: no NAT, the server sees our own address
+        nat: Option<NatBehavior>,
+        /// Include OTHER-ADDRESS in responses
+        other_address: bool,
+        /// Answer CHANGE-REQUEST from the requested address
+        honour_change: bool,
+    }
+
+    impl Mock {
+        fn nat(nat: NatBehavior) -> Self {
+            Self { nat: Some(nat), other_address: true, honour_change: true }
+        }
+
+        /// Mapping the server at `server_index` sees for `from`
+        fn mapping(&self, from: SocketAddr, server_index: u16) -> SocketAddr {
+            match self.nat {
+                None => from,
+                Some(NatBehavior::Symmetric) => SocketAddr::from((PUBLIC_IP, 40000 + server_index)),
+                Some(_) => SocketAddr::from((PUBLIC_IP, 40000)),
+            }
+        }
+
+        /// Whether our NAT lets an answer from the changed address in
+        fn passes(&self, change_ip: bool, change_port: bool) -> bool {
+            match self.nat {
+                None | Some(NatBehavior::FullCone) => true,
+                Some(NatBehavior::RestrictedCone) => !change_ip,
+                _ => !change_ip && !change_port,
+            }
+        }
+    }
+
+    /// RFC 5780 server on 127.0.0.1 and 127.0.0.2, two ports each, behind a simulated NAT
+    struct MockServer {
+        /// Primary address, then the alternate IP with the primary's port index
+        addrs: [SocketAddr; 2],
+        stop: Arc<AtomicBool>,
+    }
+
+    impl MockServer {
+        fn start(mock: Mock) -> Self {
+            // [primary IP primary port, primary IP other port, other IP primary port, other IP other port]
+            let sockets: Vec<UdpSocket> = [1, 1, 2, 2]
+                .iter()
+                .map(|last: &u8| {
+                    let socket = UdpSocket::bind((Ipv4Addr::new(127, 0, 0, *last), 0)).unwrap();
+                    socket.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+                    socket
+                })
+                .collect();
+            let addr = |index: usize| sockets[index].local_addr().unwrap();
+            let addrs = [addr(0), addr(2)];
+            let other_addr = addr(3);
+            let stop = Arc::new(AtomicBool::new(false));
+
+            let running = Arc::clone(&stop);
+            std::thread::spawn(move || {
+                let mut buffer = [0u8; 512];
+                while !running.load(Ordering::Relaxed) {
+                    for (index, socket) in sockets.iter().enumerate() {
+                        let Ok((len, from)) = socket.recv_from(&mut buffer) else {
+                            continue;
+                        };
+                        let request = &buffer[..len];
+                        // CHANGE-REQUEST is the only attribute the client sends
+                        let flags = if len >= 28 { request[27] } else { 0 };
+                        let (change_ip, change_port) = (flags & 0x04 != 0, flags & 0x02 != 0);
+                        let reply_from = if mock.honour_change {
+                            index ^ (usize::from(change_ip) << 1) ^ usize::from(change_port)
+                        } else {
+                            index
+                        };
+                        if !mock.passes(reply_from & 2 != index & 2, reply_from & 1 != index & 1) {
+                            continue;
+                        }
+                        let mapping = mock.mapping(from, (index / 2) as u16);
+                        let response = binding_response(&request[8..20], mapping, mock.other_address.then_some(other_addr));
+                        let _ = sockets[reply_from].send_to(&response, from);
+                    }
+                }
+            });
+
+            Self { addrs, stop }
+        }
+    }
+
+    impl Drop for MockServer {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn binding_response(transaction_id: &[u8], mapping: SocketAddr, other_addr: Option<SocketAddr>) -> Vec<u8> {
+        let v4 = |addr: SocketAddr| match addr {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!("the mock is IPv4 only"),
+        };
+        let address = |attr_type: u16, addr: SocketAddrV4, xor: u32| {
+            let mut attr = attr_type.to_be_bytes().to_vec();
+            attr.extend_from_slice(&[0, 8, 0, 0x01]);
+            attr.extend_from_slice(&(addr.port() ^ (xor >> 16) as u16).to_be_bytes());
+            attr.extend_from_slice(&(u32::from(*addr.ip()) ^ xor).to_be_bytes());
+            attr
+        };
+
+        // XOR-MAPPED-ADDRESS, then OTHER-ADDRESS (not XORed)
+        let mut body = address(0x0020, v4(mapping), MAGIC_COOKIE);
+        if let Some(other_addr) = other_addr {
+            body.extend(address(0x802C, v4(other_addr), 0));
+        }
+        let mut response = vec![0x01, 0x01];
+        response.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        response.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        response.extend_from_slice(transaction_id);
+        response.extend(body);
+        response
+    }
+
+    fn classify_behind(mock: Mock) -> NatBehavior {
+        let server = MockServer::start(mock);
+        classify(&server.addrs[..1]).unwrap()
+    }
+
+    #[test]
+    fn no_nat_is_open() {
+        assert_eq!(classify_behind(Mock { nat: None, other_address: true, honour_change: true }), NatBehavior::Open);
+    }
+
+    #[test]
+    fn mapping_per_server_is_symmetric() {
+        assert_eq!(classify_behind(Mock::nat(NatBehavior::Symmetric)), NatBehavior::Symmetric);
+    }
+
+    #[test]
+    fn filtering_tells_the_cones_apart() {
+        for nat in [NatBehavior::FullCone, NatBehavior::RestrictedCone, NatBehavior::PortRestrictedCone] {
+            assert_eq!(classify_behind(Mock::nat(nat)), nat);
+        }
+    }
+
+    #[test]
+    fn untestable_filtering_is_unknown() {
+        // A second server gives the mapping test, but without OTHER-ADDRESS there is no filtering test
+        let server = MockServer::start(Mock { other_address: false, ..Mock::nat(NatBehavior::FullCone) });
+        assert_eq!(classify(&server.addrs).unwrap(), NatBehavior::Unknown);
+
+        let ignores_change = Mock { honour_change: false, ..Mock::nat(NatBehavior::FullCone) };
+        assert_eq!(classify_behind(ignores_change), NatBehavior::Unknown);
+    }
+
+    #[test]
+    fn silent_server_is_unknown() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert_eq!(classify(&[silent.local_addr().unwrap()]).unwrap(), NatBehavior::Unknown);
+    }
+}
@@ -0,0 +1,47 @@
+/**
+ * nat_traversal/resolve.rs
+ *
+ * Name resolution for the signalling and STUN servers, kept off the connect path
+ */
+
+use anyhow::{anyhow, Context, Result};
+use std::net::{SocketAddr, ToSocketAddrs};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+/// Resolve `host:port` to every address the system resolver returns
+pub fn resolve_host(host_port: &str) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = host_port
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve {}", host_port))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(anyhow!("{} resolved to no addresses", host_port));
+    }
+    Ok(addrs)
+}
+
+/// Resolve a STUN server to the IPv4 address the (IPv4-bound) client can reach
+pub fn resolve_stun(host_port: &str) -> Result<SocketAddr> {
+    resolve_host(host_port)?
+        .into_iter()
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| anyhow!("STUN server {} has no IPv4 address", host_port))
+}
+
+/// Resolve a STUN server to an IPv6 address, for `stun_server_addr_v6`
+pub fn resolve_stun_v6(host_port: &str) -> Result<SocketAddr> {
+    resolve_host(host_port)?
+        .into_iter()
+        .find(SocketAddr::is_ipv6)
+        .ok_or_else(|| anyhow!("STUN server {} has no IPv6 address", host_port))
+}
+
+/// Resolve the host of a signalling URL (wss://host:port)
+pub fn resolve_signalling(url: &str) -> Result<Vec<SocketAddr>> {
+    let req = url.into_client_request()
+        .context("Invalid signalling URL")?;
+    let host = req.uri().host().ok_or_else(|| anyhow!("Missing hostname"))?;
+    let port = req.uri().port_u16().unwrap_or(443);
+    // IPv6 literals keep their brackets in the URI host
+    resolve_host(&format!("{}:{}", host, port))
+}
@@ -1,19 +1,75 @@
 /**
  * nat_traversal/signalling.rs
  *
- * TLS WebSocket signalling client (self-signed certs allowed for development)
+ * TLS WebSocket signalling client (self-signed certs only when opted in)
  */
 
 use anyhow::{Context, Result, anyhow};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream, MaybeTlsStream};
-use tokio_tungstenite::client_async_tls_with_config;
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio::net::TcpStream as TokioTcpStream;
 use futures_util::{StreamExt, SinkExt};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::SocketAddr;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
 use native_tls::TlsConnector;
-use crate::nat_traversal::types::PeerInfo;
+use std::time::Duration;
+use crate::nat_traversal::nat_behavior::NatBehavior;
+use crate::nat_traversal::types::{LocalOffer, NatTraversalConfig, PeerInfo};
+use crate::network;
+
+/// Default gap between keepalives while waiting on the server
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long `is_peer_online` waits for the server's answer
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// WebSocket subprotocol a client offers to use MessagePack
+pub const MSGPACK_SUBPROTOCOL: &str = "pineapple.msgpack";
+
+/// Encoding of signalling messages on one connection
+///
+/// JSON travels in text frames and MessagePack in binary frames. A client
+/// asks for MessagePack by offering `MSGPACK_SUBPROTOCOL`; servers that do
+/// not echo it back get JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SignallingCodec {
+        #[default]
+        Json,
+        MessagePack,
+}
+
+impl SignallingCodec {
+        /// Frame carrying `msg` in this encoding
+        pub fn encode(self, msg: &SignallingMessage) -> Result<Message> {
+                Ok(match self {
+                        SignallingCodec::Json => Message::Text(
+                                serde_json::to_string(msg).context("Message serialization failed")?,
+                        ),
+                        // Named fields, so the `type` tag and optional fields work as in JSON
+                        SignallingCodec::MessagePack => Message::Binary(
+                                rmp_serde::to_vec_named(msg).context("Message serialization failed")?,
+                        ),
+                })
+        }
+
+        /// Decode a text (JSON) or binary (MessagePack) frame
+        ///
+        /// Either is accepted whatever was negotiated, since the peer's
+        /// encoding is known from the frame type. Other frames give None.
+        pub fn decode(frame: &Message) -> Result<Option<SignallingMessage>> {
+                match frame {
+                        Message::Text(text) => serde_json::from_str(text)
+                                .map(Some)
+                                .context("Failed to decode signalling message"),
+                        Message::Binary(data) => rmp_serde::from_slice(data)
+                                .map(Some)
+                                .context("Failed to decode signalling message"),
+                        _ => Ok(None),
+                }
+        }
+}
 
 /// Signalling message types
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,6 +90,27 @@ pub enum SignallingMessage {
                 local_port: u16,
                 nonce: u64,
                 fingerprint: String,
+                /// Reflexive addresses (ip:port) in other address families
+                #[serde(default, skip_serializing_if = "Vec::is_empty")]
+                extra_addrs: Vec<String>,
+                /// Sender's connection attempt id, for correlating both peers' logs
+                #[serde(default, skip_serializing_if = "String::is_empty")]
+                attempt_id: String,
+                /// TCP port the sender will use for simultaneous open
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                tcp_port: Option<u16>,
+                /// The sender got no STUN response at all and cannot hole punch
+                #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+                udp_blocked: bool,
+                /// Hex of the Ed25519 key the sender signs its probes with
+                #[serde(default, skip_serializing_if = "String::is_empty")]
+                verifying_key: String,
+                /// TURN relayed address (ip:port) the peer should connect to
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                relay_addr: Option<String>,
+                /// Sender's NAT type (see `NatBehavior::as_str`)
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                nat_behavior: Option<String>,
         },
         ForwardOffer {
                 from_fingerprint: String,
@@ -42,11 +119,44 @@ pub enum SignallingMessage {
                 local_ip: String,
                 local_port: u16,
                 nonce: u64,
+                #[serde(default, skip_serializing_if = "Vec::is_empty")]
+                extra_addrs: Vec<String>,
+                #[serde(default, skip_serializing_if = "String::is_empty")]
+                attempt_id: String,
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                tcp_port: Option<u16>,
+                #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+                udp_blocked: bool,
+                #[serde(default, skip_serializing_if = "String::is_empty")]
+                verifying_key: String,
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                relay_addr: Option<String>,
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                nat_behavior: Option<String>,
         },
         OfferResponse {
                 success: bool,
                 message: Option<String>,
         },
+        /// Store this client's prekey bundle (hex) so offline peers can fetch it
+        PublishBundle {
+                fingerprint: String,
+                bundle: String,
+        },
+        FetchBundle {
+                target_fingerprint: String,
+        },
+        BundleResponse {
+                bundle: String,
+        },
+        /// Ask whether one peer is registered right now
+        PresenceQuery {
+                target_fingerprint: String,
+        },
+        Presence {
+                fingerprint: String,
+                online: bool,
+        },
         Keepalive,
         Error {
                 message: String,
@@ -61,6 +171,10 @@ pub enum SignallingError {
         SendFailed(String),
         ReceiveFailed(String),
         InvalidMessage(String),
+        /// The server forwarded an offer from a peer other than the one dialled
+        UnexpectedPeer { expected: String, found: String },
+        /// Certificate validation failed for the signalling server
+        CertificateRejected { host: String, reason: String },
 }
 
 impl std::fmt::Display for SignallingError {
@@ -71,108 +185,172 @@ impl std::fmt::Display for SignallingError {
                         SignallingError::SendFailed(e) => write!(f, "Send failed: {}", e),
                         SignallingError::ReceiveFailed(e) => write!(f, "Receive failed: {}", e),
                         SignallingError::InvalidMessage(e) => write!(f, "Invalid message: {}", e),
+                        SignallingError::UnexpectedPeer { expected, found } => {
+                                write!(f, "Expected an offer from {} but the server forwarded one from {}", expected, found)
+                        }
+                        SignallingError::CertificateRejected { host, reason } => write!(
+                                f,
+                                "Signalling server certificate for {} was rejected: {} (self-signed servers need accept_invalid_certs)",
+                                host, reason
+                        ),
                 }
         }
 }
 
 impl std::error::Error for SignallingError {}
 
-// WebSocket signalling client
-/*
-pub struct SignallingClient {
-        ws_stream: WebSocketStream<MaybeTlsStream<TokioTcpStream>>,
-        local_fingerprint: Option<String>,
+/// How `SignallingClient::connect_with_options` reaches the server
+#[derive(Debug, Clone, Default)]
+pub struct SignallingConnectOptions {
+        /// Already-resolved addresses for the URL's host (empty: resolve the host as usual)
+        pub addrs: Vec<SocketAddr>,
+        /// TLS SNI name to present instead of the URL's host (None: the URL's host)
+        ///
+        /// For servers reached by IP behind a proxy or CDN that routes on SNI.
+        /// Only TLS sees the override: the TCP connection still goes to the URL's
+        /// host and the WebSocket `Host` header still names it. The server
+        /// certificate is checked against the SNI name, so a certificate (or
+        /// pin) must match the override rather than the IP.
+        pub sni_hostname: Option<String>,
+        /// Encoding to ask the server for
+        pub codec: SignallingCodec,
+        /// Accept any server certificate, for development servers with
+        /// self-signed certificates; anyone on the path can then impersonate
+        /// the server. Otherwise the certificate chain and name are validated.
+        pub accept_invalid_certs: bool,
 }
-*/
 
-pub struct SignallingClient {
-        ws_stream: WebSocketStream<MaybeTlsStream<tokio_native_tls::TlsStream<TokioTcpStream>>>,
-        local_fingerprint: Option<String>,
+impl SignallingConnectOptions {
+        /// The signalling settings of a `NatTraversalConfig`
+        pub fn from_config(config: &NatTraversalConfig) -> Self {
+                Self {
+                        addrs: config.signalling_addrs.clone(),
+                        sni_hostname: config.sni_hostname.clone(),
+                        codec: config.signalling_codec,
+                        accept_invalid_certs: config.accept_invalid_certs,
+                }
+        }
 }
 
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+compile_error!("enable the `native-tls` or `rustls` feature for signalling TLS");
 
-impl SignallingClient {
-
-        // Connect to signalling server (TLS, accepts self-signed)
-        /*
-        pub async fn connect(url: &str) -> Result<Self> {
-
-                let req = url.into_client_request()
-                        .context("Invalid signalling URL")?;
+/// TLS stream from the selected backend (`rustls` wins if both features are on)
+#[cfg(feature = "rustls")]
+type TlsStream = tokio_rustls::client::TlsStream<TokioTcpStream>;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+type TlsStream = tokio_native_tls::TlsStream<TokioTcpStream>;
 
-                // Allow self-signed certificate for development
-                let tls = TlsConnector::builder()
-                        .danger_accept_invalid_certs(true)
-                        .build()
-                        .expect("Failed to build TLS connector");
-
-                // Extract host and port from URL
-                let host = req.uri().host().ok_or_else(|| anyhow!("Missing hostname"))?;
-                let port = req.uri().port_u16().unwrap_or(443);
-
-                // TCP connect
-                let stream = TokioTcpStream::connect((host, port))
-                        .await
-                        .context("TCP connection to signalling server failed")?;
+pub struct SignallingClient {
+        ws_stream: WebSocketStream<TlsStream>,
+        local_fingerprint: Option<String>,
+        /// Last published prekey bundle, republished on every registration
+        bundle: Option<Vec<u8>>,
+        keepalive_interval: Duration,
+        keepalive_jitter: f64,
+        /// Local end of the TCP connection to the server
+        local_addr: SocketAddr,
+        /// Offers that arrived while waiting for a different peer's, by sender
+        pending_offers: HashMap<String, PeerInfo>,
+        /// Keep offers from other peers instead of failing on them
+        keep_other_offers: bool,
+        /// Encoding agreed with the server
+        codec: SignallingCodec,
+}
 
-                // Perform TLS + WebSocket handshake
-                let (ws_stream, _resp) = client_async_tls_with_config(
-                        req,
-                        stream,
-                        None,
-                        Some(tls.into())
-                )
-                        .await
-                        .context("TLS WebSocket handshake failed")?;
 
-                Ok(Self {
-                        ws_stream,
-                        local_fingerprint: None,
-                })
-        }
-        */
+impl SignallingClient {
 
+    /// Connect to the signalling server with the default options
     pub async fn connect(url: &str) -> Result<Self> {
-        let req = url.into_client_request()
+        Self::connect_with_options(url, &SignallingConnectOptions::default()).await
+    }
+
+    /// Connect to the signalling server (see `SignallingConnectOptions`)
+    ///
+    /// MessagePack is only used if the server accepts it during the
+    /// WebSocket upgrade; otherwise the connection falls back to JSON.
+    /// `codec()` tells which one was agreed.
+    pub async fn connect_with_options(url: &str, options: &SignallingConnectOptions) -> Result<Self> {
+        let mut req = url.into_client_request()
                 .context("Invalid signalling URL")?;
-
-        // Allow self-signed certs in DEV
-        let mut tls_builder = TlsConnector::builder();
-        tls_builder.danger_accept_invalid_certs(true);
-        let tls = tls_builder.build().unwrap();
-        let tls = tokio_native_tls::TlsConnector::from(tls);
+        if options.codec == SignallingCodec::MessagePack {
+                req.headers_mut().insert("Sec-WebSocket-Protocol", MSGPACK_SUBPROTOCOL.parse()?);
+        }
 
         // Parse host + port from URL
         let host = req.uri().host().ok_or_else(|| anyhow!("Missing hostname"))?;
         let port = req.uri().port_u16().unwrap_or(443);
 
         // STEP 1: Raw TCP connect
-        let tcp = TokioTcpStream::connect((host, port))
-                .await
-                .context("TCP connection failed")?;
+        let tcp = if options.addrs.is_empty() {
+                TokioTcpStream::connect((host, port)).await
+        } else {
+                TokioTcpStream::connect(options.addrs.as_slice()).await
+        }
+        .context("TCP connection failed")?;
+        let local_addr = tcp.local_addr().context("TCP connection failed")?;
 
         // STEP 2: TLS handshake over TCP
-        let tls_stream = tls.connect(host, tcp)
+        let tls_stream = tls_connect(options.sni_hostname.as_deref().unwrap_or(host), tcp, options.accept_invalid_certs)
                 .await
                 .context("TLS handshake failed")?;
 
-        // STEP 3: WebSocket upgrade over TLS
-        let (ws_stream, _resp) =
-                tokio_tungstenite::client_async_tls_with_config(
+        // STEP 3: WebSocket upgrade over TLS (already encrypted, so no second TLS layer)
+        let (ws_stream, resp) =
+                tokio_tungstenite::client_async_with_config(
                         req,
                         tls_stream,
-                        None,
                         None
                 )
                 .await
                 .context("WebSocket upgrade failed")?;
 
+        let accepted = resp
+                .headers()
+                .get("Sec-WebSocket-Protocol")
+                .is_some_and(|protocol| protocol == MSGPACK_SUBPROTOCOL);
+        let codec = if accepted { SignallingCodec::MessagePack } else { SignallingCodec::Json };
+
         Ok(Self {
                 ws_stream,
                 local_fingerprint: None,
+                bundle: None,
+                keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+                keepalive_jitter: network::DEFAULT_KEEPALIVE_JITTER,
+                local_addr,
+                pending_offers: HashMap::new(),
+                keep_other_offers: false,
+                codec,
         })
 }
 
+        /// Encoding agreed with the server at connect time
+        pub fn codec(&self) -> SignallingCodec {
+                self.codec
+        }
+
+        /// Local address of the connection to the server
+        /// Usable as a host candidate when STUN is unavailable
+        pub fn local_addr(&self) -> SocketAddr {
+                self.local_addr
+        }
+
+        /// Keep offers from peers other than the one being dialled for their
+        /// own `send_offer`, for a client shared by attempts to several peers
+        pub fn keep_other_offers(&mut self, keep: bool) {
+                self.keep_other_offers = keep;
+        }
+
+        /// Set the keepalive interval and the fraction it is randomly varied by
+        ///
+        /// Each wait is drawn from `interval * (1 ± jitter)` (see
+        /// `network::jittered`), so clients do not refresh in lockstep.
+        pub fn set_keepalive(&mut self, interval: Duration, jitter: f64) {
+                self.keepalive_interval = interval;
+                self.keepalive_jitter = jitter;
+        }
+
 
         /// Register with the signalling server
         pub async fn register(&mut self, fingerprint: &str) -> Result<()> {
@@ -188,6 +366,9 @@ impl SignallingClient {
                         SignallingMessage::RegisterAck { success, message } => {
                                 if success {
                                         self.local_fingerprint = Some(fingerprint.to_string());
+                                        if let Some(bundle) = self.bundle.clone() {
+                                                self.publish_bundle(bundle).await?;
+                                        }
                                         Ok(())
                                 } else {
                                         Err(anyhow!("Registration failed: {}", message))
@@ -198,54 +379,103 @@ impl SignallingClient {
         }
 
         /// Send offer and wait for peer offer
-        pub async fn send_offer(
-                &mut self,
-                target_fingerprint: &str,
-                external_addr: SocketAddr,
-                local_addr: SocketAddr,
-        ) -> Result<PeerInfo> {
+        ///
+        /// The peer only punches address families both sides advertised, and
+        /// skips punching altogether if either side reports `udp_blocked`.
+        ///
+        /// Only an offer from `target_fingerprint` is returned. An offer from
+        /// any other peer fails with `SignallingError::UnexpectedPeer`, since
+        /// the server should never forward one, unless `keep_other_offers` is
+        /// set: then it is kept (the latest per peer) for a later `send_offer`.
+        pub async fn send_offer(&mut self, target_fingerprint: &str, offer: &LocalOffer) -> Result<PeerInfo> {
+                self.post_offer(target_fingerprint, offer).await?;
+                self.wait_for_offer(target_fingerprint).await
+        }
 
+        /// Send an offer without waiting for the peer's
+        pub async fn post_offer(&mut self, target_fingerprint: &str, offer: &LocalOffer) -> Result<()> {
                 let nonce = rand::random::<u64>();
 
                 let msg = SignallingMessage::Offer {
                         target_fingerprint: target_fingerprint.to_string(),
-                        external_ip: external_addr.ip().to_string(),
-                        external_port: external_addr.port(),
-                        local_ip: local_addr.ip().to_string(),
-                        local_port: local_addr.port(),
+                        external_ip: offer.external_addr.ip().to_string(),
+                        external_port: offer.external_addr.port(),
+                        local_ip: offer.local_addr.ip().to_string(),
+                        local_port: offer.local_addr.port(),
                         nonce,
                         fingerprint: self.local_fingerprint
                                 .as_ref()
                                 .ok_or_else(|| anyhow!("Not registered"))?
                                 .clone(),
+                        extra_addrs: offer.extra_addrs.iter().map(SocketAddr::to_string).collect(),
+                        attempt_id: offer.attempt_id.clone(),
+                        tcp_port: Some(offer.tcp_port),
+                        udp_blocked: offer.udp_blocked,
+                        verifying_key: hex::encode(offer.verifying_key.as_bytes()),
+                        relay_addr: offer.relay_addr.map(|addr| addr.to_string()),
+                        nat_behavior: (offer.nat_behavior != NatBehavior::Unknown)
+                                .then(|| offer.nat_behavior.as_str().to_string()),
                 };
 
+                self.send_message(&msg).await
+        }
+
+        /// Wait for `target_fingerprint`'s next offer (see `send_offer`)
+        pub async fn wait_for_offer(&mut self, target_fingerprint: &str) -> Result<PeerInfo> {
+                if let Some(peer_info) = self.pending_offers.remove(target_fingerprint) {
+                        return Ok(peer_info);
+                }
+
+                loop {
+                        let response = self.receive_with_keepalive().await?;
+                        if let SignallingMessage::Error { message } = &response {
+                                return Err(anyhow!("Signalling error: {}", message));
+                        }
+                        let Some(peer_info) = forwarded_offer(response)? else {
+                                continue;
+                        };
+                        if peer_info.fingerprint == target_fingerprint {
+                                return Ok(peer_info);
+                        }
+                        if !self.keep_other_offers {
+                                return Err(SignallingError::UnexpectedPeer {
+                                        expected: target_fingerprint.to_string(),
+                                        found: peer_info.fingerprint,
+                                }
+                                .into());
+                        }
+                        self.pending_offers.insert(peer_info.fingerprint.clone(), peer_info);
+                }
+        }
+
+        /// Publish our prekey bundle (from `serialize_prekey_bundle`) to the server
+        ///
+        /// The bundle is kept and republished after every `register`; call
+        /// this again whenever the prekeys change.
+        pub async fn publish_bundle(&mut self, bundle: Vec<u8>) -> Result<()> {
+                let fingerprint = self.local_fingerprint
+                        .clone()
+                        .ok_or_else(|| anyhow!("Not registered"))?;
+                let msg = SignallingMessage::PublishBundle {
+                        fingerprint,
+                        bundle: hex::encode(&bundle),
+                };
+                self.bundle = Some(bundle);
+                self.send_message(&msg).await
+        }
+
+        /// Fetch a peer's published prekey bundle, e.g. to start a session while it is offline
+        pub async fn fetch_bundle(&mut self, target_fingerprint: &str) -> Result<Vec<u8>> {
+                let msg = SignallingMessage::FetchBundle {
+                        target_fingerprint: target_fingerprint.to_string(),
+                };
                 self.send_message(&msg).await?;
 
                 loop {
-                        let response = self.receive_message().await?;
-                        match response {
-                                SignallingMessage::ForwardOffer {
-                                        from_fingerprint,
-                                        external_ip,
-                                        external_port,
-                                        local_ip,
-                                        local_port,
-                                        nonce: peer_nonce,
-                                } => {
-                                        let external = format!("{}:{}", external_ip, external_port)
-                                                .parse()
-                                                .context("Invalid external addr")?;
-                                        let local = format!("{}:{}", local_ip, local_port)
-                                                .parse()
-                                                .context("Invalid local addr")?;
-
-                                        return Ok(PeerInfo {
-                                                fingerprint: from_fingerprint,
-                                                external_addr: external,
-                                                local_addr: local,
-                                                nonce: peer_nonce,
-                                        });
+                        match self.receive_with_keepalive().await? {
+                                SignallingMessage::BundleResponse { bundle } => {
+                                        return hex::decode(bundle)
+                                                .map_err(|e| SignallingError::InvalidMessage(format!("bundle is not hex: {}", e)).into());
                                 }
                                 SignallingMessage::Error { message } => {
                                         return Err(anyhow!("Signalling error: {}", message));
@@ -255,18 +485,62 @@ impl SignallingClient {
                 }
         }
 
+        /// Whether a peer is registered with the server right now, without dialling it
+        ///
+        /// Offers that arrive while waiting are kept for a later `send_offer`.
+        /// Fails if the server does not answer within `PRESENCE_TIMEOUT`,
+        /// e.g. because it predates presence queries.
+        pub async fn is_peer_online(&mut self, fingerprint: &str) -> Result<bool> {
+                let msg = SignallingMessage::PresenceQuery {
+                        target_fingerprint: fingerprint.to_string(),
+                };
+                self.send_message(&msg).await?;
+
+                let answer = async {
+                        loop {
+                                match self.receive_message().await? {
+                                        SignallingMessage::Presence { fingerprint: peer, online } if peer == fingerprint => {
+                                                return Ok(online);
+                                        }
+                                        SignallingMessage::Error { message } => {
+                                                return Err(anyhow!("Signalling error: {}", message));
+                                        }
+                                        other => {
+                                                if let Some(peer_info) = forwarded_offer(other)? {
+                                                        self.pending_offers.insert(peer_info.fingerprint.clone(), peer_info);
+                                                }
+                                        }
+                                }
+                        }
+                };
+                tokio::time::timeout(PRESENCE_TIMEOUT, answer)
+                        .await
+                        .map_err(|_| SignallingError::ReceiveFailed(format!("no presence answer for {} within {:?}", fingerprint, PRESENCE_TIMEOUT)))?
+        }
+
         async fn send_message(&mut self, msg: &SignallingMessage) -> Result<()> {
-                let json = serde_json::to_string(msg)
-                        .context("Message serialization failed")?;
+                let frame = self.codec.encode(msg)?;
 
                 self.ws_stream
-                        .send(Message::Text(json))
+                        .send(frame)
                         .await
                         .context("WebSocket send failed")?;
 
                 Ok(())
         }
 
+        /// Receive the next message, sending a keepalive each time the server
+        /// stays quiet for a jittered keepalive interval
+        async fn receive_with_keepalive(&mut self) -> Result<SignallingMessage> {
+                loop {
+                        let wait = network::jittered(self.keepalive_interval, self.keepalive_jitter);
+                        match tokio::time::timeout(wait, self.receive_message()).await {
+                                Ok(result) => return result,
+                                Err(_) => self.send_message(&SignallingMessage::Keepalive).await?,
+                        }
+                }
+        }
+
         async fn receive_message(&mut self) -> Result<SignallingMessage> {
                 loop {
                         let msg = self.ws_stream
@@ -275,10 +549,10 @@ impl SignallingClient {
                                 .ok_or_else(|| anyhow!("Connection closed"))??;
 
                         match msg {
-                                Message::Text(text) => {
-                                        let parsed = serde_json::from_str(&text)
-                                                .context("Failed to decode signalling message")?;
-                                        return Ok(parsed);
+                                Message::Text(_) | Message::Binary(_) => {
+                                        if let Some(parsed) = SignallingCodec::decode(&msg)? {
+                                                return Ok(parsed);
+                                        }
                                 }
                                 Message::Ping(data) => {
                                         self.ws_stream.send(Message::Pong(data)).await?;
@@ -301,3 +575,348 @@ impl SignallingClient {
         }
 }
 
+/// The offer a `ForwardOffer` carries, or `None` for any other message
+fn forwarded_offer(msg: SignallingMessage) -> Result<Option<PeerInfo>> {
+        let SignallingMessage::ForwardOffer {
+                from_fingerprint,
+                external_ip,
+                external_port,
+                local_ip,
+                local_port,
+                nonce,
+                extra_addrs,
+                attempt_id,
+                tcp_port,
+                udp_blocked,
+                verifying_key,
+                relay_addr,
+                nat_behavior,
+        } = msg
+        else {
+                return Ok(None);
+        };
+
+        let external = format!("{}:{}", external_ip, external_port)
+                .parse()
+                .context("Invalid external addr")?;
+        let local = format!("{}:{}", local_ip, local_port)
+                .parse()
+                .context("Invalid local addr")?;
+
+        let extra_addrs = extra_addrs
+                .iter()
+                .map(|addr| addr.parse())
+                .collect::<Result<_, _>>()
+                .context("Invalid extra addr")?;
+
+        let relay_addr = relay_addr
+                .map(|addr| addr.parse())
+                .transpose()
+                .context("Invalid relay addr")?;
+
+        Ok(Some(PeerInfo {
+                fingerprint: from_fingerprint,
+                external_addr: external,
+                local_addr: local,
+                nonce,
+                extra_addrs,
+                attempt_id,
+                tcp_port,
+                udp_blocked,
+                verifying_key: Some(verifying_key).filter(|key| !key.is_empty()),
+                relay_addr,
+                nat_behavior: nat_behavior.as_deref().map(NatBehavior::from_name).unwrap_or_default(),
+        }))
+}
+
+/// TLS handshake with native-tls against the platform's trust store
+/// `host` is the SNI name; IP literals send no SNI
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+async fn tls_connect(host: &str, tcp: TokioTcpStream, accept_invalid_certs: bool) -> Result<TlsStream> {
+        let mut tls_builder = TlsConnector::builder();
+        tls_builder.danger_accept_invalid_certs(accept_invalid_certs);
+        let tls = tls_builder.build().context("Failed to build TLS connector")?;
+        let tls = tokio_native_tls::TlsConnector::from(tls);
+        tls.connect(host, tcp).await.map_err(|e| {
+                // native-tls has no typed verification error; every backend names the certificate
+                let reason = e.to_string();
+                if !accept_invalid_certs && reason.to_lowercase().contains("certificate") {
+                        SignallingError::CertificateRejected { host: host.to_string(), reason }.into()
+                } else {
+                        anyhow::Error::new(e)
+                }
+        })
+}
+
+/// TLS handshake with rustls against the bundled webpki roots
+/// `host` is the SNI name; IP literals send no SNI
+#[cfg(feature = "rustls")]
+async fn tls_connect(host: &str, tcp: TokioTcpStream, accept_invalid_certs: bool) -> Result<TlsStream> {
+        use std::sync::Arc;
+        use tokio_rustls::rustls::pki_types::{Der, ServerName, TrustAnchor};
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+        let config = if accept_invalid_certs {
+                ClientConfig::builder()
+                        .dangerous()
+                        .with_custom_certificate_verifier(Arc::new(dev_tls::AcceptAnyCert::new()))
+                        .with_no_client_auth()
+        } else {
+                let mut roots = RootCertStore::empty();
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().map(|root| TrustAnchor {
+                        subject: Der::from_slice(root.subject),
+                        subject_public_key_info: Der::from_slice(root.spki),
+                        name_constraints: root.name_constraints.map(Der::from_slice),
+                }));
+                ClientConfig::builder().with_root_certificates(roots).with_no_client_auth()
+        };
+        let server_name = ServerName::try_from(host.to_string())
+                .map_err(|_| anyhow!("Invalid TLS server name: {}", host))?;
+        let tls = tokio_rustls::TlsConnector::from(Arc::new(config));
+        tls.connect(server_name, tcp).await.map_err(|e| {
+                match e.get_ref().and_then(|inner| inner.downcast_ref::<tokio_rustls::rustls::Error>()) {
+                        Some(tokio_rustls::rustls::Error::InvalidCertificate(reason)) => SignallingError::CertificateRejected {
+                                host: host.to_string(),
+                                reason: format!("{:?}", reason),
+                        }
+                        .into(),
+                        _ => anyhow::Error::new(e),
+                }
+        })
+}
+
+/// rustls equivalent of native-tls `danger_accept_invalid_certs`
+#[cfg(feature = "rustls")]
+mod dev_tls {
+        use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+        use tokio_rustls::rustls::crypto::{self, WebPkiSupportedAlgorithms};
+        use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+        use tokio_rustls::rustls::{DigitallySignedStruct, Error, SignatureScheme};
+
+        /// Accepts any certificate chain, but still checks the handshake
+        /// signatures so the peer must hold the certificate's private key
+        #[derive(Debug)]
+        pub struct AcceptAnyCert {
+                algorithms: WebPkiSupportedAlgorithms,
+        }
+
+        impl AcceptAnyCert {
+                pub fn new() -> Self {
+                        Self {
+                                algorithms: crypto::ring::default_provider().signature_verification_algorithms,
+                        }
+                }
+        }
+
+        impl ServerCertVerifier for AcceptAnyCert {
+                fn verify_server_cert(
+                        &self,
+                        _end_entity: &CertificateDer<'_>,
+                        _intermediates: &[CertificateDer<'_>],
+                        _server_name: &ServerName<'_>,
+                        _ocsp_response: &[u8],
+                        _now: UnixTime,
+                ) -> Result<ServerCertVerified, Error> {
+                        Ok(ServerCertVerified::assertion())
+                }
+
+                fn verify_tls12_signature(
+                        &self,
+                        message: &[u8],
+                        cert: &CertificateDer<'_>,
+                        dss: &DigitallySignedStruct,
+                ) -> Result<HandshakeSignatureValid, Error> {
+                        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+                }
+
+                fn verify_tls13_signature(
+                        &self,
+                        message: &[u8],
+                        cert: &CertificateDer<'_>,
+                        dss: &DigitallySignedStruct,
+                ) -> Result<HandshakeSignatureValid, Error> {
+                        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+                }
+
+                fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+                        self.algorithms.supported_schemes()
+                }
+        }
+}
+
+#[cfg(test)]
+mod tests {
+        use super::*;
+
+        fn forward_offer(verifying_key: String) -> SignallingMessage {
+                SignallingMessage::ForwardOffer {
+                        from_fingerprint: "alice".to_string(),
+                        external_ip: "203.0.113.5".to_string(),
+                        external_port: 4000,
+                        local_ip: "192.168.1.5".to_string(),
+                        local_port: 5000,
+                        nonce: 7,
+                        extra_addrs: vec!["[2001:db8::5]:4000".to_string()],
+                        attempt_id: "attempt".to_string(),
+                        tcp_port: Some(6000),
+                        udp_blocked: true,
+                        verifying_key,
+                        relay_addr: Some("198.51.100.1:3478".to_string()),
+                        nat_behavior: Some("symmetric".to_string()),
+                }
+        }
+
+        /// One of each variant, optional fields both set and left out
+        fn every_message() -> Vec<SignallingMessage> {
+                vec![
+                        SignallingMessage::Register { fingerprint: "alice".to_string() },
+                        SignallingMessage::RegisterAck { success: true, message: "ok".to_string() },
+                        SignallingMessage::Offer {
+                                target_fingerprint: "bob".to_string(),
+                                external_ip: "203.0.113.5".to_string(),
+                                external_port: 4000,
+                                local_ip: "192.168.1.5".to_string(),
+                                local_port: 5000,
+                                nonce: 7,
+                                fingerprint: "alice".to_string(),
+                                extra_addrs: vec!["[2001:db8::5]:4000".to_string()],
+                                attempt_id: "attempt".to_string(),
+                                tcp_port: Some(6000),
+                                udp_blocked: true,
+                                verifying_key: "00".repeat(32),
+                                relay_addr: Some("198.51.100.1:3478".to_string()),
+                                nat_behavior: Some("full_cone".to_string()),
+                        },
+                        SignallingMessage::Offer {
+                                target_fingerprint: "bob".to_string(),
+                                external_ip: "203.0.113.5".to_string(),
+                                external_port: 4000,
+                                local_ip: "192.168.1.5".to_string(),
+                                local_port: 5000,
+                                nonce: 8,
+                                fingerprint: "alice".to_string(),
+                                extra_addrs: Vec::new(),
+                                attempt_id: String::new(),
+                                tcp_port: None,
+                                udp_blocked: false,
+                                verifying_key: String::new(),
+                                relay_addr: None,
+                                nat_behavior: None,
+                        },
+                        forward_offer("11".repeat(32)),
+                        SignallingMessage::OfferResponse { success: false, message: None },
+                        SignallingMessage::OfferResponse { success: true, message: Some("sent".to_string()) },
+                        SignallingMessage::PublishBundle { fingerprint: "alice".to_string(), bundle: "abcd".to_string() },
+                        SignallingMessage::FetchBundle { target_fingerprint: "bob".to_string() },
+                        SignallingMessage::BundleResponse { bundle: "abcd".to_string() },
+                        SignallingMessage::PresenceQuery { target_fingerprint: "bob".to_string() },
+                        SignallingMessage::Presence { fingerprint: "bob".to_string(), online: true },
+                        SignallingMessage::Keepalive,
+                        SignallingMessage::Error { message: "unknown peer".to_string() },
+                ]
+        }
+
+        #[test]
+        fn every_message_round_trips_through_both_codecs() {
+                for codec in [SignallingCodec::Json, SignallingCodec::MessagePack] {
+                        for msg in every_message() {
+                                let frame = codec.encode(&msg).unwrap();
+                                match codec {
+                                        SignallingCodec::Json => assert!(matches!(frame, Message::Text(_))),
+                                        SignallingCodec::MessagePack => assert!(matches!(frame, Message::Binary(_))),
+                                }
+                                let decoded = SignallingCodec::decode(&frame).unwrap().unwrap();
+                                assert_eq!(
+                                        serde_json::to_value(&decoded).unwrap(),
+                                        serde_json::to_value(&msg).unwrap(),
+                                        "{:?} through {:?}",
+                                        msg,
+                                        codec
+                                );
+                        }
+                }
+                assert!(SignallingCodec::decode(&Message::Ping(Vec::new())).unwrap().is_none());
+        }
+
+        #[test]
+        fn verifying_key_survives_the_offer_round_trip() {
+                use ed25519_dalek::SigningKey;
+
+                let key = SigningKey::generate(&mut rand::rngs::OsRng).verifying_key();
+                for codec in [SignallingCodec::Json, SignallingCodec::MessagePack] {
+                        let frame = codec.encode(&forward_offer(hex::encode(key.as_bytes()))).unwrap();
+                        let decoded = SignallingCodec::decode(&frame).unwrap().unwrap();
+                        let peer_info = forwarded_offer(decoded).unwrap().unwrap();
+                        assert_eq!(peer_info.decode_verifying_key().unwrap(), key);
+
+                        // PeerInfo itself is serde too, e.g. in checkpoints
+                        let json = serde_json::to_string(&peer_info).unwrap();
+                        let restored: PeerInfo = serde_json::from_str(&json).unwrap();
+                        assert_eq!(restored.decode_verifying_key().unwrap(), key);
+                }
+
+                let missing = forwarded_offer(forward_offer(String::new())).unwrap().unwrap();
+                assert!(missing.decode_verifying_key().is_err());
+                let truncated = forwarded_offer(forward_offer("11".repeat(31))).unwrap().unwrap();
+                assert!(truncated.decode_verifying_key().is_err());
+        }
+
+        async fn registered(url: &str, fingerprint: &str) -> SignallingClient {
+                // The harness certificate is self-signed
+                let options = SignallingConnectOptions { accept_invalid_certs: true, ..Default::default() };
+                let mut client = SignallingClient::connect_with_options(url, &options).await.unwrap();
+                client.register(fingerprint).await.unwrap();
+                client
+        }
+
+        #[tokio::test]
+        async fn presence_follows_registration() {
+                let server = crate::nat_traversal::test_harness::SignallingForwarder::start().await.unwrap();
+                let mut alice = registered(&server.url(), "alice").await;
+                assert!(!alice.is_peer_online("bob").await.unwrap());
+
+                let bob = registered(&server.url(), "bob").await;
+                assert!(alice.is_peer_online("bob").await.unwrap());
+
+                bob.close().await.unwrap();
+                // The server notices the close asynchronously
+                let mut online = true;
+                for _ in 0..50 {
+                        online = alice.is_peer_online("bob").await.unwrap();
+                        if !online {
+                                break;
+                        }
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                assert!(!online);
+        }
+
+        #[tokio::test]
+        async fn keepalives_while_waiting_do_not_disturb_the_offer() {
+                let server = crate::nat_traversal::test_harness::SignallingForwarder::start().await.unwrap();
+                let mut alice = registered(&server.url(), "alice").await;
+                let mut bob = registered(&server.url(), "bob").await;
+                alice.set_keepalive(Duration::from_millis(20), network::MAX_KEEPALIVE_JITTER);
+
+                let offer = LocalOffer {
+                        external_addr: "203.0.113.5:4000".parse().unwrap(),
+                        local_addr: "192.168.1.5:5000".parse().unwrap(),
+                        extra_addrs: Vec::new(),
+                        attempt_id: "attempt".to_string(),
+                        tcp_port: 6000,
+                        udp_blocked: false,
+                        verifying_key: ed25519_dalek::SigningKey::from_bytes(&[7; 32]).verifying_key(),
+                        relay_addr: None,
+                        nat_behavior: NatBehavior::Unknown,
+                };
+                // Several keepalive intervals pass before the offer arrives
+                let late_offer = async {
+                        tokio::time::sleep(Duration::from_millis(300)).await;
+                        bob.post_offer("alice", &offer).await.unwrap();
+                };
+                let (peer_info, ()) = tokio::join!(alice.wait_for_offer("bob"), late_offer);
+                let peer_info = peer_info.unwrap();
+                assert_eq!(peer_info.fingerprint, "bob");
+                assert_eq!(peer_info.tcp_port, Some(6000));
+        }
+}
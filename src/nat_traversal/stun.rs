@@ -11,70 +11,240 @@ use std::time::Duration;
 /// STUN message types
 const STUN_BINDING_REQUEST: u16 = 0x0001;
 const STUN_BINDING_RESPONSE: u16 = 0x0101;
+const STUN_BINDING_ERROR_RESPONSE: u16 = 0x0111;
 
 /// STUN magic cookie
 const STUN_MAGIC_COOKIE: u32 = 0x2112A442;
 
+/// How often `NatTraversal` refreshes the STUN mapping while waiting for the
+/// peer's offer; NATs commonly expire idle UDP mappings after 30 seconds
+pub const DEFAULT_STUN_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// How long `query` waits for the response
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// STUN attribute types
 const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+const ATTR_CHANGED_ADDRESS: u16 = 0x0005;
 const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_OTHER_ADDRESS: u16 = 0x802C;
 
 /// STUN query response
 #[derive(Debug, Clone)]
 pub struct StunResponse {
     pub external_ip: IpAddr,
     pub external_port: u16,
+    /// Attribute the mapping was read from
+    pub attribute: AddressAttribute,
+    /// The server's alternate address (RFC 5780 OTHER-ADDRESS, or RFC 3489
+    /// CHANGED-ADDRESS), present on servers that support CHANGE-REQUEST
+    pub other_addr: Option<SocketAddr>,
+}
+
+/// Ask the server to answer from its alternate address (RFC 5780 CHANGE-REQUEST)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ChangeRequest {
+    None,
+    Port,
+    IpAndPort,
+}
+
+/// Binding response attributes that carry the mapped address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressAttribute {
+    /// XOR-MAPPED-ADDRESS, immune to NATs that rewrite addresses in payloads
+    XorMapped,
+    /// MAPPED-ADDRESS, the RFC 3489 attribute some older servers only send
+    Mapped,
 }
 
+/// Which address attribute of a binding response to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttributePreference {
+    /// XOR-MAPPED-ADDRESS if present, otherwise MAPPED-ADDRESS
+    /// When both are present and disagree, XOR wins and the mismatch is logged
+    #[default]
+    PreferXor,
+    /// Only ever use this attribute, for servers known to get the other one wrong
+    Only(AddressAttribute),
+}
+
+/// STUN errors that callers may want to match on
+#[derive(Debug)]
+pub enum StunError {
+    /// Binding error response from the server (RFC 5389 ERROR-CODE)
+    ErrorResponse { code: u16, reason: String },
+    /// Mapped address is unusable as a peer endpoint (unspecified, loopback or port 0)
+    InvalidMapping { addr: SocketAddr },
+    /// Nothing came back before the read timeout; the path may block UDP
+    NoResponse { server: SocketAddr },
+}
+
+impl std::fmt::Display for StunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StunError::ErrorResponse { code, reason } => {
+                write!(f, "STUN server error {} {}", code, reason)
+            }
+            StunError::InvalidMapping { addr } => {
+                write!(f, "STUN server returned an unusable mapped address: {}", addr)
+            }
+            StunError::NoResponse { server } => {
+                write!(f, "No STUN response from {}", server)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StunError {}
+
 /// STUN client
 pub struct StunClient {
     socket: UdpSocket,
     server_addr: SocketAddr,
+    preference: AttributePreference,
 }
 
 impl StunClient {
-    /// Create a new STUN client
+    /// Create a new STUN client on a socket of the server's address family
     pub fn new(server_addr: &SocketAddr) -> Result<Self> {
-        let socket = UdpSocket::bind("0.0.0.0:0")
+        let bind_addr = if server_addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+        let socket = UdpSocket::bind(bind_addr)
             .context("Failed to bind UDP socket")?;
         
-        socket.set_read_timeout(Some(Duration::from_secs(5)))
+        socket.set_read_timeout(Some(READ_TIMEOUT))
             .context("Failed to set read timeout")?;
 
         Ok(Self {
             socket,
             server_addr: *server_addr,
+            preference: AttributePreference::default(),
         })
     }
 
+    /// Choose which address attribute to read the mapping from
+    pub fn with_preference(mut self, preference: AttributePreference) -> Self {
+        self.preference = preference;
+        self
+    }
+
     /// Query STUN server for external address
+    ///
+    /// The socket is blocking, so the exchange runs on tokio's blocking pool
+    /// and queries to several servers can overlap.
     pub async fn query(&self) -> Result<StunResponse> {
         let transaction_id: [u8; 12] = rand::random();
-        let request = self.build_binding_request(&transaction_id);
+        let request = self.build_binding_request(&transaction_id, ChangeRequest::None);
+        let socket = self.socket.try_clone().context("Failed to clone STUN socket")?;
+        let server_addr = self.server_addr;
+
+        let buffer = tokio::task::spawn_blocking(move || {
+            // Send STUN binding request
+            socket
+                .send_to(&request, server_addr)
+                .context("Failed to send STUN request")?;
+
+            // Receive response
+            let mut buffer = vec![0u8; 1024];
+            match socket.recv_from(&mut buffer) {
+                Ok((len, _)) => {
+                    buffer.truncate(len);
+                    Ok(buffer)
+                }
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    Err(StunError::NoResponse { server: server_addr }.into())
+                }
+                Err(e) => Err(anyhow::Error::new(e).context("Failed to receive STUN response")),
+            }
+        })
+        .await
+        .context("STUN query task failed")??;
+
+        let response = self.parse_binding_response(&buffer, &transaction_id)?;
+        Self::check_mapping(&response)?;
+        Ok(response)
+    }
 
-        // Send STUN binding request
+    /// Binding request to any server, for NAT classification
+    ///
+    /// Returns the response with the address it came from, or None if nothing
+    /// answered within `timeout`. Unlike `query`, stray packets and responses
+    /// to earlier requests are skipped rather than failing the request.
+    pub(super) fn probe(
+        &self,
+        server_addr: SocketAddr,
+        change: ChangeRequest,
+        timeout: Duration,
+    ) -> Result<Option<(StunResponse, SocketAddr)>> {
+        let transaction_id: [u8; 12] = rand::random();
         self.socket
-            .send_to(&request, self.server_addr)
+            .send_to(&self.build_binding_request(&transaction_id, change), server_addr)
             .context("Failed to send STUN request")?;
 
-        // Receive response
+        let deadline = std::time::Instant::now() + timeout;
         let mut buffer = vec![0u8; 1024];
-        let (len, _) = self.socket
-            .recv_from(&mut buffer)
-            .context("Failed to receive STUN response")?;
+        let result = loop {
+            let Some(remaining) = deadline.checked_duration_since(std::time::Instant::now()).filter(|d| !d.is_zero()) else {
+                break Ok(None);
+            };
+            self.socket.set_read_timeout(Some(remaining))?;
+            let (len, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    break Ok(None);
+                }
+                Err(e) => break Err(anyhow::Error::new(e).context("Failed to receive STUN response")),
+            };
+            if len < 20 || buffer[8..20] != transaction_id {
+                continue;
+            }
+            break self
+                .parse_binding_response(&buffer[..len], &transaction_id)
+                .and_then(|response| Self::check_mapping(&response).map(|_| Some((response, from))));
+        };
+        self.socket.set_read_timeout(Some(READ_TIMEOUT))?;
+        result
+    }
 
-        self.parse_binding_response(&buffer[..len], &transaction_id)
+    /// Send a binding request without waiting for the response
+    ///
+    /// Traffic to the server keeps the NAT mapping `query` found from expiring
+    /// while the socket is otherwise idle. The response is left unread; the
+    /// hole puncher discards it as it does any non-probe packet.
+    pub fn keepalive(&self) -> Result<()> {
+        let transaction_id: [u8; 12] = rand::random();
+        self.socket
+            .send_to(&self.build_binding_request(&transaction_id, ChangeRequest::None), self.server_addr)
+            .context("Failed to send STUN keepalive")?;
+        Ok(())
+    }
+
+    /// Reject mappings that could never be reached by the peer
+    /// (the test harness maps to loopback, so that is let through there and in tests)
+    fn check_mapping(response: &StunResponse) -> Result<()> {
+        let ip = response.external_ip;
+        let loopback = ip.is_loopback() && !cfg!(any(test, feature = "test-harness"));
+        if ip.is_unspecified() || loopback || response.external_port == 0 {
+            return Err(StunError::InvalidMapping {
+                addr: SocketAddr::new(ip, response.external_port),
+            }
+            .into());
+        }
+        Ok(())
     }
 
     /// Build a STUN binding request
-    fn build_binding_request(&self, transaction_id: &[u8; 12]) -> Vec<u8> {
+    fn build_binding_request(&self, transaction_id: &[u8; 12], change: ChangeRequest) -> Vec<u8> {
         let mut request = Vec::new();
 
         // Message type (16 bits)
         request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
 
-        // Message length (16 bits) - 0 for now, no attributes
-        request.extend_from_slice(&0u16.to_be_bytes());
+        // Message length (16 bits) - only CHANGE-REQUEST is ever added
+        let msg_len: u16 = if change == ChangeRequest::None { 0 } else { 8 };
+        request.extend_from_slice(&msg_len.to_be_bytes());
 
         // Magic cookie (32 bits)
         request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
@@ -82,6 +252,16 @@ impl StunClient {
         // Transaction ID (96 bits)
         request.extend_from_slice(transaction_id);
 
+        // CHANGE-REQUEST flags: 0x04 change IP, 0x02 change port
+        let flags: u32 = match change {
+            ChangeRequest::None => return request,
+            ChangeRequest::Port => 0x02,
+            ChangeRequest::IpAndPort => 0x06,
+        };
+        request.extend_from_slice(&ATTR_CHANGE_REQUEST.to_be_bytes());
+        request.extend_from_slice(&4u16.to_be_bytes());
+        request.extend_from_slice(&flags.to_be_bytes());
+
         request
     }
 
@@ -93,7 +273,7 @@ impl StunClient {
 
         // Check message type
         let msg_type = u16::from_be_bytes([data[0], data[1]]);
-        if msg_type != STUN_BINDING_RESPONSE {
+        if msg_type != STUN_BINDING_RESPONSE && msg_type != STUN_BINDING_ERROR_RESPONSE {
             return Err(anyhow!("Invalid STUN response type: 0x{:04x}", msg_type));
         }
 
@@ -114,7 +294,89 @@ impl StunClient {
             return Err(anyhow!("STUN response truncated"));
         }
 
-        // Parse attributes
+        let attributes = Self::attributes(data, msg_len);
+
+        if msg_type == STUN_BINDING_ERROR_RESPONSE {
+            let error = attributes
+                .iter()
+                .find(|(attr_type, _)| *attr_type == ATTR_ERROR_CODE)
+                .map(|(_, attr_data)| Self::parse_error_code(attr_data))
+                .unwrap_or_else(|| StunError::ErrorResponse {
+                    code: 0,
+                    reason: "error response without ERROR-CODE".to_string(),
+                });
+            return Err(error.into());
+        }
+
+        let find = |wanted: u16| {
+            attributes
+                .iter()
+                .find(|(attr_type, _)| *attr_type == wanted)
+                .map(|(_, attr_data)| *attr_data)
+        };
+        let xor_mapped = find(ATTR_XOR_MAPPED_ADDRESS)
+            .map(|attr_data| self.parse_xor_mapped_address(attr_data, expected_transaction_id));
+        let mapped = find(ATTR_MAPPED_ADDRESS).map(|attr_data| self.parse_mapped_address(attr_data));
+
+        // OTHER-ADDRESS is not XORed despite being newer; CHANGED-ADDRESS is its RFC 3489 name
+        let other_addr = find(ATTR_OTHER_ADDRESS)
+            .or_else(|| find(ATTR_CHANGED_ADDRESS))
+            .and_then(|attr_data| self.parse_mapped_address(attr_data).ok())
+            .map(|other| SocketAddr::new(other.external_ip, other.external_port));
+
+        let mut response = Self::select_address(self.preference, xor_mapped, mapped)?;
+        response.other_addr = other_addr;
+        Ok(response)
+    }
+
+    /// Pick the mapping according to the preference, independent of attribute order
+    fn select_address(
+        preference: AttributePreference,
+        xor_mapped: Option<Result<StunResponse>>,
+        mapped: Option<Result<StunResponse>>,
+    ) -> Result<StunResponse> {
+        let missing = |name: &str| anyhow!("No {} attribute found in STUN response", name);
+
+        match preference {
+            AttributePreference::Only(AddressAttribute::XorMapped) => {
+                xor_mapped.unwrap_or_else(|| Err(missing("XOR-MAPPED-ADDRESS")))
+            }
+            AttributePreference::Only(AddressAttribute::Mapped) => {
+                mapped.unwrap_or_else(|| Err(missing("MAPPED-ADDRESS")))
+            }
+            AttributePreference::PreferXor => match (xor_mapped, mapped) {
+                (Some(Ok(xor_mapped)), Some(Ok(mapped))) => {
+                    if (xor_mapped.external_ip, xor_mapped.external_port) != (mapped.external_ip, mapped.external_port) {
+                        println!(
+                            "STUN MAPPED-ADDRESS {}:{} disagrees with XOR-MAPPED-ADDRESS {}:{}, using XOR",
+                            mapped.external_ip,
+                            mapped.external_port,
+                            xor_mapped.external_ip,
+                            xor_mapped.external_port,
+                        );
+                        tracing::warn!(
+                            mapped = %SocketAddr::new(mapped.external_ip, mapped.external_port),
+                            xor_mapped = %SocketAddr::new(xor_mapped.external_ip, xor_mapped.external_port),
+                            "STUN address attributes disagree, using XOR-MAPPED-ADDRESS"
+                        );
+                    }
+                    Ok(xor_mapped)
+                }
+                (Some(Ok(xor_mapped)), _) => Ok(xor_mapped),
+                (Some(Err(e)), Some(Ok(mapped))) => {
+                    println!("Unusable XOR-MAPPED-ADDRESS ({}), falling back to MAPPED-ADDRESS", e);
+                    Ok(mapped)
+                }
+                (Some(Err(e)), _) => Err(e),
+                (None, Some(mapped)) => mapped,
+                (None, None) => Err(anyhow!("No address attribute found in STUN response")),
+            },
+        }
+    }
+
+    /// Split the attribute section into (type, value) pairs
+    fn attributes(data: &[u8], msg_len: usize) -> Vec<(u16, &[u8])> {
+        let mut attributes = Vec::new();
         let mut offset = 20;
         while offset < 20 + msg_len {
             if offset + 4 > data.len() {
@@ -129,19 +391,31 @@ impl StunClient {
                 break;
             }
 
-            let attr_data = &data[offset..offset + attr_len];
-
-            if attr_type == ATTR_XOR_MAPPED_ADDRESS {
-                return self.parse_xor_mapped_address(attr_data, expected_transaction_id);
-            } else if attr_type == ATTR_MAPPED_ADDRESS {
-                return self.parse_mapped_address(attr_data);
-            }
+            attributes.push((attr_type, &data[offset..offset + attr_len]));
 
             // Move to next attribute (attributes are padded to 4-byte boundaries)
             offset += (attr_len + 3) & !3;
         }
+        attributes
+    }
+
+    /// Parse ERROR-CODE attribute (class * 100 + number, then UTF-8 reason)
+    fn parse_error_code(data: &[u8]) -> StunError {
+        if data.len() < 4 {
+            return StunError::ErrorResponse {
+                code: 0,
+                reason: "ERROR-CODE too short".to_string(),
+            };
+        }
+
+        let class = (data[2] & 0x07) as u16;
+        let number = data[3] as u16;
+        let reason = String::from_utf8_lossy(&data[4..]).trim_end_matches('\0').to_string();
 
-        Err(anyhow!("No address attribute found in STUN response"))
+        StunError::ErrorResponse {
+            code: class * 100 + number,
+            reason,
+        }
     }
 
     /// Parse XOR-MAPPED-ADDRESS attribute
@@ -191,6 +465,8 @@ impl StunClient {
         Ok(StunResponse {
             external_ip: ip,
             external_port: port,
+            attribute: AddressAttribute::XorMapped,
+            other_addr: None,
         })
     }
 
@@ -228,6 +504,8 @@ impl StunClient {
         Ok(StunResponse {
             external_ip: ip,
             external_port: port,
+            attribute: AddressAttribute::Mapped,
+            other_addr: None,
         })
     }
 
@@ -241,3 +519,134 @@ impl StunClient {
         self.socket
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answer the first request on a loopback socket with `reply(transaction_id)`
+    fn serve_once(reply: impl FnOnce([u8; 12]) -> Vec<u8> + Send + 'static) -> SocketAddr {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 1024];
+            let (len, from) = server.recv_from(&mut buffer).unwrap();
+            assert!(len >= 20);
+            let transaction_id: [u8; 12] = buffer[8..20].try_into().unwrap();
+            server.send_to(&reply(transaction_id), from).unwrap();
+        });
+        addr
+    }
+
+    fn message(msg_type: u16, transaction_id: &[u8; 12], attributes: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (attr_type, value) in attributes {
+            body.extend_from_slice(&attr_type.to_be_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            body.extend_from_slice(value);
+            body.resize((body.len() + 3) & !3, 0);
+        }
+        let mut data = Vec::new();
+        data.extend_from_slice(&msg_type.to_be_bytes());
+        data.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        data.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        data.extend_from_slice(transaction_id);
+        data.extend_from_slice(&body);
+        data
+    }
+
+    /// XOR-MAPPED-ADDRESS value for an IPv4 mapping
+    fn xor_mapped_v4(addr: std::net::SocketAddrV4) -> Vec<u8> {
+        let mut value = vec![0, 0x01];
+        value.extend_from_slice(&(addr.port() ^ (STUN_MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+        value.extend_from_slice(&(u32::from(*addr.ip()) ^ STUN_MAGIC_COOKIE).to_be_bytes());
+        value
+    }
+
+    /// MAPPED-ADDRESS value for an IPv4 mapping
+    fn mapped_v4(addr: std::net::SocketAddrV4) -> Vec<u8> {
+        let mut value = vec![0, 0x01];
+        value.extend_from_slice(&addr.port().to_be_bytes());
+        value.extend_from_slice(&addr.ip().octets());
+        value
+    }
+
+    #[tokio::test]
+    async fn error_response_becomes_stun_error() {
+        let server = serve_once(|transaction_id| {
+            let mut error_code = vec![0, 0, 4, 20];
+            error_code.extend_from_slice(b"Unknown Attribute");
+            message(STUN_BINDING_ERROR_RESPONSE, &transaction_id, &[(ATTR_ERROR_CODE, error_code)])
+        });
+
+        let error = StunClient::new(&server).unwrap().query().await.unwrap_err();
+        match error.downcast_ref::<StunError>() {
+            Some(StunError::ErrorResponse { code, reason }) => {
+                assert_eq!(*code, 420);
+                assert_eq!(reason, "Unknown Attribute");
+            }
+            other => panic!("expected an error response, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn zeroed_mapping_is_rejected() {
+        let server = serve_once(|transaction_id| {
+            let zeroed = xor_mapped_v4("0.0.0.0:0".parse().unwrap());
+            message(STUN_BINDING_RESPONSE, &transaction_id, &[(ATTR_XOR_MAPPED_ADDRESS, zeroed)])
+        });
+
+        let error = StunClient::new(&server).unwrap().query().await.unwrap_err();
+        match error.downcast_ref::<StunError>() {
+            Some(StunError::InvalidMapping { addr }) => assert_eq!(*addr, "0.0.0.0:0".parse().unwrap()),
+            other => panic!("expected an invalid mapping, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn disagreeing_attributes_follow_the_preference() {
+        let xor_addr: std::net::SocketAddrV4 = "203.0.113.5:4000".parse().unwrap();
+        let mapped_addr: std::net::SocketAddrV4 = "198.51.100.7:5000".parse().unwrap();
+        let reply = move |transaction_id: [u8; 12]| {
+            // MAPPED-ADDRESS first, so the choice cannot come from attribute order
+            message(
+                STUN_BINDING_RESPONSE,
+                &transaction_id,
+                &[(ATTR_MAPPED_ADDRESS, mapped_v4(mapped_addr)), (ATTR_XOR_MAPPED_ADDRESS, xor_mapped_v4(xor_addr))],
+            )
+        };
+
+        let response = StunClient::new(&serve_once(reply)).unwrap().query().await.unwrap();
+        assert_eq!(SocketAddr::new(response.external_ip, response.external_port), SocketAddr::V4(xor_addr));
+        assert_eq!(response.attribute, AddressAttribute::XorMapped);
+
+        let response = StunClient::new(&serve_once(reply))
+            .unwrap()
+            .with_preference(AttributePreference::Only(AddressAttribute::Mapped))
+            .query()
+            .await
+            .unwrap();
+        assert_eq!(SocketAddr::new(response.external_ip, response.external_port), SocketAddr::V4(mapped_addr));
+        assert_eq!(response.attribute, AddressAttribute::Mapped);
+    }
+
+    #[tokio::test]
+    async fn slow_servers_are_queried_concurrently() {
+        const DELAY: Duration = Duration::from_millis(500);
+        let slow = || {
+            serve_once(|transaction_id| {
+                std::thread::sleep(DELAY);
+                let mapping = xor_mapped_v4("203.0.113.5:4000".parse().unwrap());
+                message(STUN_BINDING_RESPONSE, &transaction_id, &[(ATTR_XOR_MAPPED_ADDRESS, mapping)])
+            })
+        };
+        let (first, second) = (StunClient::new(&slow()).unwrap(), StunClient::new(&slow()).unwrap());
+
+        // A single-threaded runtime: blocking queries would take two delays
+        let start = std::time::Instant::now();
+        let (first, second) = tokio::join!(first.query(), second.query());
+        first.unwrap();
+        second.unwrap();
+        assert!(start.elapsed() < DELAY * 2 - Duration::from_millis(100), "took {:?}", start.elapsed());
+    }
+}
@@ -5,9 +5,12 @@
  */
 
 use anyhow::{Context, Result, anyhow};
-use std::net::{SocketAddr, TcpStream, TcpListener};
+use std::net::{Shutdown, SocketAddr, TcpStream, TcpListener};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind};
+use tokio::task::JoinSet;
+use crate::nat_traversal::candidates::IceRole;
 
 /// TCP connection error
 #[derive(Debug)]
@@ -29,25 +32,325 @@ impl std::fmt::Display for TcpConnectError {
 
 impl std::error::Error for TcpConnectError {}
 
-/// Perform TCP simultaneous open
-/// 
+/// How long connections completing after the first are still collected,
+/// so they can be closed rather than left half-open on the peer
+pub const DEFAULT_DUPLICATE_GRACE: Duration = Duration::from_millis(250);
+
+/// Perform TCP simultaneous open against a set of candidate peer addresses
+///
+/// Up to `parallelism` candidates are attempted at once from the same local
+/// address; the first stream to connect wins and the remaining attempts are
+/// cancelled. Further candidates are started as earlier ones fail. Streams
+/// that connect within `DEFAULT_DUPLICATE_GRACE` of the winner are shut down.
+///
+/// A port of 0 in `local_addr` is replaced by one free port shared by every
+/// attempt. Either way the stream's `local_addr()` reports the port it
+/// ended up on.
+pub async fn tcp_simultaneous_open(
+    local_addr: SocketAddr,
+    targets: &[SocketAddr],
+    parallelism: usize,
+    timeout: Duration,
+) -> Result<TcpStream> {
+    open_candidates(local_addr, targets, parallelism, timeout, None, DEFAULT_DUPLICATE_GRACE).await
+}
+
+/// `tcp_simultaneous_open` that also copes with the peers' SYNs not crossing
+///
+/// A pure simultaneous open fails when one SYN reaches the other host before
+/// its connect is under way, which a small skew between peers (or loopback,
+/// where such a SYN is refused at once) is enough for. So the controlled
+/// peer also listens on `local_addr`, and the controlling peer retries
+/// refused candidates until `timeout`.
+///
+/// More than one connection can still succeed, e.g. one accepted and one
+/// crossing to another candidate. Connections completing within `grace` of
+/// the first are collected: the controlling peer keeps its first and shuts
+/// the rest down, and the controlled peer keeps the one left open, so both
+/// sides end up on the same connection. A zero `grace` keeps the first.
+pub async fn tcp_simultaneous_open_as(
+    role: IceRole,
+    local_addr: SocketAddr,
+    targets: &[SocketAddr],
+    parallelism: usize,
+    timeout: Duration,
+    grace: Duration,
+) -> Result<TcpStream> {
+    open_candidates(local_addr, targets, parallelism, timeout, Some(role), grace).await
+}
+
+/// Longest the fixed-role phase of `tcp_connect_as` may take
+const DIRECT_PHASE: Duration = Duration::from_secs(3);
+
+/// How much longer the listener waits than the dialer dials, so a connect
+/// completing at the end of the phase is still accepted
+const LISTEN_GRACE: Duration = Duration::from_millis(500);
+
+/// Connect with fixed roles, falling back to simultaneous open
+///
+/// The controlled peer only listens on `local_addr` and the controlling peer
+/// only dials the candidates from it, which many NATs handle more reliably
+/// than crossing SYNs. If that has not connected after `DIRECT_PHASE` (at
+/// most half of `timeout`), both run `tcp_simultaneous_open_as` for the
+/// rest. Both peers derive the same roles from their fingerprints (see
+/// `IceRole::for_peers`), so they agree on who listens. `grace` is passed
+/// on to the fallback.
+pub async fn tcp_connect_as(
+    role: IceRole,
+    local_addr: SocketAddr,
+    targets: &[SocketAddr],
+    parallelism: usize,
+    timeout: Duration,
+    grace: Duration,
+) -> Result<TcpStream> {
+    let (local_addr, _reservation) = pin_port(local_addr)?;
+    let direct_phase = DIRECT_PHASE.min(timeout / 2);
+    let start = tokio::time::Instant::now();
+
+    let direct = match role {
+        IceRole::Controlled => {
+            println!("Listening for the peer's TCP connect on {}...", local_addr);
+            let listener = bound_socket(local_addr)?;
+            listener.listen(8)?;
+            accept_until(&listener.into(), start + direct_phase + LISTEN_GRACE).await
+        }
+        IceRole::Controlling => {
+            println!("Connecting to the listening peer over TCP...");
+            dial_from(local_addr, targets, start + direct_phase).await
+        }
+    };
+    match direct {
+        Ok(stream) => return Ok(stream),
+        Err(e) => println!("Direct TCP connect failed ({}), falling back to simultaneous open", e),
+    }
+
+    let remaining = timeout.saturating_sub(start.elapsed());
+    tcp_simultaneous_open_as(role, local_addr, targets, parallelism, remaining, grace).await
+}
+
+/// Dial the candidates in turn from `local_addr`, retrying until `deadline`
+/// (the peer's listener may not be up yet)
+async fn dial_from(local_addr: SocketAddr, targets: &[SocketAddr], deadline: tokio::time::Instant) -> Result<TcpStream> {
+    if targets.is_empty() {
+        return Err(anyhow!("No TCP candidates to connect to"));
+    }
+
+    let mut last_error = None;
+    while tokio::time::Instant::now() < deadline {
+        for &addr in targets {
+            let connect = tokio::task::spawn_blocking(move || {
+                try_connect(local_addr, addr, Duration::from_millis(500))
+            });
+            match connect.await? {
+                Ok(stream) => {
+                    println!("Outbound TCP connection to {} succeeded!", addr);
+                    return Ok(stream);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+    Err(last_error.unwrap_or_else(|| TcpConnectError::Timeout.into()))
+}
+
+/// Candidate scheduling shared by both entry points
+async fn open_candidates(
+    local_addr: SocketAddr,
+    targets: &[SocketAddr],
+    parallelism: usize,
+    timeout: Duration,
+    role: Option<IceRole>,
+    grace: Duration,
+) -> Result<TcpStream> {
+    if targets.is_empty() {
+        return Err(anyhow!("No TCP candidates to connect to"));
+    }
+
+    // Pin a random port now so every attempt (and the listener) shares it
+    let (local_addr, _reservation) = pin_port(local_addr)?;
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut pending = targets.iter().copied();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+
+    for addr in pending.by_ref().take(parallelism.max(1)) {
+        attempts.spawn(attempt(local_addr, addr, timeout, Duration::ZERO));
+    }
+    let listener = match role {
+        Some(IceRole::Controlled) => {
+            let listener = bound_socket(local_addr)?;
+            listener.listen(8)?;
+            let listener = Arc::new(TcpListener::from(listener));
+            attempts.spawn(accept_from(Arc::clone(&listener), deadline));
+            Some(listener)
+        }
+        _ => None,
+    };
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => {
+                return Err(TcpConnectError::Timeout.into());
+            }
+            joined = attempts.join_next() => {
+                let (addr, error) = match joined {
+                    Some(Ok((_, Ok(stream)))) => {
+                        return Ok(keep_one(stream, &mut attempts, listener, role, grace).await);
+                    }
+                    Some(Ok((addr, Err(e)))) => (addr, e),
+                    Some(Err(e)) => (None, anyhow!("Connect task failed: {}", e)),
+                    None => {
+                        return Err(last_error
+                            .unwrap_or_else(|| anyhow!("All TCP candidates failed")));
+                    }
+                };
+                println!("TCP candidate failed: {}", error);
+                last_error = Some(error);
+                if let Some(addr) = pending.next() {
+                    attempts.spawn(attempt(local_addr, addr, timeout, Duration::ZERO));
+                } else if let (Some(IceRole::Controlling), Some(addr)) = (role, addr) {
+                    // The peer may not be listening yet
+                    attempts.spawn(attempt(local_addr, addr, timeout, RETRY_DELAY));
+                }
+            }
+        }
+    }
+}
+
+/// Outcome of one candidate attempt, or of the listener when untagged
+type Attempt = (Option<SocketAddr>, Result<TcpStream>);
+
+/// Settle on one connection once `first` has succeeded
+///
+/// Attempts (and the listener) keep running for `grace`, collecting any
+/// other connection that completes. The controlling peer (or either, without
+/// roles) keeps `first`; the controlled peer then waits up to `grace` more
+/// for the controlling peer to close the ones it dropped, and keeps the
+/// first still open. The rest are shut down.
+async fn keep_one(
+    first: TcpStream,
+    attempts: &mut JoinSet<Attempt>,
+    listener: Option<Arc<TcpListener>>,
+    role: Option<IceRole>,
+    grace: Duration,
+) -> TcpStream {
+    let mut streams = vec![first];
+    let window_end = tokio::time::Instant::now() + grace;
+    if let Some(listener) = &listener {
+        attempts.spawn(accept_from(Arc::clone(listener), window_end));
+    }
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(window_end) => break,
+            joined = attempts.join_next() => match joined {
+                Some(Ok((addr, Ok(stream)))) => {
+                    println!("Duplicate TCP connection to {:?}", stream.peer_addr().ok());
+                    if let (None, Some(listener)) = (addr, &listener) {
+                        attempts.spawn(accept_from(Arc::clone(listener), window_end));
+                    }
+                    streams.push(stream);
+                }
+                Some(_) => {}
+                None => break,
+            },
+        }
+    }
+    attempts.abort_all();
+
+    if role == Some(IceRole::Controlled) {
+        let wait_end = tokio::time::Instant::now() + grace;
+        while streams.len() > 1 && tokio::time::Instant::now() < wait_end {
+            match streams.iter().position(closed_by_peer) {
+                Some(closed) => drop(streams.remove(closed)),
+                None => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+    }
+
+    let kept = streams.remove(0);
+    for duplicate in streams {
+        println!("Closing duplicate TCP connection to {:?}", duplicate.peer_addr().ok());
+        let _ = duplicate.shutdown(Shutdown::Both);
+    }
+    kept
+}
+
+/// Whether the peer has closed or reset a stream we have not read from yet
+fn closed_by_peer(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let closed = match stream.peek(&mut [0u8; 1]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != ErrorKind::WouldBlock,
+    };
+    closed || stream.set_nonblocking(false).is_err()
+}
+
+/// Pause before retrying a refused candidate
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// One candidate attempt after `delay`, tagged with its address
+async fn attempt(
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    timeout: Duration,
+    delay: Duration,
+) -> Attempt {
+    tokio::time::sleep(delay).await;
+    (Some(peer_addr), simultaneous_open_one(local_addr, peer_addr, timeout).await)
+}
+
+/// Accept one connection on the shared listener, untagged
+async fn accept_from(listener: Arc<TcpListener>, deadline: tokio::time::Instant) -> Attempt {
+    (None, accept_until(&listener, deadline).await)
+}
+
+/// Accept the first connection on a listener sharing the attempts' port
+async fn accept_until(listener: &TcpListener, deadline: tokio::time::Instant) -> Result<TcpStream> {
+    listener.set_nonblocking(true)?;
+    while tokio::time::Instant::now() < deadline {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                println!("Accepted TCP connection from {}", addr);
+                stream.set_nonblocking(false)?;
+                return Ok(stream);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(TcpConnectError::Timeout.into())
+}
+
+/// TCP simultaneous open towards a single peer address
+///
 /// This is a complex technique where both peers:
 /// 1. Bind to a local port
 /// 2. Attempt to connect to each other simultaneously
 /// 3. NATs will typically allow the SYN packets through because of the prior UDP hole punching
-pub async fn tcp_simultaneous_open(
-    local_port: u16,
+async fn simultaneous_open_one(
+    local_addr: SocketAddr,
     peer_addr: SocketAddr,
     timeout: Duration,
 ) -> Result<TcpStream> {
     println!("Starting TCP simultaneous open...");
-    println!("  Local port: {}", local_port);
+    println!("  Local address: {}", local_addr);
     println!("  Peer address: {}", peer_addr);
 
     let start = Instant::now();
 
     // Strategy 1: Try direct connection first (might work if peer connected first)
-    match try_connect(peer_addr, Duration::from_millis(500)) {
+    let direct = tokio::task::spawn_blocking(move || {
+        try_connect(local_addr, peer_addr, Duration::from_millis(500))
+    });
+    match direct.await? {
         Ok(stream) => {
             println!("Direct TCP connection succeeded!");
             return Ok(stream);
@@ -58,21 +361,7 @@ pub async fn tcp_simultaneous_open(
     }
 
     // Strategy 2: Simultaneous open
-    // Bind to specific local port
-    let local_addr = SocketAddr::from(([0, 0, 0, 0], local_port));
-    
-    // Set SO_REUSEADDR to allow rebinding
-    let socket = socket2::Socket::new(
-        socket2::Domain::IPV4,
-        socket2::Type::STREAM,
-        Some(socket2::Protocol::TCP),
-    )?;
-    
-    socket.set_reuse_address(true)?;
-    #[cfg(unix)]
-    socket.set_reuse_port(true)?;
-    
-    socket.bind(&local_addr.into())?;
+    let socket = bound_socket(local_addr)?;
     socket.set_nonblocking(true)?;
 
     // Initiate connection attempt
@@ -84,7 +373,7 @@ pub async fn tcp_simultaneous_open(
             println!("TCP connection established immediately!");
             return Ok(std_socket);
         }
-        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+        Err(e) if connect_in_progress(&e) => {
             // Connection in progress, this is expected
         }
         Err(e) => {
@@ -110,6 +399,10 @@ pub async fn tcp_simultaneous_open(
                 return Ok(std_socket);
             }
             Err(_) => {
+                // A refused or unreachable attempt is final; let the next candidate run
+                if let Some(e) = std_socket.take_error()? {
+                    return Err(TcpConnectError::ConnectFailed(e.to_string()).into());
+                }
                 // Not connected yet, wait and retry
                 tokio::time::sleep(Duration::from_millis(100)).await;
             }
@@ -117,23 +410,155 @@ pub async fn tcp_simultaneous_open(
     }
 }
 
-/// Try a simple TCP connection with timeout
-fn try_connect(addr: SocketAddr, timeout: Duration) -> Result<TcpStream> {
-    let stream = TcpStream::connect_timeout(&addr, timeout)
+/// Whether a non-blocking connect is still under way
+/// (Unix reports EINPROGRESS rather than EWOULDBLOCK)
+fn connect_in_progress(e: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::EINPROGRESS) {
+        return true;
+    }
+    e.kind() == ErrorKind::WouldBlock
+}
+
+/// TCP socket bound to `local_addr`, with address reuse so every attempt can share it
+fn bound_socket(local_addr: SocketAddr) -> Result<socket2::Socket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(local_addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+
+    // Set SO_REUSEADDR to allow rebinding
+    let reuse_address = socket.set_reuse_address(true);
+    #[cfg(unix)]
+    let reuse_port = Some(socket.set_reuse_port(true));
+    #[cfg(not(unix))]
+    let reuse_port = None;
+    require_reuse(reuse_address, reuse_port)?;
+
+    socket.bind(&local_addr.into())?;
+    Ok(socket)
+}
+
+/// Check the results of setting SO_REUSEADDR and (on unix) SO_REUSEPORT
+///
+/// Either one lets the attempts share the port, and some kernels (certain
+/// Android and older unix builds) refuse SO_REUSEPORT, so one failure only
+/// warns. Fails only if neither could be set.
+fn require_reuse(reuse_address: io::Result<()>, reuse_port: Option<io::Result<()>>) -> Result<()> {
+    match (reuse_address, reuse_port) {
+        (Ok(()), None | Some(Ok(()))) => Ok(()),
+        (Ok(()), Some(Err(e))) => {
+            println!("SO_REUSEPORT unsupported ({}), continuing with SO_REUSEADDR", e);
+            tracing::warn!(error = %e, "SO_REUSEPORT unsupported");
+            Ok(())
+        }
+        (Err(e), Some(Ok(()))) => {
+            println!("SO_REUSEADDR failed ({}), continuing with SO_REUSEPORT", e);
+            tracing::warn!(error = %e, "SO_REUSEADDR failed");
+            Ok(())
+        }
+        (Err(e), None) => Err(e).context("Failed to set SO_REUSEADDR"),
+        (Err(address), Some(Err(port))) => Err(anyhow!(
+            "Failed to enable address reuse (SO_REUSEADDR: {}, SO_REUSEPORT: {})",
+            address,
+            port
+        )),
+    }
+}
+
+/// Resolve a port of 0 to a concrete free port
+///
+/// The socket that picked the port is returned too and should be held while
+/// the port is in use, so nothing else takes it in the meantime.
+fn pin_port(local_addr: SocketAddr) -> Result<(SocketAddr, Option<socket2::Socket>)> {
+    if local_addr.port() != 0 {
+        return Ok((local_addr, None));
+    }
+    let socket = bound_socket(local_addr).map_err(|e| TcpConnectError::BindFailed(e.to_string()))?;
+    let pinned = socket
+        .local_addr()?
+        .as_socket()
+        .context("Bound TCP socket has no IP address")?;
+    Ok((pinned, Some(socket)))
+}
+
+/// Try a simple TCP connection with timeout, from the configured local address
+fn try_connect(local_addr: SocketAddr, addr: SocketAddr, timeout: Duration) -> Result<TcpStream> {
+    let socket = bound_socket(local_addr)?;
+    socket.connect_timeout(&addr.into(), timeout)
         .context("Connection failed")?;
-    Ok(stream)
+    Ok(socket.into())
+}
+
+/// Dial the peer's candidates in turn until one accepts
+///
+/// The peer may not be listening yet, so refused attempts are retried until
+/// `timeout` runs out.
+pub async fn tcp_dial(targets: &[SocketAddr], timeout: Duration) -> Result<TcpStream> {
+    if targets.is_empty() {
+        return Err(anyhow!("No TCP candidates to connect to"));
+    }
+
+    let start = Instant::now();
+    loop {
+        for addr in targets {
+            match TcpStream::connect_timeout(addr, Duration::from_millis(500)) {
+                Ok(stream) => {
+                    println!("Outbound TCP connection to {} succeeded!", addr);
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    if start.elapsed() > timeout {
+                        return Err(TcpConnectError::ConnectFailed(e.to_string()).into());
+                    }
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+/// Wait for the peer to connect to `local_addr`
+pub async fn tcp_accept(local_addr: SocketAddr, timeout: Duration) -> Result<TcpStream> {
+    let start = Instant::now();
+
+    let listener = TcpListener::bind(local_addr)
+        .map_err(|e| TcpConnectError::BindFailed(e.to_string()))?;
+    listener.set_nonblocking(true)?;
+
+    loop {
+        if start.elapsed() > timeout {
+            return Err(TcpConnectError::Timeout.into());
+        }
+
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                println!("Accepted TCP connection from {}", addr);
+                stream.set_nonblocking(false)?;
+                return Ok(stream);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(e) => {
+                println!("Accept error: {}", e);
+            }
+        }
+    }
 }
 
 /// Alternative approach: Listen and connect simultaneously
+/// Listens on `local_addr`, so a forwarded port can be used for the direct path
 pub async fn tcp_listen_and_connect(
-    local_port: u16,
+    local_addr: SocketAddr,
     peer_addr: SocketAddr,
     timeout: Duration,
 ) -> Result<TcpStream> {
     let start = Instant::now();
     
     // Start listening
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", local_port))
+    let listener = TcpListener::bind(local_addr)
         .context("Failed to bind listener")?;
     listener.set_nonblocking(true)?;
 
@@ -172,3 +597,159 @@ pub async fn tcp_listen_and_connect(
         tokio::time::sleep(Duration::from_millis(100)).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refused() -> io::Result<()> {
+        Err(io::Error::from(ErrorKind::PermissionDenied))
+    }
+
+    #[test]
+    fn reuse_needs_one_option() {
+        assert!(require_reuse(Ok(()), None).is_ok());
+        assert!(require_reuse(Ok(()), Some(Ok(()))).is_ok());
+        assert!(require_reuse(Ok(()), Some(refused())).is_ok());
+        assert!(require_reuse(refused(), Some(Ok(()))).is_ok());
+        assert!(require_reuse(refused(), None).is_err());
+        assert!(require_reuse(refused(), Some(refused())).is_err());
+    }
+
+    /// Loopback address nothing listens on
+    fn closed_port() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn reaches_the_one_listening_candidate() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listening = listener.local_addr().unwrap();
+        let targets = [closed_port(), closed_port(), listening, closed_port()];
+
+        let stream = tcp_simultaneous_open("127.0.0.1:0".parse().unwrap(), &targets, 2, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listening);
+        let (accepted, _) = listener.accept().unwrap();
+        assert_eq!(accepted.peer_addr().unwrap(), stream.local_addr().unwrap());
+    }
+
+    /// Check the two streams are the two ends of one connection
+    fn assert_paired(a: &TcpStream, b: &TcpStream) {
+        use std::io::{Read, Write};
+        assert_eq!(a.local_addr().unwrap(), b.peer_addr().unwrap());
+        assert_eq!(a.peer_addr().unwrap(), b.local_addr().unwrap());
+        (&*a).write_all(b"ping").unwrap();
+        let mut buffer = [0u8; 4];
+        (&*b).read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fixed_roles_connect() {
+        let controlled_addr = closed_port();
+        let controlling_addr = closed_port();
+        let (to_controlling, to_controlled) = ([controlling_addr], [controlled_addr]);
+
+        let (controlled, controlling) = tokio::join!(
+            tcp_connect_as(
+                IceRole::Controlled,
+                controlled_addr,
+                &to_controlling,
+                1,
+                Duration::from_secs(10),
+                DEFAULT_DUPLICATE_GRACE,
+            ),
+            tcp_connect_as(
+                IceRole::Controlling,
+                controlling_addr,
+                &to_controlled,
+                1,
+                Duration::from_secs(10),
+                DEFAULT_DUPLICATE_GRACE,
+            ),
+        );
+        let (controlled, controlling) = (controlled.unwrap(), controlling.unwrap());
+        assert_eq!(controlled.local_addr().unwrap(), controlled_addr);
+        assert_eq!(controlling.local_addr().unwrap(), controlling_addr);
+        assert_paired(&controlling, &controlled);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn duplicates_settle_on_one_connection() {
+        // The controlled peer listens on every loopback address, so both targets connect
+        let port = closed_port().port();
+        let controlled_addr = SocketAddr::from(([0, 0, 0, 0], port));
+        let controlling_addr = closed_port();
+        let to_controlled = [SocketAddr::from(([127, 0, 0, 1], port)), SocketAddr::from(([127, 0, 0, 2], port))];
+        let to_controlling = [controlling_addr];
+
+        let (controlled, controlling) = tokio::join!(
+            tcp_simultaneous_open_as(
+                IceRole::Controlled,
+                controlled_addr,
+                &to_controlling,
+                2,
+                Duration::from_secs(5),
+                DEFAULT_DUPLICATE_GRACE,
+            ),
+            async {
+                // Let the listener come up so neither connection is refused
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                tcp_simultaneous_open_as(
+                    IceRole::Controlling,
+                    controlling_addr,
+                    &to_controlled,
+                    2,
+                    Duration::from_secs(5),
+                    DEFAULT_DUPLICATE_GRACE,
+                )
+                .await
+            },
+        );
+        let (controlled, controlling) = (controlled.unwrap(), controlling.unwrap());
+        assert_paired(&controlling, &controlled);
+        assert_paired(&controlled, &controlling);
+    }
+
+    #[test]
+    fn only_port_zero_is_pinned() {
+        let fixed = closed_port();
+        let (addr, reservation) = pin_port(fixed).unwrap();
+        assert_eq!(addr, fixed);
+        assert!(reservation.is_none());
+
+        let (addr, reservation) = pin_port("127.0.0.1:0".parse().unwrap()).unwrap();
+        assert_ne!(addr.port(), 0);
+        let reservation = reservation.expect("the pinned port is held");
+        assert_eq!(reservation.local_addr().unwrap().as_socket(), Some(addr));
+    }
+
+    #[tokio::test]
+    async fn parallel_attempts_share_the_pinned_port() {
+        let listeners = [TcpListener::bind("127.0.0.1:0").unwrap(), TcpListener::bind("127.0.0.1:0").unwrap()];
+        let targets = listeners.each_ref().map(|listener| listener.local_addr().unwrap());
+
+        let stream = tcp_simultaneous_open("127.0.0.1:0".parse().unwrap(), &targets, 2, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let port = stream.local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        // Both attempts reached their listener, from the same port
+        for listener in &listeners {
+            listener.set_nonblocking(true).unwrap();
+            let deadline = std::time::Instant::now() + Duration::from_secs(2);
+            let accepted = loop {
+                match listener.accept() {
+                    Ok((accepted, _)) => break accepted,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock && std::time::Instant::now() < deadline => {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                    Err(e) => panic!("no attempt reached {:?}: {}", listener.local_addr(), e),
+                }
+            };
+            assert_eq!(accepted.peer_addr().unwrap().port(), port);
+        }
+    }
+}
@@ -0,0 +1,408 @@
+/**
+ * nat_traversal/test_harness.rs
+ *
+ * In-process STUN responder and signalling forwarder for loopback tests
+ * (`test-harness` feature and unit tests, never for production builds)
+ */
+
+use anyhow::{Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream as TokioTcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use crate::nat_traversal::signalling::{SignallingCodec, SignallingMessage, MSGPACK_SUBPROTOCOL};
+use crate::nat_traversal::types::NatTraversalConfig;
+
+/// Self-signed certificate for `localhost`; clients accept any certificate
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+const CERT_PEM: &[u8] = include_bytes!("testdata/harness_cert.pem");
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+const KEY_PEM: &[u8] = include_bytes!("testdata/harness_key.pem");
+#[cfg(feature = "rustls")]
+const CERT_DER: &[u8] = include_bytes!("testdata/harness_cert.der");
+#[cfg(feature = "rustls")]
+const KEY_DER: &[u8] = include_bytes!("testdata/harness_key.der");
+
+const STUN_MAGIC_COOKIE: u32 = 0x2112A442;
+
+/// A STUN server and a signalling server on loopback
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use pineapple::nat_traversal::{NatTraversal, test_harness::LoopbackHarness};
+///
+/// let harness = LoopbackHarness::start().await?;
+/// let mut alice = NatTraversal::new(harness.config("alice"));
+/// let mut bob = NatTraversal::new(harness.config("bob"));
+/// let (a, b) = tokio::join!(alice.connect("bob"), bob.connect("alice"));
+/// # Ok(())
+/// # }
+/// ```
+pub struct LoopbackHarness {
+    pub stun: StunResponder,
+    pub signalling: SignallingForwarder,
+}
+
+impl LoopbackHarness {
+    /// Start both servers on ephemeral loopback ports
+    pub async fn start() -> Result<Self> {
+        Ok(Self {
+            stun: StunResponder::start()?,
+            signalling: SignallingForwarder::start().await?,
+        })
+    }
+
+    /// Configuration pointing a peer at the harness
+    pub fn config(&self, fingerprint: &str) -> NatTraversalConfig {
+        NatTraversalConfig {
+            signalling_url: self.signalling.url(),
+            signalling_addrs: Vec::new(),
+            sni_hostname: None,
+            signalling_codec: Default::default(),
+            // The forwarder's certificate is self-signed
+            accept_invalid_certs: true,
+            stun_server_addr: self.stun.addr(),
+            stun_server_host: None,
+            stun_server_addr_v6: None,
+            stun_secondary_addr: None,
+            stun_attribute_preference: Default::default(),
+            stun_keepalive: Some(crate::nat_traversal::DEFAULT_STUN_KEEPALIVE),
+            turn_server_addr: None,
+            turn_credentials: None,
+            local_fingerprint: fingerprint.to_string(),
+            signing_key: ed25519_dalek::SigningKey::from_bytes(&rand::random::<[u8; 32]>()),
+            tcp_port: 0,
+            tcp_bind_ip: Ipv4Addr::UNSPECIFIED.into(),
+            app_id: Vec::new(),
+            socket_options: Default::default(),
+            duplicate_grace: crate::nat_traversal::DEFAULT_DUPLICATE_GRACE,
+        }
+    }
+}
+
+/// Answers binding requests with the sender's address as XOR-MAPPED-ADDRESS
+///
+/// On loopback that address is 127.0.0.1, which the client only accepts
+/// as a mapping with the `test-harness` feature enabled.
+pub struct StunResponder {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    answered: Arc<AtomicUsize>,
+}
+
+impl StunResponder {
+    pub fn start() -> Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).context("Failed to bind STUN responder")?;
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let addr = socket.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let answered = Arc::new(AtomicUsize::new(0));
+
+        let running = Arc::clone(&stop);
+        let count = Arc::clone(&answered);
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while !running.load(Ordering::Relaxed) {
+                let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+                if let Some(response) = binding_response(&buf[..len], from) {
+                    let _ = socket.send_to(&response, from);
+                    count.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+
+        Ok(Self { addr, stop, answered })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Binding requests answered so far, keepalives included
+    pub fn answered(&self) -> usize {
+        self.answered.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for StunResponder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Success response to an IPv4 binding request (None for anything else)
+fn binding_response(request: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+    let SocketAddr::V4(from) = from else {
+        return None;
+    };
+    if request.len() < 20 || request[0..2] != [0x00, 0x01] {
+        return None;
+    }
+
+    let mut response = vec![0x01, 0x01, 0x00, 12];
+    response.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    response.extend_from_slice(&request[8..20]);
+    // XOR-MAPPED-ADDRESS: reserved, family, port and address XORed with the cookie
+    response.extend_from_slice(&[0x00, 0x20, 0x00, 8, 0x00, 0x01]);
+    response.extend_from_slice(&(from.port() ^ (STUN_MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+    response.extend_from_slice(&(u32::from(*from.ip()) ^ STUN_MAGIC_COOKIE).to_be_bytes());
+    Some(response)
+}
+
+/// Registered clients by fingerprint, plus offers waiting for their target
+#[derive(Default)]
+struct Registry {
+    clients: HashMap<String, mpsc::UnboundedSender<SignallingMessage>>,
+    queued: HashMap<String, Vec<SignallingMessage>>,
+    bundles: HashMap<String, String>,
+}
+
+/// TLS WebSocket server that registers clients and forwards offers between them
+///
+/// Offers for a peer that has not registered yet are held until it does.
+pub struct SignallingForwarder {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl SignallingForwarder {
+    pub async fn start() -> Result<Self> {
+        let acceptor = tls_acceptor()?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .context("Failed to bind signalling forwarder")?;
+        let addr = listener.local_addr()?;
+        let registry = Arc::new(Mutex::new(Registry::default()));
+
+        let task = tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                let registry = Arc::clone(&registry);
+                tokio::spawn(async move {
+                    if let Err(e) = serve_client(acceptor, tcp, registry).await {
+                        println!("Harness signalling client failed: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(Self { addr, task })
+    }
+
+    /// URL to put in `NatTraversalConfig::signalling_url`
+    pub fn url(&self) -> String {
+        format!("wss://{}", self.addr)
+    }
+}
+
+impl Drop for SignallingForwarder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Handle one client connection until it closes
+async fn serve_client(acceptor: TlsAcceptor, tcp: TokioTcpStream, registry: Arc<Mutex<Registry>>) -> Result<()> {
+    let tls = acceptor.accept(tcp).await.context("TLS accept failed")?;
+    let mut codec = SignallingCodec::Json;
+    // tungstenite's handshake callback fixes the error type
+    #[allow(clippy::result_large_err)]
+    let negotiate = |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+        let offered = request
+            .headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|protocol| protocol.trim() == MSGPACK_SUBPROTOCOL);
+        if offered {
+            codec = SignallingCodec::MessagePack;
+            response
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", MSGPACK_SUBPROTOCOL.parse().unwrap());
+        }
+        Ok(response)
+    };
+    let ws = tokio_tungstenite::accept_hdr_async(tls, negotiate).await.context("WebSocket accept failed")?;
+    let (mut sink, mut stream) = ws.split();
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<SignallingMessage>();
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            let Ok(frame) = codec.encode(&msg) else {
+                continue;
+            };
+            if sink.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut fingerprint = None;
+    let mut attempts = Vec::new();
+    let mut outcome = Ok(());
+    while let Some(frame) = stream.next().await {
+        let msg = match frame.map_err(anyhow::Error::from).and_then(|frame| SignallingCodec::decode(&frame)) {
+            Ok(Some(msg)) => msg,
+            Ok(None) => continue,
+            Err(e) => {
+                outcome = Err(e);
+                break;
+            }
+        };
+        let mut registry = registry.lock().await;
+        match msg {
+            SignallingMessage::Register { fingerprint: name } => {
+                registry.clients.insert(name.clone(), tx.clone());
+                let _ = tx.send(SignallingMessage::RegisterAck {
+                    success: true,
+                    message: "Registered successfully".to_string(),
+                });
+                for queued in registry.queued.remove(&name).unwrap_or_default() {
+                    let _ = tx.send(queued);
+                }
+                fingerprint = Some(name);
+            }
+            SignallingMessage::Offer {
+                target_fingerprint,
+                external_ip,
+                external_port,
+                local_ip,
+                local_port,
+                nonce,
+                fingerprint: from_fingerprint,
+                extra_addrs,
+                attempt_id,
+                tcp_port,
+                udp_blocked,
+                verifying_key,
+                relay_addr,
+                nat_behavior,
+            } => {
+                attempts.push(attempt_id.clone());
+                let forward = SignallingMessage::ForwardOffer {
+                    from_fingerprint,
+                    external_ip,
+                    external_port,
+                    local_ip,
+                    local_port,
+                    nonce,
+                    extra_addrs,
+                    attempt_id,
+                    tcp_port,
+                    udp_blocked,
+                    verifying_key,
+                    relay_addr,
+                    nat_behavior,
+                };
+                match registry.clients.get(&target_fingerprint) {
+                    Some(target) => {
+                        let _ = target.send(forward);
+                    }
+                    None => registry.queued.entry(target_fingerprint).or_default().push(forward),
+                }
+            }
+            SignallingMessage::PublishBundle { fingerprint: name, bundle } => {
+                registry.bundles.insert(name, bundle);
+            }
+            SignallingMessage::FetchBundle { target_fingerprint } => {
+                let reply = match registry.bundles.get(&target_fingerprint) {
+                    Some(bundle) => SignallingMessage::BundleResponse { bundle: bundle.clone() },
+                    None => SignallingMessage::Error {
+                        message: format!("No bundle for {}", target_fingerprint),
+                    },
+                };
+                let _ = tx.send(reply);
+            }
+            SignallingMessage::PresenceQuery { target_fingerprint } => {
+                let online = registry.clients.contains_key(&target_fingerprint);
+                let _ = tx.send(SignallingMessage::Presence {
+                    fingerprint: target_fingerprint,
+                    online,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    // Offers still queued from this connection are stale once it is gone,
+    // like those of an attempt that was cancelled before the peer showed up
+    let mut registry = registry.lock().await;
+    if let Some(name) = fingerprint {
+        registry.clients.remove(&name);
+    }
+    for queue in registry.queued.values_mut() {
+        queue.retain(|queued| !matches!(
+            queued,
+            SignallingMessage::ForwardOffer { attempt_id, .. } if attempts.contains(attempt_id)
+        ));
+    }
+    writer.abort();
+    outcome
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+type TlsAcceptor = tokio_native_tls::TlsAcceptor;
+#[cfg(feature = "rustls")]
+type TlsAcceptor = tokio_rustls::TlsAcceptor;
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+fn tls_acceptor() -> Result<TlsAcceptor> {
+    let identity = native_tls::Identity::from_pkcs8(CERT_PEM, KEY_PEM).context("Invalid harness certificate")?;
+    let acceptor = native_tls::TlsAcceptor::new(identity).context("Failed to build TLS acceptor")?;
+    Ok(acceptor.into())
+}
+
+#[cfg(feature = "rustls")]
+fn tls_acceptor() -> Result<TlsAcceptor> {
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio_rustls::rustls::ServerConfig;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from(CERT_DER)],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(KEY_DER)),
+        )
+        .map_err(|e| anyhow::anyhow!("Invalid harness certificate: {}", e))?;
+    Ok(Arc::new(config).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nat_traversal::NatTraversal;
+    use std::io::{Read, Write};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn loopback_peers_connect_end_to_end() {
+        let harness = LoopbackHarness::start().await.unwrap();
+        let mut alice = NatTraversal::new(harness.config("alice"));
+        let mut bob = NatTraversal::new(harness.config("bob"));
+
+        let (alice_stream, bob_stream) = tokio::join!(alice.connect("bob"), bob.connect("alice"));
+        let (mut alice_stream, mut bob_stream) = (alice_stream.unwrap(), bob_stream.unwrap());
+        assert_eq!(alice_stream.local_addr().unwrap(), bob_stream.peer_addr().unwrap());
+        assert_eq!(alice_stream.peer_addr().unwrap(), bob_stream.local_addr().unwrap());
+        assert!(harness.stun.answered() >= 2);
+
+        alice_stream.write_all(b"hello bob").unwrap();
+        let mut buffer = [0u8; 9];
+        bob_stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello bob");
+
+        bob_stream.write_all(b"hi").unwrap();
+        let mut buffer = [0u8; 2];
+        alice_stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hi");
+    }
+}
@@ -0,0 +1,585 @@
+/**
+ * nat_traversal/turn.rs
+ *
+ * TURN client for TCP relays (RFC 5766 allocations, RFC 6062 TCP relaying)
+ */
+
+use anyhow::{Context, Result, anyhow};
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// STUN magic cookie (TURN messages are STUN messages)
+const MAGIC_COOKIE: u32 = 0x2112A442;
+
+const HEADER_LEN: usize = 20;
+
+/// TURN methods
+const METHOD_BINDING: u16 = 0x001;
+const METHOD_ALLOCATE: u16 = 0x003;
+const METHOD_REFRESH: u16 = 0x004;
+const METHOD_CREATE_PERMISSION: u16 = 0x008;
+const METHOD_CONNECTION_BIND: u16 = 0x00B;
+const METHOD_CONNECTION_ATTEMPT: u16 = 0x00C;
+
+/// Message classes
+const CLASS_REQUEST: u16 = 0x0000;
+const CLASS_INDICATION: u16 = 0x0010;
+const CLASS_SUCCESS: u16 = 0x0100;
+const CLASS_ERROR: u16 = 0x0110;
+
+/// TURN attribute types
+const ATTR_USERNAME: u16 = 0x0006;
+const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_LIFETIME: u16 = 0x000D;
+const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+const ATTR_REALM: u16 = 0x0014;
+const ATTR_NONCE: u16 = 0x0015;
+const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_CONNECTION_ID: u16 = 0x002A;
+
+/// REQUESTED-TRANSPORT protocol number for TCP allocations
+const TRANSPORT_TCP: u8 = 6;
+
+/// Unauthorized: resend with long-term credentials
+const ERROR_UNAUTHORIZED: u16 = 401;
+/// Stale Nonce: resend with the new nonce
+const ERROR_STALE_NONCE: u16 = 438;
+
+/// How long to wait for the server to answer one request
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Lifetime assumed when an Allocate response carries no LIFETIME
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(600);
+
+/// Long-term credentials for a TURN server
+#[derive(Clone)]
+pub struct TurnCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// TURN errors that callers may want to match on
+#[derive(Debug)]
+pub enum TurnError {
+    /// Error response from the server (ERROR-CODE)
+    ErrorResponse { code: u16, reason: String },
+    /// The server did not answer a request in time
+    NoResponse { server: SocketAddr },
+    /// No peer connected to the relayed address in time
+    NoPeer { relayed_addr: SocketAddr },
+}
+
+impl std::fmt::Display for TurnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TurnError::ErrorResponse { code, reason } => {
+                write!(f, "TURN server error {} {}", code, reason)
+            }
+            TurnError::NoResponse { server } => {
+                write!(f, "No TURN response from {}", server)
+            }
+            TurnError::NoPeer { relayed_addr } => {
+                write!(f, "No peer connected to relayed address {}", relayed_addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TurnError {}
+
+/// A TCP allocation on the TURN server
+#[derive(Debug, Clone, Copy)]
+pub struct TurnAllocation {
+    /// Address peers connect to; the server relays them to us
+    pub relayed_addr: SocketAddr,
+    /// Our address as the server sees it
+    pub mapped_addr: SocketAddr,
+    pub lifetime: Duration,
+}
+
+/// One STUN-format message
+struct TurnMessage {
+    method: u16,
+    class: u16,
+    transaction_id: [u8; 12],
+    attributes: Vec<(u16, Vec<u8>)>,
+}
+
+impl TurnMessage {
+    fn request(method: u16) -> Self {
+        Self {
+            method,
+            class: CLASS_REQUEST,
+            transaction_id: rand::random(),
+            attributes: Vec::new(),
+        }
+    }
+
+    fn with(mut self, attr_type: u16, value: Vec<u8>) -> Self {
+        self.attributes.push((attr_type, value));
+        self
+    }
+
+    fn attribute(&self, attr_type: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(t, _)| *t == attr_type)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn string(&self, attr_type: u16) -> Option<String> {
+        self.attribute(attr_type).map(|value| String::from_utf8_lossy(value).into_owned())
+    }
+
+    fn xor_address(&self, attr_type: u16) -> Result<SocketAddr> {
+        let value = self
+            .attribute(attr_type)
+            .ok_or_else(|| anyhow!("TURN response without attribute {:#06x}", attr_type))?;
+        decode_xor_address(value, &self.transaction_id)
+    }
+
+    fn error(&self) -> TurnError {
+        let Some(data) = self.attribute(ATTR_ERROR_CODE).filter(|data| data.len() >= 4) else {
+            return TurnError::ErrorResponse {
+                code: 0,
+                reason: "ERROR-CODE missing".to_string(),
+            };
+        };
+        let class = (data[2] & 0x07) as u16;
+        let number = data[3] as u16;
+        TurnError::ErrorResponse {
+            code: class * 100 + number,
+            reason: String::from_utf8_lossy(&data[4..]).trim_end_matches('\0').to_string(),
+        }
+    }
+
+    /// Serialize, appending MESSAGE-INTEGRITY when a key is given
+    fn encode(&self, integrity_key: Option<&[u8]>) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (attr_type, value) in &self.attributes {
+            body.extend_from_slice(&attr_type.to_be_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            body.extend_from_slice(value);
+            body.resize((body.len() + 3) & !3, 0);
+        }
+
+        // Method bits are interleaved with the two class bits
+        let method = self.method;
+        let message_type = (method & 0x000F) | ((method & 0x0070) << 1) | ((method & 0x0F80) << 2) | self.class;
+        // The length covers MESSAGE-INTEGRITY even though the HMAC does not
+        let integrity_len = if integrity_key.is_some() { 24 } else { 0 };
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + body.len() + integrity_len);
+        bytes.extend_from_slice(&message_type.to_be_bytes());
+        bytes.extend_from_slice(&((body.len() + integrity_len) as u16).to_be_bytes());
+        bytes.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        bytes.extend_from_slice(&self.transaction_id);
+        bytes.extend_from_slice(&body);
+
+        if let Some(key) = integrity_key {
+            let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key length");
+            mac.update(&bytes);
+            bytes.extend_from_slice(&ATTR_MESSAGE_INTEGRITY.to_be_bytes());
+            bytes.extend_from_slice(&20u16.to_be_bytes());
+            bytes.extend_from_slice(&mac.finalize().into_bytes());
+        }
+        bytes
+    }
+
+    /// Parse one whole message
+    fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_LEN {
+            return Err(anyhow!("TURN message too short"));
+        }
+        let message_type = u16::from_be_bytes([data[0], data[1]]);
+        let msg_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if data[4..8] != MAGIC_COOKIE.to_be_bytes() {
+            return Err(anyhow!("Invalid TURN magic cookie"));
+        }
+        if data.len() < HEADER_LEN + msg_len {
+            return Err(anyhow!("Truncated TURN message"));
+        }
+
+        let mut attributes = Vec::new();
+        let mut offset = HEADER_LEN;
+        while offset + 4 <= HEADER_LEN + msg_len {
+            let attr_type = u16::from_be_bytes([data[offset], data[offset + 1]]);
+            let attr_len = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+            offset += 4;
+            if offset + attr_len > HEADER_LEN + msg_len {
+                return Err(anyhow!("Truncated TURN attribute"));
+            }
+            attributes.push((attr_type, data[offset..offset + attr_len].to_vec()));
+            offset += (attr_len + 3) & !3;
+        }
+
+        Ok(Self {
+            method: (message_type & 0x000F) | ((message_type & 0x00E0) >> 1) | ((message_type & 0x3E00) >> 2),
+            class: message_type & 0x0110,
+            transaction_id: data[8..HEADER_LEN].try_into().context("Invalid transaction id")?,
+            attributes,
+        })
+    }
+}
+
+/// XOR-*-ADDRESS value for `addr`
+fn encode_xor_address(addr: SocketAddr, transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut value = vec![0u8];
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value.push(0x01);
+            value.extend_from_slice(&port.to_be_bytes());
+            value.extend_from_slice(&(u32::from(ip) ^ MAGIC_COOKIE).to_be_bytes());
+        }
+        IpAddr::V6(ip) => {
+            value.push(0x02);
+            value.extend_from_slice(&port.to_be_bytes());
+            let key = xor_key_v6(transaction_id);
+            value.extend(ip.octets().iter().zip(key).map(|(byte, key)| byte ^ key));
+        }
+    }
+    value
+}
+
+fn decode_xor_address(data: &[u8], transaction_id: &[u8; 12]) -> Result<SocketAddr> {
+    if data.len() < 8 {
+        return Err(anyhow!("XOR address too short"));
+    }
+    let port = u16::from_be_bytes([data[2], data[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match data[1] {
+        0x01 => {
+            let xor_addr = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+            IpAddr::from((xor_addr ^ MAGIC_COOKIE).to_be_bytes())
+        }
+        0x02 if data.len() >= 20 => {
+            let mut addr_bytes = [0u8; 16];
+            for (i, key) in xor_key_v6(transaction_id).iter().enumerate() {
+                addr_bytes[i] = data[4 + i] ^ key;
+            }
+            IpAddr::from(addr_bytes)
+        }
+        family => return Err(anyhow!("Unknown address family: {}", family)),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// IPv6 addresses are XORed with the magic cookie followed by the transaction id
+fn xor_key_v6(transaction_id: &[u8; 12]) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key[0..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    key[4..16].copy_from_slice(transaction_id);
+    key
+}
+
+/// TURN client over one TCP control connection
+///
+/// The allocation lives as long as the control connection; dropping the
+/// client releases it and closes any relayed connections. Every call
+/// blocks on the network, so from async code run it on the blocking pool
+/// (`tokio::task::spawn_blocking`).
+pub struct TurnClient {
+    server: SocketAddr,
+    control: TcpStream,
+    credentials: TurnCredentials,
+    /// From the server's 401/438, then sent with every request
+    realm: Option<String>,
+    nonce: Option<String>,
+    relayed_addr: Option<SocketAddr>,
+    /// Bytes read from the control connection but not yet parsed
+    buffer: Vec<u8>,
+    /// ConnectionAttempt indications that arrived during other transactions
+    attempts: VecDeque<TurnMessage>,
+}
+
+impl TurnClient {
+    /// Open the control connection to `server`
+    pub fn connect(server: SocketAddr, credentials: TurnCredentials) -> Result<Self> {
+        let control = TcpStream::connect_timeout(&server, TRANSACTION_TIMEOUT)
+            .with_context(|| format!("Failed to connect to TURN server {}", server))?;
+        control.set_nodelay(true)?;
+
+        Ok(Self {
+            server,
+            control,
+            credentials,
+            realm: None,
+            nonce: None,
+            relayed_addr: None,
+            buffer: Vec::new(),
+            attempts: VecDeque::new(),
+        })
+    }
+
+    /// Our address as the server sees it on the control connection
+    ///
+    /// A plain STUN Binding, so it needs no allocation or credentials.
+    pub fn mapped_address(&mut self) -> Result<SocketAddr> {
+        let response = self.transact(TurnMessage::request(METHOD_BINDING))?;
+        response.xor_address(ATTR_XOR_MAPPED_ADDRESS)
+    }
+
+    /// Allocate a TCP relay
+    pub fn allocate(&mut self) -> Result<TurnAllocation> {
+        let request = TurnMessage::request(METHOD_ALLOCATE)
+            .with(ATTR_REQUESTED_TRANSPORT, vec![TRANSPORT_TCP, 0, 0, 0]);
+        let response = self.transact(request).context("TURN allocation failed")?;
+
+        let relayed_addr = response.xor_address(ATTR_XOR_RELAYED_ADDRESS)?;
+        let mapped_addr = response.xor_address(ATTR_XOR_MAPPED_ADDRESS)?;
+        let lifetime = response
+            .attribute(ATTR_LIFETIME)
+            .and_then(|value| value.try_into().ok())
+            .map(|value| Duration::from_secs(u32::from_be_bytes(value) as u64))
+            .unwrap_or(DEFAULT_LIFETIME);
+        self.relayed_addr = Some(relayed_addr);
+
+        Ok(TurnAllocation {
+            relayed_addr,
+            mapped_addr,
+            lifetime,
+        })
+    }
+
+    /// Let connections from `peer_ips` reach the relayed address
+    ///
+    /// Addresses in the other family than the relay are skipped, since
+    /// the server rejects the whole request over any of them.
+    pub fn create_permission(&mut self, peer_ips: &[IpAddr]) -> Result<()> {
+        let relayed_addr = self.relayed_addr.ok_or_else(|| anyhow!("No TURN allocation"))?;
+        let mut request = TurnMessage::request(METHOD_CREATE_PERMISSION);
+        for ip in peer_ips.iter().filter(|ip| ip.is_ipv4() == relayed_addr.is_ipv4()) {
+            let value = encode_xor_address(SocketAddr::new(*ip, 0), &request.transaction_id);
+            request = request.with(ATTR_XOR_PEER_ADDRESS, value);
+        }
+        if request.attributes.is_empty() {
+            return Err(anyhow!("No peer address in the relay's address family"));
+        }
+        self.transact(request).context("TURN permission failed")?;
+        Ok(())
+    }
+
+    /// Wait for a peer to connect to the relayed address and bind its connection
+    ///
+    /// Returns a new TCP connection to the server that carries the peer's
+    /// bytes unchanged from then on.
+    pub fn accept(&mut self, timeout: Duration) -> Result<TcpStream> {
+        let relayed_addr = self.relayed_addr.ok_or_else(|| anyhow!("No TURN allocation"))?;
+        let deadline = Instant::now() + timeout;
+
+        let attempt = loop {
+            if let Some(attempt) = self.attempts.pop_front() {
+                break attempt;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(TurnError::NoPeer { relayed_addr }.into());
+            }
+            match self.read_message(Some(remaining))? {
+                Some(message) if message.method == METHOD_CONNECTION_ATTEMPT && message.class == CLASS_INDICATION => {
+                    break message;
+                }
+                _ => continue,
+            }
+        };
+
+        let connection_id = attempt
+            .attribute(ATTR_CONNECTION_ID)
+            .ok_or_else(|| anyhow!("ConnectionAttempt without CONNECTION-ID"))?
+            .to_vec();
+        match attempt.xor_address(ATTR_XOR_PEER_ADDRESS) {
+            Ok(peer) => println!("Peer {} connected to the relay", peer),
+            Err(_) => println!("Peer connected to the relay"),
+        }
+
+        self.bind_connection(connection_id)
+    }
+
+    /// Keep the allocation alive in the background until the relay is dropped
+    pub fn keep_alive(mut self, lifetime: Duration) -> TurnRelay {
+        let relayed_addr = self.relayed_addr;
+        let (stop, stopped) = mpsc::channel::<()>();
+        // Refresh at half the lifetime so one lost refresh is survivable
+        let interval = (lifetime / 2).max(Duration::from_secs(30));
+
+        std::thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if let Err(e) = self.refresh(None) {
+                        println!("TURN refresh failed: {}", e);
+                        tracing::warn!(error = %e, "TURN refresh failed");
+                        return;
+                    }
+                    tracing::debug!(server = %self.server, "TURN allocation refreshed");
+                }
+                _ => {
+                    // Best-effort release; the server also drops it with the connection
+                    let _ = self.refresh(Some(Duration::ZERO));
+                    return;
+                }
+            }
+        });
+
+        TurnRelay { relayed_addr, _stop: stop }
+    }
+
+    /// Refresh the allocation (`Some(ZERO)` deletes it)
+    fn refresh(&mut self, lifetime: Option<Duration>) -> Result<()> {
+        let mut request = TurnMessage::request(METHOD_REFRESH);
+        if let Some(lifetime) = lifetime {
+            request = request.with(ATTR_LIFETIME, (lifetime.as_secs() as u32).to_be_bytes().to_vec());
+        }
+        self.transact(request)?;
+        Ok(())
+    }
+
+    /// Open a data connection and bind it to the peer connection `connection_id`
+    fn bind_connection(&mut self, connection_id: Vec<u8>) -> Result<TcpStream> {
+        let mut data = TcpStream::connect_timeout(&self.server, TRANSACTION_TIMEOUT)
+            .context("Failed to open TURN data connection")?;
+        data.set_nodelay(true)?;
+
+        let request = self.authenticated(TurnMessage::request(METHOD_CONNECTION_BIND).with(ATTR_CONNECTION_ID, connection_id));
+        data.write_all(&request.encode(self.key().as_deref()))?;
+
+        // Read exactly one response; the peer's bytes follow it
+        data.set_read_timeout(Some(TRANSACTION_TIMEOUT))?;
+        let mut header = [0u8; HEADER_LEN];
+        data.read_exact(&mut header).context("No ConnectionBind response")?;
+        let msg_len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut message = header.to_vec();
+        message.resize(HEADER_LEN + msg_len, 0);
+        data.read_exact(&mut message[HEADER_LEN..]).context("Truncated ConnectionBind response")?;
+        data.set_read_timeout(None)?;
+
+        let response = TurnMessage::decode(&message)?;
+        if response.transaction_id != request.transaction_id {
+            return Err(anyhow!("ConnectionBind response for another transaction"));
+        }
+        if response.class != CLASS_SUCCESS {
+            return Err(response.error()).context("TURN ConnectionBind failed");
+        }
+        Ok(data)
+    }
+
+    /// Send a request and wait for its response, authenticating when asked to
+    fn transact(&mut self, request: TurnMessage) -> Result<TurnMessage> {
+        let mut request = request;
+        let mut retried = false;
+        loop {
+            request = self.authenticated(request);
+            self.control.write_all(&request.encode(self.key().as_deref()))?;
+
+            let deadline = Instant::now() + TRANSACTION_TIMEOUT;
+            let response = loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(TurnError::NoResponse { server: self.server }.into());
+                }
+                match self.read_message(Some(remaining))? {
+                    Some(message) if message.transaction_id == request.transaction_id => break message,
+                    Some(message) if message.method == METHOD_CONNECTION_ATTEMPT => self.attempts.push_back(message),
+                    _ => continue,
+                }
+            };
+
+            if response.class == CLASS_SUCCESS {
+                return Ok(response);
+            }
+            if response.class != CLASS_ERROR {
+                return Err(anyhow!("Unexpected TURN message class {:#06x}", response.class));
+            }
+            let error = response.error();
+            let retry = matches!(
+                error,
+                TurnError::ErrorResponse { code: ERROR_UNAUTHORIZED | ERROR_STALE_NONCE, .. }
+            );
+            if !retry || retried {
+                return Err(error.into());
+            }
+            // Long-term credentials: the challenge names the realm and nonce to use
+            if let Some(realm) = response.string(ATTR_REALM) {
+                self.realm = Some(realm);
+            }
+            self.nonce = response.string(ATTR_NONCE);
+            retried = true;
+            request = TurnMessage {
+                transaction_id: rand::random(),
+                attributes: request
+                    .attributes
+                    .into_iter()
+                    .filter(|(t, _)| ![ATTR_USERNAME, ATTR_REALM, ATTR_NONCE].contains(t))
+                    .collect(),
+                ..request
+            };
+        }
+    }
+
+    /// Add USERNAME, REALM and NONCE once the server has challenged us
+    fn authenticated(&self, request: TurnMessage) -> TurnMessage {
+        let (Some(realm), Some(nonce)) = (&self.realm, &self.nonce) else {
+            return request;
+        };
+        if request.attribute(ATTR_USERNAME).is_some() {
+            return request;
+        }
+        request
+            .with(ATTR_USERNAME, self.credentials.username.as_bytes().to_vec())
+            .with(ATTR_REALM, realm.as_bytes().to_vec())
+            .with(ATTR_NONCE, nonce.as_bytes().to_vec())
+    }
+
+    /// Long-term credential key: MD5(username ":" realm ":" password)
+    fn key(&self) -> Option<Vec<u8>> {
+        self.nonce.as_ref()?;
+        let realm = self.realm.as_ref()?;
+        let input = format!("{}:{}:{}", self.credentials.username, realm, self.credentials.password);
+        Some(Md5::digest(input.as_bytes()).to_vec())
+    }
+
+    /// Next message on the control connection, None if none arrived in `wait`
+    fn read_message(&mut self, wait: Option<Duration>) -> Result<Option<TurnMessage>> {
+        loop {
+            if self.buffer.len() >= HEADER_LEN {
+                let len = HEADER_LEN + u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as usize;
+                if self.buffer.len() >= len {
+                    let message = TurnMessage::decode(&self.buffer[..len]);
+                    self.buffer.drain(..len);
+                    return message.map(Some);
+                }
+            }
+
+            self.control.set_read_timeout(wait.map(|wait| wait.max(Duration::from_millis(1))))?;
+            let mut chunk = [0u8; 1500];
+            match self.control.read(&mut chunk) {
+                Ok(0) => return Err(anyhow!("TURN server closed the control connection")),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+                Err(e) => return Err(e).context("TURN control connection failed"),
+            }
+        }
+    }
+}
+
+/// A TURN allocation kept alive in the background
+///
+/// Dropping it releases the allocation, which closes the relayed connection.
+pub struct TurnRelay {
+    relayed_addr: Option<SocketAddr>,
+    _stop: mpsc::Sender<()>,
+}
+
+impl TurnRelay {
+    pub fn relayed_addr(&self) -> Option<SocketAddr> {
+        self.relayed_addr
+    }
+}
@@ -4,16 +4,95 @@
  * Core types for NAT traversal
  */
 
-use std::net::SocketAddr;
-use ed25519_dalek::SigningKey;
+use anyhow::{anyhow, Context, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use crate::nat_traversal::nat_behavior::NatBehavior;
+use crate::nat_traversal::resolve;
+use crate::nat_traversal::signalling::SignallingCodec;
+use crate::nat_traversal::stun::AttributePreference;
+use crate::nat_traversal::turn::TurnCredentials;
+use crate::network::SocketOptions;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
 
 /// Peer connection information
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerInfo {
     pub fingerprint: String,
     pub external_addr: SocketAddr,
     pub local_addr: SocketAddr,
     pub nonce: u64,
+    /// Reflexive addresses in other address families (e.g. IPv6 next to an IPv4 `external_addr`)
+    #[serde(default)]
+    pub extra_addrs: Vec<SocketAddr>,
+    /// The peer's connection attempt id (empty if its offer carried none)
+    #[serde(default)]
+    pub attempt_id: String,
+    /// TCP port the peer opens from (None from peers that only announce it in probes)
+    #[serde(default)]
+    pub tcp_port: Option<u16>,
+    /// The peer cannot use UDP, so the attempt goes straight to TCP
+    #[serde(default)]
+    pub udp_blocked: bool,
+    /// Hex of the Ed25519 key the peer signs its probes with
+    #[serde(default)]
+    pub verifying_key: Option<String>,
+    /// TURN relayed address to connect to, in a relay fallback offer
+    #[serde(default)]
+    pub relay_addr: Option<SocketAddr>,
+    /// The peer's NAT type (`Unknown` from peers that do not classify)
+    #[serde(default)]
+    pub nat_behavior: NatBehavior,
+}
+
+impl PeerInfo {
+    /// Every reflexive address the peer advertised, `external_addr` first
+    pub fn reflexive_addrs(&self) -> Vec<SocketAddr> {
+        std::iter::once(self.external_addr).chain(self.extra_addrs.iter().copied()).collect()
+    }
+
+    /// Every address the peer advertised, host address last
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = self.reflexive_addrs();
+        addrs.push(self.local_addr);
+        addrs
+    }
+
+    /// The peer's probe verifying key, decoded
+    /// Without one, the peer's probes cannot be authenticated
+    pub fn decode_verifying_key(&self) -> Result<VerifyingKey> {
+        let hex_key = self
+            .verifying_key
+            .as_deref()
+            .ok_or_else(|| anyhow!("Peer {} sent no verifying key", self.fingerprint))?;
+        let bytes: [u8; 32] = hex::decode(hex_key)
+            .context("Invalid verifying key encoding")?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid verifying key length"))?;
+        VerifyingKey::from_bytes(&bytes).context("Invalid verifying key")
+    }
+}
+
+/// Our side of an offer (see `SignallingClient::send_offer`)
+#[derive(Debug, Clone)]
+pub struct LocalOffer {
+    pub external_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+    /// Reflexive addresses in families other than `external_addr`'s
+    pub extra_addrs: Vec<SocketAddr>,
+    /// Shared with the peer to correlate both sides' logs
+    pub attempt_id: String,
+    /// Port we open TCP from
+    pub tcp_port: u16,
+    /// STUN got no response at all, so we cannot hole punch
+    pub udp_blocked: bool,
+    /// Key our probes are signed with, for the peer to verify them
+    pub verifying_key: VerifyingKey,
+    /// TURN relayed address the peer should connect to (relay fallback only)
+    pub relay_addr: Option<SocketAddr>,
+    /// Our NAT type, so both sides agree on whether punching can work
+    pub nat_behavior: NatBehavior,
 }
 
 /// NAT traversal configuration
@@ -21,9 +100,48 @@ pub struct PeerInfo {
 pub struct NatTraversalConfig {
     /// Signalling server URL (wss://host:port)
     pub signalling_url: String,
+
+    /// Cached addresses of the signalling host (empty: resolve on every connect)
+    /// Filled by `resolve`, or supplied directly to bypass DNS
+    pub signalling_addrs: Vec<SocketAddr>,
+
+    /// TLS SNI name for the signalling server (None: the URL's host)
+    /// Certificates are then checked against this name, not the URL's host
+    pub sni_hostname: Option<String>,
+
+    /// Encoding to ask the signalling server for (JSON if it declines)
+    pub signalling_codec: SignallingCodec,
+
+    /// Skip signalling certificate validation (self-signed development servers only)
+    pub accept_invalid_certs: bool,
     
     /// STUN server address (host:port)
     pub stun_server_addr: SocketAddr,
+
+    /// STUN server as host:port, if it was given by name
+    /// When set, `stun_server_addr` is re-resolved from it after a failed query
+    pub stun_server_host: Option<String>,
+
+    /// IPv6 STUN server, queried alongside `stun_server_addr` (None: IPv4 only)
+    /// Each family is queried on its own socket and yields its own candidates
+    pub stun_server_addr_v6: Option<SocketAddr>,
+
+    /// Second STUN server at another IP, used only to classify the NAT
+    /// (None: rely on the first server's OTHER-ADDRESS; see `classify_nat`)
+    pub stun_secondary_addr: Option<SocketAddr>,
+
+    /// Which binding response attribute the mapping is read from
+    pub stun_attribute_preference: AttributePreference,
+
+    /// Interval of the binding requests that hold the STUN mapping open
+    /// while waiting for the peer's offer (None: no keepalive)
+    pub stun_keepalive: Option<Duration>,
+
+    /// TURN server to relay through when no direct path works (None: no relay)
+    pub turn_server_addr: Option<SocketAddr>,
+
+    /// Long-term credentials for `turn_server_addr`
+    pub turn_credentials: Option<TurnCredentials>,
     
     /// Local identity fingerprint
     pub local_fingerprint: String,
@@ -32,13 +150,55 @@ pub struct NatTraversalConfig {
     pub signing_key: SigningKey,
     
     /// Local TCP port to bind (0 for random)
+    /// Set it to a manually forwarded port to make the TCP path deterministic
     pub tcp_port: u16,
+
+    /// Local address the TCP socket binds to (unspecified for all interfaces)
+    pub tcp_bind_ip: IpAddr,
+
+    /// Deployment identifier mixed into probe packets (empty for the default network)
+    /// Peers only accept probes carrying the same app_id
+    pub app_id: Vec<u8>,
+
+    /// Options applied to the TCP stream once it is established
+    pub socket_options: SocketOptions,
+
+    /// How long to wait for duplicate TCP connections after the first, to close them
+    /// (`DEFAULT_DUPLICATE_GRACE` unless tuned for the path's round trip time)
+    pub duplicate_grace: Duration,
+}
+
+impl NatTraversalConfig {
+    /// Resolve the signalling and STUN hosts now and cache the results
+    ///
+    /// The cache has no TTL: it is reused for every `connect` until an attempt
+    /// through it fails, at which point that server is resolved again.
+    pub fn resolve(&mut self) -> Result<()> {
+        self.refresh_signalling()?;
+        self.refresh_stun()
+    }
+
+    /// Re-resolve the signalling host, replacing the cached addresses
+    pub fn refresh_signalling(&mut self) -> Result<()> {
+        self.signalling_addrs = resolve::resolve_signalling(&self.signalling_url)?;
+        Ok(())
+    }
+
+    /// Re-resolve the STUN host (no-op when it was given as an address)
+    pub fn refresh_stun(&mut self) -> Result<()> {
+        if let Some(host) = &self.stun_server_host {
+            self.stun_server_addr = resolve::resolve_stun(host)?;
+        }
+        Ok(())
+    }
 }
 
 /// Connection state machine
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ConnectionState {
     Idle,
+    /// Connecting to signalling and querying STUN at the same time
+    Discovering,
     ConnectingSignalling,
     Registering,
     StunDiscovery,
@@ -46,6 +206,10 @@ pub enum ConnectionState {
     WaitingForOffer,
     UdpHolePunching,
     TcpConnecting,
+    /// Direct punching was abandoned in favour of a relay path
+    RelayFallback,
+    /// Allocating a relay on the TURN server
+    TurnAllocating,
     Connected,
     Failed(String),
 }
@@ -3,11 +3,14 @@
  */
 
 use anyhow::{Context, Result};
+use rand::Rng;
+use std::fmt::Display;
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
 use ml_kem::EncodedSizeUser;
 
-use crate::pqxdh::{PQXDHInitMessage, User, SignedX25519Prekey, SignedMlKem1024Prekey};
+use crate::pqxdh::{self, PQXDHInitMessage, SealedMessage, User, SignedX25519Prekey, SignedMlKem1024Prekey};
 use crate::ratchet::{Message, MessageHeader};
 
 /// Serialize a PQXDH initial message for network transmission
@@ -62,6 +65,11 @@ pub fn deserialize_pqxdh_init_message(data: &[u8]) -> Result<PQXDHInitMessage> {
     ) as usize;
     offset += 4;
 
+    // Ciphertext plus the two flag bytes must exactly fill the rest
+    if data.len() - offset != ct_len + 2 {
+        anyhow::bail!("PQXDH message length mismatch");
+    }
+
     let mlkem_ciphertext = data[offset..offset + ct_len].to_vec();
     offset += ct_len;
 
@@ -78,10 +86,74 @@ pub fn deserialize_pqxdh_init_message(data: &[u8]) -> Result<PQXDHInitMessage> {
     })
 }
 
+/// Serialize a sealed message
+///
+/// Layout: init message length (4) | init message | signed prekey (32) |
+/// [one-time X25519 prekey (32)] | [one-time ML-KEM prekey id (32)] |
+/// ciphertext length (4) | ciphertext. The optional fields are present
+/// according to the init message's one-time prekey flags.
+pub fn serialize_sealed_message(sealed: &SealedMessage) -> Vec<u8> {
+    let init = serialize_pqxdh_init_message(&sealed.init_message);
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(&(init.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&init);
+    buffer.extend_from_slice(sealed.x25519_prekey.as_bytes());
+    if let Some(prekey) = &sealed.one_time_x25519_prekey {
+        buffer.extend_from_slice(prekey.as_bytes());
+    }
+    if let Some(id) = &sealed.one_time_mlkem_prekey {
+        buffer.extend_from_slice(id);
+    }
+    buffer.extend_from_slice(&(sealed.ciphertext.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&sealed.ciphertext);
+    buffer
+}
+
+/// Deserialize a sealed message, rejecting truncated or trailing data
+pub fn deserialize_sealed_message(data: &[u8]) -> Result<SealedMessage> {
+    let mut rest = data;
+    let mut take = |len: usize, what: &str| -> Result<&[u8]> {
+        if rest.len() < len {
+            anyhow::bail!("Sealed message truncated in {}", what);
+        }
+        let (field, tail) = rest.split_at(len);
+        rest = tail;
+        Ok(field)
+    };
+
+    let init_len = u32::from_be_bytes(take(4, "init message length")?.try_into()?) as usize;
+    let init_message = deserialize_pqxdh_init_message(take(init_len, "init message")?)?;
+    let x25519_prekey: [u8; 32] = take(32, "signed prekey")?.try_into()?;
+    let one_time_x25519_prekey = match init_message.used_one_time_x25519 {
+        true => Some(<[u8; 32]>::try_from(take(32, "one-time prekey")?)?.into()),
+        false => None,
+    };
+    let one_time_mlkem_prekey = match init_message.used_one_time_mlkem {
+        true => Some(take(32, "one-time ML-KEM prekey id")?.try_into()?),
+        false => None,
+    };
+    let ct_len = u32::from_be_bytes(take(4, "ciphertext length")?.try_into()?) as usize;
+    let ciphertext = take(ct_len, "ciphertext")?.to_vec();
+    if !rest.is_empty() {
+        anyhow::bail!("Sealed message has {} trailing bytes", rest.len());
+    }
+
+    Ok(SealedMessage {
+        init_message,
+        x25519_prekey: x25519_prekey.into(),
+        one_time_x25519_prekey,
+        one_time_mlkem_prekey,
+        ciphertext,
+    })
+}
+
 /// Serialize a Bob's public keys for prekey bundle
 pub fn serialize_prekey_bundle(bob: &User) -> Vec<u8> {
     let mut buffer = Vec::new();
 
+    // Identity signature algorithm (1 byte)
+    buffer.push(pqxdh::IDENTITY_ALGORITHM.id());
+
     // Identity key (32 bytes)
     buffer.extend_from_slice(bob.identity_public_key.as_bytes());
 
@@ -117,120 +189,39 @@ pub fn serialize_prekey_bundle(bob: &User) -> Vec<u8> {
     buffer
 }
 
-/// Deserialize Bob's prekey bundle
+/// Deserialize Bob's prekey bundle, rejecting truncated or trailing data
 pub fn deserialize_prekey_bundle(data: &[u8]) -> Result<User> {
-    let mut offset = 0;
+    let mut reader = BundleReader { data, offset: 0 };
+
+    // Identity signature algorithm
+    let algorithm = reader.take(1).context("Empty prekey bundle")?[0];
+    pqxdh::check_identity_algorithm(algorithm)?;
 
     // Identity key
-    let identity_bytes: [u8; 32] = data[offset..offset + 32]
-        .try_into()
-        .context("Invalid identity key")?;
+    let identity_bytes: [u8; 32] = reader.array().context("Invalid identity key")?;
     let identity_public_key = ed25519_dalek::VerifyingKey::from_bytes(&identity_bytes)
         .context("Failed to parse identity key")?;
-    offset += 32;
 
     // X25519 prekey
-    let x25519_bytes: [u8; 32] = data[offset..offset + 32]
-        .try_into()
-        .context("Invalid X25519 prekey")?;
-    let x25519_public_key = x25519_dalek::PublicKey::from(x25519_bytes);
-    offset += 32;
-
-    let x25519_sig_bytes: [u8; 64] = data[offset..offset + 64]
-        .try_into()
-        .context("Invalid X25519 signature")?;
-    let x25519_signature = ed25519_dalek::Signature::from_bytes(&x25519_sig_bytes);
-    offset += 64;
-
-    let x25519_prekey = SignedX25519Prekey {
-        public_key: x25519_public_key,
-        signature: x25519_signature,
-    };
+    let x25519_prekey = reader.x25519_prekey().context("Invalid X25519 prekey")?;
 
     // ML-KEM prekey
-    let mlkem_len = u32::from_be_bytes(
-        data[offset..offset + 4]
-            .try_into()
-            .context("Invalid ML-KEM length")?,
-    ) as usize;
-    offset += 4;
-
-    if mlkem_len != 1568 {
-        anyhow::bail!("Invalid ML-KEM-1024 encapsulation key length: {}", mlkem_len);
-    }
-
-    let mlkem_bytes: &[u8; 1568] = data[offset..offset + mlkem_len]
-        .try_into()
-        .context("Invalid ML-KEM bytes")?;
-    let mlkem_encap_key =
-        ml_kem::kem::EncapsulationKey::<ml_kem::MlKem1024Params>::from_bytes(mlkem_bytes.into());
-    offset += mlkem_len;
-
-    let mlkem_sig_bytes: [u8; 64] = data[offset..offset + 64]
-        .try_into()
-        .context("Invalid ML-KEM signature")?;
-    let mlkem_signature = ed25519_dalek::Signature::from_bytes(&mlkem_sig_bytes);
-    offset += 64;
-
-    let mlkem_prekey = SignedMlKem1024Prekey {
-        encap_key: mlkem_encap_key,
-        signature: mlkem_signature,
-    };
+    let mlkem_prekey = reader.mlkem_prekey().context("Invalid ML-KEM prekey")?;
 
     // One-time prekey flags
-    let has_x25519_otp = data[offset] == 1;
-    let has_mlkem_otp = data[offset + 1] == 1;
-    offset += 2;
-
-    let mut one_time_x25519_prekey = None;
-    if has_x25519_otp {
-        let otp_bytes: [u8; 32] = data[offset..offset + 32]
-            .try_into()
-            .context("Invalid one-time X25519 key")?;
-        let otp_public = x25519_dalek::PublicKey::from(otp_bytes);
-        offset += 32;
-
-        let otp_sig_bytes: [u8; 64] = data[offset..offset + 64]
-            .try_into()
-            .context("Invalid one-time X25519 signature")?;
-        let otp_signature = ed25519_dalek::Signature::from_bytes(&otp_sig_bytes);
-        offset += 64;
-
-        one_time_x25519_prekey = Some(SignedX25519Prekey {
-            public_key: otp_public,
-            signature: otp_signature,
-        });
-    }
-
-    let mut one_time_mlkem_prekey = None;
-    if has_mlkem_otp {
-        let pqotp_len = u32::from_be_bytes(
-            data[offset..offset + 4]
-                .try_into()
-                .context("Invalid one-time ML-KEM length")?,
-        ) as usize;
-        offset += 4;
+    let [has_x25519_otp, has_mlkem_otp] = reader.array().context("Missing one-time prekey flags")?;
 
-        if pqotp_len != 1568 {
-            anyhow::bail!("Invalid one-time ML-KEM-1024 encapsulation key length: {}", pqotp_len);
-        }
-
-        let pqotp_bytes: &[u8; 1568] = data[offset..offset + pqotp_len]
-            .try_into()
-            .context("Invalid one-time ML-KEM bytes")?;
-        let pqotp_encap_key =
-            ml_kem::kem::EncapsulationKey::<ml_kem::MlKem1024Params>::from_bytes(pqotp_bytes.into());
-        offset += pqotp_len;
-
-        let pqotp_sig_bytes: [u8; 64] = data[offset..offset + 64]
-            .try_into()
-            .context("Invalid one-time ML-KEM signature")?;
-        let pqotp_signature = ed25519_dalek::Signature::from_bytes(&pqotp_sig_bytes);
+    let one_time_x25519_prekey = match has_x25519_otp {
+        1 => Some(reader.x25519_prekey().context("Invalid one-time X25519 prekey")?),
+        _ => None,
+    };
+    let one_time_mlkem_prekey = match has_mlkem_otp {
+        1 => Some(reader.mlkem_prekey().context("Invalid one-time ML-KEM prekey")?),
+        _ => None,
+    };
 
-        one_time_mlkem_prekey = Some(SignedMlKem1024Prekey {
-            encap_key: pqotp_encap_key,
-            signature: pqotp_signature,
-        });
+    if reader.offset != data.len() {
+        anyhow::bail!("{} trailing bytes after prekey bundle", data.len() - reader.offset);
     }
 
     Ok(User::from_public_keys(
@@ -242,7 +233,58 @@ pub fn deserialize_prekey_bundle(data: &[u8]) -> Result<User> {
     ))
 }
 
+/// Bounds-checked cursor over a prekey bundle
+struct BundleReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl BundleReader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset.saturating_add(len))
+            .context("Prekey bundle truncated")?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn signature(&mut self) -> Result<ed25519_dalek::Signature> {
+        Ok(ed25519_dalek::Signature::from_bytes(&self.array()?))
+    }
+
+    /// Public key (32) | signature (64)
+    fn x25519_prekey(&mut self) -> Result<SignedX25519Prekey> {
+        let public_key = x25519_dalek::PublicKey::from(self.array::<32>()?);
+        Ok(SignedX25519Prekey { public_key, signature: self.signature()? })
+    }
+
+    /// Key length (4) | encapsulation key (1568) | signature (64)
+    fn mlkem_prekey(&mut self) -> Result<SignedMlKem1024Prekey> {
+        let len = u32::from_be_bytes(self.array()?) as usize;
+        if len != 1568 {
+            anyhow::bail!("Invalid ML-KEM-1024 encapsulation key length: {}", len);
+        }
+        let key_bytes: &[u8; 1568] = self.take(len)?.try_into()?;
+        let encap_key = ml_kem::kem::EncapsulationKey::<ml_kem::MlKem1024Params>::from_bytes(key_bytes.into());
+        Ok(SignedMlKem1024Prekey { encap_key, signature: self.signature()? })
+    }
+}
+
+/// Bytes of a serialized ratchet message before the ciphertext
+pub const RATCHET_HEADER_LEN: usize = 64;
+
 /// Serialize a ratchet message for network transmission
+///
+/// The output is self-delimiting: the header carries the ciphertext length,
+/// so it can be put in any framing (or none; see `ratchet_message_len`).
+/// Layout (integers big-endian):
+///   ratchet public key (32) | counter (8) | previous chain length (8)
+///   nonce (12) | ciphertext length (4) | ciphertext
 pub fn serialize_ratchet_message(msg: &Message) -> Vec<u8> {
     let mut buffer = Vec::new();
 
@@ -252,6 +294,9 @@ pub fn serialize_ratchet_message(msg: &Message) -> Vec<u8> {
     // Counter (8 bytes)
     buffer.extend_from_slice(&msg.header.counter.to_be_bytes());
 
+    // Previous chain length (8 bytes)
+    buffer.extend_from_slice(&msg.header.previous_chain_length.to_be_bytes());
+
     // Nonce (12 bytes)
     buffer.extend_from_slice(&msg.header.nonce);
 
@@ -262,9 +307,20 @@ pub fn serialize_ratchet_message(msg: &Message) -> Vec<u8> {
     buffer
 }
 
+/// Total length of the serialized ratchet message `data` starts with
+///
+/// None until the `RATCHET_HEADER_LEN` header bytes are there. Lets a
+/// stream transport read messages back to back without a length prefix.
+pub fn ratchet_message_len(data: &[u8]) -> Option<usize> {
+    let length = data.get(RATCHET_HEADER_LEN - 4..RATCHET_HEADER_LEN)?;
+    Some(RATCHET_HEADER_LEN + u32::from_be_bytes(length.try_into().ok()?) as usize)
+}
+
 /// Deserialize a ratchet message from network data
+///
+/// `data` must be exactly one message from `serialize_ratchet_message`.
 pub fn deserialize_ratchet_message(data: &[u8]) -> Result<Message> {
-    if data.len() < 56 {
+    if data.len() < RATCHET_HEADER_LEN {
         anyhow::bail!("Ratchet message too short");
     }
 
@@ -285,6 +341,14 @@ pub fn deserialize_ratchet_message(data: &[u8]) -> Result<Message> {
     );
     offset += 8;
 
+    // Previous chain length
+    let previous_chain_length = u64::from_be_bytes(
+        data[offset..offset + 8]
+            .try_into()
+            .context("Invalid previous chain length")?,
+    );
+    offset += 8;
+
     // Nonce
     let nonce: [u8; 12] = data[offset..offset + 12]
         .try_into()
@@ -299,20 +363,31 @@ pub fn deserialize_ratchet_message(data: &[u8]) -> Result<Message> {
     ) as usize;
     offset += 4;
 
+    if data.len() - offset != ct_len {
+        anyhow::bail!("Ratchet message length mismatch");
+    }
     let ciphertext = data[offset..offset + ct_len].to_vec();
 
     Ok(Message {
         header: MessageHeader {
             x25519_public_key,
             counter,
+            previous_chain_length,
             nonce,
         },
         ciphertext,
     })
 }
 
+/// Largest frame `send_message` writes and `receive_message` accepts
+/// Bigger payloads go through `Session::send_large`
+pub const MAX_MESSAGE_SIZE: usize = 10_000_000;
+
 /// Send a length-prefixed message over TCP
 pub fn send_message(stream: &mut TcpStream, data: &[u8]) -> Result<()> {
+    if data.len() > MAX_MESSAGE_SIZE {
+        anyhow::bail!("Message too large: {} bytes (max {})", data.len(), MAX_MESSAGE_SIZE);
+    }
     let len = data.len() as u32;
     stream
         .write_all(&len.to_be_bytes())
@@ -332,8 +407,8 @@ pub fn receive_message(stream: &mut TcpStream) -> Result<Vec<u8>> {
         .context("Failed to read message length")?;
     let len = u32::from_be_bytes(len_buf) as usize;
 
-    if len > 10_000_000 {
-        anyhow::bail!("Message too large: {} bytes", len);
+    if len > MAX_MESSAGE_SIZE {
+        anyhow::bail!("Message too large: {} bytes (max {})", len, MAX_MESSAGE_SIZE);
     }
 
     let mut buffer = vec![0u8; len];
@@ -342,3 +417,256 @@ pub fn receive_message(stream: &mut TcpStream) -> Result<Vec<u8>> {
         .context("Failed to read message data")?;
     Ok(buffer)
 }
+
+/// Retry schedule for outbound TCP connects
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total connection attempts (at least one is always made)
+    pub attempts: u32,
+    /// Delay after the first failure, doubled after each further failure
+    pub base_delay: Duration,
+    /// Upper bound for the backoff delay (before jitter)
+    pub max_delay: Duration,
+    /// Random extra delay of up to this much is added to every wait,
+    /// so two scripted peers don't retry in lockstep
+    pub max_jitter: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+            max_jitter: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Backoff before attempt number `attempt + 1` (0-based), including jitter
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(1u32 << attempt.min(16))
+            .min(self.max_delay);
+        let jitter_ms = self.max_jitter.as_millis() as u64;
+        let jitter = if jitter_ms > 0 {
+            Duration::from_millis(rand::thread_rng().gen_range(0..=jitter_ms))
+        } else {
+            Duration::ZERO
+        };
+        backoff + jitter
+    }
+}
+
+/// Largest keepalive jitter fraction; keeps every interval at least half its base
+pub const MAX_KEEPALIVE_JITTER: f64 = 0.5;
+
+/// Default keepalive jitter (±20%)
+pub const DEFAULT_KEEPALIVE_JITTER: f64 = 0.2;
+
+/// `base` scaled by a random factor in `1 ± jitter`
+///
+/// Spreads periodic refreshes out so many clients on one interval do not hit
+/// shared NAT or relay infrastructure in lockstep. `jitter` is clamped to
+/// `0..=MAX_KEEPALIVE_JITTER`.
+pub fn jittered(base: Duration, jitter: f64) -> Duration {
+    let jitter = if jitter.is_nan() { 0.0 } else { jitter.clamp(0.0, MAX_KEEPALIVE_JITTER) };
+    if jitter == 0.0 {
+        return base;
+    }
+    base.mul_f64(rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter))
+}
+
+/// Connect over TCP, retrying with jittered exponential backoff
+/// Returns the last error if every attempt fails
+pub fn connect_with_retry<A>(addr: A, policy: &RetryPolicy) -> Result<TcpStream>
+where
+    A: ToSocketAddrs + Display,
+{
+    let attempts = policy.attempts.max(1);
+    let mut attempt = 0;
+
+    loop {
+        match TcpStream::connect(&addr) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempt + 1 < attempts => {
+                let delay = policy.delay_for(attempt);
+                eprintln!(
+                    "Connect to {} failed ({}), retrying in {} ms ({}/{})",
+                    addr,
+                    e,
+                    delay.as_millis(),
+                    attempt + 1,
+                    attempts,
+                );
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Failed to connect to {} after {} attempts", addr, attempts)
+                });
+            }
+        }
+    }
+}
+
+/// TCP options applied to an established chat stream
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm so small chat messages go out immediately
+    pub nodelay: bool,
+    /// Idle time before keepalive probes start (None disables SO_KEEPALIVE)
+    pub keepalive_idle: Option<Duration>,
+    /// Gap between keepalive probes once the connection is idle
+    pub keepalive_interval: Duration,
+    /// Fraction both keepalive times are randomly varied by (see `jittered`)
+    pub keepalive_jitter: f64,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive_idle: Some(Duration::from_secs(60)),
+            keepalive_interval: Duration::from_secs(10),
+            keepalive_jitter: DEFAULT_KEEPALIVE_JITTER,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Apply these options to a connected stream
+    pub fn apply(&self, stream: &TcpStream) -> Result<()> {
+        stream
+            .set_nodelay(self.nodelay)
+            .context("Failed to set TCP_NODELAY")?;
+
+        let socket = socket2::SockRef::from(stream);
+        match self.keepalive_idle {
+            Some(idle) => {
+                let keepalive = socket2::TcpKeepalive::new()
+                    .with_time(jittered(idle, self.keepalive_jitter));
+                #[cfg(any(
+                    target_os = "linux",
+                    target_os = "android",
+                    target_os = "macos",
+                    target_os = "ios",
+                    target_os = "windows",
+                ))]
+                let keepalive = keepalive.with_interval(jittered(self.keepalive_interval, self.keepalive_jitter));
+                socket
+                    .set_tcp_keepalive(&keepalive)
+                    .context("Failed to enable SO_KEEPALIVE")?;
+            }
+            None => {
+                socket
+                    .set_keepalive(false)
+                    .context("Failed to disable SO_KEEPALIVE")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Listen on `port` for both IPv6 and IPv4 peers
+///
+/// Binds `[::]:port` with IPV6_V6ONLY cleared so IPv4 peers arrive as mapped
+/// addresses; falls back to `0.0.0.0:port` on hosts without IPv6.
+pub fn listen_dual_stack(port: u16) -> Result<TcpListener> {
+    match bind_ipv6_any(port) {
+        Ok(listener) => Ok(listener),
+        Err(_) => TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+            .with_context(|| format!("Failed to bind port {}", port)),
+    }
+}
+
+fn bind_ipv6_any(port: u16) -> std::io::Result<TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Check a `host:port` peer address, catching IPv6 literals written without brackets
+pub fn validate_peer_address(address: &str) -> Result<()> {
+    if address.parse::<SocketAddr>().is_ok() {
+        return Ok(());
+    }
+    if address.matches(':').count() > 1 && !address.starts_with('[') {
+        anyhow::bail!("IPv6 addresses must be bracketed as [addr]:port, got '{}'", address);
+    }
+    if !address.contains(':') {
+        anyhow::bail!("Missing port in address '{}' (expected host:port)", address);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn policy(attempts: u32, max_jitter: Duration) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            base_delay: Duration::from_millis(50),
+            max_delay: Duration::from_millis(150),
+            max_jitter,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_plus_jitter() {
+        let delays: Vec<u128> = (0..5).map(|attempt| policy(5, Duration::ZERO).delay_for(attempt).as_millis()).collect();
+        assert_eq!(delays, [50, 100, 150, 150, 150]);
+        assert_eq!(policy(5, Duration::ZERO).delay_for(u32::MAX), Duration::from_millis(150));
+
+        let jittery = policy(5, Duration::from_millis(20));
+        for _ in 0..50 {
+            let delay = jittery.delay_for(1);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(120), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn jitter_stays_within_its_clamped_range() {
+        let base = Duration::from_secs(10);
+        assert_eq!(jittered(base, 0.0), base);
+        assert_eq!(jittered(base, f64::NAN), base);
+        assert_eq!(jittered(base, -1.0), base);
+        for (jitter, low, high) in [(0.2, 8.0, 12.0), (3.0, 5.0, 15.0)] {
+            for _ in 0..100 {
+                let secs = jittered(base, jitter).as_secs_f64();
+                assert!((low..=high).contains(&secs), "{} with jitter {}", secs, jitter);
+            }
+        }
+    }
+
+    #[test]
+    fn connect_retries_until_the_listener_is_up() {
+        // A port that is free now and taken a little later
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(120));
+            let listener = TcpListener::bind(address).unwrap();
+            listener.accept().unwrap();
+        });
+        connect_with_retry(address, &policy(20, Duration::ZERO)).unwrap();
+        listener.join().unwrap();
+    }
+
+    #[test]
+    fn connect_gives_up_after_its_attempts() {
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let started = Instant::now();
+        let error = connect_with_retry(address, &policy(3, Duration::ZERO)).unwrap_err();
+        // Two waits: 50 ms then 100 ms
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert_eq!(error.to_string(), format!("Failed to connect to {} after 3 attempts", address));
+    }
+}
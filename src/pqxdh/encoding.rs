@@ -0,0 +1,186 @@
+/**
+ * pqxdh/encoding.rs
+ *
+ * Byte encoding of a full User, private keys included, for storage
+ */
+
+use super::types::{User, SignedX25519Prekey, SignedMlKem1024Prekey, DEFAULT_REPLAY_WINDOW};
+use anyhow::{Context, Result};
+use ed25519_dalek as ed25519;
+use ml_kem::{kem::DecapsulationKey, EncodedSizeUser, MlKem1024Params};
+use std::collections::VecDeque;
+use x25519_dalek as x25519;
+
+/// Version byte leading every encoded User
+pub const USER_ENCODING_VERSION: u8 = 1;
+
+/// Length of an encoded ML-KEM-1024 decapsulation key
+const MLKEM_DECAP_KEY_LEN: usize = 3168;
+
+impl User {
+    /// Encode this identity with all of its private keys
+    ///
+    /// Layout (integers big-endian):
+    ///   version (1) | identity signing key (32)
+    ///   | X25519 prekey secret (32) | signature (64)
+    ///   | ML-KEM decapsulation key length (4) | key | signature (64)
+    ///   | one-time X25519 count (2) | count x (secret (32) | signature (64))
+    ///   | one-time ML-KEM count (2) | count x (length (4) | key | signature (64))
+    ///
+    /// Public keys are derived again on decode. The output is secret: store
+    /// it like a private key. Ephemeral identities refuse to be encoded, and
+    /// the sealed-message replay history is not kept.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.ephemeral {
+            anyhow::bail!("Ephemeral identities must not be persisted");
+        }
+
+        let mut buffer = vec![USER_ENCODING_VERSION];
+        buffer.extend_from_slice(&self.identity_private_key.to_bytes());
+
+        buffer.extend_from_slice(self.x25519_prekey_private_key.as_bytes());
+        buffer.extend_from_slice(&self.x25519_prekey.signature.to_bytes());
+
+        put_mlkem(&mut buffer, &self.mlkem1024_prekey_decap_key, &self.mlkem1024_prekey);
+
+        buffer.extend_from_slice(&(self.one_time_x25519_prekeys.len() as u16).to_be_bytes());
+        for (secret, prekey) in &self.one_time_x25519_prekeys {
+            buffer.extend_from_slice(secret.as_bytes());
+            buffer.extend_from_slice(&prekey.signature.to_bytes());
+        }
+
+        buffer.extend_from_slice(&(self.one_time_mlkem_prekeys.len() as u16).to_be_bytes());
+        for (decap_key, prekey) in &self.one_time_mlkem_prekeys {
+            put_mlkem(&mut buffer, decap_key, prekey);
+        }
+
+        Ok(buffer)
+    }
+
+    /// Decode an identity written by `to_bytes`
+    ///
+    /// Every prekey signature is checked against the identity key, so a
+    /// corrupted or mismatched encoding fails here instead of producing a
+    /// bundle peers would reject.
+    pub fn from_bytes(data: &[u8]) -> Result<User> {
+        let mut reader = Reader { data, offset: 0 };
+
+        let version = reader.u8().context("Empty user encoding")?;
+        if version != USER_ENCODING_VERSION {
+            anyhow::bail!("Unsupported user encoding version {}", version);
+        }
+
+        let identity_private_key = ed25519::SigningKey::from_bytes(&reader.array().context("Invalid identity key")?);
+        let identity_public_key = identity_private_key.verifying_key();
+
+        let x25519_prekey_private_key = x25519::StaticSecret::from(reader.array::<32>().context("Invalid X25519 prekey")?);
+        let x25519_prekey = SignedX25519Prekey {
+            public_key: x25519::PublicKey::from(&x25519_prekey_private_key),
+            signature: reader.signature().context("Invalid X25519 prekey signature")?,
+        };
+        identity_public_key
+            .verify_strict(x25519_prekey.public_key.as_bytes(), &x25519_prekey.signature)
+            .context("X25519 prekey signature does not match the identity key")?;
+
+        let (mlkem1024_prekey_decap_key, mlkem1024_prekey) =
+            reader.mlkem(&identity_public_key).context("Invalid ML-KEM prekey")?;
+
+        let mut one_time_x25519_prekeys = Vec::new();
+        for _ in 0..reader.u16().context("Invalid one-time X25519 count")? {
+            let secret = x25519::StaticSecret::from(reader.array::<32>().context("Invalid one-time X25519 prekey")?);
+            let prekey = SignedX25519Prekey {
+                public_key: x25519::PublicKey::from(&secret),
+                signature: reader.signature().context("Invalid one-time X25519 signature")?,
+            };
+            identity_public_key
+                .verify_strict(prekey.public_key.as_bytes(), &prekey.signature)
+                .context("One-time X25519 prekey signature does not match the identity key")?;
+            one_time_x25519_prekeys.push((secret, prekey));
+        }
+
+        let mut one_time_mlkem_prekeys = Vec::new();
+        for _ in 0..reader.u16().context("Invalid one-time ML-KEM count")? {
+            one_time_mlkem_prekeys.push(reader.mlkem(&identity_public_key).context("Invalid one-time ML-KEM prekey")?);
+        }
+
+        if reader.offset != data.len() {
+            anyhow::bail!("{} trailing bytes after user encoding", data.len() - reader.offset);
+        }
+
+        Ok(User {
+            identity_private_key,
+            identity_public_key,
+            x25519_prekey_private_key,
+            x25519_prekey,
+            mlkem1024_prekey_decap_key,
+            mlkem1024_prekey,
+            one_time_x25519_prekeys,
+            one_time_mlkem_prekeys,
+            ephemeral: false,
+            seen_init_messages: VecDeque::new(),
+            replay_window: DEFAULT_REPLAY_WINDOW,
+        })
+    }
+}
+
+fn put_mlkem(buffer: &mut Vec<u8>, decap_key: &DecapsulationKey<MlKem1024Params>, prekey: &SignedMlKem1024Prekey) {
+    let key_bytes = decap_key.as_bytes();
+    buffer.extend_from_slice(&(key_bytes.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&key_bytes);
+    buffer.extend_from_slice(&prekey.signature.to_bytes());
+}
+
+/// Bounds-checked cursor over an encoded User
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + len)
+            .context("User encoding truncated")?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn signature(&mut self) -> Result<ed25519::Signature> {
+        Ok(ed25519::Signature::from_bytes(&self.array()?))
+    }
+
+    fn mlkem(
+        &mut self,
+        identity_public_key: &ed25519::VerifyingKey,
+    ) -> Result<(DecapsulationKey<MlKem1024Params>, SignedMlKem1024Prekey)> {
+        let len = u32::from_be_bytes(self.array()?) as usize;
+        if len != MLKEM_DECAP_KEY_LEN {
+            anyhow::bail!("Invalid ML-KEM-1024 decapsulation key length: {}", len);
+        }
+        let key_bytes: &[u8; MLKEM_DECAP_KEY_LEN] = self.take(len)?.try_into()?;
+        let decap_key = DecapsulationKey::<MlKem1024Params>::from_bytes(key_bytes.into());
+
+        let prekey = SignedMlKem1024Prekey {
+            encap_key: decap_key.encapsulation_key().clone(),
+            signature: self.signature()?,
+        };
+        identity_public_key
+            .verify_strict(&prekey.encap_key.as_bytes(), &prekey.signature)
+            .context("ML-KEM prekey signature does not match the identity key")?;
+
+        Ok((decap_key, prekey))
+    }
+}
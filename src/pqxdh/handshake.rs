@@ -5,9 +5,10 @@
 use super::types::{User, PQXDHInitOutput, PQXDHInitMessage};
 use super::conversions::{ed25519_sk_to_x25519, ed25519_pk_to_x25519};
 use anyhow::{Context, Error};
+use ed25519_dalek as ed25519;
 use ml_kem::{
-    EncodedSizeUser,
-    kem::{Encapsulate, Decapsulate},
+    EncodedSizeUser, MlKem1024Params,
+    kem::{Encapsulate, Decapsulate, DecapsulationKey},
 };
 use sha3::{Shake256, digest::{ExtendableOutput, Update}};
 use x25519_dalek as x25519;
@@ -16,6 +17,14 @@ use x25519_dalek as x25519;
  * TODO-RENAME : Function and parameter names are mid
  */
 pub fn init_pqxdh(alice: &User, bob: &User) -> Result<PQXDHInitOutput, Error> {
+    initiate_with_identity(&alice.identity_private_key, bob)
+}
+
+/// Initiator side of PQXDH for an identity key that need not belong to a full `User`
+pub(super) fn initiate_with_identity(
+    identity_private_key: &ed25519::SigningKey,
+    bob: &User,
+) -> Result<PQXDHInitOutput, Error> {
     /**
      * TODO : This is deprecated, so I have to replace this
      * It seems to be just a rename though...
@@ -67,7 +76,8 @@ pub fn init_pqxdh(alice: &User, bob: &User) -> Result<PQXDHInitOutput, Error> {
         };
 
     // Convert the Ed25519 keys to X25519 keys for the Diffie-Hellman key exchanges
-    let alice_identity_secret_key_x25519 = ed25519_sk_to_x25519(&alice.identity_private_key);
+    let alice_identity_public_key = identity_private_key.verifying_key();
+    let alice_identity_secret_key_x25519 = ed25519_sk_to_x25519(identity_private_key);
     let bob_identity_public_key_x25519 = ed25519_pk_to_x25519(&bob.identity_public_key);
 
     // DH1 = DH(IKA, SPKB)
@@ -102,11 +112,11 @@ pub fn init_pqxdh(alice: &User, bob: &User) -> Result<PQXDHInitOutput, Error> {
 
     // Construct associated data: EncodeEC(IK_A) || EncodeEC(IK_B)
     let mut associated_data = Vec::new();
-    associated_data.extend_from_slice(alice.identity_public_key.as_bytes());
+    associated_data.extend_from_slice(alice_identity_public_key.as_bytes());
     associated_data.extend_from_slice(bob.identity_public_key.as_bytes());
 
     let init_message = PQXDHInitMessage {
-        peer_identity_public_key: alice.identity_public_key,
+        peer_identity_public_key: alice_identity_public_key,
         ephemeral_x25519_public_key: x25519::PublicKey::from(&ephemeral_x25519_private_key),
         mlkem_ciphertext: mlkem_ciphertext.to_vec(),
         used_one_time_x25519,
@@ -121,20 +131,115 @@ pub fn init_pqxdh(alice: &User, bob: &User) -> Result<PQXDHInitOutput, Error> {
     })
 }
 
+/// Why an init message was refused before running the handshake
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// Already accepted once (see `User::set_replay_window`)
+    Replayed,
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::Replayed => write!(f, "Init message was already accepted"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Responder side of PQXDH, consuming the one-time prekeys the message used
+///
+/// A message already accepted within the replay window is refused with
+/// `HandshakeError::Replayed` before any prekey is touched.
 pub fn complete_pqxdh(bob: &mut User, message: &PQXDHInitMessage) -> Result<([u8; 32], Vec<u8>), Error> {
-    // Decapsulate using the appropriate ML-KEM key
-    let mlkem_shared_secret = if message.used_one_time_mlkem {
+    let id = message.id();
+    if bob.seen_init_message(&id) {
+        return Err(HandshakeError::Replayed.into());
+    }
+
+    let one_time_mlkem = if message.used_one_time_mlkem {
         if bob.one_time_mlkem_prekeys.is_empty() {
             return Err(Error::msg("One-time ML-KEM prekey was used but not available"));
         }
-        let (decap_key, _) = bob.one_time_mlkem_prekeys.remove(0);
-        decap_key
-            .decapsulate(message.mlkem_ciphertext.as_slice().try_into().unwrap())
-            .map_err(|_| Error::msg("failed to decapsulate with one-time ML-KEM-1024"))?
+        Some(bob.one_time_mlkem_prekeys.remove(0).0)
     } else {
-        bob.mlkem1024_prekey_decap_key
-            .decapsulate(message.mlkem_ciphertext.as_slice().try_into().unwrap())
-            .map_err(|_| Error::msg("failed to decapsulate with ML-KEM-1024"))?
+        None
+    };
+
+    let one_time_x25519 = if message.used_one_time_x25519 {
+        if bob.one_time_x25519_prekeys.is_empty() {
+            return Err(Error::msg("One-time X25519 prekey was used but not available"));
+        }
+        Some(bob.one_time_x25519_prekeys.remove(0).0)
+    } else {
+        None
+    };
+
+    // One-time prekey private keys are deleted above when removed from the vectors (forward secrecy)
+    let keys = respond_with_prekeys(bob, message, one_time_x25519.as_ref(), one_time_mlkem.as_ref())?;
+    bob.remember_init_message(id);
+    Ok(keys)
+}
+
+impl PQXDHInitMessage {
+    /// Identifies an init message by its ephemeral key and KEM ciphertext, fresh per handshake
+    pub(crate) fn id(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new_derive_key("PINEAPPLE_INIT_MESSAGE_ID");
+        hasher.update(self.ephemeral_x25519_public_key.as_bytes());
+        hasher.update(&self.mlkem_ciphertext);
+        hasher.finalize().into()
+    }
+}
+
+impl User {
+    /// How many accepted init messages to remember (0 turns replay checks off)
+    ///
+    /// Covers both `complete_pqxdh` and `User::open`, so a relay delivering
+    /// a stored first message twice cannot start a second session from it.
+    /// The window is held in memory only.
+    pub fn set_replay_window(&mut self, messages: usize) {
+        self.replay_window = messages;
+        while self.seen_init_messages.len() > messages {
+            self.seen_init_messages.pop_front();
+        }
+    }
+
+    pub(super) fn seen_init_message(&self, id: &[u8; 32]) -> bool {
+        self.seen_init_messages.contains(id)
+    }
+
+    pub(super) fn remember_init_message(&mut self, id: [u8; 32]) {
+        if self.replay_window == 0 {
+            return;
+        }
+        if self.seen_init_messages.len() == self.replay_window {
+            self.seen_init_messages.pop_front();
+        }
+        self.seen_init_messages.push_back(id);
+    }
+}
+
+/// Responder side of PQXDH with the one-time prekeys (if any) chosen by the caller
+///
+/// Nothing is removed from `bob`; deleting used one-time prekeys is up to the caller.
+pub(super) fn respond_with_prekeys(
+    bob: &User,
+    message: &PQXDHInitMessage,
+    one_time_x25519: Option<&x25519::StaticSecret>,
+    one_time_mlkem: Option<&DecapsulationKey<MlKem1024Params>>,
+) -> Result<([u8; 32], Vec<u8>), Error> {
+    let ciphertext = message.mlkem_ciphertext.as_slice().try_into()
+        .map_err(|_| Error::msg("invalid ML-KEM-1024 ciphertext length"))?;
+
+    // Decapsulate using the appropriate ML-KEM key
+    let mlkem_shared_secret = match one_time_mlkem {
+        Some(decap_key) => decap_key
+            .decapsulate(ciphertext)
+            .map_err(|_| Error::msg("failed to decapsulate with one-time ML-KEM-1024"))?,
+        None => bob.mlkem1024_prekey_decap_key
+            .decapsulate(ciphertext)
+            .map_err(|_| Error::msg("failed to decapsulate with ML-KEM-1024"))?,
     };
 
     // Convert the Ed25519 keys to X25519 keys for the Diffie-Hellman key exchanges
@@ -151,16 +256,8 @@ pub fn complete_pqxdh(bob: &mut User, message: &PQXDHInitMessage) -> Result<([u8
         .diffie_hellman(&message.ephemeral_x25519_public_key);
 
     // DH4 if one-time prekey was used
-    let dh_4_opt = if message.used_one_time_x25519 {
-        if bob.one_time_x25519_prekeys.is_empty() {
-            return Err(Error::msg("One-time X25519 prekey was used but not available"));
-        }
-        let (opk_secret, _) = bob.one_time_x25519_prekeys.remove(0);
-        let dh4 = opk_secret.diffie_hellman(&message.ephemeral_x25519_public_key);
-        Some(dh4)
-    } else {
-        None
-    };
+    let dh_4_opt = one_time_x25519
+        .map(|opk_secret| opk_secret.diffie_hellman(&message.ephemeral_x25519_public_key));
 
     // SK = KDF(DH1 || DH2 || DH3 [|| DH4] || SS)
     let secret_key = kdf(
@@ -176,8 +273,6 @@ pub fn complete_pqxdh(bob: &mut User, message: &PQXDHInitMessage) -> Result<([u8
     associated_data.extend_from_slice(message.peer_identity_public_key.as_bytes());
     associated_data.extend_from_slice(bob.identity_public_key.as_bytes());
 
-    // One-time prekey private keys are deleted above when removed from the vectors (forward secrecy)
-
     Ok((secret_key, associated_data))
 }
 
@@ -6,8 +6,16 @@
 mod types;
 mod handshake;
 mod conversions;
+mod sealed;
+mod encoding;
+mod signature;
 
 /* ...are selectively made available publicly */
 pub use types::{User, PQXDHInitOutput, PQXDHInitMessage, SignedX25519Prekey, SignedMlKem1024Prekey};
-pub use handshake::{init_pqxdh, complete_pqxdh};
+pub use types::{fingerprint, is_fingerprint, FINGERPRINT_LEN, DEFAULT_REPLAY_WINDOW};
+pub use types::{SignatureAlgorithm, IDENTITY_ALGORITHM, check_identity_algorithm};
+pub use signature::{SignatureScheme, Ed25519, Ed448};
+pub use handshake::{init_pqxdh, complete_pqxdh, HandshakeError};
+pub use sealed::{seal, SealedMessage, SealError};
+pub use encoding::USER_ENCODING_VERSION;
 pub use conversions::{ed25519_sk_to_x25519, ed25519_pk_to_x25519};
@@ -0,0 +1,237 @@
+/**
+ * pqxdh/sealed.rs
+ *
+ * One-shot messages sealed to a prekey bundle, with no ratchet state
+ */
+
+use super::handshake::{initiate_with_identity, respond_with_prekeys};
+use super::types::{User, PQXDHInitMessage};
+use aes_gcm::{Aes256Gcm, KeyInit, aead::{Aead, Payload}};
+use anyhow::{Error, Result};
+use ed25519_dalek as ed25519;
+use ml_kem::EncodedSizeUser;
+use x25519_dalek as x25519;
+
+/// A message encrypted to a peer's prekey bundle in a single PQXDH exchange
+///
+/// The sender uses a throwaway identity, so the recipient learns nothing
+/// about who sealed it. The prekeys used are named so the recipient can
+/// tell a stale bundle from a corrupted message.
+pub struct SealedMessage {
+    pub init_message: PQXDHInitMessage,
+    /// Signed X25519 prekey the message was sealed to
+    pub x25519_prekey: x25519::PublicKey,
+    /// One-time X25519 prekey used, if the bundle had one
+    pub one_time_x25519_prekey: Option<x25519::PublicKey>,
+    /// BLAKE3 hash of the one-time ML-KEM prekey used, if the bundle had one
+    pub one_time_mlkem_prekey: Option<[u8; 32]>,
+    pub ciphertext: Vec<u8>,
+}
+
+/// Why a sealed message could not be opened
+#[derive(Debug)]
+pub enum SealError {
+    /// Sealed to a signed prekey this user no longer has
+    StalePrekey,
+    /// Sealed to a one-time prekey that was already used up
+    PrekeyConsumed,
+    /// Already opened once (see `User::set_replay_window`)
+    Replayed,
+    DecryptFailed,
+}
+
+impl std::fmt::Display for SealError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SealError::StalePrekey => write!(f, "Sealed to an outdated signed prekey"),
+            SealError::PrekeyConsumed => write!(f, "Sealed to a one-time prekey that has already been used"),
+            SealError::Replayed => write!(f, "Sealed message was already opened"),
+            SealError::DecryptFailed => write!(f, "Failed to decrypt sealed message"),
+        }
+    }
+}
+
+impl std::error::Error for SealError {}
+
+/// Encrypt `plaintext` to the owner of `peer_bundle` without setting up a session
+///
+/// The bundle's prekey signatures are verified first. A one-time prekey in
+/// the bundle is used if present; the bundle should then not be reused.
+pub fn seal(peer_bundle: &User, plaintext: &[u8]) -> Result<SealedMessage> {
+    let mut rng = rand::thread_rng();
+    let sender_identity = ed25519::SigningKey::generate(&mut rng);
+    let output = initiate_with_identity(&sender_identity, peer_bundle)?;
+
+    let one_time_x25519_prekey = output.message.used_one_time_x25519
+        .then(|| peer_bundle.one_time_x25519_prekeys[0].1.public_key);
+    let one_time_mlkem_prekey = output.message.used_one_time_mlkem
+        .then(|| mlkem_prekey_id(&peer_bundle.one_time_mlkem_prekeys[0].1.encap_key.as_bytes()));
+
+    let (key, nonce) = sealing_key(&output.secret_key);
+    let ciphertext = Aes256Gcm::new(&key.into())
+        .encrypt((&nonce).into(), Payload { msg: plaintext, aad: &output.associated_data })
+        .map_err(|_| Error::msg("Failed to encrypt sealed message"))?;
+
+    Ok(SealedMessage {
+        init_message: output.message,
+        x25519_prekey: output.bob_ratchet_key,
+        one_time_x25519_prekey,
+        one_time_mlkem_prekey,
+        ciphertext,
+    })
+}
+
+impl User {
+    /// Decrypt a message sealed to this user's prekey bundle
+    ///
+    /// One-time prekeys it used are deleted only once it has decrypted,
+    /// so a forged message cannot burn them. A message delivered again is
+    /// refused with `Replayed` while it is in the replay window, and after
+    /// that with `PrekeyConsumed` if it used a one-time prekey.
+    pub fn open(&mut self, sealed: &SealedMessage) -> Result<Vec<u8>> {
+        let id = sealed.init_message.id();
+        if self.seen_init_message(&id) {
+            return Err(SealError::Replayed.into());
+        }
+        if sealed.x25519_prekey != self.x25519_prekey.public_key {
+            return Err(SealError::StalePrekey.into());
+        }
+        if sealed.init_message.used_one_time_x25519 != sealed.one_time_x25519_prekey.is_some()
+            || sealed.init_message.used_one_time_mlkem != sealed.one_time_mlkem_prekey.is_some()
+        {
+            return Err(SealError::DecryptFailed.into());
+        }
+
+        let x25519_index = match sealed.one_time_x25519_prekey {
+            Some(public_key) => Some(
+                self.one_time_x25519_prekeys
+                    .iter()
+                    .position(|(_, prekey)| prekey.public_key == public_key)
+                    .ok_or(SealError::PrekeyConsumed)?,
+            ),
+            None => None,
+        };
+        let mlkem_index = match sealed.one_time_mlkem_prekey {
+            Some(id) => Some(
+                self.one_time_mlkem_prekeys
+                    .iter()
+                    .position(|(_, prekey)| mlkem_prekey_id(&prekey.encap_key.as_bytes()) == id)
+                    .ok_or(SealError::PrekeyConsumed)?,
+            ),
+            None => None,
+        };
+
+        let (secret_key, associated_data) = respond_with_prekeys(
+            self,
+            &sealed.init_message,
+            x25519_index.map(|i| &self.one_time_x25519_prekeys[i].0),
+            mlkem_index.map(|i| &self.one_time_mlkem_prekeys[i].0),
+        )?;
+
+        let (key, nonce) = sealing_key(&secret_key);
+        let plaintext = Aes256Gcm::new(&key.into())
+            .decrypt((&nonce).into(), Payload { msg: &sealed.ciphertext, aad: &associated_data })
+            .map_err(|_| SealError::DecryptFailed)?;
+
+        // Forward secrecy: the one-time prekeys are gone once the message is read
+        if let Some(i) = x25519_index {
+            self.one_time_x25519_prekeys.remove(i);
+        }
+        if let Some(i) = mlkem_index {
+            self.one_time_mlkem_prekeys.remove(i);
+        }
+        self.remember_init_message(id);
+
+        Ok(plaintext)
+    }
+}
+
+/// Identifier of a one-time ML-KEM prekey, short enough to carry in every sealed message
+fn mlkem_prekey_id(encap_key: &[u8]) -> [u8; 32] {
+    blake3::hash(encap_key).into()
+}
+
+/// AES key and nonce for a sealed message; the secret is fresh per message
+fn sealing_key(secret_key: &[u8; 32]) -> ([u8; 32], [u8; 12]) {
+    let key = blake3::derive_key("PINEAPPLE_SEALED_KEY", secret_key);
+    let nonce = blake3::derive_key("PINEAPPLE_SEALED_NONCE", secret_key);
+    (key, nonce[..12].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{deserialize_prekey_bundle, deserialize_sealed_message, serialize_prekey_bundle, serialize_sealed_message};
+
+    /// The copy of `user`'s prekey bundle a sender would fetch
+    fn bundle(user: &User) -> User {
+        deserialize_prekey_bundle(&serialize_prekey_bundle(user)).unwrap()
+    }
+
+    fn seal_error(result: Result<Vec<u8>>) -> SealError {
+        let error = result.expect_err("the sealed message was opened");
+        error.downcast::<SealError>().unwrap()
+    }
+
+    #[test]
+    fn sealed_message_round_trips_through_the_wire_format() {
+        let mut bob = User::new();
+        let (x25519_before, mlkem_before) = bob.one_time_prekey_count();
+        let sealed = seal(&bundle(&bob), b"hello").unwrap();
+        assert!(sealed.one_time_x25519_prekey.is_some() && sealed.one_time_mlkem_prekey.is_some());
+
+        let received = deserialize_sealed_message(&serialize_sealed_message(&sealed)).unwrap();
+        assert_eq!(bob.open(&received).unwrap(), b"hello");
+        assert_eq!(bob.one_time_prekey_count(), (x25519_before - 1, mlkem_before - 1));
+    }
+
+    #[test]
+    fn consumed_one_time_prekey_is_reported() {
+        let mut bob = User::new();
+        let bundle = bundle(&bob);
+        let first = seal(&bundle, b"first").unwrap();
+        let second = seal(&bundle, b"second").unwrap();
+
+        assert_eq!(bob.open(&first).unwrap(), b"first");
+        assert!(matches!(seal_error(bob.open(&second)), SealError::PrekeyConsumed));
+    }
+
+    #[test]
+    fn rotated_signed_prekey_is_reported_as_stale() {
+        let mut bob = User::new();
+        let sealed = seal(&bundle(&bob), b"hello").unwrap();
+
+        let rotated = x25519::StaticSecret::random_from_rng(rand::thread_rng());
+        bob.x25519_prekey.public_key = x25519::PublicKey::from(&rotated);
+        bob.x25519_prekey_private_key = rotated;
+        let counts = bob.one_time_prekey_count();
+        assert!(matches!(seal_error(bob.open(&sealed)), SealError::StalePrekey));
+        assert_eq!(bob.one_time_prekey_count(), counts);
+    }
+
+    #[test]
+    fn tampered_message_does_not_burn_the_one_time_prekeys() {
+        let mut bob = User::new();
+        let mut sealed = seal(&bundle(&bob), b"hello").unwrap();
+        let counts = bob.one_time_prekey_count();
+
+        sealed.ciphertext[0] ^= 0x01;
+        assert!(matches!(seal_error(bob.open(&sealed)), SealError::DecryptFailed));
+        assert_eq!(bob.one_time_prekey_count(), counts);
+
+        sealed.ciphertext[0] ^= 0x01;
+        assert_eq!(bob.open(&sealed).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn message_sealed_to_the_signed_prekey_opens_once() {
+        let mut bob = User::new();
+        let mut bundle = bundle(&bob);
+        bundle.one_time_x25519_prekeys.clear();
+        bundle.one_time_mlkem_prekeys.clear();
+        let sealed = seal(&bundle, b"hello").unwrap();
+
+        assert_eq!(bob.open(&sealed).unwrap(), b"hello");
+        assert!(matches!(seal_error(bob.open(&sealed)), SealError::Replayed));
+    }
+}
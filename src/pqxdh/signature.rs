@@ -0,0 +1,127 @@
+/**
+ * pqxdh/signature.rs
+ *
+ * Identity signature schemes behind one trait
+ */
+
+use super::types::SignatureAlgorithm;
+use anyhow::{Context, Result};
+use ed25519_dalek::{Signer, Verifier};
+use rand::rngs::OsRng;
+
+/// An identity signature algorithm
+///
+/// Signatures travel as plain bytes of `SIGNATURE_LEN`, so wire formats only
+/// need the algorithm id to know how to parse them.
+pub trait SignatureScheme {
+    /// Id carried on the wire next to signatures of this scheme
+    const ALGORITHM: SignatureAlgorithm;
+    /// Length of an encoded signature
+    const SIGNATURE_LEN: usize;
+
+    type SigningKey: Clone + Send + Sync;
+    type VerifyingKey: Clone + Send + Sync;
+
+    /// Fresh signing key from the OS RNG
+    fn generate() -> Self::SigningKey;
+
+    fn verifying_key(signing_key: &Self::SigningKey) -> Self::VerifyingKey;
+
+    /// Sign `message`, returning `SIGNATURE_LEN` bytes
+    fn sign(signing_key: &Self::SigningKey, message: &[u8]) -> Vec<u8>;
+
+    /// Check `signature` over `message`
+    fn verify(verifying_key: &Self::VerifyingKey, message: &[u8], signature: &[u8]) -> Result<()>;
+}
+
+/// Ed25519 (RFC 8032), the default and the only scheme PQXDH identities use
+pub struct Ed25519;
+
+impl SignatureScheme for Ed25519 {
+    const ALGORITHM: SignatureAlgorithm = SignatureAlgorithm::Ed25519;
+    const SIGNATURE_LEN: usize = ed25519_dalek::SIGNATURE_LENGTH;
+
+    type SigningKey = ed25519_dalek::SigningKey;
+    type VerifyingKey = ed25519_dalek::VerifyingKey;
+
+    fn generate() -> Self::SigningKey {
+        ed25519_dalek::SigningKey::generate(&mut OsRng)
+    }
+
+    fn verifying_key(signing_key: &Self::SigningKey) -> Self::VerifyingKey {
+        signing_key.verifying_key()
+    }
+
+    fn sign(signing_key: &Self::SigningKey, message: &[u8]) -> Vec<u8> {
+        signing_key.sign(message).to_bytes().to_vec()
+    }
+
+    fn verify(verifying_key: &Self::VerifyingKey, message: &[u8], signature: &[u8]) -> Result<()> {
+        let signature = ed25519_dalek::Signature::from_slice(signature).context("Invalid Ed25519 signature")?;
+        verifying_key.verify(message, &signature).context("Ed25519 signature mismatch")
+    }
+}
+
+/// Ed448-Goldilocks (RFC 8032), for deployments wanting a larger security margin
+pub struct Ed448;
+
+impl SignatureScheme for Ed448 {
+    const ALGORITHM: SignatureAlgorithm = SignatureAlgorithm::Ed448;
+    const SIGNATURE_LEN: usize = ed448_goldilocks_plus::SIGNATURE_LENGTH;
+
+    type SigningKey = ed448_goldilocks_plus::SigningKey;
+    type VerifyingKey = ed448_goldilocks_plus::VerifyingKey;
+
+    fn generate() -> Self::SigningKey {
+        ed448_goldilocks_plus::SigningKey::generate(&mut OsRng)
+    }
+
+    fn verifying_key(signing_key: &Self::SigningKey) -> Self::VerifyingKey {
+        signing_key.verifying_key()
+    }
+
+    fn sign(signing_key: &Self::SigningKey, message: &[u8]) -> Vec<u8> {
+        signing_key.sign_raw(message).to_bytes().to_vec()
+    }
+
+    fn verify(verifying_key: &Self::VerifyingKey, message: &[u8], signature: &[u8]) -> Result<()> {
+        let bytes = signature.try_into().context("Invalid Ed448 signature length")?;
+        let signature = ed448_goldilocks_plus::Signature::from_bytes(bytes)
+            .map_err(|e| anyhow::anyhow!("Invalid Ed448 signature: {}", e))?;
+        verifying_key
+            .verify_raw(&signature, message)
+            .map_err(|e| anyhow::anyhow!("Ed448 signature mismatch: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign_and_verify<S: SignatureScheme>() {
+        let key = S::generate();
+        let signature = S::sign(&key, b"message");
+        assert_eq!(signature.len(), S::SIGNATURE_LEN);
+        S::verify(&S::verifying_key(&key), b"message", &signature).unwrap();
+        assert!(S::verify(&S::verifying_key(&key), b"massage", &signature).is_err());
+        assert!(S::verify(&S::verifying_key(&S::generate()), b"message", &signature).is_err());
+    }
+
+    #[test]
+    fn both_schemes_sign_and_verify() {
+        sign_and_verify::<Ed25519>();
+        sign_and_verify::<Ed448>();
+    }
+
+    #[test]
+    fn bundles_of_another_algorithm_are_refused() {
+        let mut bundle = crate::network::serialize_prekey_bundle(&crate::pqxdh::User::new());
+        assert!(crate::network::deserialize_prekey_bundle(&bundle).is_ok());
+
+        bundle[0] = Ed448::ALGORITHM.id();
+        let Err(error) = crate::network::deserialize_prekey_bundle(&bundle) else {
+            panic!("an Ed448 bundle was accepted");
+        };
+        assert_eq!(error.to_string(), "Peer identity uses Ed448, this side uses Ed25519");
+    }
+}
@@ -3,6 +3,7 @@
  */
 
 use ed25519_dalek::{self as ed25519, Signer};
+use std::collections::VecDeque;
 use ml_kem::{
     kem::{DecapsulationKey, EncapsulationKey},
     EncodedSizeUser, KemCore, MlKem1024, MlKem1024Params,
@@ -22,6 +23,13 @@ pub struct User {
     // One-time prekeys for enhanced forward secrecy
     pub(crate) one_time_x25519_prekeys: Vec<(x25519::StaticSecret, SignedX25519Prekey)>,
     pub(crate) one_time_mlkem_prekeys: Vec<(DecapsulationKey<MlKem1024Params>, SignedMlKem1024Prekey)>,
+
+    // Burner identity that must never be persisted
+    pub(crate) ephemeral: bool,
+
+    // Ids of recently accepted init messages, newest last
+    pub(crate) seen_init_messages: VecDeque<[u8; 32]>,
+    pub(crate) replay_window: usize,
 }
 
 #[derive(Clone)]
@@ -113,9 +121,33 @@ impl User {
             mlkem1024_prekey,
             one_time_x25519_prekeys,
             one_time_mlkem_prekeys,
+            ephemeral: false,
+            seen_init_messages: VecDeque::new(),
+            replay_window: DEFAULT_REPLAY_WINDOW,
         }
     }
 
+    /// Create a throwaway identity for a burner conversation
+    ///
+    /// The identity key is freshly generated and not linked to any long-term
+    /// key, so the peer cannot recognise you across ephemeral sessions.
+    /// It is NOT meant to be persisted; drop it once the session is set up.
+    pub fn new_ephemeral() -> User {
+        let mut user = User::new();
+        user.ephemeral = true;
+        user
+    }
+
+    /// Whether this identity was created with `new_ephemeral`
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    /// Fingerprint of this user's identity key (see `fingerprint`)
+    pub fn fingerprint(&self) -> String {
+        fingerprint(self.identity_public_key.as_bytes())
+    }
+
     /// Create a User representation from public keys only (for remote peer)
     pub fn from_public_keys(
         identity_public_key: ed25519::VerifyingKey,
@@ -152,6 +184,9 @@ impl User {
             mlkem1024_prekey,
             one_time_x25519_prekeys,
             one_time_mlkem_prekeys,
+            ephemeral: false,
+            seen_init_messages: VecDeque::new(),
+            replay_window: DEFAULT_REPLAY_WINDOW,
         }
     }
 
@@ -160,3 +195,68 @@ impl User {
         (self.one_time_x25519_prekeys.len(), self.one_time_mlkem_prekeys.len())
     }
 }
+
+/// Init messages a user remembers having accepted, to refuse replays
+pub const DEFAULT_REPLAY_WINDOW: usize = 64;
+
+/// Length of an identity fingerprint in hex characters
+pub const FINGERPRINT_LEN: usize = 32;
+
+/// Fingerprint of an Ed25519 identity key: 16 bytes of a keyed BLAKE3 hash, hex-encoded
+pub fn fingerprint(identity_public_key: &[u8]) -> String {
+    let hash = blake3::derive_key("PINEAPPLE_FINGERPRINT", identity_public_key);
+    hex::encode(&hash[..FINGERPRINT_LEN / 2])
+}
+
+/// Whether a string has the shape of an identity fingerprint
+pub fn is_fingerprint(value: &str) -> bool {
+    value.len() == FINGERPRINT_LEN && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Identity signature algorithms known to the wire formats
+///
+/// The id is carried in prekey bundles and UDP probes so peers using
+/// different algorithms refuse each other up front.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    Ed25519,
+    Ed448,
+}
+
+impl SignatureAlgorithm {
+    /// Wire identifier
+    pub fn id(self) -> u8 {
+        match self {
+            SignatureAlgorithm::Ed25519 => 1,
+            SignatureAlgorithm::Ed448 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(SignatureAlgorithm::Ed25519),
+            2 => Some(SignatureAlgorithm::Ed448),
+            _ => None,
+        }
+    }
+
+    /// Fail unless a peer's algorithm id is this algorithm
+    pub fn check_peer(self, id: u8) -> anyhow::Result<()> {
+        match SignatureAlgorithm::from_id(id) {
+            Some(algorithm) if algorithm == self => Ok(()),
+            Some(algorithm) => anyhow::bail!("Peer identity uses {:?}, this side uses {:?}", algorithm, self),
+            None => anyhow::bail!("Unknown identity signature algorithm {}", id),
+        }
+    }
+}
+
+/// Algorithm PQXDH identities sign with
+///
+/// Identity keys double as X25519 keys in the handshake, so prekey bundles
+/// are always Ed25519. Other `SignatureScheme`s sign probes only.
+pub const IDENTITY_ALGORITHM: SignatureAlgorithm = SignatureAlgorithm::Ed25519;
+
+/// Fail unless a peer's algorithm id matches `IDENTITY_ALGORITHM`
+pub fn check_identity_algorithm(id: u8) -> anyhow::Result<()> {
+    IDENTITY_ALGORITHM.check_peer(id)
+}
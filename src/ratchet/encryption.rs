@@ -2,10 +2,11 @@
  * ratchet/encryption.rs
  */
 
-use super::types::{RatchetState, Message, MessageHeader};
-use super::kdf::{kdf_root_key, kdf_chain_key};
+use super::types::{RatchetState, Message, MessageHeader, NonceScheme, RatchetError, SkippedChain, MAX_SKIP, EVICTED_CHAIN_MEMORY};
+use super::kdf::{kdf_root_key, kdf_chain_key, kdf_nonce, kdf_mix_root_key};
 use aes_gcm::{Aes256Gcm, KeyInit, aead::{AeadMut, Payload}};
 use anyhow::{Error};
+use std::collections::BTreeMap;
 use x25519_dalek as x25519;
 
 pub fn send_message(state: &mut RatchetState, plaintext: &str, additional_data: &[u8]) -> Result<Message, Error> {
@@ -18,74 +19,418 @@ pub fn send_bytes(state: &mut RatchetState, data: &[u8], additional_data: &[u8])
     state.chain_key_sending = new_chain_key_sending;
 
     // Safe to use random nonce as each message uses a different key
-    let nonce: [u8; 12] = rand::random();
+    let (nonce, header_nonce) = match state.nonce_scheme {
+        NonceScheme::Random => {
+            let nonce: [u8; 12] = rand::random();
+            (nonce, nonce)
+        }
+        NonceScheme::DerivedFromKey => (kdf_nonce(&message_key), [0u8; 12]),
+    };
 
     let header = MessageHeader {
         x25519_public_key: state.sending_x25519_public_key,
-        counter: state.sending_counter,
-        nonce,
+        counter: state.sending_chain_length,
+        previous_chain_length: state.previous_sending_chain_length,
+        nonce: header_nonce,
     };
 
     // ENCRYPT(mk, data, AD || header)
-    let mut cipher = Aes256Gcm::new(&message_key.try_into().unwrap());
+    let mut cipher = Aes256Gcm::new(&message_key.into());
     let ciphertext = cipher
         .encrypt(
             (&nonce).into(),
             Payload {
                 msg: data,
-                aad: additional_data,
+                aad: &header_aad(additional_data, &header),
             },
         )
         .map_err(|_| Error::msg("Failed to encrypt message"))?;
 
     state.sending_counter += 1;
+    state.sending_chain_length += 1;
 
     Ok(Message { header, ciphertext })
 }
 
+/// State changes a message would cause, worked out without touching the ratchet
+enum ReceivePlan {
+    /// Decrypted with a stored skipped key (chain index in skipped_chains)
+    Skipped { chain: usize, plaintext: Vec<u8> },
+    /// Decrypted by advancing the receiving chain, possibly after a DH step
+    /// (which consumes any scheduled rekey secrets)
+    Advance {
+        root_key: [u8; 32],
+        chain_key_receiving: [u8; 32],
+        receiving_chain_length: u64,
+        new_sending_chain: Option<(x25519::StaticSecret, x25519::PublicKey, [u8; 32])>,
+        skipped: Vec<(x25519::PublicKey, u64, [u8; 32])>,
+        plaintext: Vec<u8>,
+    },
+}
+
+/// Decrypt a message, skipping and storing keys for any messages it overtook
+///
+/// All state changes are computed on the side and only applied once the
+/// message authenticates, so a forged or corrupted message leaves the
+/// ratchet exactly as it was.
 pub fn receive_message(state: &mut RatchetState, message: Message, additional_data: &[u8]) -> Result<Vec<u8>, Error> {
+    let header = message.header;
+
+    match plan_receive(state, &message, additional_data)? {
+        ReceivePlan::Skipped { chain, plaintext } => {
+            let keys = &mut state.skipped_chains[chain].keys;
+            keys.remove(&header.counter);
+            if keys.is_empty() {
+                state.skipped_chains.remove(chain);
+            }
+            state.receiving_counter += 1;
+            Ok(plaintext)
+        }
+        ReceivePlan::Advance {
+            root_key,
+            chain_key_receiving,
+            receiving_chain_length,
+            new_sending_chain,
+            skipped,
+            plaintext,
+        } => {
+            state.root_key = root_key;
+            state.chain_key_receiving = chain_key_receiving;
+            state.receiving_chain_length = receiving_chain_length;
+            state.receiving_x25519_public_key = Some(header.x25519_public_key);
+            if let Some((secret_key, public_key, chain_key_sending)) = new_sending_chain {
+                state.mix_before_receive = None;
+                state.mix_before_send = None;
+                state.sending_x25519_secret_key = secret_key;
+                state.sending_x25519_public_key = public_key;
+                state.chain_key_sending = chain_key_sending;
+                state.previous_sending_chain_length = state.sending_chain_length;
+                state.sending_chain_length = 0;
+            }
+            for (public_key, counter, key) in skipped {
+                store_skipped_key(state, public_key, counter, key);
+            }
+            state.receiving_counter += 1;
+            Ok(plaintext)
+        }
+    }
+}
+
+/// Whether `receive_message` would accept this message, without changing any state
+pub fn can_decrypt(state: &RatchetState, message: &Message, additional_data: &[u8]) -> bool {
+    plan_receive(state, message, additional_data).is_ok()
+}
+
+fn plan_receive(state: &RatchetState, message: &Message, additional_data: &[u8]) -> Result<ReceivePlan, Error> {
+    let header = message.header;
+    let current_chain = state.receiving_x25519_public_key == Some(header.x25519_public_key);
+
+    // A late message from a chain whose skipped keys were evicted
+    if !current_chain && state.evicted_chains.contains(&header.x25519_public_key) {
+        return Err(RatchetError::KeyNotAvailable.into());
+    }
+
+    // A message from a chain we have skipped keys for
+    if let Some(chain) = state
+        .skipped_chains
+        .iter()
+        .position(|chain| chain.x25519_public_key == header.x25519_public_key)
+    {
+        match state.skipped_chains[chain].keys.get(&header.counter) {
+            Some(message_key) => {
+                let plaintext = decrypt(state.nonce_scheme, message_key, message, additional_data)?;
+                return Ok(ReceivePlan::Skipped { chain, plaintext });
+            }
+            // An old chain: anything not stored was already received
+            None if !current_chain => return Err(RatchetError::KeyNotAvailable.into()),
+            None => {}
+        }
+    }
+
+    let mut root_key = state.root_key;
+    let mut chain_key_receiving = state.chain_key_receiving;
+    let mut receiving_chain_length = state.receiving_chain_length;
+    let mut skipped = Vec::new();
+    let mut new_sending_chain = None;
+
     // If the sender has sent a new Diffie-Hellman public key, perform the DH ratchet
-    if state.receiving_x25519_public_key != Some(message.header.x25519_public_key) {
-        // state.DHr = header.dh
-        state.receiving_x25519_public_key = Some(message.header.x25519_public_key);
+    if !current_chain {
+        // Keep keys for the rest of the old chain: SkipMessageKeys(state, header.pn)
+        if let Some(old_key) = state.receiving_x25519_public_key {
+            skip_message_keys(
+                &mut chain_key_receiving,
+                &mut receiving_chain_length,
+                header.previous_chain_length,
+                old_key,
+                &mut skipped,
+            )?;
+        }
+
+        if let Some(secret) = &state.mix_before_receive {
+            root_key = kdf_mix_root_key(&root_key, secret);
+        }
 
         // state.RK, state.CKr = KDF_RK(state.RK, DH(state.DHs, state.DHr))
-        (state.root_key, state.chain_key_receiving) = kdf_root_key(
-            &state.root_key,
-            state.sending_x25519_secret_key
-                .diffie_hellman(&state.receiving_x25519_public_key.unwrap()),
+        (root_key, chain_key_receiving) = kdf_root_key(
+            &root_key,
+            state.sending_x25519_secret_key.diffie_hellman(&header.x25519_public_key),
         );
+        receiving_chain_length = 0;
+
+        if let Some(secret) = &state.mix_before_send {
+            root_key = kdf_mix_root_key(&root_key, secret);
+        }
 
         // Generate a new Diffie-Hellman keypair
         let mut rng = rand::thread_rng();
-        state.sending_x25519_secret_key = x25519::StaticSecret::random_from_rng(&mut rng);
-        state.sending_x25519_public_key = x25519::PublicKey::from(&state.sending_x25519_secret_key);
+        let secret_key = x25519::StaticSecret::random_from_rng(&mut rng);
+        let public_key = x25519::PublicKey::from(&secret_key);
 
         // state.RK, state.CKs = KDF_RK(state.RK, DH(state.DHs, state.DHr))
-        (state.root_key, state.chain_key_sending) = kdf_root_key(
-            &state.root_key,
-            state.sending_x25519_secret_key
-                .diffie_hellman(&state.receiving_x25519_public_key.unwrap()),
+        let chain_key_sending;
+        (root_key, chain_key_sending) = kdf_root_key(
+            &root_key,
+            secret_key.diffie_hellman(&header.x25519_public_key),
         );
+        new_sending_chain = Some((secret_key, public_key, chain_key_sending));
+    } else if header.counter < receiving_chain_length {
+        // Already received, or its skipped key was discarded
+        return Err(RatchetError::KeyNotAvailable.into());
     }
 
+    // SkipMessageKeys(state, header.n)
+    skip_message_keys(
+        &mut chain_key_receiving,
+        &mut receiving_chain_length,
+        header.counter,
+        header.x25519_public_key,
+        &mut skipped,
+    )?;
+
     // state.CKr, mk = KDF_CK(state.CKr)
-    let (chain_key_receiving, message_key) = kdf_chain_key(&state.chain_key_receiving);
-    state.chain_key_receiving = chain_key_receiving;
+    let (chain_key_receiving, message_key) = kdf_chain_key(&chain_key_receiving);
+    let plaintext = decrypt(state.nonce_scheme, &message_key, message, additional_data)?;
+
+    Ok(ReceivePlan::Advance {
+        root_key,
+        chain_key_receiving,
+        receiving_chain_length: receiving_chain_length + 1,
+        new_sending_chain,
+        skipped,
+        plaintext,
+    })
+}
+
+/// Advance a receiving chain to `until`, collecting the keys passed over
+fn skip_message_keys(
+    chain_key: &mut [u8; 32],
+    chain_length: &mut u64,
+    until: u64,
+    public_key: x25519::PublicKey,
+    skipped: &mut Vec<(x25519::PublicKey, u64, [u8; 32])>,
+) -> Result<(), Error> {
+    if until <= *chain_length {
+        return Ok(());
+    }
+    let count = until - *chain_length;
+    if count > MAX_SKIP {
+        return Err(RatchetError::TooManySkipped { skipped: count, max: MAX_SKIP }.into());
+    }
+    while *chain_length < until {
+        let (next_chain_key, message_key) = kdf_chain_key(chain_key);
+        *chain_key = next_chain_key;
+        skipped.push((public_key, *chain_length, message_key));
+        *chain_length += 1;
+    }
+    Ok(())
+}
+
+fn store_skipped_key(state: &mut RatchetState, public_key: x25519::PublicKey, counter: u64, key: [u8; 32]) {
+    match state
+        .skipped_chains
+        .iter_mut()
+        .find(|chain| chain.x25519_public_key == public_key)
+    {
+        Some(chain) => {
+            chain.keys.insert(counter, key);
+        }
+        None => {
+            let mut keys = BTreeMap::new();
+            keys.insert(counter, key);
+            state.skipped_chains.push_back(SkippedChain { x25519_public_key: public_key, keys });
+            enforce_skipped_chain_limit(state);
+        }
+    }
+}
 
-    // DECRYPT(mk, ciphertext, CONCAT(AD, header))
-    let mut cipher = Aes256Gcm::new(&message_key.try_into().unwrap());
-    let plaintext = cipher
+/// Drop the oldest skipped chains beyond `max_skipped_chains`
+pub fn enforce_skipped_chain_limit(state: &mut RatchetState) {
+    while state.skipped_chains.len() > state.max_skipped_chains {
+        let Some(evicted) = state.skipped_chains.pop_front() else {
+            break;
+        };
+        state.evicted_chains.push_back(evicted.x25519_public_key);
+        if state.evicted_chains.len() > EVICTED_CHAIN_MEMORY {
+            state.evicted_chains.pop_front();
+        }
+    }
+}
+
+/// DECRYPT(mk, ciphertext, CONCAT(AD, header))
+fn decrypt(nonce_scheme: NonceScheme, message_key: &[u8; 32], message: &Message, additional_data: &[u8]) -> Result<Vec<u8>, Error> {
+    let nonce = match nonce_scheme {
+        NonceScheme::Random => message.header.nonce,
+        NonceScheme::DerivedFromKey => kdf_nonce(message_key),
+    };
+
+    let mut cipher = Aes256Gcm::new(message_key.into());
+    cipher
         .decrypt(
-            (&message.header.nonce).into(),
+            (&nonce).into(),
             Payload {
                 msg: &message.ciphertext,
-                aad: additional_data,
+                aad: &header_aad(additional_data, &message.header),
             },
         )
-        .map_err(|_| Error::msg("Failed to decrypt message"))?;
+        .map_err(|_| RatchetError::AuthenticationFailed.into())
+}
 
-    state.receiving_counter += 1;
+fn header_aad(additional_data: &[u8], header: &MessageHeader) -> Vec<u8> {
+    let mut aad = additional_data.to_vec();
+    aad.extend_from_slice(&header.authenticated_bytes());
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratchet::{init_alice, init_bob};
+
+    const AD: &[u8] = b"associated data";
+
+    /// Alice's and Bob's ratchets right after PQXDH, before any message
+    fn pair() -> (RatchetState, RatchetState) {
+        let shared_key = rand::random();
+        let bob_prekey = x25519::StaticSecret::random_from_rng(rand::thread_rng());
+        let alice = init_alice(shared_key, x25519::PublicKey::from(&bob_prekey));
+        (alice, init_bob(shared_key, bob_prekey))
+    }
+
+    /// Every field of the state, so two snapshots are equal only if nothing moved
+    fn snapshot(state: &RatchetState) -> Vec<u8> {
+        let mut bytes = state.sending_x25519_secret_key.to_bytes().to_vec();
+        bytes.extend_from_slice(state.sending_x25519_public_key.as_bytes());
+        bytes.extend_from_slice(state.receiving_x25519_public_key.map_or([0; 32], |key| key.to_bytes()).as_slice());
+        bytes.extend_from_slice(&state.root_key);
+        bytes.extend_from_slice(&state.chain_key_sending);
+        bytes.extend_from_slice(&state.chain_key_receiving);
+        for counter in [
+            state.sending_counter,
+            state.receiving_counter,
+            state.sending_chain_length,
+            state.previous_sending_chain_length,
+            state.receiving_chain_length,
+        ] {
+            bytes.extend_from_slice(&counter.to_be_bytes());
+        }
+        for chain in &state.skipped_chains {
+            bytes.extend_from_slice(chain.x25519_public_key.as_bytes());
+            for (counter, key) in &chain.keys {
+                bytes.extend_from_slice(&counter.to_be_bytes());
+                bytes.extend_from_slice(key);
+            }
+        }
+        for key in &state.evicted_chains {
+            bytes.extend_from_slice(key.as_bytes());
+        }
+        for mix in [state.mix_before_receive, state.mix_before_send] {
+            bytes.extend_from_slice(&mix.unwrap_or([0; 32]));
+        }
+        bytes
+    }
 
-    Ok(plaintext)
+    /// Copies of `message` with one ciphertext or header byte changed
+    fn tampered(message: &Message) -> Vec<Message> {
+        let copy = || Message { header: message.header, ciphertext: message.ciphertext.clone() };
+        let mut variants = Vec::new();
+
+        for i in [0, message.ciphertext.len() - 1] {
+            let mut m = copy();
+            m.ciphertext[i] ^= 0x01;
+            variants.push(m);
+        }
+        let mut m = copy();
+        m.header.nonce[0] ^= 0x01;
+        variants.push(m);
+        let mut m = copy();
+        // Ahead of the real counter; behind it the key would be gone instead
+        m.header.counter += 5;
+        variants.push(m);
+        let mut m = copy();
+        let mut key = m.header.x25519_public_key.to_bytes();
+        key[0] ^= 0x01;
+        m.header.x25519_public_key = key.into();
+        variants.push(m);
+        variants
+    }
+
+    fn assert_refused_without_change(state: &mut RatchetState, message: &Message) {
+        for forged in tampered(message) {
+            let before = snapshot(state);
+            let error = receive_message(state, forged, AD).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(RatchetError::AuthenticationFailed)), "{}", error);
+            assert!(snapshot(state) == before, "a forged message changed the ratchet");
+        }
+    }
+
+    #[test]
+    fn tampered_messages_fail_authentication_and_leave_state_alone() {
+        let (mut alice, mut bob) = pair();
+
+        // The first message, which also takes Bob's first DH step
+        let first = send_message(&mut alice, "first", AD).unwrap();
+        assert_refused_without_change(&mut bob, &first);
+        assert_eq!(receive_message(&mut bob, first, AD).unwrap(), b"first");
+
+        // The next message in the same chain, and one whose key was skipped
+        let skipped = send_message(&mut alice, "skipped", AD).unwrap();
+        let next = send_message(&mut alice, "next", AD).unwrap();
+        assert_refused_without_change(&mut bob, &next);
+        assert_eq!(receive_message(&mut bob, next, AD).unwrap(), b"next");
+        assert_refused_without_change(&mut bob, &skipped);
+        assert_eq!(receive_message(&mut bob, skipped, AD).unwrap(), b"skipped");
+
+        // Wrong associated data is refused the same way
+        let reply = send_message(&mut bob, "reply", AD).unwrap();
+        let before = snapshot(&alice);
+        let copy = Message { header: reply.header, ciphertext: reply.ciphertext.clone() };
+        let error = receive_message(&mut alice, copy, b"other data").unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(RatchetError::AuthenticationFailed)));
+        assert!(snapshot(&alice) == before);
+        assert_eq!(receive_message(&mut alice, reply, AD).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn can_decrypt_predicts_receive_without_moving_the_ratchet() {
+        let (mut alice, mut bob) = pair();
+        let first = send_message(&mut alice, "first", AD).unwrap();
+        let skipped = send_message(&mut alice, "skipped", AD).unwrap();
+        let next = send_message(&mut alice, "next", AD).unwrap();
+
+        // A new chain, the current chain ahead of its counter, and a skipped key
+        for message in [&first, &next, &skipped] {
+            let before = snapshot(&bob);
+            assert!(can_decrypt(&bob, message, AD));
+            assert!(!can_decrypt(&bob, message, b"other data"));
+            for forged in tampered(message) {
+                assert!(!can_decrypt(&bob, &forged, AD));
+            }
+            assert!(snapshot(&bob) == before, "can_decrypt changed the ratchet");
+
+            let copy = Message { header: message.header, ciphertext: message.ciphertext.clone() };
+            receive_message(&mut bob, copy, AD).unwrap();
+            let copy = Message { header: message.header, ciphertext: message.ciphertext.clone() };
+            assert!(!can_decrypt(&bob, &copy, AD), "an accepted message still decrypts");
+        }
+    }
 }
@@ -22,6 +22,15 @@ pub fn kdf_root_key(key: &[u8; 32], shared_secret: x25519::SharedSecret) -> ([u8
     (root_key, chain_key)
 }
 
+/// Input: root_key, rekey_secret
+/// Output: root_key with the rekey secret folded in
+pub fn kdf_mix_root_key(key: &[u8; 32], secret: &[u8; 32]) -> [u8; 32] {
+    let mut kdf = blake3::Hasher::new_derive_key("DOUBLE_RATCHET_KDF_REKEY");
+    kdf.update(key);
+    kdf.update(secret);
+    *kdf.finalize().as_bytes()
+}
+
 /// Input: chain_key
 /// Output: (chain_key, message_key)
 pub fn kdf_chain_key(key: &[u8]) -> ([u8; 32], [u8; 32]) {
@@ -37,3 +46,16 @@ pub fn kdf_chain_key(key: &[u8]) -> ([u8; 32], [u8; 32]) {
 
     (chain_key, message_key)
 }
+
+/// Input: message_key
+/// Output: AEAD nonce (for NonceScheme::DerivedFromKey)
+pub fn kdf_nonce(message_key: &[u8]) -> [u8; 12] {
+    let mut kdf = blake3::Hasher::new_derive_key("DOUBLE_RATCHET_KDF_NONCE");
+    kdf.update(message_key);
+    let mut xof = kdf.finalize_xof();
+
+    let mut nonce = [0u8; 12];
+    xof.fill(&mut nonce);
+
+    nonce
+}
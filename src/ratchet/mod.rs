@@ -6,9 +6,12 @@ mod types;
 mod kdf;
 mod encryption;
 
-pub use types::{RatchetState, Message, MessageHeader};
-pub use encryption::{send_message, send_bytes, receive_message};
-pub use kdf::{kdf_root_key, kdf_chain_key};
+use std::collections::VecDeque;
+
+pub use types::{RatchetState, Message, MessageHeader, NonceScheme, RatchetError, MAX_SKIP, DEFAULT_MAX_SKIPPED_CHAINS};
+pub(crate) use types::SkippedChain;
+pub use encryption::{send_message, send_bytes, receive_message, can_decrypt, enforce_skipped_chain_limit};
+pub use kdf::{kdf_root_key, kdf_chain_key, kdf_nonce, kdf_mix_root_key};
 
 /// Initialize Alice's ratchet state with shared key from PQXDH
 pub fn init_alice(shared_key: [u8; 32], bob_x25519_public_key: x25519_dalek::PublicKey) -> RatchetState {
@@ -33,6 +36,15 @@ pub fn init_alice(shared_key: [u8; 32], bob_x25519_public_key: x25519_dalek::Pub
         chain_key_receiving: [0u8; 32],
         sending_counter: 0,
         receiving_counter: 0,
+        sending_chain_length: 0,
+        previous_sending_chain_length: 0,
+        receiving_chain_length: 0,
+        skipped_chains: VecDeque::new(),
+        max_skipped_chains: DEFAULT_MAX_SKIPPED_CHAINS,
+        evicted_chains: VecDeque::new(),
+        mix_before_receive: None,
+        mix_before_send: None,
+        nonce_scheme: NonceScheme::default(),
     }
 }
 
@@ -49,5 +61,14 @@ pub fn init_bob(shared_key: [u8; 32], bob_prekey_private: x25519_dalek::StaticSe
         chain_key_receiving: [0u8; 32],
         sending_counter: 0,
         receiving_counter: 0,
+        sending_chain_length: 0,
+        previous_sending_chain_length: 0,
+        receiving_chain_length: 0,
+        skipped_chains: VecDeque::new(),
+        max_skipped_chains: DEFAULT_MAX_SKIPPED_CHAINS,
+        evicted_chains: VecDeque::new(),
+        mix_before_receive: None,
+        mix_before_send: None,
+        nonce_scheme: NonceScheme::default(),
     }
 }
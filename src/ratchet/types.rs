@@ -2,7 +2,18 @@
  * ratchet/types.rs
  */
 
+use std::collections::{BTreeMap, VecDeque};
 use x25519_dalek as x25519;
+use zeroize::Zeroize;
+
+/// Most message keys skipped in a single chain before a message is refused
+pub const MAX_SKIP: u64 = 1000;
+
+/// Default number of older receiving chains whose skipped keys are retained
+pub const DEFAULT_MAX_SKIPPED_CHAINS: usize = 8;
+
+/// How many evicted chains are remembered so their messages fail as KeyNotAvailable
+pub(crate) const EVICTED_CHAIN_MEMORY: usize = 64;
 
 pub struct RatchetState {
     pub(crate) sending_x25519_secret_key: x25519::StaticSecret,
@@ -15,8 +26,108 @@ pub struct RatchetState {
 
     pub(crate) sending_counter: u64,
     pub(crate) receiving_counter: u64,
+
+    // Messages sent under the current sending chain (Ns) and the previous one (PN)
+    pub(crate) sending_chain_length: u64,
+    pub(crate) previous_sending_chain_length: u64,
+
+    // Messages received under the current receiving ratchet key (Nr)
+    pub(crate) receiving_chain_length: u64,
+
+    // Keys of messages not yet received, oldest chain first
+    pub(crate) skipped_chains: VecDeque<SkippedChain>,
+    pub(crate) max_skipped_chains: usize,
+
+    // Ratchet keys of chains dropped from skipped_chains, newest last
+    pub(crate) evicted_chains: VecDeque<x25519::PublicKey>,
+
+    // Rekey secrets folded into the root key at the next DH ratchet step,
+    // before the receiving and before the new sending chain is derived
+    pub(crate) mix_before_receive: Option<[u8; 32]>,
+    pub(crate) mix_before_send: Option<[u8; 32]>,
+
+    pub(crate) nonce_scheme: NonceScheme,
 }
 
+/// Wipes every key and returns the counters to zero
+///
+/// The state is unusable afterwards: the sending key is all zeroes and
+/// there is no receiving chain.
+impl Zeroize for RatchetState {
+    fn zeroize(&mut self) {
+        self.sending_x25519_secret_key.zeroize();
+        self.sending_x25519_public_key = x25519::PublicKey::from([0u8; 32]);
+        self.receiving_x25519_public_key = None;
+        self.root_key.zeroize();
+        self.chain_key_sending.zeroize();
+        self.chain_key_receiving.zeroize();
+        self.sending_counter = 0;
+        self.receiving_counter = 0;
+        self.sending_chain_length = 0;
+        self.previous_sending_chain_length = 0;
+        self.receiving_chain_length = 0;
+        for chain in self.skipped_chains.iter_mut() {
+            for key in chain.keys.values_mut() {
+                key.zeroize();
+            }
+        }
+        self.skipped_chains.clear();
+        self.evicted_chains.clear();
+        self.mix_before_receive.zeroize();
+        self.mix_before_send.zeroize();
+    }
+}
+
+/// How the AEAD nonce for each message is chosen
+///
+/// This is part of the wire protocol: both peers must use the same
+/// scheme, or every message fails authentication on the other side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NonceScheme {
+    /// Random 96-bit nonce carried in the message header
+    #[default]
+    Random,
+    /// Nonce derived from the message key (header nonce is all zeroes)
+    DerivedFromKey,
+}
+
+/// Message keys skipped within one receiving chain, by message number
+pub(crate) struct SkippedChain {
+    pub(crate) x25519_public_key: x25519::PublicKey,
+    pub(crate) keys: BTreeMap<u64, [u8; 32]>,
+}
+
+/// Ratchet failures that callers may want to match on
+#[derive(Debug)]
+pub enum RatchetError {
+    /// The AEAD tag did not verify: the message was tampered with or corrupted
+    /// in transit (or the peers' keys have diverged). Treat it as a possible
+    /// attack; the ratchet state is left unchanged
+    AuthenticationFailed,
+    /// The message's key was already used or has been discarded
+    KeyNotAvailable,
+    /// Accepting the message would skip more than `max` keys in one chain
+    TooManySkipped { skipped: u64, max: u64 },
+}
+
+impl std::fmt::Display for RatchetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RatchetError::AuthenticationFailed => {
+                write!(f, "Message failed authentication (tampered or corrupted)")
+            }
+            RatchetError::KeyNotAvailable => {
+                write!(f, "Message key not available (duplicate or discarded message)")
+            }
+            RatchetError::TooManySkipped { skipped, max } => {
+                write!(f, "Message would skip {} keys (limit {})", skipped, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RatchetError {}
+
 pub struct Message {
     pub header: MessageHeader,
     pub ciphertext: Vec<u8>,
@@ -25,6 +136,32 @@ pub struct Message {
 #[derive(Clone, Copy)]
 pub struct MessageHeader {
     pub x25519_public_key: x25519::PublicKey,
+    /// Message number within the sender's current chain (N)
     pub counter: u64,
+    /// Length of the sender's previous chain (PN), so gaps can be skipped
+    pub previous_chain_length: u64,
     pub nonce: [u8; 12],
 }
+
+impl MessageHeader {
+    /// Header fields bound into the AEAD associated data (everything but the nonce)
+    pub(crate) fn authenticated_bytes(&self) -> [u8; 48] {
+        let mut bytes = [0u8; 48];
+        bytes[..32].copy_from_slice(self.x25519_public_key.as_bytes());
+        bytes[32..40].copy_from_slice(&self.counter.to_be_bytes());
+        bytes[40..].copy_from_slice(&self.previous_chain_length.to_be_bytes());
+        bytes
+    }
+
+    /// Identifier of the message, the same for sender and receiver
+    ///
+    /// Derived from the sender's ratchet key and counter, which never repeat
+    /// within a session, so a receipt can name a message by it.
+    pub fn message_id(&self) -> u64 {
+        let mut kdf = blake3::Hasher::new_derive_key("PINEAPPLE_MESSAGE_ID");
+        kdf.update(self.x25519_public_key.as_bytes());
+        kdf.update(&self.counter.to_be_bytes());
+        let hash = kdf.finalize();
+        u64::from_be_bytes(hash.as_bytes()[..8].try_into().unwrap())
+    }
+}
@@ -2,62 +2,2422 @@
  * session.rs
  */
 
+use crate::messages::{self, MessageType};
+use crate::network;
 use crate::pqxdh::{self, User, PQXDHInitMessage};
-use crate::ratchet::{self, RatchetState, Message};
-use anyhow::Result;
+use crate::ratchet::{self, RatchetState, Message, NonceScheme, SkippedChain};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use zeroize::Zeroize;
+
+/// Magic header of the portable session format
+const PORTABLE_MAGIC: &[u8; 4] = b"PNPS";
+
+/// Current portable session format version
+pub const PORTABLE_VERSION: u8 = 5;
+
+/// Default read timeout while the handshake is in progress
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Payload bytes per segment in `send_large`, well inside `network::MAX_MESSAGE_SIZE`
+pub const SEGMENT_SIZE: usize = 1024 * 1024;
+
+/// Most segments one `send_large` payload may be split into
+pub const MAX_SEGMENTS: u32 = 1024;
 
 /// A complete secure messaging session
 pub struct Session {
     ratchet: RatchetState,
     associated_data: Vec<u8>,
+    transcript_hash: [u8; 32],
+    rekey_policy: Option<RekeyPolicy>,
+    stats: SessionStats,
+    max_received_bytes: Option<u64>,
+    received_file_bytes: u64,
+    /// Our ephemeral key for a rekey we started, until the peer acknowledges it
+    rekey_secret: Option<x25519_dalek::StaticSecret>,
+    /// The peer's ephemeral key from a rekey we have not acknowledged yet
+    rekey_request: Option<[u8; 32]>,
+    /// Payloads from `send_large` still missing segments, by payload id
+    partial_payloads: HashMap<u64, PartialPayload>,
+    /// Features we advertise in `send_capabilities`
+    local_features: Vec<String>,
+    /// Features from the peer's last `Capabilities` (None until one arrives)
+    peer_features: Option<Vec<String>>,
+    /// The peer's last `Profile` (None until one arrives)
+    peer_profile: Option<PeerProfile>,
+    capabilities_sent: bool,
+    /// Cleared by `reset`; sending and receiving refuse to run without keys
+    established: bool,
+    /// Initiator whose init message may not have reached the peer yet
+    awaiting_peer: bool,
+    /// Received messages after which `maybe_emit_heartbeat_ratchet` sends (None: never)
+    heartbeat_after: Option<u64>,
+    /// Messages received since we last sent anything
+    received_since_send: u64,
+    /// When a message was last sent or received (creation or import before that)
+    last_activity: SystemTime,
+    /// Encrypted messages from `queue_bytes` not yet taken by the writer
+    outbound: VecDeque<Message>,
+    /// Most messages `outbound` may hold (None: unbounded)
+    outbound_limit: Option<usize>,
+}
+
+/// Display name and avatar the peer sent in a `Profile`
+///
+/// Authenticated by the session, so it really comes from the peer's
+/// identity key, but the peer chose it: it may imitate someone else's name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerProfile {
+    pub display_name: String,
+    pub avatar: Option<Vec<u8>>,
+}
+
+/// Segments received so far for one `send_large` payload
+struct PartialPayload {
+    segments: Vec<Option<Vec<u8>>>,
+    received: u32,
+}
+
+/// Per-session traffic counters (byte counts are plaintext sizes)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionStats {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub decrypt_failures: u64,
+    pub ratchet_steps: u64,
+}
+
+/// An encrypted message with what the sender needs to match it up later
+pub struct SentMessage {
+    pub message: Message,
+    /// `MessageHeader::message_id`, which the receiver derives identically
+    pub message_id: u64,
+    /// Position in the sending chain
+    pub counter: u64,
+}
+
+/// Ratchet key material at the current step (test-only, `debug-keys` feature)
+#[cfg(feature = "debug-keys")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugChainKeys {
+    pub root_key: [u8; 32],
+    pub sending_chain_key: [u8; 32],
+    pub receiving_chain_key: [u8; 32],
 }
 
+/// What `receive` does when the peer keeps reusing one ratchet key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RekeyAction {
+    /// Print a warning and decrypt the message anyway
+    Warn,
+    /// Refuse the message with `SessionError::PeerNotRotating`
+    Reject,
+}
+
+/// Strict receive policy: the peer must send a new ratchet key
+/// at least every `max_messages_per_key` messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    pub max_messages_per_key: u64,
+    pub action: RekeyAction,
+}
+
+/// Session errors that callers may want to match on
+#[derive(Debug)]
+pub enum SessionError {
+    PeerNotRotating { messages: u64 },
+    QuotaExceeded { limit: u64, attempted: u64 },
+    HandshakeTimeout { after: Duration },
+    UnsupportedVersion { found: u8, supported: u8 },
+    IdentityMismatch { expected: String },
+    RekeyInProgress,
+    /// The outbound queue holds `limit` messages; wait for the writer to drain it
+    QueueFull { limit: usize },
+    /// The session was `reset`, or is an initiator's whose init message
+    /// has not been sent yet (see `Session::init_message_sent`)
+    NotEstablished,
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::PeerNotRotating { messages } => {
+                write!(f, "Peer reused its ratchet key for {} messages", messages)
+            }
+            SessionError::QuotaExceeded { limit, attempted } => {
+                write!(f, "Received file quota exceeded: {} of {} bytes", attempted, limit)
+            }
+            SessionError::HandshakeTimeout { after } => {
+                write!(f, "Handshake timed out: peer sent nothing for {:?}", after)
+            }
+            SessionError::UnsupportedVersion { found, supported } => {
+                write!(f, "Unsupported portable session version {} (this build reads version {})", found, supported)
+            }
+            SessionError::IdentityMismatch { expected } => {
+                write!(f, "Peer identity key does not match fingerprint {}", expected)
+            }
+            SessionError::RekeyInProgress => {
+                write!(f, "A rekey is already in progress")
+            }
+            SessionError::QueueFull { limit } => {
+                write!(f, "Outbound queue is full ({} messages)", limit)
+            }
+            SessionError::NotEstablished => {
+                write!(f, "Session is not established yet or has been reset")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
 impl Session {
     /// Create a new session as the initiator
+    ///
+    /// The session refuses to send until `init_message_sent` is called or a
+    /// message from the peer arrives; `establish` takes care of this.
     pub fn new_initiator(alice: &User, bob: &mut User) -> Result<(Self, PQXDHInitMessage)> {
+        Self::initiate(alice, bob, None)
+    }
+
+    /// Create a new session as the initiator, mixing a pre-shared key into the root key
+    ///
+    /// The responder must use `new_responder_with_psk` with the same key. With a
+    /// different key the sessions still form, but the first message fails to decrypt.
+    pub fn new_initiator_with_psk(alice: &User, bob: &mut User, psk: &[u8]) -> Result<(Self, PQXDHInitMessage)> {
+        Self::initiate(alice, bob, Some(psk))
+    }
+
+    fn initiate(alice: &User, bob: &mut User, psk: Option<&[u8]>) -> Result<(Self, PQXDHInitMessage)> {
         // Phase 1: PQXDH key agreement (bob is mutable to consume one-time prekeys)
         let pqxdh_output = pqxdh::init_pqxdh(alice, bob)?;
+        let secret_key = mix_psk(pqxdh_output.secret_key, psk)?;
 
         // Phase 2: Initialize Double Ratchet
         let ratchet = ratchet::init_alice(
-            pqxdh_output.secret_key,
+            secret_key,
             pqxdh_output.bob_ratchet_key,
         );
 
-        let session = Session {
-            ratchet,
-            associated_data: pqxdh_output.associated_data,
-        };
+        let transcript_hash = compute_transcript_hash(
+            &pqxdh_output.associated_data,
+            &network::serialize_pqxdh_init_message(&pqxdh_output.message),
+        );
+        let mut session = Session::from_parts(ratchet, pqxdh_output.associated_data, transcript_hash);
+        session.awaiting_peer = true;
 
         Ok((session, pqxdh_output.message))
     }
 
+    /// Wrap freshly keyed ratchet state with default local settings
+    fn from_parts(ratchet: RatchetState, associated_data: Vec<u8>, transcript_hash: [u8; 32]) -> Self {
+        Session {
+            ratchet,
+            associated_data,
+            transcript_hash,
+            rekey_policy: None,
+            stats: SessionStats::default(),
+            max_received_bytes: None,
+            received_file_bytes: 0,
+            rekey_secret: None,
+            rekey_request: None,
+            partial_payloads: HashMap::new(),
+            local_features: messages::FEATURES.iter().map(|f| f.to_string()).collect(),
+            peer_features: None,
+            peer_profile: None,
+            capabilities_sent: false,
+            established: true,
+            awaiting_peer: false,
+            heartbeat_after: None,
+            received_since_send: 0,
+            last_activity: SystemTime::now(),
+            outbound: VecDeque::new(),
+            outbound_limit: None,
+        }
+    }
+
+    /// Create a new session as the initiator under a fresh burner identity
+    ///
+    /// The ephemeral identity is generated here and dropped afterwards, so
+    /// the peer cannot link this conversation to your long-term key
+    pub fn new_ephemeral_initiator(bob: &mut User) -> Result<(Self, PQXDHInitMessage)> {
+        let burner = User::new_ephemeral();
+        Self::new_initiator(&burner, bob)
+    }
+
     /// Create a new session as the responder
     pub fn new_responder(bob: &mut User, init_message: &PQXDHInitMessage) -> Result<Self> {
+        Self::respond(bob, init_message, None)
+    }
+
+    /// Create a new session as the responder, mixing in the initiator's pre-shared key
+    pub fn new_responder_with_psk(bob: &mut User, init_message: &PQXDHInitMessage, psk: &[u8]) -> Result<Self> {
+        Self::respond(bob, init_message, Some(psk))
+    }
+
+    fn respond(bob: &mut User, init_message: &PQXDHInitMessage, psk: Option<&[u8]>) -> Result<Self> {
         // Phase 1: Complete PQXDH (bob is mutable for potential one-time prekey deletion)
         let (secret_key, associated_data) = pqxdh::complete_pqxdh(bob, init_message)?;
+        let secret_key = mix_psk(secret_key, psk)?;
 
         // Phase 2: Initialize Double Ratchet
         let ratchet = ratchet::init_bob(secret_key, bob.x25519_prekey_private_key.clone());
 
-        Ok(Session {
-            ratchet,
-            associated_data,
-        })
+        let transcript_hash = compute_transcript_hash(
+            &associated_data,
+            &network::serialize_pqxdh_init_message(init_message),
+        );
+        Ok(Session::from_parts(ratchet, associated_data, transcript_hash))
+    }
+
+    /// Export the session in the stable, versioned portable format
+    ///
+    /// Layout (all integers big-endian):
+    ///   magic "PNPS" (4) | version (1) | nonce scheme (1)
+    ///   sending DH secret (32) | has receiving key (1) [| receiving DH public (32)]
+    ///   root key (32) | sending chain key (32) | receiving chain key (32)
+    ///   sending counter (8) | receiving counter (8) | receiving chain length (8)
+    ///   sending chain length (8) | previous sending chain length (8)
+    ///   associated data length (4) | associated data | transcript hash (32)
+    ///   skipped chain count (4), then per chain:
+    ///     DH public (32) | key count (4) | (message number (8) | key (32)) per key
+    ///   evicted chain count (4) | DH public (32) per evicted chain
+    ///   rekey state: four optional keys, each present (1) [| key (32)]:
+    ///     mix before receive | mix before send | our rekey secret | peer rekey key
+    ///
+    /// Versions 1 and 2 predate per-chain message numbers and are refused.
+    /// Version 3 lacks the evicted chains and imports with none remembered.
+    /// Versions 3 and 4 lack the rekey state and import with no rekey pending.
+    ///
+    /// Only the cryptographic state travels; local policies, quotas and stats
+    /// start from their defaults on import. The output holds live key material
+    /// and must only be sent over an already encrypted channel.
+    pub fn to_portable_bytes(&self) -> Vec<u8> {
+        let r = &self.ratchet;
+        let mut buffer = Vec::new();
+
+        buffer.extend_from_slice(PORTABLE_MAGIC);
+        buffer.push(PORTABLE_VERSION);
+        buffer.push(match r.nonce_scheme {
+            NonceScheme::Random => 0,
+            NonceScheme::DerivedFromKey => 1,
+        });
+
+        buffer.extend_from_slice(&r.sending_x25519_secret_key.to_bytes());
+        match r.receiving_x25519_public_key {
+            Some(key) => {
+                buffer.push(1);
+                buffer.extend_from_slice(key.as_bytes());
+            }
+            None => buffer.push(0),
+        }
+
+        buffer.extend_from_slice(&r.root_key);
+        buffer.extend_from_slice(&r.chain_key_sending);
+        buffer.extend_from_slice(&r.chain_key_receiving);
+
+        buffer.extend_from_slice(&r.sending_counter.to_be_bytes());
+        buffer.extend_from_slice(&r.receiving_counter.to_be_bytes());
+        buffer.extend_from_slice(&r.receiving_chain_length.to_be_bytes());
+        buffer.extend_from_slice(&r.sending_chain_length.to_be_bytes());
+        buffer.extend_from_slice(&r.previous_sending_chain_length.to_be_bytes());
+
+        buffer.extend_from_slice(&(self.associated_data.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(&self.associated_data);
+        buffer.extend_from_slice(&self.transcript_hash);
+
+        buffer.extend_from_slice(&(r.skipped_chains.len() as u32).to_be_bytes());
+        for chain in &r.skipped_chains {
+            buffer.extend_from_slice(chain.x25519_public_key.as_bytes());
+            buffer.extend_from_slice(&(chain.keys.len() as u32).to_be_bytes());
+            for (counter, key) in &chain.keys {
+                buffer.extend_from_slice(&counter.to_be_bytes());
+                buffer.extend_from_slice(key);
+            }
+        }
+
+        buffer.extend_from_slice(&(r.evicted_chains.len() as u32).to_be_bytes());
+        for key in &r.evicted_chains {
+            buffer.extend_from_slice(key.as_bytes());
+        }
+
+        let rekey_secret = self.rekey_secret.as_ref().map(|secret| secret.to_bytes());
+        for key in [r.mix_before_receive, r.mix_before_send, rekey_secret, self.rekey_request] {
+            match key {
+                Some(key) => {
+                    buffer.push(1);
+                    buffer.extend_from_slice(&key);
+                }
+                None => buffer.push(0),
+            }
+        }
+
+        buffer
+    }
+
+    /// Import a session exported with `to_portable_bytes`
+    ///
+    /// The skipped keys and the replay state (chain positions and evicted
+    /// chains) are restored together and checked for consistency first, so
+    /// a corrupt or edited export fails instead of yielding a session that
+    /// would accept an already received message again.
+    pub fn from_portable_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < 6 || &data[0..4] != PORTABLE_MAGIC {
+            anyhow::bail!("Not a portable session export");
+        }
+        let version = data[4];
+        if !(3..=PORTABLE_VERSION).contains(&version) {
+            return Err(SessionError::UnsupportedVersion {
+                found: version,
+                supported: PORTABLE_VERSION,
+            }
+            .into());
+        }
+
+        let mut reader = PortableReader { data, offset: 5 };
+
+        let nonce_scheme = match reader.take::<1>()?[0] {
+            0 => NonceScheme::Random,
+            1 => NonceScheme::DerivedFromKey,
+            other => anyhow::bail!("Unknown nonce scheme: {}", other),
+        };
+
+        let sending_x25519_secret_key = x25519_dalek::StaticSecret::from(reader.take::<32>()?);
+        let sending_x25519_public_key = x25519_dalek::PublicKey::from(&sending_x25519_secret_key);
+        let receiving_x25519_public_key = match reader.take::<1>()?[0] {
+            0 => None,
+            1 => Some(x25519_dalek::PublicKey::from(reader.take::<32>()?)),
+            other => anyhow::bail!("Invalid receiving key flag: {}", other),
+        };
+
+        let root_key = reader.take::<32>()?;
+        let chain_key_sending = reader.take::<32>()?;
+        let chain_key_receiving = reader.take::<32>()?;
+
+        let sending_counter = u64::from_be_bytes(reader.take::<8>()?);
+        let receiving_counter = u64::from_be_bytes(reader.take::<8>()?);
+        let receiving_chain_length = u64::from_be_bytes(reader.take::<8>()?);
+        let sending_chain_length = u64::from_be_bytes(reader.take::<8>()?);
+        let previous_sending_chain_length = u64::from_be_bytes(reader.take::<8>()?);
+
+        let ad_len = u32::from_be_bytes(reader.take::<4>()?) as usize;
+        let associated_data = reader.take_slice(ad_len)?.to_vec();
+        let transcript_hash = reader.take::<32>()?;
+
+        let chain_count = u32::from_be_bytes(reader.take::<4>()?);
+        let mut skipped_chains = VecDeque::new();
+        for _ in 0..chain_count {
+            let x25519_public_key = x25519_dalek::PublicKey::from(reader.take::<32>()?);
+            let key_count = u32::from_be_bytes(reader.take::<4>()?);
+            let mut keys = BTreeMap::new();
+            for _ in 0..key_count {
+                let counter = u64::from_be_bytes(reader.take::<8>()?);
+                if keys.insert(counter, reader.take::<32>()?).is_some() {
+                    anyhow::bail!("Portable session stores message {} twice", counter);
+                }
+            }
+            skipped_chains.push_back(SkippedChain { x25519_public_key, keys });
+        }
+
+        let mut evicted_chains = VecDeque::new();
+        if version >= 4 {
+            let evicted_count = u32::from_be_bytes(reader.take::<4>()?);
+            for _ in 0..evicted_count {
+                evicted_chains.push_back(x25519_dalek::PublicKey::from(reader.take::<32>()?));
+            }
+        }
+
+        let mut rekey_state = [None; 4];
+        if version >= 5 {
+            for key in &mut rekey_state {
+                *key = match reader.take::<1>()?[0] {
+                    0 => None,
+                    1 => Some(reader.take::<32>()?),
+                    other => anyhow::bail!("Invalid rekey state flag: {}", other),
+                };
+            }
+        }
+        let [mix_before_receive, mix_before_send, rekey_secret, rekey_request] = rekey_state;
+
+        if reader.offset != data.len() {
+            anyhow::bail!("Trailing bytes after portable session");
+        }
+
+        check_replay_state(
+            receiving_x25519_public_key,
+            receiving_chain_length,
+            &skipped_chains,
+            &evicted_chains,
+        )?;
+
+        let ratchet = RatchetState {
+            sending_x25519_secret_key,
+            sending_x25519_public_key,
+            receiving_x25519_public_key,
+            root_key,
+            chain_key_sending,
+            chain_key_receiving,
+            sending_counter,
+            receiving_counter,
+            sending_chain_length,
+            previous_sending_chain_length,
+            receiving_chain_length,
+            skipped_chains,
+            max_skipped_chains: ratchet::DEFAULT_MAX_SKIPPED_CHAINS,
+            evicted_chains,
+            mix_before_receive,
+            mix_before_send,
+            nonce_scheme,
+        };
+
+        let mut session = Session::from_parts(ratchet, associated_data, transcript_hash);
+        session.rekey_secret = rekey_secret.map(x25519_dalek::StaticSecret::from);
+        session.rekey_request = rekey_request;
+        Ok(session)
+    }
+
+    /// Hash of the handshake: both identity keys (sorted) and the PQXDH init message
+    pub fn transcript_hash(&self) -> [u8; 32] {
+        self.transcript_hash
+    }
+
+    /// Short identifier both peers compute identically, for logs and routing
+    /// Derived from the transcript hash, so it is unique per handshake
+    pub fn session_id(&self) -> [u8; 16] {
+        let hash = blake3::derive_key("PINEAPPLE_SESSION_ID", &self.transcript_hash);
+        let mut id = [0u8; 16];
+        id.copy_from_slice(&hash[..16]);
+        id
+    }
+
+    /// Whether the peer's handshake identity key has the given fingerprint
+    ///
+    /// Checks the identity keys bound into the handshake, so a relay that
+    /// substituted its own key fails the check. `expected` must be the
+    /// peer's fingerprint, never your own.
+    pub fn verify_peer_fingerprint(&self, expected: &str) -> bool {
+        let (first, second) = self.associated_data.split_at(self.associated_data.len() / 2);
+        [first, second]
+            .iter()
+            .any(|key| pqxdh::fingerprint(key).eq_ignore_ascii_case(expected))
+    }
+
+    /// The peer's identity key from the handshake, given our own `local` user
+    ///
+    /// `local` must be the user this session was established with.
+    pub fn peer_identity_key(&self, local: &User) -> &[u8] {
+        let (first, second) = self.associated_data.split_at(self.associated_data.len() / 2);
+        if first == local.identity_public_key.as_bytes() { second } else { first }
+    }
+
+    /// Safety number for out-of-band identity verification
+    ///
+    /// Derived from both identity keys in sorted order, so both peers see the
+    /// same 60 digits regardless of role. Works for ephemeral identities too.
+    pub fn safety_number(&self) -> String {
+        let (low, high) = sorted_identities(&self.associated_data);
+
+        let mut kdf = blake3::Hasher::new_derive_key("PINEAPPLE_SAFETY_NUMBER");
+        kdf.update(low);
+        kdf.update(high);
+        let mut xof = kdf.finalize_xof();
+
+        let mut groups = Vec::with_capacity(12);
+        for _ in 0..12 {
+            let mut chunk = [0u8; 8];
+            xof.fill(&mut chunk[..5]);
+            groups.push(format!("{:05}", u64::from_le_bytes(chunk) % 100_000));
+        }
+        groups.join(" ")
+    }
+
+    /// Short authentication string to read aloud, e.g. on a voice call
+    ///
+    /// Four characters of ZRTP's base32 alphabet (20 bits) derived from the
+    /// transcript hash, so both peers get the same code for the same
+    /// handshake and a man in the middle (running two handshakes) almost
+    /// certainly shows different codes on each side.
+    pub fn sas(&self) -> String {
+        const ALPHABET: &[u8; 32] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+
+        let hash = blake3::derive_key("PINEAPPLE_SAS", &self.transcript_hash);
+        let bits = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]);
+        (0..4)
+            .map(|i| ALPHABET[(bits >> (27 - 5 * i)) as usize & 31] as char)
+            .collect()
+    }
+
+    /// Select the AEAD nonce scheme (call right after construction)
+    /// Both peers must choose the same scheme or no message will decrypt
+    pub fn with_nonce_scheme(mut self, scheme: NonceScheme) -> Self {
+        self.ratchet.nonce_scheme = scheme;
+        self
+    }
+
+    /// AEAD nonce scheme in use
+    pub fn nonce_scheme(&self) -> NonceScheme {
+        self.ratchet.nonce_scheme
+    }
+
+    /// Cap how many older receiving chains keep their skipped message keys
+    ///
+    /// When a new chain would exceed the cap the oldest is dropped, and late
+    /// messages from it fail with `RatchetError::KeyNotAvailable`. Each chain
+    /// holds at most `ratchet::MAX_SKIP` keys, so memory stays bounded.
+    pub fn set_max_skipped_chains(&mut self, max: usize) {
+        self.ratchet.max_skipped_chains = max;
+        ratchet::enforce_skipped_chain_limit(&mut self.ratchet);
+    }
+
+    /// Number of chains currently holding skipped message keys
+    pub fn skipped_chain_count(&self) -> usize {
+        self.ratchet.skipped_chains.len()
+    }
+
+    /// Public half of our current sending ratchet key
+    ///
+    /// Changes whenever a DH ratchet step replaces the sending key, so a
+    /// monitor can pin it and audit rotation. Safe to expose: the peer sees
+    /// it in every message header.
+    pub fn current_send_dh_public(&self) -> [u8; 32] {
+        self.ratchet.sending_x25519_public_key.to_bytes()
+    }
+
+    /// Current root and chain keys, for checking ratchet agreement against
+    /// another implementation
+    ///
+    /// Only built with the `debug-keys` feature, which is off by default and
+    /// must never be enabled outside test harnesses.
+    #[cfg(feature = "debug-keys")]
+    pub fn debug_chain_keys(&self) -> DebugChainKeys {
+        DebugChainKeys {
+            root_key: self.ratchet.root_key,
+            sending_chain_key: self.ratchet.chain_key_sending,
+            receiving_chain_key: self.ratchet.chain_key_receiving,
+        }
+    }
+
+    /// Require the peer to rotate its ratchet key (None disables the check)
+    pub fn set_rekey_on_receive(&mut self, policy: Option<RekeyPolicy>) {
+        self.rekey_policy = policy;
+    }
+
+    /// Send a `Heartbeat` after this many received messages without sending
+    /// (None, the default, never does)
+    ///
+    /// A side that only receives never advances its DH ratchet, so the
+    /// peer's sending chain never gets a new key and a compromised chain key
+    /// keeps decrypting everything the peer sends. The heartbeat carries our
+    /// next ratchet key, and the peer switches to a new chain when it replies.
+    pub fn set_heartbeat_after(&mut self, messages: Option<u64>) {
+        self.heartbeat_after = messages.map(|n| n.max(1));
+    }
+
+    /// A `Heartbeat` to send, if the policy from `set_heartbeat_after` is due
+    ///
+    /// Call it after receiving and send what it returns whenever convenient;
+    /// any other message sent first does the same job and resets the count.
+    pub fn maybe_emit_heartbeat_ratchet(&mut self) -> Result<Option<Message>> {
+        match self.heartbeat_after {
+            Some(after) if self.established && self.received_since_send >= after => {
+                tracing::debug!(received = self.received_since_send, "sending heartbeat");
+                self.send_bytes(&messages::serialize_message(&MessageType::Heartbeat)).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// When a message was last sent or received successfully
+    /// Before any traffic, when the session was created or imported
+    pub fn last_activity(&self) -> SystemTime {
+        self.last_activity
+    }
+
+    /// Time since `last_activity` (zero if the clock went backwards)
+    pub fn idle_duration(&self) -> Duration {
+        self.idle_duration_at(SystemTime::now())
+    }
+
+    /// `idle_duration` as of `now`, for callers with their own clock
+    pub fn idle_duration_at(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.last_activity).unwrap_or(Duration::ZERO)
+    }
+
+    /// Traffic counters accumulated since creation or the last reset
+    pub fn stats(&self) -> SessionStats {
+        self.stats
+    }
+
+    /// Zero all traffic counters
+    pub fn reset_stats(&mut self) {
+        self.stats = SessionStats::default();
     }
 
     /// Send an encrypted message (text - kept for backwards compatibility)
     pub fn send(&mut self, plaintext: &str) -> Result<Message> {
-        ratchet::send_message(&mut self.ratchet, plaintext, &self.associated_data)
+        self.send_bytes(plaintext.as_bytes())
     }
 
     /// Send encrypted bytes (for files and structured messages)
+    ///
+    /// Emits `tracing` events with counters and sizes only; plaintext and key
+    /// material are never logged
     pub fn send_bytes(&mut self, data: &[u8]) -> Result<Message> {
-        ratchet::send_bytes(&mut self.ratchet, data, &self.associated_data)
+        self.send_tracked(data).map(|sent| sent.message)
+    }
+
+    /// `send_bytes`, also returning the message's id and counter
+    ///
+    /// The peer gets the same id from `message.header.message_id()`, so
+    /// receipts and transcript entries can be matched to the send.
+    pub fn send_tracked(&mut self, data: &[u8]) -> Result<SentMessage> {
+        if !self.is_established() {
+            return Err(SessionError::NotEstablished.into());
+        }
+        let message = match ratchet::send_bytes(&mut self.ratchet, data, &self.associated_data) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::warn!(error = %e, "encrypt failed");
+                return Err(e);
+            }
+        };
+        self.stats.messages_sent += 1;
+        self.stats.bytes_sent += data.len() as u64;
+        self.received_since_send = 0;
+        self.last_activity = SystemTime::now();
+        tracing::debug!(counter = message.header.counter, len = data.len(), "message sent");
+        Ok(SentMessage {
+            message_id: message.header.message_id(),
+            counter: message.header.counter,
+            message,
+        })
+    }
+
+    /// Encrypt `data` to a standalone byte string, for transports with their own framing
+    ///
+    /// `send_bytes` followed by `network::serialize_ratchet_message`; the
+    /// result carries its own length and needs no prefix from `network`.
+    /// The peer passes it to `decrypt`.
+    pub fn encrypt(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let message = self.send_bytes(data)?;
+        Ok(network::serialize_ratchet_message(&message))
+    }
+
+    /// Decrypt one byte string from the peer's `encrypt`
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let message = network::deserialize_ratchet_message(data)?;
+        self.receive(message)
+    }
+
+    /// Bound the outbound queue used by `queue_bytes` (None: unbounded)
+    ///
+    /// Messages already queued stay even if there are more than `limit`.
+    pub fn set_outbound_limit(&mut self, limit: Option<usize>) {
+        self.outbound_limit = limit;
+    }
+
+    /// Whether `queue_bytes` would accept another message
+    pub fn outbound_ready(&self) -> bool {
+        self.outbound_limit.is_none_or(|limit| self.outbound.len() < limit)
+    }
+
+    /// Messages waiting in the outbound queue
+    pub fn outbound_len(&self) -> usize {
+        self.outbound.len()
+    }
+
+    /// Encrypt `data` and queue it for a writer to send
+    ///
+    /// For apps that produce messages faster than the link drains: a writer
+    /// (e.g. a thread sharing the session) takes them in order with
+    /// `pop_outbound`. When the queue is at its limit this fails with
+    /// `SessionError::QueueFull` before encrypting, so a rejected message
+    /// consumes no ratchet state and can simply be retried.
+    pub fn queue_bytes(&mut self, data: &[u8]) -> Result<()> {
+        if let Some(limit) = self.outbound_limit.filter(|&limit| self.outbound.len() >= limit) {
+            return Err(SessionError::QueueFull { limit }.into());
+        }
+        let message = self.send_bytes(data)?;
+        self.outbound.push_back(message);
+        Ok(())
+    }
+
+    /// The oldest queued message, in the order the ratchet encrypted them
+    pub fn pop_outbound(&mut self) -> Option<Message> {
+        self.outbound.pop_front()
+    }
+
+    /// Send an app payload of any size (up to `MAX_SEGMENTS` * `SEGMENT_SIZE` bytes)
+    ///
+    /// Payloads up to `SEGMENT_SIZE` go out as a single `Payload` message;
+    /// larger ones are split into `Segment` messages the peer's
+    /// `decode_message` reassembles. Send the returned messages in order.
+    pub fn send_large(&mut self, data: &[u8]) -> Result<Vec<Message>> {
+        if data.len() <= SEGMENT_SIZE {
+            let payload = MessageType::Payload { data: data.to_vec() };
+            return Ok(vec![self.send_bytes(&messages::serialize_message(&payload))?]);
+        }
+
+        let count = data.len().div_ceil(SEGMENT_SIZE);
+        if count > MAX_SEGMENTS as usize {
+            anyhow::bail!(
+                "Payload too large: {} bytes (max {})",
+                data.len(),
+                MAX_SEGMENTS as usize * SEGMENT_SIZE
+            );
+        }
+
+        let payload_id: u64 = rand::random();
+        data.chunks(SEGMENT_SIZE)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let segment = MessageType::Segment {
+                    payload_id,
+                    index: index as u32,
+                    count: count as u32,
+                    data: chunk.to_vec(),
+                };
+                self.send_bytes(&messages::serialize_message(&segment))
+            })
+            .collect()
     }
 
     /// Receive and decrypt a message (returns bytes)
+    /// Instrumented like `send_bytes`: plaintext is never logged
     pub fn receive(&mut self, message: Message) -> Result<Vec<u8>> {
-        ratchet::receive_message(&mut self.ratchet, message, &self.associated_data)
+        if !self.established {
+            return Err(SessionError::NotEstablished.into());
+        }
+        self.check_rekey_policy(&message)?;
+
+        let counter = message.header.counter;
+        let previous_key = self.ratchet.receiving_x25519_public_key;
+        match ratchet::receive_message(&mut self.ratchet, message, &self.associated_data) {
+            Ok(plaintext) => {
+                let dh_step = self.ratchet.receiving_x25519_public_key != previous_key;
+                self.stats.messages_received += 1;
+                self.stats.bytes_received += plaintext.len() as u64;
+                self.received_since_send += 1;
+                self.last_activity = SystemTime::now();
+                self.awaiting_peer = false;
+                if dh_step {
+                    self.stats.ratchet_steps += 1;
+                }
+                tracing::debug!(counter, dh_step, len = plaintext.len(), "message received");
+                Ok(plaintext)
+            }
+            Err(e) => {
+                self.stats.decrypt_failures += 1;
+                tracing::warn!(counter, error = %e, "decrypt failed");
+                Err(e)
+            }
+        }
+    }
+
+    /// Receive, decrypt and decode a message in one step
+    /// The intermediate plaintext is wiped once decoded
+    pub fn receive_message(&mut self, message: Message) -> Result<MessageType> {
+        let mut plaintext = self.receive(message)?;
+        let decoded = self.decode_message(&plaintext);
+        plaintext.zeroize();
+        decoded
+    }
+
+    /// Whether `receive` would decrypt this message, without consuming keys or advancing the ratchet
+    ///
+    /// Useful for routing a message to the right session when several share
+    /// a transport. Keys are trial-derived on copies of the chain state.
+    pub fn can_decrypt(&self, message: &Message) -> bool {
+        self.established && ratchet::can_decrypt(&self.ratchet, message, &self.associated_data)
+    }
+
+    /// Wipe all key material and counters, leaving the session unusable
+    ///
+    /// The ratchet keys, associated data, transcript hash, pending rekey
+    /// keys and partially received payloads are zeroized and the stats and
+    /// peer capabilities cleared. Local settings (policies, quota limit,
+    /// advertised features) are kept. Until a new session is established in
+    /// its place (e.g. assigned into the same pool slot), `send_bytes` and
+    /// `receive` fail with `SessionError::NotEstablished`.
+    pub fn reset(&mut self) {
+        self.ratchet.zeroize();
+        self.associated_data.zeroize();
+        self.transcript_hash.zeroize();
+        // StaticSecret wipes itself on drop
+        self.rekey_secret = None;
+        self.rekey_request.zeroize();
+        for partial in self.partial_payloads.values_mut() {
+            for segment in partial.segments.iter_mut().flatten() {
+                segment.zeroize();
+            }
+        }
+        self.partial_payloads.clear();
+        self.outbound.clear();
+        self.stats = SessionStats::default();
+        self.received_file_bytes = 0;
+        self.received_since_send = 0;
+        self.peer_features = None;
+        self.peer_profile = None;
+        self.capabilities_sent = false;
+        self.established = false;
+    }
+
+    /// Whether the session can send: it has keys (has not been `reset`)
+    /// and, for an initiator, the init message is on its way to the peer
+    pub fn is_established(&self) -> bool {
+        self.established && !self.awaiting_peer
+    }
+
+    /// Allow an initiator to send before hearing from the peer
+    ///
+    /// Call once the init message has been handed to the transport ahead of
+    /// anything sent next, e.g. over an ordered stream or as the first item
+    /// of an offline exchange. Until then (or until the peer's first message
+    /// arrives) sending fails with `SessionError::NotEstablished`, since the
+    /// peer could not decrypt it yet.
+    pub fn init_message_sent(&mut self) {
+        self.awaiting_peer = false;
+    }
+
+    /// Receive a batch of buffered messages, e.g. everything queued during a reconnect
+    ///
+    /// Messages are processed chain by chain in message-number order, so as few
+    /// keys as possible have to be skipped: first the current receiving chain
+    /// and chains with skipped keys, then chains not seen yet in the order
+    /// they first appear in the batch. Results keep the order of `messages`.
+    /// Messages that cannot be decrypted fail individually without affecting the rest.
+    pub fn receive_many(&mut self, messages: Vec<Message>) -> Vec<Result<MessageType>> {
+        let current = self.ratchet.receiving_x25519_public_key;
+
+        // Chains in order of first appearance, known chains first
+        let mut chains: Vec<x25519_dalek::PublicKey> = Vec::new();
+        for message in &messages {
+            let key = message.header.x25519_public_key;
+            if !chains.contains(&key) {
+                chains.push(key);
+            }
+        }
+        chains.sort_by_key(|key| {
+            let known = Some(*key) == current
+                || self.ratchet.skipped_chains.iter().any(|chain| chain.x25519_public_key == *key);
+            !known
+        });
+
+        let mut order: Vec<usize> = (0..messages.len()).collect();
+        order.sort_by_key(|&i| {
+            let header = &messages[i].header;
+            let chain = chains.iter().position(|key| *key == header.x25519_public_key);
+            (chain, header.counter)
+        });
+
+        let mut slots: Vec<Option<Message>> = messages.into_iter().map(Some).collect();
+        let mut results: Vec<Option<Result<MessageType>>> = (0..slots.len()).map(|_| None).collect();
+        for i in order {
+            let message = slots[i].take().expect("each message is processed once");
+            results[i] = Some(self.receive_message(message));
+        }
+        results.into_iter().map(|r| r.expect("every message has a result")).collect()
+    }
+
+    /// Start a rekey: fold a fresh X25519 secret into the root key
+    ///
+    /// Returns the `Rekey` message to send. The peer answers with a
+    /// `RekeyAck` (see `rekey_ack`), and only then does either side switch:
+    /// the responder mixes the secret in before its next sending chain and
+    /// we mix it in before receiving that chain, so both sides change keys
+    /// at the same DH ratchet step and nothing is ever encrypted under a key
+    /// the other side cannot derive yet. Messages in flight meanwhile are
+    /// unaffected. Requires a transport that keeps messages in order.
+    pub fn rekey(&mut self) -> Result<Message> {
+        if self.rekey_secret.is_some() || self.ratchet.mix_before_receive.is_some() {
+            return Err(SessionError::RekeyInProgress.into());
+        }
+
+        let secret = x25519_dalek::StaticSecret::random_from_rng(rand::thread_rng());
+        let public_key = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        let message = self.send_bytes(&messages::serialize_message(&MessageType::Rekey { public_key }))?;
+        self.rekey_secret = Some(secret);
+        Ok(message)
+    }
+
+    /// Whether a rekey we started is still waiting for the peer's acknowledgement
+    pub fn rekey_pending(&self) -> bool {
+        self.rekey_secret.is_some()
+    }
+
+    /// Acknowledge a `Rekey` received from the peer, if one is waiting
+    ///
+    /// The secret takes effect from our next sending chain on, which is only
+    /// derived after this message, so send it before anything encrypted later.
+    pub fn rekey_ack(&mut self) -> Result<Option<Message>> {
+        let Some(peer_public) = self.rekey_request else {
+            return Ok(None);
+        };
+
+        let secret = x25519_dalek::StaticSecret::random_from_rng(rand::thread_rng());
+        let public_key = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        let message = self.send_bytes(&messages::serialize_message(&MessageType::RekeyAck { public_key }))?;
+
+        let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(peer_public));
+        self.ratchet.mix_before_send = Some(*shared.as_bytes());
+        self.rekey_request = None;
+        Ok(Some(message))
+    }
+
+    /// Record a rekey message from the peer
+    fn handle_rekey(&mut self, message: &MessageType) -> Result<()> {
+        match message {
+            MessageType::Rekey { public_key } => {
+                if self.rekey_request.is_some() || self.ratchet.mix_before_send.is_some() {
+                    return Err(SessionError::RekeyInProgress.into());
+                }
+                self.rekey_request = Some(*public_key);
+            }
+            MessageType::RekeyAck { public_key } => {
+                let secret = self
+                    .rekey_secret
+                    .take()
+                    .context("Rekey acknowledged but none was started")?;
+                let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(*public_key));
+                self.ratchet.mix_before_receive = Some(*shared.as_bytes());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Advertise a different feature set from now on (defaults to `messages::FEATURES`)
+    pub fn set_local_features(&mut self, features: &[&str]) {
+        self.local_features = features.iter().map(|f| f.to_string()).collect();
+    }
+
+    /// Tell the peer which features we understand
+    ///
+    /// `establish` sends this for the initiator. The responder cannot send
+    /// until the initiator's first message has arrived, so it answers with
+    /// `capabilities_reply` once the initiator's `Capabilities` is received.
+    pub fn send_capabilities(&mut self) -> Result<Message> {
+        let features = self.local_features.clone();
+        let message = self.send_bytes(&messages::serialize_message(&MessageType::Capabilities { features }))?;
+        self.capabilities_sent = true;
+        Ok(message)
+    }
+
+    /// Our `Capabilities`, if the peer has sent theirs and we have not answered yet
+    pub fn capabilities_reply(&mut self) -> Result<Option<Message>> {
+        if self.capabilities_sent || self.peer_features.is_none() {
+            return Ok(None);
+        }
+        self.send_capabilities().map(Some)
+    }
+
+    /// Whether the peer advertised `feature`
+    ///
+    /// False until the peer's `Capabilities` arrives, so a peer that predates
+    /// the exchange is only sent the always-supported messages. Not carried
+    /// by the portable format; an imported session must exchange again.
+    pub fn peer_supports(&self, feature: &str) -> bool {
+        self.peer_features
+            .as_ref()
+            .is_some_and(|features| features.iter().any(|f| f == feature))
+    }
+
+    /// The peer's advertised features (None until its `Capabilities` arrives)
+    pub fn peer_features(&self) -> Option<&[String]> {
+        self.peer_features.as_deref()
+    }
+
+    /// Send our display name and optional avatar (see `messages::validate_profile`)
+    pub fn send_profile(&mut self, display_name: &str, avatar: Option<Vec<u8>>) -> Result<Message> {
+        let profile = MessageType::profile(display_name, avatar)?;
+        self.send_bytes(&messages::serialize_message(&profile))
+    }
+
+    /// The peer's latest profile (None until its `Profile` arrives)
+    pub fn peer_profile(&self) -> Option<&PeerProfile> {
+        self.peer_profile.as_ref()
+    }
+
+    /// Buffer a segment, returning the whole payload once every segment is in
+    fn reassemble(&mut self, payload_id: u64, index: u32, count: u32, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        if count > MAX_SEGMENTS {
+            anyhow::bail!("Payload {} has too many segments: {} (max {})", payload_id, count, MAX_SEGMENTS);
+        }
+
+        let partial = self.partial_payloads.entry(payload_id).or_insert_with(|| PartialPayload {
+            segments: vec![None; count as usize],
+            received: 0,
+        });
+        if partial.segments.len() != count as usize {
+            anyhow::bail!("Payload {} changed its segment count", payload_id);
+        }
+        let slot = &mut partial.segments[index as usize];
+        if slot.is_some() {
+            anyhow::bail!("Payload {} repeated segment {}", payload_id, index);
+        }
+        *slot = Some(data.to_vec());
+        partial.received += 1;
+        if partial.received < count {
+            return Ok(None);
+        }
+
+        let partial = self.partial_payloads.remove(&payload_id).expect("payload was just updated");
+        Ok(Some(partial.segments.into_iter().flatten().flatten().collect()))
+    }
+
+    /// Decode decrypted bytes, charging file and payload data against the received quota
+    /// Text, control and transfer bookkeeping messages are always allowed through;
+    /// rekey messages also advance the rekey (answer a `Rekey` with `rekey_ack`)
+    /// and `Capabilities` replaces the peer's feature set (`Profile` its profile).
+    /// The segment completing a `send_large` payload decodes to `Payload`;
+    /// the segments before it are returned as they are.
+    pub fn decode_message(&mut self, plaintext: &[u8]) -> Result<MessageType> {
+        let message = messages::deserialize_message(plaintext)?;
+        self.handle_rekey(&message)?;
+        if let MessageType::Capabilities { features } = &message {
+            self.peer_features = Some(features.clone());
+        }
+        if let MessageType::Profile { display_name, avatar } = &message {
+            self.peer_profile = Some(PeerProfile {
+                display_name: display_name.clone(),
+                avatar: avatar.clone(),
+            });
+        }
+
+        let size = match &message {
+            MessageType::File { data, .. }
+            | MessageType::FileChunk { data, .. }
+            | MessageType::Segment { data, .. }
+            | MessageType::Payload { data } => data.len() as u64,
+            MessageType::Archive { entries } => entries.iter().map(|(_, data)| data.len() as u64).sum(),
+            _ => return Ok(message),
+        };
+
+        let attempted = self.received_file_bytes + size;
+        if let Some(limit) = self.max_received_bytes {
+            if attempted > limit {
+                return Err(SessionError::QuotaExceeded { limit, attempted }.into());
+            }
+        }
+        // A segment is only charged once reassembly has taken it
+        let payload = match &message {
+            MessageType::Segment { payload_id, index, count, data } => self.reassemble(*payload_id, *index, *count, data)?,
+            _ => None,
+        };
+        self.received_file_bytes = attempted;
+        Ok(payload.map_or(message, |data| MessageType::Payload { data }))
+    }
+
+    /// Cap the cumulative bytes of received file and payload data (None for unlimited)
+    pub fn set_max_received_bytes(&mut self, limit: Option<u64>) {
+        self.max_received_bytes = limit;
+    }
+
+    /// Cumulative bytes of file and payload data accepted so far
+    pub fn received_file_bytes(&self) -> u64 {
+        self.received_file_bytes
+    }
+
+    /// Enforce the rekey policy before any ratchet state is consumed
+    fn check_rekey_policy(&self, message: &Message) -> Result<()> {
+        let Some(policy) = self.rekey_policy else {
+            return Ok(());
+        };
+
+        // A new ratchet key always resets the count
+        if self.ratchet.receiving_x25519_public_key != Some(message.header.x25519_public_key) {
+            return Ok(());
+        }
+
+        let messages = self.ratchet.receiving_chain_length + 1;
+        if messages <= policy.max_messages_per_key {
+            return Ok(());
+        }
+
+        tracing::warn!(messages, action = ?policy.action, "peer not rotating ratchet key");
+        match policy.action {
+            RekeyAction::Warn => Ok(()),
+            RekeyAction::Reject => Err(SessionError::PeerNotRotating { messages }.into()),
+        }
+    }
+}
+
+/// Bounds-checked cursor over a portable session export
+struct PortableReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> PortableReader<'a> {
+    fn take_slice(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.offset < len {
+            anyhow::bail!("Portable session truncated");
+        }
+        let slice = &self.data[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(slice)
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take_slice(N)?.try_into().unwrap())
+    }
+}
+
+/// Reject imported replay state that could accept a message twice
+///
+/// Skipped keys must sit behind the position of their chain, no chain may
+/// be both stored and evicted or appear twice, and nothing can have been
+/// skipped before any message was received.
+fn check_replay_state(
+    receiving_key: Option<x25519_dalek::PublicKey>,
+    receiving_chain_length: u64,
+    skipped_chains: &VecDeque<SkippedChain>,
+    evicted_chains: &VecDeque<x25519_dalek::PublicKey>,
+) -> Result<()> {
+    if receiving_key.is_none() && (receiving_chain_length != 0 || !skipped_chains.is_empty()) {
+        anyhow::bail!("Portable session has receive state but no receiving chain");
+    }
+
+    for (i, chain) in skipped_chains.iter().enumerate() {
+        let key = chain.x25519_public_key;
+        if skipped_chains.iter().skip(i + 1).any(|other| other.x25519_public_key == key) {
+            anyhow::bail!("Portable session stores a skipped chain twice");
+        }
+        if evicted_chains.contains(&key) {
+            anyhow::bail!("Portable session both stores and evicted a skipped chain");
+        }
+        if chain.keys.len() as u64 > ratchet::MAX_SKIP {
+            anyhow::bail!("Portable session stores {} keys in one chain", chain.keys.len());
+        }
+        if Some(key) == receiving_key {
+            if let Some((&last, _)) = chain.keys.last_key_value() {
+                if last >= receiving_chain_length {
+                    anyhow::bail!(
+                        "Portable session skipped message {} but the chain is only at {}",
+                        last,
+                        receiving_chain_length,
+                    );
+                }
+            }
+        }
+    }
+
+    if receiving_key.is_some_and(|key| evicted_chains.contains(&key)) {
+        anyhow::bail!("Portable session evicted its current receiving chain");
+    }
+    Ok(())
+}
+
+/// Which side of the PQXDH handshake this peer plays
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+/// Run the PQXDH handshake over a connected stream and return the session
+///
+/// Every handshake read is bounded by `handshake_timeout` so a peer that
+/// connects but never completes the exchange fails with
+/// `SessionError::HandshakeTimeout` instead of hanging. The read timeout is
+/// cleared again before returning. The initiator then sends its
+/// `Capabilities`; the responder answers through `capabilities_reply`.
+pub fn establish(
+    stream: &mut TcpStream,
+    role: Role,
+    local: &mut User,
+    handshake_timeout: Option<Duration>,
+) -> Result<Session> {
+    stream
+        .set_read_timeout(handshake_timeout)
+        .context("Failed to set handshake timeout")?;
+
+    let result = match role {
+        Role::Initiator => establish_initiator(stream, local),
+        Role::Responder => establish_responder(stream, local),
+    };
+
+    stream
+        .set_read_timeout(None)
+        .context("Failed to clear handshake timeout")?;
+
+    let mut session = result.map_err(|e| match handshake_timeout {
+        Some(after) if is_timeout(&e) => SessionError::HandshakeTimeout { after }.into(),
+        _ => e,
+    })?;
+
+    if role == Role::Initiator {
+        let capabilities = session.send_capabilities()?;
+        network::send_message(stream, &network::serialize_ratchet_message(&capabilities))?;
+    }
+    Ok(session)
+}
+
+/// Run the handshake and require the peer to own `peer_fingerprint`
+///
+/// On a mismatch the peer is sent a `Bye`, the stream is shut down and
+/// `SessionError::IdentityMismatch` is returned, so neither side is left
+/// waiting on a connection that will never be used.
+pub fn establish_verified(
+    stream: &mut TcpStream,
+    role: Role,
+    local: &mut User,
+    handshake_timeout: Option<Duration>,
+    peer_fingerprint: &str,
+) -> Result<Session> {
+    let mut session = establish(stream, role, local, handshake_timeout)?;
+    if session.verify_peer_fingerprint(peer_fingerprint) {
+        return Ok(session);
+    }
+
+    // Best effort: the peer may already be gone
+    let bye = messages::serialize_message(&MessageType::Bye);
+    if let Ok(msg) = session.send_bytes(&bye) {
+        let _ = network::send_message(stream, &network::serialize_ratchet_message(&msg));
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+
+    Err(SessionError::IdentityMismatch {
+        expected: peer_fingerprint.to_string(),
+    }
+    .into())
+}
+
+/// Messages `spawn_receiver` buffers before it stops reading from the transport
+pub const RECEIVE_QUEUE_CAPACITY: usize = 64;
+
+/// Decrypt and decode incoming messages on a background thread
+///
+/// The session is locked only while a message is decrypted, so the caller
+/// can keep sending on it. Each message, or the reason it could not be
+/// received, is queued in order; when the queue is full the thread stops
+/// reading and the peer is held back by TCP flow control. The channel
+/// closes after a `Bye`, when the transport fails, or once the returned
+/// receiver is dropped.
+pub fn spawn_receiver(session: Arc<Mutex<Session>>, mut transport: TcpStream) -> Receiver<Result<MessageType>> {
+    let (sender, receiver) = mpsc::sync_channel(RECEIVE_QUEUE_CAPACITY);
+
+    thread::spawn(move || {
+        while let Ok(data) = network::receive_message(&mut transport) {
+            let received = network::deserialize_ratchet_message(&data).and_then(|message| {
+                let mut session = session.lock().unwrap();
+                session.receive_message(message)
+            });
+
+            let bye = matches!(received, Ok(MessageType::Bye));
+            if sender.send(received).is_err() || bye {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+fn establish_initiator(stream: &mut TcpStream, alice: &mut User) -> Result<Session> {
+    let mut handshake = Handshake::new(Role::Initiator, alice);
+    network::send_message(stream, &handshake.start())?;
+
+    let bundle = network::receive_message(stream)?;
+    if let Some(init_message) = handshake.accept_peer_bundle(&bundle)? {
+        network::send_message(stream, &init_message)?;
+    }
+
+    let mut session = handshake.finish()?;
+    session.init_message_sent();
+    Ok(session)
+}
+
+fn establish_responder(stream: &mut TcpStream, bob: &mut User) -> Result<Session> {
+    let mut handshake = Handshake::new(Role::Responder, bob);
+    let bundle = network::receive_message(stream)?;
+    handshake.accept_peer_bundle(&bundle)?;
+    network::send_message(stream, &handshake.start())?;
+
+    let init_message = network::receive_message(stream)?;
+    handshake.accept_init_message(&init_message)?;
+
+    handshake.finish()
+}
+
+/// PQXDH handshake state, independent of any transport
+///
+/// Each side sends the bundle from `start` and passes the peer's bundle to
+/// `accept_peer_bundle`. For the initiator that returns the init message to
+/// send; the responder passes the init message it receives to
+/// `accept_init_message`. `finish` then returns the session. All messages
+/// are the serialized forms from `network`, so they can travel over any
+/// transport in any order the transport allows. The initiator's session
+/// cannot send until `Session::init_message_sent` is called for it.
+pub struct Handshake<'a> {
+    role: Role,
+    local: &'a mut User,
+    session: Option<Session>,
+}
+
+impl<'a> Handshake<'a> {
+    pub fn new(role: Role, local: &'a mut User) -> Self {
+        Self {
+            role,
+            local,
+            session: None,
+        }
+    }
+
+    /// Our serialized prekey bundle, to send to the peer
+    pub fn start(&self) -> Vec<u8> {
+        network::serialize_prekey_bundle(self.local)
+    }
+
+    /// Take the peer's serialized bundle
+    ///
+    /// Returns the serialized init message the initiator must send; the
+    /// responder gets None (its peer's identity arrives with the init message).
+    pub fn accept_peer_bundle(&mut self, bundle: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut peer = network::deserialize_prekey_bundle(bundle)?;
+        match self.role {
+            Role::Initiator => {
+                if self.session.is_some() {
+                    anyhow::bail!("Handshake already complete");
+                }
+                let (session, init_message) = Session::new_initiator(self.local, &mut peer)?;
+                self.session = Some(session);
+                Ok(Some(network::serialize_pqxdh_init_message(&init_message)))
+            }
+            Role::Responder => Ok(None),
+        }
+    }
+
+    /// Take the initiator's serialized init message (responder only)
+    pub fn accept_init_message(&mut self, data: &[u8]) -> Result<()> {
+        if self.role != Role::Responder {
+            anyhow::bail!("Only the responder receives an init message");
+        }
+        if self.session.is_some() {
+            anyhow::bail!("Handshake already complete");
+        }
+        let init_message = network::deserialize_pqxdh_init_message(data)?;
+        self.session = Some(Session::new_responder(self.local, &init_message)?);
+        Ok(())
+    }
+
+    /// The established session, once the exchange is complete
+    pub fn finish(self) -> Result<Session> {
+        self.session.ok_or_else(|| anyhow::anyhow!("Handshake not complete"))
+    }
+}
+
+/// Whether an error was caused by a socket read timing out
+fn is_timeout(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut))
+    })
+}
+
+/// Split associated data into the two identity keys, lowest first
+fn sorted_identities(associated_data: &[u8]) -> (&[u8], &[u8]) {
+    let (first, second) = associated_data.split_at(associated_data.len() / 2);
+    if first <= second { (first, second) } else { (second, first) }
+}
+
+/// Role-independent handshake transcript hash
+fn compute_transcript_hash(associated_data: &[u8], init_message: &[u8]) -> [u8; 32] {
+    let (low, high) = sorted_identities(associated_data);
+    let mut hasher = blake3::Hasher::new_derive_key("PINEAPPLE_TRANSCRIPT");
+    hasher.update(low);
+    hasher.update(high);
+    hasher.update(init_message);
+    *hasher.finalize().as_bytes()
+}
+
+/// Fold an optional pre-shared key into the PQXDH output before it seeds the ratchet
+fn mix_psk(secret_key: [u8; 32], psk: Option<&[u8]>) -> Result<[u8; 32]> {
+    let Some(psk) = psk else {
+        return Ok(secret_key);
+    };
+    if psk.is_empty() {
+        anyhow::bail!("Pre-shared key must not be empty");
+    }
+    let mut kdf = blake3::Hasher::new_derive_key("PINEAPPLE_PSK_MIX");
+    kdf.update(&secret_key);
+    kdf.update(psk);
+    Ok(*kdf.finalize().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pqxdh::HandshakeError;
+
+    /// The copy of `user`'s prekey bundle a peer would hold
+    fn bundle(user: &User) -> User {
+        network::deserialize_prekey_bundle(&network::serialize_prekey_bundle(user)).unwrap()
+    }
+
+    /// Alice's and Bob's sessions once Alice's init message has gone out
+    fn established() -> (Session, Session) {
+        established_with_psks(None, None).unwrap()
+    }
+
+    /// Like `established`, with each side's own pre-shared key if any
+    fn established_with_psks(alice_psk: Option<&[u8]>, bob_psk: Option<&[u8]>) -> Result<(Session, Session)> {
+        let alice = User::new();
+        let mut bob = User::new();
+        let (mut alice_session, init) = match alice_psk {
+            Some(psk) => Session::new_initiator_with_psk(&alice, &mut bundle(&bob), psk)?,
+            None => Session::new_initiator(&alice, &mut bundle(&bob))?,
+        };
+        alice_session.init_message_sent();
+        let bob_session = match bob_psk {
+            Some(psk) => Session::new_responder_with_psk(&mut bob, &init, psk)?,
+            None => Session::new_responder(&mut bob, &init)?,
+        };
+        Ok((alice_session, bob_session))
+    }
+
+    /// `message` as it would arrive a second time
+    fn copy(message: &Message) -> Message {
+        Message { header: message.header, ciphertext: message.ciphertext.clone() }
+    }
+
+    fn error_text(result: Result<Session>) -> String {
+        match result {
+            Ok(_) => panic!("the import was accepted"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn replayed_offline_init_message_is_refused() {
+        let alice = User::new();
+        let mut bob = User::new();
+        let (mut alice_session, init) = Session::new_initiator(&alice, &mut bundle(&bob)).unwrap();
+        alice_session.init_message_sent();
+
+        // What a relay holds for bob: the init message and the first ciphertext
+        let stored_init = network::serialize_pqxdh_init_message(&init);
+        let stored_first = network::serialize_ratchet_message(&alice_session.send("hello").unwrap());
+        let deliver = |bob: &mut User| -> Result<Vec<u8>> {
+            let init = network::deserialize_pqxdh_init_message(&stored_init)?;
+            let mut session = Session::new_responder(bob, &init)?;
+            session.receive(network::deserialize_ratchet_message(&stored_first)?)
+        };
+
+        assert_eq!(deliver(&mut bob).unwrap(), b"hello");
+        let error = deliver(&mut bob).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(HandshakeError::Replayed)));
+
+        // Past the window only the one-time prekeys stand in the way
+        bob.set_replay_window(0);
+        assert!(deliver(&mut bob).is_err());
+    }
+
+    #[test]
+    fn imported_session_refuses_messages_received_before_export() {
+        let (mut alice, mut bob) = established();
+        let sent: Vec<Message> = ["one", "two", "three"].iter().map(|text| alice.send(text).unwrap()).collect();
+        bob.receive(copy(&sent[0])).unwrap();
+        bob.receive(copy(&sent[2])).unwrap();
+
+        let mut imported = Session::from_portable_bytes(&bob.to_portable_bytes()).unwrap();
+        for message in [&sent[0], &sent[2]] {
+            let error = imported.receive(copy(message)).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(ratchet::RatchetError::KeyNotAvailable)));
+        }
+        // The skipped message still arrives, once
+        assert_eq!(imported.receive(copy(&sent[1])).unwrap(), b"two");
+        assert!(imported.receive(copy(&sent[1])).is_err());
+    }
+
+    #[test]
+    fn import_refuses_inconsistent_replay_state() {
+        let (mut alice, mut bob) = established();
+        let first = alice.send("one").unwrap();
+        alice.send("two").unwrap();
+        bob.receive(alice.send("three").unwrap()).unwrap();
+        // Bob keeps the skipped key for "two" while Alice moves to a new chain
+        bob.receive(first).unwrap();
+        alice.receive(bob.send("reply").unwrap()).unwrap();
+        bob.receive(alice.send("four").unwrap()).unwrap();
+        let old_chain = bob.ratchet.skipped_chains[0].x25519_public_key;
+        let current = bob.ratchet.receiving_x25519_public_key.unwrap();
+        assert!(Session::from_portable_bytes(&bob.to_portable_bytes()).is_ok());
+
+        let edited = |edit: &dyn Fn(&mut RatchetState)| {
+            let mut session = Session::from_portable_bytes(&bob.to_portable_bytes()).unwrap();
+            edit(&mut session.ratchet);
+            Session::from_portable_bytes(&session.to_portable_bytes())
+        };
+
+        // A skipped key at or past the chain's own position
+        let error = edited(&|r| {
+            let position = r.receiving_chain_length;
+            let keys = BTreeMap::from([(position, [7; 32])]);
+            r.skipped_chains.push_back(SkippedChain { x25519_public_key: current, keys });
+        });
+        assert_eq!(error_text(error), "Portable session skipped message 1 but the chain is only at 1");
+
+        // A chain both stored and evicted
+        let error = edited(&|r| r.evicted_chains.push_back(old_chain));
+        assert_eq!(error_text(error), "Portable session both stores and evicted a skipped chain");
+
+        // The same chain stored twice
+        let error = edited(&|r| {
+            let keys = BTreeMap::from([(0, [7; 32])]);
+            r.skipped_chains.push_back(SkippedChain { x25519_public_key: old_chain, keys });
+        });
+        assert_eq!(error_text(error), "Portable session stores a skipped chain twice");
+
+        // The current receiving chain evicted
+        let error = edited(&|r| r.evicted_chains.push_back(current));
+        assert_eq!(error_text(error), "Portable session evicted its current receiving chain");
+    }
+
+    #[test]
+    fn older_portable_versions_import() {
+        let (mut alice, mut bob) = established();
+        bob.receive(alice.send("one").unwrap()).unwrap();
+        let evicted = x25519_dalek::PublicKey::from([9; 32]);
+        bob.ratchet.evicted_chains.push_back(evicted);
+        let current = bob.to_portable_bytes();
+
+        // Version 4 ends before the rekey state (four absent-key flags here)
+        let mut v4 = current[..current.len() - 4].to_vec();
+        v4[4] = 4;
+        let mut imported = Session::from_portable_bytes(&v4).unwrap();
+        assert_eq!(imported.ratchet.evicted_chains, [evicted]);
+        assert!(!imported.rekey_pending());
+        assert_eq!(imported.receive(alice.send("two").unwrap()).unwrap(), b"two");
+
+        // Version 3 also lacks the evicted chains (a count and one key)
+        let mut v3 = current[..current.len() - 4 - 4 - 32].to_vec();
+        v3[4] = 3;
+        let mut imported = Session::from_portable_bytes(&v3).unwrap();
+        assert!(imported.ratchet.evicted_chains.is_empty());
+        assert_eq!(imported.receive(alice.send("three").unwrap()).unwrap(), b"three");
+        alice.receive(imported.send("reply").unwrap()).unwrap();
+
+        let mut v2 = v3;
+        v2[4] = 2;
+        let Err(error) = Session::from_portable_bytes(&v2) else { panic!("version 2 was accepted") };
+        assert!(matches!(error.downcast_ref(), Some(SessionError::UnsupportedVersion { found: 2, .. })));
+    }
+
+    #[test]
+    fn pre_shared_keys_must_match() {
+        let (mut alice, mut bob) = established_with_psks(Some(b"correct horse"), Some(b"correct horse")).unwrap();
+        assert_eq!(bob.receive(alice.send("hello").unwrap()).unwrap(), b"hello");
+        assert_eq!(alice.receive(bob.send("hi").unwrap()).unwrap(), b"hi");
+
+        // A different key, or a key on one side only, still forms sessions that cannot talk
+        for (alice_psk, bob_psk) in [
+            (Some(&b"correct horse"[..]), Some(&b"battery staple"[..])),
+            (Some(&b"correct horse"[..]), None),
+            (None, Some(&b"correct horse"[..])),
+        ] {
+            let (mut alice, mut bob) = established_with_psks(alice_psk, bob_psk).unwrap();
+            let error = bob.receive(alice.send("hello").unwrap()).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(ratchet::RatchetError::AuthenticationFailed)));
+        }
+    }
+
+    #[test]
+    fn empty_pre_shared_key_is_refused() {
+        let error = |result: Result<(Session, Session)>| match result {
+            Ok(_) => panic!("an empty pre-shared key was accepted"),
+            Err(e) => e.to_string(),
+        };
+        assert_eq!(error(established_with_psks(Some(b""), None)), "Pre-shared key must not be empty");
+        assert_eq!(error(established_with_psks(None, Some(b""))), "Pre-shared key must not be empty");
+    }
+
+    fn assert_not_established<T>(result: Result<T>) {
+        let Err(error) = result else { panic!("a reset session still works") };
+        assert!(matches!(error.downcast_ref(), Some(SessionError::NotEstablished)));
+    }
+
+    #[test]
+    fn reset_wipes_keys_and_refuses_traffic() {
+        let (mut alice, mut bob) = established();
+        bob.receive(alice.send("one").unwrap()).unwrap();
+        let pending = alice.send("two").unwrap();
+        assert!(bob.can_decrypt(&pending));
+
+        bob.reset();
+        #[cfg(feature = "debug-keys")]
+        {
+            let keys = bob.debug_chain_keys();
+            assert_eq!(keys.root_key, [0; 32]);
+            assert_eq!(keys.sending_chain_key, [0; 32]);
+            assert_eq!(keys.receiving_chain_key, [0; 32]);
+        }
+        assert_eq!(bob.transcript_hash(), [0; 32]);
+        assert!(bob.associated_data.is_empty());
+
+        assert!(!bob.is_established());
+        assert!(!bob.can_decrypt(&pending));
+        assert_not_established(bob.send_bytes(b"three"));
+        assert_not_established(bob.receive(pending));
+    }
+
+    #[test]
+    fn skipped_chains_beyond_the_cap_are_dropped_oldest_first() {
+        let (mut alice, mut bob) = established();
+        bob.set_max_skipped_chains(2);
+
+        // Each round leaves one of Alice's messages behind in its own chain
+        let mut late = Vec::new();
+        for round in 0..3 {
+            late.push(alice.send(&format!("late {}", round)).unwrap());
+            bob.receive(alice.send("on time").unwrap()).unwrap();
+            alice.receive(bob.send("reply").unwrap()).unwrap();
+        }
+        assert_eq!(bob.skipped_chain_count(), 2);
+
+        let mut late = late.into_iter();
+        let error = bob.receive(late.next().unwrap()).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(ratchet::RatchetError::KeyNotAvailable)));
+
+        // Lowering the cap drops the oldest remaining chain at once
+        bob.set_max_skipped_chains(1);
+        assert_eq!(bob.skipped_chain_count(), 1);
+        let error = bob.receive(late.next().unwrap()).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(ratchet::RatchetError::KeyNotAvailable)));
+        assert_eq!(bob.receive(late.next().unwrap()).unwrap(), b"late 2");
+        assert_eq!(bob.skipped_chain_count(), 0);
+    }
+
+    #[test]
+    fn both_nonce_schemes_round_trip_and_must_match() {
+        for scheme in [NonceScheme::Random, NonceScheme::DerivedFromKey] {
+            let (alice, bob) = established();
+            let (mut alice, mut bob) = (alice.with_nonce_scheme(scheme), bob.with_nonce_scheme(scheme));
+            let message = alice.send("hello").unwrap();
+            assert_eq!(message.header.nonce == [0; 12], scheme == NonceScheme::DerivedFromKey);
+            assert_eq!(bob.receive(message).unwrap(), b"hello");
+            assert_eq!(alice.receive(bob.send("hi").unwrap()).unwrap(), b"hi");
+
+            // The scheme survives an export
+            let mut bob = Session::from_portable_bytes(&bob.to_portable_bytes()).unwrap();
+            assert_eq!(bob.nonce_scheme(), scheme);
+            assert_eq!(bob.receive(alice.send("again").unwrap()).unwrap(), b"again");
+        }
+
+        let (alice, mut bob) = established();
+        let mut alice = alice.with_nonce_scheme(NonceScheme::DerivedFromKey);
+        let error = bob.receive(alice.send("hello").unwrap()).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(ratchet::RatchetError::AuthenticationFailed)));
+    }
+
+    fn rekey_policy(action: RekeyAction) -> Option<RekeyPolicy> {
+        Some(RekeyPolicy { max_messages_per_key: 2, action })
+    }
+
+    #[test]
+    fn rekey_policy_warns_or_rejects_a_peer_that_never_rotates() {
+        let (mut alice, mut bob) = established();
+        bob.set_rekey_on_receive(rekey_policy(RekeyAction::Reject));
+        bob.receive(alice.send("one").unwrap()).unwrap();
+        bob.receive(alice.send("two").unwrap()).unwrap();
+        let error = bob.receive(alice.send("three").unwrap()).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SessionError::PeerNotRotating { messages: 3 })));
+
+        // Once Alice moves to a new ratchet key the count starts over
+        alice.receive(bob.send("reply").unwrap()).unwrap();
+        assert_eq!(bob.receive(alice.send("four").unwrap()).unwrap(), b"four");
+
+        let (mut alice, mut bob) = established();
+        bob.set_rekey_on_receive(rekey_policy(RekeyAction::Warn));
+        for text in ["one", "two", "three"] {
+            assert_eq!(bob.receive(alice.send(text).unwrap()).unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn forged_message_does_not_count_toward_the_rekey_policy() {
+        let (mut alice, mut bob) = established();
+        bob.set_rekey_on_receive(rekey_policy(RekeyAction::Reject));
+        bob.receive(alice.send("one").unwrap()).unwrap();
+
+        let two = alice.send("two").unwrap();
+        for _ in 0..3 {
+            let mut forged = copy(&two);
+            forged.ciphertext[0] ^= 0x01;
+            let error = bob.receive(forged).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(ratchet::RatchetError::AuthenticationFailed)));
+        }
+        assert_eq!(bob.stats().decrypt_failures, 3);
+
+        assert_eq!(bob.receive(two).unwrap(), b"two");
+        let error = bob.receive(alice.send("three").unwrap()).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SessionError::PeerNotRotating { messages: 3 })));
+    }
+
+    /// A peer's session with the messages it has sent but the other side has not received yet
+    struct Peer {
+        session: Session,
+        in_flight: VecDeque<Message>,
+        received: Vec<String>,
+    }
+
+    impl Peer {
+        fn send(&mut self, text: &str) {
+            let message = MessageType::Text { text: text.into(), ttl_secs: None };
+            let sent = self.session.send_bytes(&messages::serialize_message(&message)).unwrap();
+            self.in_flight.push_back(sent);
+        }
+
+        fn rekey(&mut self) {
+            let sent = self.session.rekey().unwrap();
+            self.in_flight.push_back(sent);
+        }
+
+        /// Deliver the oldest of `from`'s messages, acknowledging a rekey like the CLI does
+        fn receive_from(&mut self, from: &mut Peer) {
+            let message = from.in_flight.pop_front().expect("nothing in flight");
+            match self.session.receive_message(message).unwrap() {
+                MessageType::Text { text, .. } => self.received.push(text),
+                MessageType::Rekey { .. } => {
+                    let ack = self.session.rekey_ack().unwrap().unwrap();
+                    self.in_flight.push_back(ack);
+                }
+                MessageType::RekeyAck { .. } => {}
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    fn peers() -> (Peer, Peer) {
+        let (alice, bob) = established();
+        let peer = |session| Peer { session, in_flight: VecDeque::new(), received: Vec::new() };
+        (peer(alice), peer(bob))
+    }
+
+    /// Exchange a few more messages each way so both sides take their next DH steps
+    fn settle(alice: &mut Peer, bob: &mut Peer) {
+        for round in 0..3 {
+            alice.send(&format!("a settle {}", round));
+            bob.receive_from(alice);
+            bob.send(&format!("b settle {}", round));
+            alice.receive_from(bob);
+        }
+        for peer in [alice, bob] {
+            assert!(!peer.session.rekey_pending());
+            assert!(peer.session.rekey_request.is_none());
+            assert!(peer.session.ratchet.mix_before_receive.is_none());
+            assert!(peer.session.ratchet.mix_before_send.is_none());
+        }
+    }
+
+    fn texts(received: &[String]) -> Vec<&str> {
+        received.iter().map(String::as_str).filter(|text| !text.contains("settle")).collect()
+    }
+
+    #[test]
+    fn messages_in_flight_across_a_rekey_are_not_lost() {
+        let (mut alice, mut bob) = peers();
+        alice.send("a1");
+        bob.receive_from(&mut alice);
+        bob.send("b1");
+        alice.receive_from(&mut bob);
+
+        // Both sides keep talking while the rekey and its acknowledgement cross
+        alice.send("a2");
+        alice.rekey();
+        alice.send("a3");
+        bob.send("b2");
+        bob.send("b3");
+        bob.receive_from(&mut alice);
+        bob.receive_from(&mut alice);
+        bob.send("b4");
+        alice.receive_from(&mut bob);
+        alice.send("a4");
+        for _ in 0..3 {
+            alice.receive_from(&mut bob);
+        }
+        bob.receive_from(&mut alice);
+        bob.receive_from(&mut alice);
+        assert!(alice.in_flight.is_empty() && bob.in_flight.is_empty());
+
+        settle(&mut alice, &mut bob);
+        assert_eq!(texts(&bob.received), ["a1", "a2", "a3", "a4"]);
+        assert_eq!(texts(&alice.received), ["b1", "b2", "b3", "b4"]);
+    }
+
+    #[test]
+    fn simultaneous_rekeys_both_complete() {
+        let (mut alice, mut bob) = peers();
+        alice.send("a1");
+        bob.receive_from(&mut alice);
+        bob.send("b1");
+        alice.receive_from(&mut bob);
+
+        alice.rekey();
+        bob.rekey();
+        alice.send("a2");
+        bob.send("b2");
+        bob.receive_from(&mut alice);
+        alice.receive_from(&mut bob);
+        while !alice.in_flight.is_empty() || !bob.in_flight.is_empty() {
+            if !bob.in_flight.is_empty() {
+                alice.receive_from(&mut bob);
+            }
+            if !alice.in_flight.is_empty() {
+                bob.receive_from(&mut alice);
+            }
+        }
+
+        settle(&mut alice, &mut bob);
+        assert_eq!(texts(&bob.received), ["a1", "a2"]);
+        assert_eq!(texts(&alice.received), ["b1", "b2"]);
+    }
+
+    #[test]
+    fn initiator_sends_only_once_its_init_message_is_out() {
+        let alice = User::new();
+        let mut bob = User::new();
+        let (mut alice_session, init) = Session::new_initiator(&alice, &mut bundle(&bob)).unwrap();
+
+        assert!(!alice_session.is_established());
+        assert_not_established(alice_session.send("too early"));
+        assert_not_established(alice_session.send_bytes(b"too early"));
+        assert_not_established(alice_session.queue_bytes(b"too early"));
+        assert_not_established(alice_session.send_large(&[0; 10]));
+        assert_not_established(alice_session.rekey());
+        assert_eq!(alice_session.stats().messages_sent, 0);
+        assert_eq!(alice_session.ratchet.sending_chain_length, 0);
+
+        // A message from the peer also shows the handshake went through. Here a
+        // copy of Alice's state does the sending so Bob has something to answer.
+        let mut sender = Session::from_portable_bytes(&alice_session.to_portable_bytes()).unwrap();
+        let mut bob_session = Session::new_responder(&mut bob, &init).unwrap();
+        bob_session.receive(sender.send("hello").unwrap()).unwrap();
+        assert_eq!(alice_session.receive(bob_session.send("hi").unwrap()).unwrap(), b"hi");
+        assert!(alice_session.is_established());
+        assert!(alice_session.send("now").is_ok());
+
+        let (mut alice_session, _) = Session::new_initiator(&alice, &mut bundle(&bob)).unwrap();
+        alice_session.init_message_sent();
+        assert!(alice_session.is_established());
+        assert!(alice_session.send("now").is_ok());
+    }
+
+    #[test]
+    fn received_quota_is_cumulative_and_skips_refused_segments() {
+        let (_, mut bob) = established();
+        bob.set_max_received_bytes(Some(100));
+        let decode = |bob: &mut Session, message: MessageType| bob.decode_message(&messages::serialize_message(&message));
+        let segment = |index: u32, len: usize| MessageType::Segment { payload_id: 7, index, count: 2, data: vec![index as u8; len] };
+
+        decode(&mut bob, MessageType::File { filename: "a".into(), data: vec![0; 30], ttl_secs: None }).unwrap();
+        decode(&mut bob, MessageType::FileChunk { transfer_id: 1, data: vec![0; 20] }).unwrap();
+        decode(&mut bob, segment(0, 10)).unwrap();
+        assert_eq!(bob.received_file_bytes(), 60);
+
+        // Segments reassembly refuses cost nothing
+        assert!(decode(&mut bob, segment(0, 10)).is_err());
+        let too_many = MessageType::Segment { payload_id: 8, index: 0, count: MAX_SEGMENTS + 1, data: vec![0; 10] };
+        assert!(decode(&mut bob, too_many).is_err());
+        assert_eq!(bob.received_file_bytes(), 60);
+
+        let Ok(MessageType::Payload { data }) = decode(&mut bob, segment(1, 10)) else {
+            panic!("the payload was not reassembled");
+        };
+        assert_eq!(data, [vec![0; 10], vec![1; 10]].concat());
+        assert_eq!(bob.received_file_bytes(), 70);
+
+        let error = decode(&mut bob, MessageType::FileChunk { transfer_id: 2, data: vec![0; 31] }).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SessionError::QuotaExceeded { limit: 100, attempted: 101 })));
+        assert_eq!(bob.received_file_bytes(), 70);
+        decode(&mut bob, MessageType::FileChunk { transfer_id: 2, data: vec![0; 30] }).unwrap();
+        assert_eq!(bob.received_file_bytes(), 100);
+    }
+
+    #[test]
+    fn mismatched_fingerprint_says_bye_and_closes_the_stream() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut bob = User::new();
+            let mut session = establish(&mut stream, Role::Responder, &mut bob, Some(Duration::from_secs(10))).unwrap();
+            let mut next = || {
+                let data = network::receive_message(&mut stream)?;
+                session.receive_message(network::deserialize_ratchet_message(&data)?)
+            };
+            let capabilities = next().unwrap();
+            assert!(matches!(capabilities, MessageType::Capabilities { .. }));
+            assert!(matches!(next().unwrap(), MessageType::Bye));
+            assert!(next().is_err());
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut alice = User::new();
+        let stranger = User::new().fingerprint();
+        let result = establish_verified(&mut stream, Role::Initiator, &mut alice, Some(Duration::from_secs(10)), &stranger);
+        let Err(error) = result else {
+            panic!("a session with the wrong peer was returned");
+        };
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(SessionError::IdentityMismatch { expected }) if *expected == stranger
+        ));
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn stats_count_traffic_steps_and_failures() {
+        let (mut alice, mut bob) = established();
+        let first = alice.send("hi").unwrap();
+        bob.receive(copy(&first)).unwrap();
+        bob.receive(alice.send("there").unwrap()).unwrap();
+        alice.receive(bob.send("ok").unwrap()).unwrap();
+        bob.receive(alice.send("bye").unwrap()).unwrap();
+        assert!(bob.receive(first).is_err());
+
+        let expected = SessionStats {
+            messages_sent: 1,
+            messages_received: 3,
+            bytes_sent: 2,
+            bytes_received: 10,
+            decrypt_failures: 1,
+            ratchet_steps: 2,
+        };
+        assert_eq!(bob.stats(), expected);
+        let expected = SessionStats {
+            messages_sent: 3,
+            messages_received: 1,
+            bytes_sent: 10,
+            bytes_received: 2,
+            decrypt_failures: 0,
+            ratchet_steps: 1,
+        };
+        assert_eq!(alice.stats(), expected);
+
+        bob.reset_stats();
+        assert_eq!(bob.stats(), SessionStats::default());
+        bob.receive(alice.send("again").unwrap()).unwrap();
+        assert_eq!(bob.stats().messages_received, 1);
+    }
+
+    #[test]
+    fn establish_times_out_on_a_silent_peer() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _silent = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let after = Duration::from_millis(200);
+        let Err(error) = establish(&mut stream, Role::Responder, &mut User::new(), Some(after)) else {
+            panic!("a handshake completed with a silent peer");
+        };
+        assert!(matches!(error.downcast_ref(), Some(SessionError::HandshakeTimeout { after: a }) if *a == after));
+        assert_eq!(stream.read_timeout().unwrap(), None);
+    }
+
+    #[test]
+    fn establish_over_tcp_clears_the_timeout() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let session = establish(&mut stream, Role::Responder, &mut User::new(), Some(Duration::from_secs(10))).unwrap();
+            (session, stream)
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut alice = establish(&mut stream, Role::Initiator, &mut User::new(), Some(Duration::from_secs(10))).unwrap();
+        let (mut bob, mut bob_stream) = responder.join().unwrap();
+        assert_eq!(stream.read_timeout().unwrap(), None);
+        assert_eq!(bob_stream.read_timeout().unwrap(), None);
+        assert_eq!(alice.session_id(), bob.session_id());
+
+        let capabilities = network::receive_message(&mut bob_stream).unwrap();
+        let message = bob.receive_message(network::deserialize_ratchet_message(&capabilities).unwrap()).unwrap();
+        assert!(matches!(message, MessageType::Capabilities { .. }));
+        assert_eq!(bob.receive(alice.send("hello").unwrap()).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn exported_session_carries_on_both_ways() {
+        let (mut alice, mut bob) = established();
+        bob.receive(alice.send("one").unwrap()).unwrap();
+        alice.receive(bob.send("two").unwrap()).unwrap();
+        bob.set_max_received_bytes(Some(10));
+
+        let exported = bob.to_portable_bytes();
+        let mut imported = Session::from_portable_bytes(&exported).unwrap();
+        assert_eq!(imported.session_id(), bob.session_id());
+        assert_eq!(imported.to_portable_bytes(), exported);
+        // Local limits stay behind
+        let file = messages::serialize_message(&MessageType::FileChunk { transfer_id: 1, data: vec![0; 20] });
+        imported.decode_message(&file).unwrap();
+
+        assert_eq!(imported.receive(alice.send("three").unwrap()).unwrap(), b"three");
+        assert_eq!(alice.receive(imported.send("four").unwrap()).unwrap(), b"four");
+    }
+
+    #[test]
+    fn damaged_exports_are_refused() {
+        let (_, bob) = established();
+        let exported = bob.to_portable_bytes();
+
+        let mut magic = exported.clone();
+        magic[0] = b'X';
+        assert_eq!(error_text(Session::from_portable_bytes(&magic)), "Not a portable session export");
+
+        let mut future = exported.clone();
+        future[4] = PORTABLE_VERSION + 1;
+        let Err(error) = Session::from_portable_bytes(&future) else { panic!("a future version was accepted") };
+        assert!(matches!(error.downcast_ref(), Some(SessionError::UnsupportedVersion { .. })));
+
+        for len in [0, 5, exported.len() / 2, exported.len() - 1] {
+            assert!(Session::from_portable_bytes(&exported[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn both_sides_share_a_session_id_unique_to_the_handshake() {
+        let alice = User::new();
+        let mut bob = User::new();
+        let mut handshake = || {
+            let (alice_session, init) = Session::new_initiator(&alice, &mut bundle(&bob)).unwrap();
+            let bob_session = Session::new_responder(&mut bob, &init).unwrap();
+            (alice_session, bob_session)
+        };
+        let (first_alice, first_bob) = handshake();
+        let (second_alice, second_bob) = handshake();
+
+        assert_eq!(first_alice.transcript_hash(), first_bob.transcript_hash());
+        assert_eq!(first_alice.session_id(), first_bob.session_id());
+        assert_eq!(second_alice.session_id(), second_bob.session_id());
+        assert_ne!(first_alice.session_id(), second_alice.session_id());
+        assert_ne!(&first_alice.session_id()[..], &first_alice.transcript_hash()[..16]);
+    }
+
+    #[test]
+    fn peers_verify_each_others_fingerprints() {
+        let alice = User::new();
+        let mut bob = User::new();
+        let (alice_session, init) = Session::new_initiator(&alice, &mut bundle(&bob)).unwrap();
+        let bob_session = Session::new_responder(&mut bob, &init).unwrap();
+
+        for user in [&alice, &bob] {
+            assert!(pqxdh::is_fingerprint(&user.fingerprint()));
+        }
+        assert!(alice_session.verify_peer_fingerprint(&bob.fingerprint()));
+        assert!(alice_session.verify_peer_fingerprint(&bob.fingerprint().to_uppercase()));
+        assert!(bob_session.verify_peer_fingerprint(&alice.fingerprint()));
+
+        let stranger = User::new().fingerprint();
+        assert!(!alice_session.verify_peer_fingerprint(&stranger));
+        assert!(!bob_session.verify_peer_fingerprint(&stranger));
+        assert!(!alice_session.verify_peer_fingerprint(&bob.fingerprint()[..pqxdh::FINGERPRINT_LEN - 1]));
+        assert!(!pqxdh::is_fingerprint("not a fingerprint"));
+        assert!(!pqxdh::is_fingerprint(&"g".repeat(pqxdh::FINGERPRINT_LEN)));
+    }
+
+    #[test]
+    fn receive_many_replays_a_shuffled_backlog_without_skipping() {
+        let (mut alice, mut bob) = established();
+        let send = |session: &mut Session, text: &str| {
+            let message = MessageType::Text { text: text.into(), ttl_secs: None };
+            session.send_bytes(&messages::serialize_message(&message)).unwrap()
+        };
+        bob.receive(send(&mut alice, "first")).unwrap();
+        let old = [send(&mut alice, "old 1"), send(&mut alice, "old 2")];
+        alice.receive(send(&mut bob, "reply")).unwrap();
+        let new = [send(&mut alice, "new 0"), send(&mut alice, "new 1")];
+        let mut forged = copy(&new[0]);
+        forged.ciphertext[0] ^= 1;
+
+        let batch = vec![copy(&new[1]), forged, copy(&old[1]), copy(&new[0]), copy(&old[0])];
+        let results = bob.receive_many(batch);
+        let texts: Vec<Option<String>> = results
+            .into_iter()
+            .map(|result| match result {
+                Ok(MessageType::Text { text, .. }) => Some(text),
+                Ok(other) => panic!("unexpected {:?}", other),
+                Err(_) => None,
+            })
+            .collect();
+        let expected = [Some("new 1"), None, Some("old 2"), Some("new 0"), Some("old 1")];
+        assert_eq!(texts, expected.map(|text| text.map(String::from)));
+        assert!(bob.ratchet.skipped_chains.is_empty());
+    }
+
+    #[test]
+    fn send_dh_public_matches_headers_and_rotates_per_turn() {
+        let (mut alice, mut bob) = established();
+        let first = alice.current_send_dh_public();
+        let message = alice.send("one").unwrap();
+        assert_eq!(message.header.x25519_public_key.to_bytes(), first);
+        bob.receive(message).unwrap();
+        // Same chain until the peer answers
+        bob.receive(alice.send("two").unwrap()).unwrap();
+        assert_eq!(alice.current_send_dh_public(), first);
+
+        let reply = bob.send("reply").unwrap();
+        assert_eq!(reply.header.x25519_public_key.to_bytes(), bob.current_send_dh_public());
+        alice.receive(reply).unwrap();
+        let rotated = alice.current_send_dh_public();
+        assert_ne!(rotated, first);
+        assert_eq!(alice.send("three").unwrap().header.x25519_public_key.to_bytes(), rotated);
+    }
+
+    #[test]
+    fn received_messages_wait_in_the_queue_without_holding_the_session() {
+        let (mut alice, bob) = established();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut to_bob = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (bob_end, _) = listener.accept().unwrap();
+
+        let bob = Arc::new(Mutex::new(bob));
+        let incoming = spawn_receiver(Arc::clone(&bob), bob_end);
+        let mut deliver = |message: &MessageType| {
+            let sent = alice.send_bytes(&messages::serialize_message(message)).unwrap();
+            network::send_message(&mut to_bob, &network::serialize_ratchet_message(&sent)).unwrap();
+        };
+        for text in ["one", "two"] {
+            deliver(&MessageType::Text { text: text.into(), ttl_secs: None });
+        }
+        deliver(&MessageType::Bye);
+
+        // Wait until all three are decrypted and queued, none handled yet
+        for _ in 0..200 {
+            if bob.lock().unwrap().stats().messages_received == 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The session is free to send while the queue is unread
+        assert_eq!(bob.lock().unwrap().stats().messages_received, 3);
+        let reply = bob.lock().unwrap().send("reply").unwrap();
+        assert_eq!(alice.receive(reply).unwrap(), b"reply");
+
+        let received: Vec<MessageType> = incoming.iter().map(Result::unwrap).collect();
+        assert!(matches!(&received[..], [MessageType::Text { .. }, MessageType::Text { .. }, MessageType::Bye]));
+    }
+
+    #[test]
+    fn large_payloads_reassemble_in_any_order() {
+        let (mut alice, mut bob) = established();
+        let small = alice.send_large(b"small").unwrap();
+        assert_eq!(small.len(), 1);
+        let Ok(MessageType::Payload { data }) = bob.receive_message(small.into_iter().next().unwrap()) else {
+            panic!("a small payload was segmented");
+        };
+        assert_eq!(data, b"small");
+
+        let large: Vec<u8> = (0..2 * SEGMENT_SIZE + 100).map(|i| i as u8).collect();
+        let mut segments = alice.send_large(&large).unwrap();
+        assert_eq!(segments.len(), 3);
+        let last = segments.remove(1);
+        for segment in segments {
+            assert!(matches!(bob.receive_message(segment).unwrap(), MessageType::Segment { .. }));
+        }
+        let Ok(MessageType::Payload { data }) = bob.receive_message(last) else {
+            panic!("the payload was not reassembled");
+        };
+        assert!(data == large);
+        assert!(bob.partial_payloads.is_empty());
+
+        // A segment that disagrees on the count is refused
+        let segment = |count| messages::serialize_message(&MessageType::Segment { payload_id: 9, index: 0, count, data: vec![1] });
+        bob.decode_message(&segment(2)).unwrap();
+        assert!(bob.decode_message(&segment(3)).is_err());
+    }
+
+    #[test]
+    fn capabilities_are_exchanged_once_and_answered() {
+        let (mut alice, mut bob) = established();
+        alice.set_local_features(&["compression", "profiles-v9"]);
+        assert!(!bob.peer_supports("compression"));
+        assert!(bob.peer_features().is_none());
+        // Nothing to answer before the initiator's capabilities arrive
+        assert!(bob.capabilities_reply().unwrap().is_none());
+
+        bob.receive_message(alice.send_capabilities().unwrap()).unwrap();
+        assert!(bob.peer_supports("compression"));
+        assert!(bob.peer_supports("profiles-v9"));
+        assert!(!bob.peer_supports("file-ref"));
+
+        let reply = bob.capabilities_reply().unwrap().expect("bob answers once");
+        assert!(bob.capabilities_reply().unwrap().is_none());
+        alice.receive_message(reply).unwrap();
+        let expected: Vec<String> = messages::FEATURES.iter().map(|f| f.to_string()).collect();
+        assert_eq!(alice.peer_features(), Some(expected.as_slice()));
+        // Alice already sent hers, so she has nothing to answer
+        assert!(alice.capabilities_reply().unwrap().is_none());
+    }
+
+    #[test]
+    fn messages_from_a_newer_peer_are_skipped_not_fatal() {
+        let (mut alice, mut bob) = established();
+        bob.set_max_received_bytes(Some(0));
+        for body in [&[200u8][..], &[250, 1, 2, 3]] {
+            let message = bob.receive_message(alice.send_bytes(body).unwrap()).unwrap();
+            assert!(matches!(message, MessageType::Unknown { tag, raw } if tag == body[0] && raw == body[1..]));
+        }
+        assert_eq!(bob.received_file_bytes(), 0);
+        assert!(matches!(
+            bob.receive_message(alice.send_bytes(&messages::serialize_message(&MessageType::Bye)).unwrap()).unwrap(),
+            MessageType::Bye
+        ));
+    }
+
+    #[test]
+    fn sas_matches_unless_someone_sits_in_the_middle() {
+        let (alice, bob) = established();
+        let code = alice.sas();
+        assert_eq!(code, bob.sas());
+        assert_eq!(code.len(), 4);
+        assert!(code.chars().all(|c| "ybndrfg8ejkmcpqxot1uwisza345h769".contains(c)), "{}", code);
+
+        // Mallory runs one handshake with each of them
+        let (alice_side, mallory_to_alice) = established();
+        let (mallory_to_bob, bob_side) = established();
+        assert_eq!(alice_side.sas(), mallory_to_alice.sas());
+        assert_eq!(mallory_to_bob.sas(), bob_side.sas());
+        assert_ne!(alice_side.sas(), bob_side.sas());
+    }
+
+    #[test]
+    fn heartbeats_move_a_receive_only_side_forward() {
+        let (mut alice, mut bob) = established();
+        bob.receive(alice.send("one").unwrap()).unwrap();
+        assert!(bob.maybe_emit_heartbeat_ratchet().unwrap().is_none(), "off by default");
+
+        bob.set_heartbeat_after(Some(2));
+        assert!(bob.maybe_emit_heartbeat_ratchet().unwrap().is_none());
+        bob.receive(alice.send("two").unwrap()).unwrap();
+        let heartbeat = bob.maybe_emit_heartbeat_ratchet().unwrap().expect("two received, none sent");
+        // Sending resets the count
+        assert!(bob.maybe_emit_heartbeat_ratchet().unwrap().is_none());
+
+        let alice_key = alice.current_send_dh_public();
+        assert!(matches!(alice.receive_message(heartbeat).unwrap(), MessageType::Heartbeat));
+        let next = alice.send("three").unwrap();
+        assert_ne!(next.header.x25519_public_key.to_bytes(), alice_key);
+        bob.receive(next).unwrap();
+
+        // Any other send also resets it
+        bob.receive(alice.send("four").unwrap()).unwrap();
+        alice.receive(bob.send("reply").unwrap()).unwrap();
+        bob.receive(alice.send("five").unwrap()).unwrap();
+        assert!(bob.maybe_emit_heartbeat_ratchet().unwrap().is_none());
+    }
+
+    #[test]
+    fn tracked_sends_share_their_id_with_the_receiver() {
+        let (mut alice, mut bob) = established();
+        let mut ids = Vec::new();
+        for (counter, text) in ["one", "two", "three"].iter().enumerate() {
+            let sent = alice.send_tracked(text.as_bytes()).unwrap();
+            assert_eq!(sent.counter, counter as u64);
+            assert_eq!(sent.message.header.counter, sent.counter);
+            // What the receiver sees on the wire gives the same id
+            let arrived = network::deserialize_ratchet_message(&network::serialize_ratchet_message(&sent.message)).unwrap();
+            assert_eq!(arrived.header.message_id(), sent.message_id);
+            bob.receive(arrived).unwrap();
+            ids.push(sent.message_id);
+        }
+
+        // A new chain starts counting again but keeps ids distinct
+        alice.receive(bob.send("reply").unwrap()).unwrap();
+        let sent = alice.send_tracked(b"four").unwrap();
+        assert_eq!(sent.counter, 0);
+        ids.push(sent.message_id);
+        let unique: std::collections::HashSet<u64> = ids.iter().copied().collect();
+        assert_eq!(unique.len(), ids.len());
+    }
+
+    #[test]
+    fn encrypted_byte_strings_split_back_to_back_and_decrypt() {
+        let (mut alice, mut bob) = established();
+        let texts: [&[u8]; 3] = [b"one", b"", b"three"];
+        let stream: Vec<u8> = texts.iter().flat_map(|text| alice.encrypt(text).unwrap()).collect();
+
+        // No length prefixes: each message says where it ends
+        assert_eq!(network::ratchet_message_len(&stream[..network::RATCHET_HEADER_LEN - 1]), None);
+        let mut rest = &stream[..];
+        for text in texts {
+            let length = network::ratchet_message_len(rest).unwrap();
+            assert_eq!(bob.decrypt(&rest[..length]).unwrap(), text);
+            rest = &rest[length..];
+        }
+        assert!(rest.is_empty());
+
+        let reply = bob.encrypt(b"reply").unwrap();
+        assert!(alice.decrypt(&reply[..reply.len() - 1]).is_err());
+        let mut forged = reply.clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(alice.decrypt(&forged).is_err());
+        assert_eq!(alice.decrypt(&reply).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn full_outbound_queue_refuses_without_using_the_ratchet() {
+        let (mut alice, mut bob) = established();
+        assert!(alice.outbound_ready());
+        alice.set_outbound_limit(Some(2));
+        alice.queue_bytes(b"one").unwrap();
+        alice.queue_bytes(b"two").unwrap();
+        assert!(!alice.outbound_ready());
+        let error = alice.queue_bytes(b"three").unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SessionError::QueueFull { limit: 2 })));
+        assert_eq!(alice.outbound_len(), 2);
+
+        // Draining one makes room; the retried message follows on in order
+        let first = alice.pop_outbound().unwrap();
+        alice.queue_bytes(b"three").unwrap();
+        let rest: Vec<Message> = std::iter::from_fn(|| alice.pop_outbound()).collect();
+        let counters: Vec<u64> = std::iter::once(&first).chain(&rest).map(|message| message.header.counter).collect();
+        assert_eq!(counters, [0, 1, 2]);
+        for (message, text) in std::iter::once(first).chain(rest).zip(["one", "two", "three"]) {
+            assert_eq!(bob.receive(message).unwrap(), text.as_bytes());
+        }
+
+        alice.set_outbound_limit(None);
+        for _ in 0..5 {
+            alice.queue_bytes(b"more").unwrap();
+        }
+        assert!(alice.outbound_ready());
+        alice.reset();
+        assert_eq!(alice.outbound_len(), 0);
+        assert!(alice.pop_outbound().is_none());
+    }
+
+    #[test]
+    fn latest_valid_profile_is_kept_until_reset() {
+        let (mut alice, mut bob) = established();
+        assert!(bob.peer_profile().is_none());
+        assert!(alice.send_profile("", None).is_err());
+
+        bob.receive_message(alice.send_profile("Alice", Some(vec![1, 2, 3])).unwrap()).unwrap();
+        let expected = PeerProfile { display_name: "Alice".into(), avatar: Some(vec![1, 2, 3]) };
+        assert_eq!(bob.peer_profile(), Some(&expected));
+
+        // An oversized profile from a peer bypassing the checks changes nothing
+        let oversized = MessageType::Profile { display_name: "x".repeat(messages::MAX_DISPLAY_NAME_LEN + 1), avatar: None };
+        let sent = alice.send_bytes(&messages::serialize_message(&oversized)).unwrap();
+        assert!(bob.receive_message(sent).is_err());
+        assert_eq!(bob.peer_profile(), Some(&expected));
+
+        bob.receive_message(alice.send_profile("Alice B.", None).unwrap()).unwrap();
+        assert_eq!(bob.peer_profile().unwrap().display_name, "Alice B.");
+        assert_eq!(bob.peer_profile().unwrap().avatar, None);
+        bob.reset();
+        assert!(bob.peer_profile().is_none());
     }
 }
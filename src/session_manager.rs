@@ -0,0 +1,195 @@
+/**
+ * session_manager.rs
+ *
+ * Sessions with several peers at once, keyed by peer fingerprint
+ */
+
+use crate::session::Session;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// How the transport under a session was set up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionPath {
+    /// Dialed or accepted directly (`connect`/`listen` modes)
+    Direct,
+    /// Through `NatTraversal::connect`
+    NatTraversal,
+}
+
+/// One conversation, for a contacts or conversations list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerSummary {
+    pub peer_fingerprint: String,
+    pub safety_number: String,
+    /// See `Session::last_activity`
+    pub last_activity: SystemTime,
+    pub path: ConnectionPath,
+    /// From the peer's `Profile`, if it sent one
+    pub display_name: Option<String>,
+}
+
+struct ManagedSession {
+    session: Session,
+    path: ConnectionPath,
+}
+
+/// Established sessions by peer fingerprint
+#[derive(Default)]
+pub struct SessionManager {
+    sessions: HashMap<String, ManagedSession>,
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the session with a peer, returning the one it replaces
+    pub fn insert(&mut self, peer_fingerprint: &str, session: Session, path: ConnectionPath) -> Option<Session> {
+        self.sessions
+            .insert(peer_fingerprint.to_string(), ManagedSession { session, path })
+            .map(|replaced| replaced.session)
+    }
+
+    pub fn get(&self, peer_fingerprint: &str) -> Option<&Session> {
+        self.sessions.get(peer_fingerprint).map(|managed| &managed.session)
+    }
+
+    pub fn get_mut(&mut self, peer_fingerprint: &str) -> Option<&mut Session> {
+        self.sessions.get_mut(peer_fingerprint).map(|managed| &mut managed.session)
+    }
+
+    pub fn remove(&mut self, peer_fingerprint: &str) -> Option<Session> {
+        self.sessions.remove(peer_fingerprint).map(|managed| managed.session)
+    }
+
+    /// Summaries of every established session, most recently active first
+    ///
+    /// Reads only what the sessions already hold; nothing is decrypted.
+    /// Sessions that were `reset` are left out.
+    pub fn active_peers(&self) -> Vec<PeerSummary> {
+        let mut peers: Vec<PeerSummary> = self
+            .sessions
+            .iter()
+            .filter(|(_, managed)| managed.session.is_established())
+            .map(|(peer_fingerprint, managed)| PeerSummary {
+                peer_fingerprint: peer_fingerprint.clone(),
+                safety_number: managed.session.safety_number(),
+                last_activity: managed.session.last_activity(),
+                path: managed.path,
+                display_name: managed.session.peer_profile().map(|profile| profile.display_name.clone()),
+            })
+            .collect();
+        peers.sort_by(|a, b| {
+            b.last_activity
+                .cmp(&a.last_activity)
+                .then_with(|| a.peer_fingerprint.cmp(&b.peer_fingerprint))
+        });
+        peers
+    }
+
+    /// Peers whose sessions have been idle longer than `max_idle` as of `now`
+    ///
+    /// To keep such sessions, export them (`Session::to_portable_bytes`)
+    /// before calling `evict_idle_at` with the same arguments.
+    pub fn idle_peers_at(&self, max_idle: Duration, now: SystemTime) -> Vec<String> {
+        self.sessions
+            .iter()
+            .filter(|(_, managed)| managed.session.idle_duration_at(now) > max_idle)
+            .map(|(peer_fingerprint, _)| peer_fingerprint.clone())
+            .collect()
+    }
+
+    /// Wipe and drop every session idle longer than `max_idle`
+    ///
+    /// Returns the evicted peers' fingerprints.
+    pub fn evict_idle(&mut self, max_idle: Duration) -> Vec<String> {
+        self.evict_idle_at(max_idle, SystemTime::now())
+    }
+
+    /// `evict_idle` as of `now`, for callers with their own clock
+    pub fn evict_idle_at(&mut self, max_idle: Duration, now: SystemTime) -> Vec<String> {
+        let idle = self.idle_peers_at(max_idle, now);
+        for peer_fingerprint in &idle {
+            if let Some(mut managed) = self.sessions.remove(peer_fingerprint) {
+                managed.session.reset();
+            }
+        }
+        idle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network;
+    use crate::pqxdh::User;
+
+    /// Our session with a new peer, and the peer's with us
+    fn pair() -> (Session, Session) {
+        let (alice, mut bob) = (User::new(), User::new());
+        let mut bundle = network::deserialize_prekey_bundle(&network::serialize_prekey_bundle(&bob)).unwrap();
+        let (mut ours, init) = Session::new_initiator(&alice, &mut bundle).unwrap();
+        ours.init_message_sent();
+        (ours, Session::new_responder(&mut bob, &init).unwrap())
+    }
+
+    #[test]
+    fn active_peers_are_summarised_most_recent_first() {
+        let mut manager = SessionManager::new();
+        let (carol, _) = pair();
+        let (mut dave, mut dave_peer) = pair();
+        let safety_number = dave.safety_number();
+        manager.insert("carol", carol, ConnectionPath::Direct);
+        std::thread::sleep(Duration::from_millis(10));
+        dave.receive_message(dave_peer.send_profile("Dave", None).unwrap()).unwrap();
+        manager.insert("dave", dave, ConnectionPath::NatTraversal);
+
+        let peers = manager.active_peers();
+        let names: Vec<&str> = peers.iter().map(|peer| peer.peer_fingerprint.as_str()).collect();
+        assert_eq!(names, ["dave", "carol"]);
+        assert_eq!(peers[0].safety_number, safety_number);
+        assert_eq!(peers[0].path, ConnectionPath::NatTraversal);
+        assert_eq!(peers[0].display_name.as_deref(), Some("Dave"));
+        assert_eq!(peers[1].display_name, None);
+
+        // Activity reorders the list; reset sessions drop out of it
+        std::thread::sleep(Duration::from_millis(10));
+        manager.get_mut("carol").unwrap().send("hi").unwrap();
+        assert_eq!(manager.active_peers()[0].peer_fingerprint, "carol");
+        manager.get_mut("carol").unwrap().reset();
+        assert_eq!(manager.active_peers().len(), 1);
+
+        let (replacement, _) = pair();
+        assert!(manager.insert("dave", replacement, ConnectionPath::Direct).is_some());
+        assert_eq!(manager.active_peers()[0].path, ConnectionPath::Direct);
+        assert!(manager.remove("dave").is_some());
+        assert!(manager.active_peers().is_empty());
+    }
+
+    #[test]
+    fn idle_sessions_are_listed_and_evicted() {
+        let mut manager = SessionManager::new();
+        let (old, _) = pair();
+        let old_activity = old.last_activity();
+        manager.insert("old", old, ConnectionPath::Direct);
+        std::thread::sleep(Duration::from_millis(20));
+        let (fresh, _) = pair();
+        let now = fresh.last_activity() + Duration::from_millis(30);
+        manager.insert("fresh", fresh, ConnectionPath::Direct);
+
+        let old_session = manager.get("old").unwrap();
+        assert!(old_session.idle_duration_at(now) >= Duration::from_millis(50));
+        // A clock that went backwards counts as no idle time
+        assert_eq!(old_session.idle_duration_at(old_activity - Duration::from_secs(1)), Duration::ZERO);
+
+        let max_idle = Duration::from_millis(40);
+        assert_eq!(manager.idle_peers_at(max_idle, now), ["old"]);
+        assert!(manager.get("old").is_some(), "listing does not evict");
+        assert_eq!(manager.evict_idle_at(max_idle, now), ["old"]);
+        assert!(manager.get("old").is_none());
+        assert!(manager.get("fresh").is_some());
+        assert!(manager.evict_idle(Duration::from_secs(3600)).is_empty());
+    }
+}
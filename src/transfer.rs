@@ -0,0 +1,706 @@
+/**
+ * transfer.rs
+ *
+ * File transfers: chunked streams that can be listed and
+ * cancelled while in progress, and multi-file archives
+ */
+
+use crate::messages::MessageType;
+#[cfg(feature = "file-index")]
+use crate::file_index::{FileIndex, ReceivedFile};
+use aes_gcm::{Aes256Gcm, KeyInit, aead::{Aead, Payload}};
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Largest slice of file data carried by one FileChunk message
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    Outgoing,
+    Incoming,
+}
+
+/// Snapshot of an in-progress transfer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferInfo {
+    pub transfer_id: u64,
+    pub filename: String,
+    pub direction: TransferDirection,
+    pub bytes_done: u64,
+    pub total_size: u64,
+}
+
+/// Transfer failures the application may want to tell apart
+#[derive(Debug)]
+pub enum TransferError {
+    /// The received data does not hash to the value in FileEnd; the file was deleted
+    IntegrityFailure { transfer_id: u64 },
+    /// A blob fetched for a FileRef does not match its hash, size or key
+    BlobMismatch { url: String },
+}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::IntegrityFailure { transfer_id } => {
+                write!(f, "Transfer {} failed integrity check", transfer_id)
+            }
+            TransferError::BlobMismatch { url } => {
+                write!(f, "Blob from {} does not match its file reference", url)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+/// Called with the updated transfer after every chunk sent or received
+pub type ProgressHandler = Box<dyn FnMut(&TransferInfo) + Send>;
+
+/// Outcome of feeding an incoming transfer message to the manager
+#[derive(Debug)]
+pub enum TransferEvent {
+    Started(TransferInfo),
+    Progress(TransferInfo),
+    Completed { info: TransferInfo, path: PathBuf },
+    /// The peer cancelled; any partial file has been removed
+    Cancelled(TransferInfo),
+}
+
+struct OutgoingTransfer {
+    info: TransferInfo,
+    file: File,
+    /// Running hash of the chunks sent so far
+    hasher: blake3::Hasher,
+}
+
+struct IncomingTransfer {
+    info: TransferInfo,
+    path: PathBuf,
+    file: File,
+    /// Running hash of the chunks received so far
+    hasher: blake3::Hasher,
+}
+
+/// Tracks chunked transfers in both directions for one session
+pub struct TransferManager {
+    download_dir: PathBuf,
+    next_id: u64,
+    outgoing: HashMap<u64, OutgoingTransfer>,
+    incoming: HashMap<u64, IncomingTransfer>,
+    on_progress: Option<ProgressHandler>,
+    /// Index of received files and the fingerprint they are attributed to
+    #[cfg(feature = "file-index")]
+    index: Option<(FileIndex, String)>,
+}
+
+impl TransferManager {
+    /// Incoming files are written to `download_dir`
+    pub fn new(download_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            download_dir: download_dir.into(),
+            next_id: 1,
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            on_progress: None,
+            #[cfg(feature = "file-index")]
+            index: None,
+        }
+    }
+
+    /// Record every file received from now on in `index`
+    ///
+    /// Covers chunked transfers (including ones failing the integrity check)
+    /// and files saved with `save_file` or `save_archive`.
+    #[cfg(feature = "file-index")]
+    pub fn set_index(&mut self, index: FileIndex, sender_fingerprint: &str) {
+        self.index = Some((index, sender_fingerprint.to_string()));
+    }
+
+    /// Report every chunk of every transfer, in either direction
+    ///
+    /// `bytes_done` only grows and reaches `total_size` with the last chunk.
+    /// Received chunks are also reported as `TransferEvent::Progress`.
+    pub fn set_progress_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&TransferInfo) + Send + 'static,
+    {
+        self.on_progress = Some(Box::new(handler));
+    }
+
+    /// Begin sending a file, returning the FileStart message to send
+    /// Follow up with `next_message` until it returns None
+    pub fn start_send(&mut self, path: &Path) -> Result<MessageType> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open file: {}", path.display()))?;
+        let total_size = file.metadata()?.len();
+        let filename = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("Invalid filename: {}", path.display()))?
+            .to_string();
+
+        let transfer_id = self.next_id;
+        self.next_id += 1;
+
+        let info = TransferInfo {
+            transfer_id,
+            filename: filename.clone(),
+            direction: TransferDirection::Outgoing,
+            bytes_done: 0,
+            total_size,
+        };
+        self.outgoing.insert(transfer_id, OutgoingTransfer {
+            info,
+            file,
+            hasher: blake3::Hasher::new(),
+        });
+
+        Ok(MessageType::FileStart { transfer_id, filename, total_size })
+    }
+
+    /// Next message for an outgoing transfer: chunks, then FileEnd
+    /// Returns None once the transfer has finished or was cancelled
+    pub fn next_message(&mut self, transfer_id: u64) -> Result<Option<MessageType>> {
+        let Some(transfer) = self.outgoing.get_mut(&transfer_id) else {
+            return Ok(None);
+        };
+
+        let mut data = vec![0u8; CHUNK_SIZE];
+        let n = transfer.file.read(&mut data)
+            .with_context(|| format!("Failed to read {}", transfer.info.filename))?;
+
+        if n == 0 {
+            let hash = transfer.hasher.finalize().into();
+            self.outgoing.remove(&transfer_id);
+            return Ok(Some(MessageType::FileEnd { transfer_id, hash }));
+        }
+
+        data.truncate(n);
+        transfer.hasher.update(&data);
+        transfer.info.bytes_done += n as u64;
+        if let Some(on_progress) = self.on_progress.as_mut() {
+            on_progress(&transfer.info);
+        }
+        Ok(Some(MessageType::FileChunk { transfer_id, data }))
+    }
+
+    /// Apply an incoming transfer message
+    /// Returns Ok(None) for messages that are not part of a chunked transfer
+    pub fn handle_incoming(&mut self, message: &MessageType) -> Result<Option<TransferEvent>> {
+        match message {
+            MessageType::FileStart { transfer_id, filename, total_size } => {
+                if self.incoming.contains_key(transfer_id) {
+                    bail!("Duplicate transfer id {}", transfer_id);
+                }
+                let path = self.unused_received_path(filename)?;
+                let file = File::create(&path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                let info = TransferInfo {
+                    transfer_id: *transfer_id,
+                    filename: filename.clone(),
+                    direction: TransferDirection::Incoming,
+                    bytes_done: 0,
+                    total_size: *total_size,
+                };
+                self.incoming.insert(*transfer_id, IncomingTransfer {
+                    info: info.clone(),
+                    path,
+                    file,
+                    hasher: blake3::Hasher::new(),
+                });
+                Ok(Some(TransferEvent::Started(info)))
+            }
+            MessageType::FileChunk { transfer_id, data } => {
+                let transfer = self.incoming.get_mut(transfer_id)
+                    .ok_or_else(|| anyhow!("Chunk for unknown transfer {}", transfer_id))?;
+
+                let done = transfer.info.bytes_done + data.len() as u64;
+                if done > transfer.info.total_size {
+                    let transfer = self.incoming.remove(transfer_id).unwrap();
+                    let _ = fs::remove_file(&transfer.path);
+                    bail!(
+                        "Transfer {} exceeded its declared size of {} bytes",
+                        transfer_id,
+                        transfer.info.total_size,
+                    );
+                }
+
+                transfer.file.write_all(data)
+                    .with_context(|| format!("Failed to write {}", transfer.path.display()))?;
+                transfer.hasher.update(data);
+                transfer.info.bytes_done = done;
+                if let Some(on_progress) = self.on_progress.as_mut() {
+                    on_progress(&transfer.info);
+                }
+                Ok(Some(TransferEvent::Progress(transfer.info.clone())))
+            }
+            MessageType::FileEnd { transfer_id, hash } => {
+                let transfer = self.incoming.remove(transfer_id)
+                    .ok_or_else(|| anyhow!("End of unknown transfer {}", transfer_id))?;
+
+                if transfer.info.bytes_done != transfer.info.total_size {
+                    let _ = fs::remove_file(&transfer.path);
+                    bail!(
+                        "Transfer {} ended after {} of {} bytes",
+                        transfer_id,
+                        transfer.info.bytes_done,
+                        transfer.info.total_size,
+                    );
+                }
+
+                if transfer.hasher.finalize() != *hash {
+                    let _ = fs::remove_file(&transfer.path);
+                    self.record_received(&transfer.info.filename, &transfer.path, transfer.info.bytes_done, false);
+                    return Err(TransferError::IntegrityFailure { transfer_id: *transfer_id }.into());
+                }
+
+                self.record_received(&transfer.info.filename, &transfer.path, transfer.info.bytes_done, true);
+
+                Ok(Some(TransferEvent::Completed {
+                    info: transfer.info,
+                    path: transfer.path,
+                }))
+            }
+            // The peer's uploads are our downloads and the other way round
+            MessageType::FileCancel { transfer_id, sender_side: true } => match self.incoming.remove(transfer_id) {
+                Some(transfer) => {
+                    let _ = fs::remove_file(&transfer.path);
+                    Ok(Some(TransferEvent::Cancelled(transfer.info)))
+                }
+                None => Ok(None),
+            },
+            MessageType::FileCancel { transfer_id, sender_side: false } => {
+                Ok(self.outgoing.remove(transfer_id).map(|transfer| TransferEvent::Cancelled(transfer.info)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// All transfers currently in progress, ordered by id
+    pub fn list(&self) -> Vec<TransferInfo> {
+        let mut transfers: Vec<TransferInfo> = self.outgoing.values()
+            .map(|t| t.info.clone())
+            .chain(self.incoming.values().map(|t| t.info.clone()))
+            .collect();
+        transfers.sort_by_key(|t| (t.transfer_id, t.direction == TransferDirection::Incoming));
+        transfers
+    }
+
+    /// Abandon a transfer locally, returning the FileCancel message to send to the peer
+    /// Partial incoming data is deleted from disk
+    ///
+    /// Outgoing and incoming ids are numbered independently (by us and by
+    /// the peer), so the direction from `list` picks which one is meant.
+    pub fn cancel_transfer(&mut self, transfer_id: u64, direction: TransferDirection) -> Result<MessageType> {
+        match direction {
+            TransferDirection::Outgoing => {
+                self.outgoing.remove(&transfer_id)
+                    .ok_or_else(|| anyhow!("No outgoing transfer with id {}", transfer_id))?;
+            }
+            TransferDirection::Incoming => {
+                let transfer = self.incoming.remove(&transfer_id)
+                    .ok_or_else(|| anyhow!("No incoming transfer with id {}", transfer_id))?;
+                fs::remove_file(&transfer.path)
+                    .with_context(|| format!("Failed to remove {}", transfer.path.display()))?;
+            }
+        }
+        Ok(MessageType::FileCancel {
+            transfer_id,
+            sender_side: direction == TransferDirection::Outgoing,
+        })
+    }
+
+    /// `received_path` for a new chunked transfer, or `received_<n>_<name>`
+    /// if another transfer in progress is already writing to it
+    fn unused_received_path(&self, filename: &str) -> Result<PathBuf> {
+        let path = received_path(&self.download_dir, filename)?;
+        let in_use = |candidate: &Path| self.incoming.values().any(|t| t.path == candidate);
+        if !in_use(&path) {
+            return Ok(path);
+        }
+
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        let base = name.strip_prefix("received_").unwrap_or(name).to_string();
+        (2..)
+            .map(|n| self.download_dir.join(format!("received_{}_{}", n, base)))
+            .find(|candidate| !in_use(candidate))
+            .ok_or_else(|| anyhow!("No free path for {}", filename))
+    }
+
+    /// Save the contents of a File message into the download directory
+    pub fn save_file(&self, filename: &str, data: &[u8]) -> Result<PathBuf> {
+        let path = received_path(&self.download_dir, filename)?;
+        fs::write(&path, data)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        self.record_received(filename, &path, data.len() as u64, true);
+        Ok(path)
+    }
+
+    /// Save every entry of an Archive message into the download directory
+    /// (see the free function `save_archive`)
+    pub fn save_archive(&self, entries: &[(String, Vec<u8>)]) -> Result<Vec<PathBuf>> {
+        let paths = save_archive(&self.download_dir, entries)?;
+        for ((filename, data), path) in entries.iter().zip(&paths) {
+            self.record_received(filename, path, data.len() as u64, true);
+        }
+        Ok(paths)
+    }
+
+    /// Add a received file to the index, if one is set
+    /// A failed write is logged rather than failing the transfer
+    #[cfg_attr(not(feature = "file-index"), allow(unused_variables))]
+    fn record_received(&self, filename: &str, path: &Path, size: u64, integrity_ok: bool) {
+        #[cfg(feature = "file-index")]
+        if let Some((index, sender)) = &self.index {
+            let entry = ReceivedFile::new(sender, filename, path, size, integrity_ok);
+            if let Err(e) = index.record(&entry) {
+                tracing::warn!(error = %e, "failed to record received file");
+            }
+        }
+    }
+}
+
+/// Where a received file is saved: `received_<name>` inside `dir`
+/// Any directory components in the peer-supplied name are discarded
+pub fn received_path(dir: &Path, filename: &str) -> Result<PathBuf> {
+    let name = Path::new(filename)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Invalid filename: {}", filename))?;
+    Ok(dir.join(format!("received_{}", name)))
+}
+
+/// Save every entry of an Archive message into `dir`
+///
+/// All names are sanitized and checked for duplicates before anything is
+/// written; if a write fails, files already saved from this archive are removed.
+pub fn save_archive(dir: &Path, entries: &[(String, Vec<u8>)]) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::with_capacity(entries.len());
+    for (filename, _) in entries {
+        let path = received_path(dir, filename)?;
+        if paths.contains(&path) {
+            bail!("Archive contains {} more than once", filename);
+        }
+        paths.push(path);
+    }
+
+    for (i, ((_, data), path)) in entries.iter().zip(&paths).enumerate() {
+        if let Err(e) = fs::write(path, data) {
+            for written in &paths[..i] {
+                let _ = fs::remove_file(written);
+            }
+            return Err(e).with_context(|| format!("Failed to write {}", path.display()));
+        }
+    }
+    Ok(paths)
+}
+
+/// Associated data binding blob ciphertexts to their purpose
+const BLOB_AAD: &[u8] = b"PINEAPPLE_FILE_REF";
+
+/// A file encrypted for upload to external storage
+///
+/// The app uploads `ciphertext` wherever it likes and sends
+/// `file_ref(url)` through the session; only that small message carries
+/// the key. The key is fresh per blob.
+pub struct EncryptedBlob {
+    pub ciphertext: Vec<u8>,
+    key: [u8; 32],
+    nonce: [u8; 12],
+    hash: [u8; 32],
+    size: u64,
+}
+
+impl EncryptedBlob {
+    /// The message announcing this blob once it is stored at `url`
+    pub fn file_ref(&self, url: &str) -> MessageType {
+        MessageType::FileRef {
+            url: url.to_string(),
+            key: self.key,
+            nonce: self.nonce,
+            hash: self.hash,
+            size: self.size,
+        }
+    }
+}
+
+impl Drop for EncryptedBlob {
+    fn drop(&mut self) {
+        use zeroize::Zeroize;
+        self.key.zeroize();
+    }
+}
+
+/// Encrypt `data` (AES-256-GCM under a random key) for external storage
+pub fn encrypt_blob(data: &[u8]) -> Result<EncryptedBlob> {
+    let key: [u8; 32] = rand::random();
+    let nonce: [u8; 12] = rand::random();
+    let ciphertext = Aes256Gcm::new(&key.into())
+        .encrypt((&nonce).into(), Payload { msg: data, aad: BLOB_AAD })
+        .map_err(|_| anyhow!("Failed to encrypt blob"))?;
+    Ok(EncryptedBlob {
+        hash: blake3::hash(&ciphertext).into(),
+        ciphertext,
+        key,
+        nonce,
+        size: data.len() as u64,
+    })
+}
+
+/// Verify and decrypt a blob the app fetched for a `FileRef`
+///
+/// The hash is checked before decrypting, so a wrong or tampered download
+/// fails with `TransferError::BlobMismatch` without touching the key.
+pub fn open_file_ref(file_ref: &MessageType, blob: &[u8]) -> Result<Vec<u8>> {
+    let MessageType::FileRef { url, key, nonce, hash, size } = file_ref else {
+        bail!("Not a file reference");
+    };
+    let mismatch = || TransferError::BlobMismatch { url: url.clone() };
+
+    if blake3::hash(blob) != *hash {
+        return Err(mismatch().into());
+    }
+    let data = Aes256Gcm::new(key.into())
+        .decrypt(nonce.into(), Payload { msg: blob, aad: BLOB_AAD })
+        .map_err(|_| mismatch())?;
+    if data.len() as u64 != *size {
+        return Err(mismatch().into());
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{deserialize_message, serialize_message};
+    use tempfile::TempDir;
+
+    /// A manager downloading into its own temporary directory
+    fn manager() -> (TempDir, TransferManager) {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = TransferManager::new(dir.path());
+        (dir, manager)
+    }
+
+    /// Deliver `message` over the wire format to `to`
+    fn deliver(to: &mut TransferManager, message: &MessageType) -> Option<TransferEvent> {
+        let message = deserialize_message(&serialize_message(message)).unwrap();
+        to.handle_incoming(&message).unwrap()
+    }
+
+    /// Start sending a two-chunk file and deliver its start and first chunk
+    fn start_upload(from: &mut TransferManager, from_dir: &Path, to: &mut TransferManager, name: &str) -> u64 {
+        let path = from_dir.join(name);
+        fs::write(&path, vec![7u8; CHUNK_SIZE + 10]).unwrap();
+        let start = from.start_send(&path).unwrap();
+        let MessageType::FileStart { transfer_id, .. } = start else { unreachable!() };
+        deliver(to, &start);
+        let chunk = from.next_message(transfer_id).unwrap().unwrap();
+        deliver(to, &chunk);
+        transfer_id
+    }
+
+    fn ids(manager: &TransferManager) -> Vec<(u64, TransferDirection)> {
+        manager.list().iter().map(|t| (t.transfer_id, t.direction)).collect()
+    }
+
+    #[test]
+    fn transfers_are_listed_and_cancelled_from_either_end() {
+        let (alice_dir, mut alice) = manager();
+        let (bob_dir, mut bob) = manager();
+
+        let id = start_upload(&mut alice, alice_dir.path(), &mut bob, "a.bin");
+        assert_eq!(ids(&alice), [(id, TransferDirection::Outgoing)]);
+        assert_eq!(ids(&bob), [(id, TransferDirection::Incoming)]);
+        assert_eq!(bob.list()[0].bytes_done, CHUNK_SIZE as u64);
+        let partial = bob_dir.path().join("received_a.bin");
+        assert!(partial.exists());
+
+        // The receiver gives up: the sender stops sending
+        let cancel = bob.cancel_transfer(id, TransferDirection::Incoming).unwrap();
+        assert!(!partial.exists());
+        assert!(matches!(deliver(&mut alice, &cancel), Some(TransferEvent::Cancelled(info)) if info.transfer_id == id));
+        assert!(alice.list().is_empty());
+        assert!(alice.next_message(id).unwrap().is_none());
+
+        // The sender gives up: the receiver drops its partial file
+        let id = start_upload(&mut alice, alice_dir.path(), &mut bob, "b.bin");
+        let partial = bob_dir.path().join("received_b.bin");
+        assert!(partial.exists());
+        let cancel = alice.cancel_transfer(id, TransferDirection::Outgoing).unwrap();
+        assert!(matches!(deliver(&mut bob, &cancel), Some(TransferEvent::Cancelled(info)) if info.transfer_id == id));
+        assert!(!partial.exists());
+        assert!(bob.list().is_empty());
+
+        assert!(alice.cancel_transfer(id, TransferDirection::Outgoing).is_err());
+    }
+
+    #[test]
+    fn cancel_only_touches_the_transfer_with_that_owner() {
+        let (alice_dir, mut alice) = manager();
+        let (bob_dir, mut bob) = manager();
+
+        // Both sides number their first upload 1
+        let alice_upload = start_upload(&mut alice, alice_dir.path(), &mut bob, "from_alice.bin");
+        let bob_upload = start_upload(&mut bob, bob_dir.path(), &mut alice, "from_bob.bin");
+        assert_eq!(alice_upload, bob_upload);
+
+        let cancel = bob.cancel_transfer(bob_upload, TransferDirection::Outgoing).unwrap();
+        let Some(TransferEvent::Cancelled(info)) = deliver(&mut alice, &cancel) else {
+            panic!("bob's upload was not cancelled");
+        };
+        assert_eq!(info.direction, TransferDirection::Incoming);
+        assert_eq!(info.filename, "from_bob.bin");
+        assert!(!alice_dir.path().join("received_from_bob.bin").exists());
+
+        // Alice's own upload with the same id carries on
+        assert_eq!(ids(&alice), [(alice_upload, TransferDirection::Outgoing)]);
+        let chunk = alice.next_message(alice_upload).unwrap().unwrap();
+        assert!(matches!(deliver(&mut bob, &chunk), Some(TransferEvent::Progress(_))));
+        let end = alice.next_message(alice_upload).unwrap().unwrap();
+        assert!(matches!(deliver(&mut bob, &end), Some(TransferEvent::Completed { .. })));
+    }
+
+    #[test]
+    fn same_name_transfers_write_separate_files() {
+        let (dir, mut bob) = manager();
+        let start = |transfer_id| MessageType::FileStart { transfer_id, filename: "same.txt".into(), total_size: 5 };
+        let chunk = |transfer_id, data: &[u8]| MessageType::FileChunk { transfer_id, data: data.to_vec() };
+        let end = |transfer_id, data: &[u8]| MessageType::FileEnd { transfer_id, hash: blake3::hash(data).into() };
+
+        bob.handle_incoming(&start(1)).unwrap();
+        bob.handle_incoming(&start(2)).unwrap();
+        bob.handle_incoming(&chunk(1, b"first")).unwrap();
+        bob.handle_incoming(&chunk(2, b"other")).unwrap();
+
+        let Some(TransferEvent::Completed { path: first, .. }) = bob.handle_incoming(&end(1, b"first")).unwrap() else {
+            panic!("first transfer did not complete");
+        };
+        let Some(TransferEvent::Completed { path: second, .. }) = bob.handle_incoming(&end(2, b"other")).unwrap() else {
+            panic!("second transfer did not complete");
+        };
+        assert_eq!(first, dir.path().join("received_same.txt"));
+        assert_eq!(second, dir.path().join("received_2_same.txt"));
+        assert_eq!(fs::read(first).unwrap(), b"first");
+        assert_eq!(fs::read(second).unwrap(), b"other");
+    }
+
+    #[test]
+    fn directories_become_archives_saved_all_or_nothing() {
+        let source = tempfile::tempdir().unwrap();
+        fs::write(source.path().join("b.txt"), b"bee").unwrap();
+        fs::write(source.path().join("a.txt"), b"ay").unwrap();
+        fs::create_dir(source.path().join("nested")).unwrap();
+        let input = format!("/file {}", source.path().display());
+        let Ok(crate::messages::ParsedInput::Message(MessageType::Archive { entries })) = crate::messages::parse_input(&input)
+        else {
+            panic!("the directory was not read as an archive");
+        };
+        assert_eq!(entries, vec![("a.txt".to_string(), b"ay".to_vec()), ("b.txt".to_string(), b"bee".to_vec())]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let paths = save_archive(dir.path(), &entries).unwrap();
+        assert_eq!(paths, vec![dir.path().join("received_a.txt"), dir.path().join("received_b.txt")]);
+        assert_eq!(fs::read(&paths[1]).unwrap(), b"bee");
+
+        // Names that collide once sanitized are refused before anything is written
+        let dir = tempfile::tempdir().unwrap();
+        let duplicates = vec![("x/a.txt".to_string(), vec![1]), ("a.txt".to_string(), vec![2])];
+        assert!(save_archive(dir.path(), &duplicates).is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // A failed write removes the files already saved
+        fs::create_dir(dir.path().join("received_b.txt")).unwrap();
+        assert!(save_archive(dir.path(), &entries).is_err());
+        assert!(!dir.path().join("received_a.txt").exists());
+    }
+
+    #[test]
+    fn files_failing_the_hash_check_are_deleted() {
+        for corrupt in [false, true] {
+            let (alice_dir, mut alice) = manager();
+            let (bob_dir, mut bob) = manager();
+            let transfer_id = start_upload(&mut alice, alice_dir.path(), &mut bob, "report.bin");
+            let path = bob_dir.path().join("received_report.bin");
+            assert!(path.exists());
+
+            deliver(&mut bob, &alice.next_message(transfer_id).unwrap().unwrap());
+            let Some(MessageType::FileEnd { transfer_id, mut hash }) = alice.next_message(transfer_id).unwrap() else {
+                panic!("the transfer did not end");
+            };
+            assert_eq!(hash, *blake3::hash(&vec![7u8; CHUNK_SIZE + 10]).as_bytes());
+            if corrupt {
+                hash[0] ^= 1;
+            }
+
+            let end = MessageType::FileEnd { transfer_id, hash };
+            let result = bob.handle_incoming(&deserialize_message(&serialize_message(&end)).unwrap());
+            if corrupt {
+                let error = result.unwrap_err();
+                assert!(matches!(error.downcast_ref(), Some(TransferError::IntegrityFailure { transfer_id: id }) if *id == transfer_id));
+                assert!(!path.exists());
+            } else {
+                assert!(matches!(result.unwrap(), Some(TransferEvent::Completed { .. })));
+                assert_eq!(fs::read(&path).unwrap().len(), CHUNK_SIZE + 10);
+            }
+            assert!(bob.list().is_empty());
+        }
+    }
+
+    #[test]
+    fn progress_is_reported_for_every_chunk_both_ways() {
+        let log = |manager: &mut TransferManager| {
+            let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let sink = seen.clone();
+            manager.set_progress_handler(move |info| sink.lock().unwrap().push((info.direction, info.bytes_done, info.total_size)));
+            seen
+        };
+        let (alice_dir, mut alice) = manager();
+        let (_bob_dir, mut bob) = manager();
+        let sent = log(&mut alice);
+        let received = log(&mut bob);
+
+        let transfer_id = start_upload(&mut alice, alice_dir.path(), &mut bob, "big.bin");
+        let last = alice.next_message(transfer_id).unwrap().unwrap();
+        let Some(TransferEvent::Progress(info)) = deliver(&mut bob, &last) else {
+            panic!("a received chunk was not reported as progress");
+        };
+        assert_eq!(info.bytes_done, info.total_size);
+
+        let total = (CHUNK_SIZE + 10) as u64;
+        let expected = |direction| vec![(direction, CHUNK_SIZE as u64, total), (direction, total, total)];
+        assert_eq!(*sent.lock().unwrap(), expected(TransferDirection::Outgoing));
+        assert_eq!(*received.lock().unwrap(), expected(TransferDirection::Incoming));
+    }
+
+    #[test]
+    fn file_refs_open_only_the_blob_they_describe() {
+        let data = vec![9u8; 1000];
+        let blob = encrypt_blob(&data).unwrap();
+        assert_ne!(blob.ciphertext, data);
+        let file_ref = deserialize_message(&serialize_message(&blob.file_ref("https://example.com/blob"))).unwrap();
+        assert_eq!(open_file_ref(&file_ref, &blob.ciphertext).unwrap(), data);
+
+        let refused = |file_ref: &MessageType, blob: &[u8]| {
+            let error = open_file_ref(file_ref, blob).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(TransferError::BlobMismatch { url }) if url == "https://example.com/blob"));
+        };
+        let mut tampered = blob.ciphertext.clone();
+        tampered[0] ^= 1;
+        refused(&file_ref, &tampered);
+        refused(&file_ref, &blob.ciphertext[1..]);
+        // The same data encrypted again is a different blob
+        let other = encrypt_blob(&data).unwrap();
+        refused(&file_ref, &other.ciphertext);
+        let MessageType::FileRef { url, key, nonce, hash, .. } = &file_ref else { unreachable!() };
+        let resized = MessageType::FileRef { url: url.clone(), key: *key, nonce: *nonce, hash: *hash, size: 999 };
+        refused(&resized, &blob.ciphertext);
+
+        assert!(open_file_ref(&MessageType::Payload { data: Vec::new() }, &blob.ciphertext).is_err());
+    }
+}
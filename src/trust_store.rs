@@ -0,0 +1,154 @@
+/**
+ * trust_store.rs
+ *
+ * Trust-on-first-use pins of peer identity keys, kept in a file
+ */
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How an identity key compares with the one pinned for a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrustResult {
+    /// Nothing pinned for this peer yet
+    New,
+    Matched,
+    /// A different key is pinned: possibly a man in the middle, or the
+    /// peer reinstalled. Verify out of band before revoking the old pin.
+    Changed,
+}
+
+/// A pinned identity key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustedPeer {
+    pub fingerprint: String,
+    /// Hex of the Ed25519 identity key
+    pub identity_key: String,
+    /// Seconds since the Unix epoch
+    pub pinned_at: u64,
+}
+
+/// Pinned identity keys by peer fingerprint, saved as a JSON list
+///
+/// The fingerprint is whatever the peer is dialled by (a key fingerprint
+/// or a signalling username). Every change is written straight away.
+pub struct TrustStore {
+    path: PathBuf,
+    peers: BTreeMap<String, TrustedPeer>,
+}
+
+impl TrustStore {
+    /// Load the store at `path` (empty if the file does not exist yet)
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let mut peers = BTreeMap::new();
+        if path.exists() {
+            let json = fs::read(&path)
+                .with_context(|| format!("Failed to read trust store: {}", path.display()))?;
+            let list: Vec<TrustedPeer> = serde_json::from_slice(&json)
+                .context("Failed to decode trust store")?;
+            for peer in list {
+                peers.insert(peer.fingerprint.clone(), peer);
+            }
+        }
+        Ok(Self { path, peers })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Compare `identity_key` with the key pinned for `fingerprint`
+    pub fn verify(&self, fingerprint: &str, identity_key: &[u8]) -> TrustResult {
+        match self.peers.get(fingerprint) {
+            None => TrustResult::New,
+            Some(peer) if peer.identity_key == hex::encode(identity_key) => TrustResult::Matched,
+            Some(_) => TrustResult::Changed,
+        }
+    }
+
+    /// Pin `identity_key` for `fingerprint`, replacing any earlier pin
+    pub fn record(&mut self, fingerprint: &str, identity_key: &[u8]) -> Result<()> {
+        let peer = TrustedPeer {
+            fingerprint: fingerprint.to_string(),
+            identity_key: hex::encode(identity_key),
+            pinned_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+        self.peers.insert(fingerprint.to_string(), peer);
+        self.save()
+    }
+
+    /// Forget the pin for `fingerprint`; the next key seen is `New` again
+    ///
+    /// Returns whether anything was pinned.
+    pub fn revoke(&mut self, fingerprint: &str) -> Result<bool> {
+        if self.peers.remove(fingerprint).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Every pinned peer, by fingerprint
+    pub fn peers(&self) -> Vec<&TrustedPeer> {
+        self.peers.values().collect()
+    }
+
+    /// Write through a temporary file so a crash cannot leave half a store
+    fn save(&self) -> Result<()> {
+        let list: Vec<&TrustedPeer> = self.peers.values().collect();
+        let json = serde_json::to_vec_pretty(&list).context("Trust store serialization failed")?;
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, json)
+            .with_context(|| format!("Failed to write trust store: {}", temp.display()))?;
+        fs::rename(&temp, &self.path)
+            .with_context(|| format!("Failed to write trust store: {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_are_checked_revoked_and_reloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trust.json");
+        let mut store = TrustStore::open(&path).unwrap();
+        assert!(store.peers().is_empty());
+
+        assert_eq!(store.verify("alice", b"key one"), TrustResult::New);
+        store.record("alice", b"key one").unwrap();
+        store.record("bob", b"key two").unwrap();
+        assert_eq!(store.verify("alice", b"key one"), TrustResult::Matched);
+        assert_eq!(store.verify("alice", b"key two"), TrustResult::Changed);
+
+        let reloaded = TrustStore::open(&path).unwrap();
+        assert_eq!(reloaded.peers(), store.peers());
+        assert_eq!(reloaded.verify("bob", b"key two"), TrustResult::Matched);
+        assert!(!path.with_extension("tmp").exists());
+
+        assert!(store.revoke("alice").unwrap());
+        assert!(!store.revoke("alice").unwrap());
+        assert_eq!(store.verify("alice", b"key one"), TrustResult::New);
+        let reloaded = TrustStore::open(&path).unwrap();
+        assert_eq!(reloaded.verify("alice", b"key one"), TrustResult::New);
+        assert_eq!(reloaded.verify("bob", b"key two"), TrustResult::Matched);
+    }
+
+    #[test]
+    fn corrupt_store_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trust.json");
+        fs::write(&path, b"not json").unwrap();
+        assert!(TrustStore::open(&path).is_err());
+    }
+}